@@ -0,0 +1,105 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Pieces to build made-up words from, so that the corpus doesn't need a
+// dictionary.
+const SYLLABLES: [&str; 12] = [
+    "ka", "lo", "mi", "ren", "tu", "sha", "vo", "ne", "dri", "pel", "xo", "quin",
+];
+
+// How often, in words, a sentence ends, and how often a paragraph does.
+const SENTENCE_WORDS: u64 = 12;
+const PARAGRAPH_SENTENCES: u64 = 6;
+
+// Synthetic text for the benchmarks.  The same seed always produces the
+// same text, so that runs compare, and words repeat roughly the way they
+// do in real writing, with a few everywhere and most of them rare.
+pub struct Corpus {
+    state: u64,
+    vocabulary: Vec<String>,
+}
+
+impl Corpus {
+    // Start a corpus drawing on the given number of distinct words.
+    pub fn new(seed: u64, vocabulary_size: usize) -> Corpus {
+        Corpus {
+            // The generator never leaves zero, so avoid starting there.
+            state: seed | 1,
+            vocabulary: (0..vocabulary_size).map(made_up_word).collect(),
+        }
+    }
+
+    // The next pseudo-random number, by xorshift.
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Pick a word, favoring the start of the vocabulary, so that the
+    // chance of each word falls off about the way Zipf's law says.
+    pub fn word(&mut self) -> &str {
+        let uniform = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        let index = (self.vocabulary.len() as f64).powf(uniform) as usize - 1;
+
+        &self.vocabulary[index.min(self.vocabulary.len() - 1)]
+    }
+
+    // The word at the given rank, where the first is the most common.
+    pub fn ranked_word(&self, rank: usize) -> &str {
+        &self.vocabulary[rank]
+    }
+
+    // Write a document of the given number of words, in sentences and
+    // paragraphs.
+    pub fn document(&mut self, words: usize) -> String {
+        let mut text = String::new();
+
+        for n in 1..=words as u64 {
+            let word = self.word().to_string();
+
+            text.push_str(&word);
+            if n % (SENTENCE_WORDS * PARAGRAPH_SENTENCES) == 0 {
+                text.push_str(".\n\n");
+            } else if n % SENTENCE_WORDS == 0 {
+                text.push_str(". ");
+            } else {
+                text.push(' ');
+            }
+        }
+
+        text
+    }
+
+    // Fill a folder with documents, returning their paths.
+    pub fn write_files(
+        &mut self,
+        dir: &Path,
+        count: usize,
+        words: usize,
+    ) -> Vec<PathBuf> {
+        fs::create_dir_all(dir).unwrap();
+        (0..count)
+            .map(|n| {
+                let path = dir.join(format!("note-{}.md", n));
+
+                fs::write(&path, self.document(words)).unwrap();
+                path
+            })
+            .collect()
+    }
+}
+
+// Spell out a number in syllables, which gives every number its own word.
+fn made_up_word(index: usize) -> String {
+    let mut word = String::new();
+    let mut n = index + SYLLABLES.len();
+
+    while n > 0 {
+        word.push_str(SYLLABLES[n % SYLLABLES.len()]);
+        n /= SYLLABLES.len();
+    }
+
+    word
+}
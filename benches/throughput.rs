@@ -0,0 +1,180 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use intern::config::{accents, punctuation, RankingConfig};
+use intern::indexer::{index_file, stem_word};
+use intern::query::{collate_search, sort_search_results, Cancellation, SearchResult};
+use intern::store::{take_index_changes, ChangeKind, IndexTuple, Store, WordStem};
+use regex::Regex;
+use rust_stemmers::{Algorithm, Stemmer};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+mod corpus;
+
+use corpus::Corpus;
+
+// The size of the corpus, which is a few notes' worth of text.
+const VOCABULARY: usize = 5000;
+const FILES: usize = 20;
+const FILE_WORDS: usize = 2000;
+
+// How many tuples to insert at once, which is about what a long file has.
+const TUPLES: usize = 50_000;
+
+// Everything the indexer needs besides the database.
+struct Tools {
+    punc: Regex,
+    accents: Regex,
+    stemmer: Stemmer,
+}
+
+impl Tools {
+    fn new() -> Tools {
+        Tools {
+            punc: punctuation(),
+            accents: accents(&gjson::parse("{}")),
+            stemmer: Stemmer::create(Algorithm::English),
+        }
+    }
+}
+
+// A fresh database with its tables.
+fn empty_store() -> Store {
+    let store = Store::open_in_memory().unwrap();
+
+    store.enforce_data_model();
+    store
+}
+
+// Index every file, as the startup scan would, into a fresh database.
+fn index_all(files: &[PathBuf], tools: &Tools) -> Store {
+    let store = empty_store();
+
+    for path in files {
+        index_file(
+            &store,
+            path,
+            0,
+            ChangeKind::Added,
+            &tools.punc,
+            &tools.accents,
+            &tools.stemmer,
+            1,
+        )
+        .unwrap();
+    }
+
+    // Nobody's listening for changes, so don't let them pile up.
+    take_index_changes();
+    store
+}
+
+// Look up the stem of a word in the index.
+fn indexed_stem(store: &Store, word: &str, tools: &Tools) -> WordStem {
+    let stem = stem_word(word, &tools.accents, &tools.stemmer);
+    let id = store.select_all_stems().unwrap()[&stem];
+
+    WordStem { id, stem }
+}
+
+fn corpus_files(dir: &Path) -> Vec<PathBuf> {
+    Corpus::new(1, VOCABULARY).write_files(dir, FILES, FILE_WORDS)
+}
+
+fn bench_indexing(c: &mut Criterion) {
+    let dir = std::env::temp_dir().join(format!("intern-bench-{}", std::process::id()));
+    let files = corpus_files(&dir);
+    let tools = Tools::new();
+    let bytes = files
+        .iter()
+        .map(|p| fs::metadata(p).unwrap().len())
+        .sum::<u64>();
+    let mut group = c.benchmark_group("indexing");
+
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(bytes));
+    group.bench_function("index_file", |b| b.iter(|| index_all(&files, &tools)));
+    group.finish();
+
+    let mut group = c.benchmark_group("storage");
+
+    group.throughput(Throughput::Elements(TUPLES as u64));
+    group.bench_function("insert_bulk_word_tuples", |b| {
+        b.iter_batched(
+            || {
+                let store = empty_store();
+                let file = store.insert_file(Path::new("/notes/a.md"), &1).unwrap();
+                let stems = store
+                    .insert_bulk_stems((0..100).map(|n| format!("stem{}", n)).collect())
+                    .unwrap();
+                let tuples = (0..TUPLES)
+                    .map(|n| IndexTuple {
+                        file: file.id,
+                        stem: stems[&format!("stem{}", n % 100)],
+                        offset: n as u32,
+                        byte_offset: n as u32 * 6,
+                        word: format!("word{}", n % 100),
+                    })
+                    .collect::<Vec<_>>();
+
+                (store, tuples)
+            },
+            |(store, tuples)| {
+                store.insert_bulk_word_tuples(tuples, &tools.accents).unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn bench_searching(c: &mut Criterion) {
+    let dir =
+        std::env::temp_dir().join(format!("intern-bench-search-{}", std::process::id()));
+    let files = corpus_files(&dir);
+    let tools = Tools::new();
+    let store = index_all(&files, &tools);
+    let corpus = Corpus::new(1, VOCABULARY);
+    // The most common word, and a couple from further down the list, which
+    // still show up in most of the files.
+    let words = [0, 10, 100].map(|rank| corpus.ranked_word(rank).to_string());
+    let stems = || {
+        words
+            .iter()
+            .map(|w| indexed_stem(&store, w, &tools))
+            .collect::<Vec<_>>()
+    };
+    let stem_ids = stems().iter().map(|s| s.id).collect::<Vec<_>>();
+    let results: Vec<SearchResult> = store
+        .search_index(stems(), &Cancellation::never())
+        .unwrap();
+    let query = words.iter().map(|w| w.as_str()).collect::<Vec<_>>();
+    let ranking = RankingConfig::new(&gjson::parse("{}"));
+    let mut group = c.benchmark_group("searching");
+
+    group.bench_function("search_index", |b| {
+        b.iter_batched(
+            stems,
+            |stems| store.search_index(stems, &Cancellation::never()).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+    group.throughput(Throughput::Elements(results.len() as u64));
+    group.bench_function("collate_and_rank", |b| {
+        b.iter_batched(
+            || results.clone(),
+            |results| {
+                let never = Cancellation::never();
+                let collated = collate_search(results, stem_ids.clone(), &never);
+
+                sort_search_results(&collated, query.clone(), &ranking)
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+criterion_group!(benches, bench_indexing, bench_searching);
+criterion_main!(benches);
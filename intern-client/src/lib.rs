@@ -0,0 +1,502 @@
+use chrono::NaiveDate;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+
+// A connection to a running INTERN daemon.  The daemon answers one query
+// per connection, so each request opens a fresh socket.
+#[derive(Clone, Debug)]
+pub struct Client {
+    address: SocketAddr,
+    token: Option<String>,
+    max_results: Option<usize>,
+}
+
+// One run of the daemon, as reported by `@stats`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunStats {
+    pub current: bool,
+    pub started: String,
+    pub uptime_seconds: u64,
+    pub scan_seconds: Option<f64>,
+    pub files_indexed: u64,
+    pub events: u64,
+    pub queries: u64,
+}
+
+// Totals across every run the database remembers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotalStats {
+    pub runs: u64,
+    pub average_scan_seconds: f64,
+    pub files_indexed: u64,
+    pub events: u64,
+    pub queries: u64,
+}
+
+// The line holding a file's best match, as reported by `@lines`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineMatch {
+    pub path: PathBuf,
+    pub line: usize,
+    pub text: String,
+}
+
+// How up to date the index is, with how many files it has, when it last
+// indexed one, if ever, and how many have changed since they were indexed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexStats {
+    pub files: u64,
+    pub last_indexed: Option<String>,
+    pub changed: u64,
+}
+
+// What the latest integrity check found, among the files it sampled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IntegrityStats {
+    pub checked: String,
+    pub sampled: u64,
+    pub missing: u64,
+    pub stale: u64,
+    pub drifted: u64,
+}
+
+// The daemon's statistics, most recent run first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    pub runs: Vec<RunStats>,
+    pub total: Option<TotalStats>,
+    pub index: Option<IndexStats>,
+    pub integrity: Option<IntegrityStats>,
+}
+
+impl Client {
+    // Find the daemon at the given address, which should match the
+    // `server` block of its configuration.
+    pub fn connect<A: ToSocketAddrs>(address: A) -> io::Result<Client> {
+        match address.to_socket_addrs()?.next() {
+            Some(address) => Ok(Client {
+                address,
+                token: None,
+                max_results: None,
+            }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to connect to",
+            )),
+        }
+    }
+
+    // Present a token with every query, for a daemon that requires one.
+    pub fn with_token(mut self, token: &str) -> Client {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    // Ask for at most this many results from every query, rather than the
+    // daemon's `maxResults`.
+    pub fn with_max_results(mut self, max: usize) -> Client {
+        self.max_results = Some(max);
+        self
+    }
+
+    // Write a query the way the daemon expects it, after any token and
+    // limit on the results.
+    fn request(&self, query: &str) -> String {
+        let query = match self.max_results {
+            Some(max) => format!("max:{} {}", max, query),
+            None => query.to_string(),
+        };
+
+        match &self.token {
+            Some(token) => format!("token:{} {}\n", token, query),
+            None => format!("{}\n", query),
+        }
+    }
+
+    // Send a query and return the lines of the response, without the
+    // empty line that ends it.  A query that found nothing has no lines,
+    // and one the daemon couldn't answer is an error.
+    pub fn query(&self, query: &str) -> io::Result<Vec<String>> {
+        let mut stream = TcpStream::connect(self.address)?;
+        let mut response = String::new();
+
+        stream.write_all(self.request(query).as_bytes())?;
+        stream.read_to_string(&mut response)?;
+
+        let lines = response
+            .lines()
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_string())
+            .collect::<Vec<_>>();
+
+        match lines.first().map(|l| parse_error(l)) {
+            Some(Some(("empty", _))) => Ok(Vec::new()),
+            Some(Some((_, _))) => Err(daemon_error(&lines[0])),
+            _ => Ok(lines),
+        }
+    }
+
+    // Send a query, leaving out the line saying that the daemon cut the
+    // results short, if it did.
+    fn found(&self, query: &str) -> io::Result<Vec<String>> {
+        let mut lines = self.query(query)?;
+
+        lines.retain(|l| parse_truncated(l).is_none());
+        Ok(lines)
+    }
+
+    // Search for files containing every term, best match first.
+    pub fn search(&self, terms: &str) -> io::Result<Vec<PathBuf>> {
+        Ok(self.found(terms)?.into_iter().map(PathBuf::from).collect())
+    }
+
+    // Search, returning the line with each file's best match, best file
+    // first.
+    pub fn lines(&self, terms: &str) -> io::Result<Vec<LineMatch>> {
+        self.found(&format!("@lines {}", terms))?
+            .iter()
+            .map(|l| parse_line_match(l))
+            .collect()
+    }
+
+    // List the files modified on the given day.
+    pub fn files_on(&self, date: NaiveDate) -> io::Result<Vec<PathBuf>> {
+        let query = format!("@on {}", date.format("%Y-%m-%d"));
+
+        Ok(self.found(&query)?.into_iter().map(PathBuf::from).collect())
+    }
+
+    // List the files modified from one day through another.
+    pub fn files_between(
+        &self,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> io::Result<Vec<PathBuf>> {
+        let query = format!(
+            "@between {} {}",
+            start.format("%Y-%m-%d"),
+            end.format("%Y-%m-%d")
+        );
+
+        Ok(self.found(&query)?.into_iter().map(PathBuf::from).collect())
+    }
+
+    // Register a standing query, returning the path of each file as it
+    // starts matching.  Waiting for the next one blocks until the daemon
+    // indexes such a file.
+    pub fn subscribe(
+        &self,
+        terms: &str,
+    ) -> io::Result<impl Iterator<Item = io::Result<PathBuf>>> {
+        let mut stream = TcpStream::connect(self.address)?;
+
+        stream.write_all(self.request(&format!("@subscribe {}", terms)).as_bytes())?;
+
+        let mut lines = BufReader::new(stream).lines();
+
+        match lines.next() {
+            Some(Ok(line)) if line == "subscribed" => {
+                Ok(lines.map(|l| l.map(PathBuf::from)))
+            }
+            Some(Ok(line)) if parse_error(&line).is_some() => Err(daemon_error(&line)),
+            Some(Ok(line)) => Err(malformed(&line)),
+            Some(Err(e)) => Err(e),
+            None => Err(malformed("")),
+        }
+    }
+
+    // Retrieve statistics for recent runs and the totals.
+    pub fn stats(&self) -> io::Result<Stats> {
+        parse_stats(&self.query("@stats")?)
+    }
+}
+
+// Interpret the lines of a `@stats` response.
+pub fn parse_stats(lines: &[String]) -> io::Result<Stats> {
+    let mut stats = Stats {
+        runs: Vec::new(),
+        total: None,
+        index: None,
+        integrity: None,
+    };
+
+    for line in lines {
+        if let Some(total) = line.strip_prefix("total: ") {
+            let fields = split_fields(total);
+
+            stats.total = Some(TotalStats {
+                runs: leading_number(field(&fields, 0)?)?,
+                average_scan_seconds: leading_number(
+                    field(&fields, 1)?.trim_start_matches("average scan "),
+                )?,
+                files_indexed: leading_number(field(&fields, 2)?)?,
+                events: leading_number(field(&fields, 3)?)?,
+                queries: leading_number(field(&fields, 4)?)?,
+            });
+            continue;
+        }
+
+        if let Some(index) = line.strip_prefix("index: ") {
+            let fields = split_fields(index);
+            let last = field(&fields, 1)?.trim_start_matches("last indexed ");
+
+            stats.index = Some(IndexStats {
+                files: leading_number(field(&fields, 0)?)?,
+                last_indexed: if last == "never" {
+                    None
+                } else {
+                    Some(last.to_string())
+                },
+                changed: leading_number(field(&fields, 2)?)?,
+            });
+            continue;
+        }
+
+        if let Some(integrity) = line.strip_prefix("integrity: ") {
+            let fields = split_fields(integrity);
+
+            stats.integrity = Some(IntegrityStats {
+                checked: field(&fields, 0)?
+                    .trim_start_matches("last checked ")
+                    .to_string(),
+                sampled: leading_number(field(&fields, 1)?)?,
+                missing: leading_number(field(&fields, 2)?)?,
+                stale: leading_number(field(&fields, 3)?)?,
+                drifted: leading_number(field(&fields, 4)?)?,
+            });
+            continue;
+        }
+
+        // The timestamp has colons, but never a colon and a space.
+        let (run, details) = match line.split_once(": ") {
+            Some(parts) => parts,
+            None => return Err(malformed(line)),
+        };
+        let (kind, started) = match run.split_once(' ') {
+            Some(parts) => parts,
+            None => return Err(malformed(line)),
+        };
+        let fields = split_fields(details);
+        let scan = field(&fields, 1)?.trim_start_matches("scan ");
+
+        stats.runs.push(RunStats {
+            current: kind == "current",
+            started: started.to_string(),
+            uptime_seconds: leading_number(field(&fields, 0)?.trim_start_matches("up "))?,
+            scan_seconds: if scan == "unfinished" {
+                None
+            } else {
+                Some(leading_number(scan)?)
+            },
+            files_indexed: leading_number(field(&fields, 2)?)?,
+            events: leading_number(field(&fields, 3)?)?,
+            queries: leading_number(field(&fields, 4)?)?,
+        });
+    }
+
+    Ok(stats)
+}
+
+// Interpret a `path:line:text` line.  Paths and text can both contain
+// colons, so look for the first colon-delimited line number.
+pub fn parse_line_match(line: &str) -> io::Result<LineMatch> {
+    for (index, _) in line.match_indices(':') {
+        let rest = &line[index + 1..];
+
+        if let Some((number, text)) = rest.split_once(':') {
+            if let Ok(number) = number.parse::<usize>() {
+                return Ok(LineMatch {
+                    path: PathBuf::from(&line[..index]),
+                    line: number,
+                    text: text.to_string(),
+                });
+            }
+        }
+    }
+
+    Err(malformed(line))
+}
+
+// Break the details of a run into its comma-separated fields.
+fn split_fields(text: &str) -> Vec<&str> {
+    text.split(", ").collect()
+}
+
+// Pick out one field, complaining if the response was too short.
+fn field<'a>(fields: &[&'a str], index: usize) -> io::Result<&'a str> {
+    fields
+        .get(index)
+        .copied()
+        .ok_or_else(|| malformed(&fields.join(", ")))
+}
+
+// Parse the number at the start of a field like `12 files indexed` or
+// `3.5s`.
+fn leading_number<T: std::str::FromStr>(text: &str) -> io::Result<T> {
+    let number = text
+        .split(|c: char| !c.is_ascii_digit() && c != '.')
+        .next()
+        .unwrap_or("");
+
+    number.parse::<T>().map_err(|_| malformed(text))
+}
+
+// The number of results the daemon cut a response short at, from a
+// `truncated at N` line.
+pub fn parse_truncated(line: &str) -> Option<usize> {
+    line.strip_prefix("truncated at ")?.parse().ok()
+}
+
+// Split an `error: kind: message` line into its kind and message.
+pub fn parse_error(line: &str) -> Option<(&str, &str)> {
+    line.strip_prefix("error: ")?.split_once(": ")
+}
+
+// An error line from the daemon, as an error for the caller.
+fn daemon_error(line: &str) -> io::Error {
+    io::Error::other(line.trim_start_matches("error: "))
+}
+
+// Report a response we don't understand.
+fn malformed(text: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("unexpected response: {}", text),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn parse_stats_reads_runs_and_totals() {
+        let lines = vec![
+            "current 2021-10-04 09:15:00: up 30s, scan 1.5s, \
+             12 files indexed, 3 events, 2 queries"
+                .to_string(),
+            "run 2021-10-03 08:00:00: up 600s, scan unfinished, \
+             0 files indexed, 0 events, 0 queries"
+                .to_string(),
+            "total: 2 runs, average scan 1.5s, 12 files indexed, 3 events, 2 queries"
+                .to_string(),
+            "index: 9 files, last indexed 2021-10-04 09:15:01, 1 changed since"
+                .to_string(),
+            "integrity: last checked 2021-10-04 10:00:00, 5 files sampled, \
+             0 missing, 1 stale, 0 drifted"
+                .to_string(),
+        ];
+        let stats = parse_stats(&lines).unwrap();
+
+        assert_eq!(stats.runs.len(), 2);
+        assert!(stats.runs[0].current);
+        assert_eq!(stats.runs[0].started, "2021-10-04 09:15:00");
+        assert_eq!(stats.runs[0].uptime_seconds, 30);
+        assert_eq!(stats.runs[0].scan_seconds, Some(1.5));
+        assert_eq!(stats.runs[1].scan_seconds, None);
+        assert_eq!(stats.total.unwrap().files_indexed, 12);
+        assert_eq!(
+            stats.index,
+            Some(IndexStats {
+                files: 9,
+                last_indexed: Some("2021-10-04 09:15:01".to_string()),
+                changed: 1,
+            })
+        );
+        assert_eq!(stats.integrity.map(|i| (i.sampled, i.stale)), Some((5, 1)));
+    }
+
+    #[test]
+    fn parse_stats_rejects_garbage() {
+        assert!(parse_stats(&["nonsense".to_string()]).is_err());
+    }
+
+    #[test]
+    fn parse_line_match_allows_colons() {
+        let found = parse_line_match("/notes/a:b.md:12:Note: foxes").unwrap();
+
+        assert_eq!(found.path, PathBuf::from("/notes/a:b.md"));
+        assert_eq!(found.line, 12);
+        assert_eq!(found.text, "Note: foxes");
+    }
+
+    #[test]
+    fn truncation_is_not_a_result() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client::connect(listener.local_addr().unwrap())
+            .unwrap()
+            .with_token("3f9a")
+            .with_max_results(1);
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = String::new();
+
+            BufReader::new(&stream).read_line(&mut query).unwrap();
+            stream.write_all(b"/a.md\ntruncated at 1\n").unwrap();
+            query
+        });
+
+        assert_eq!(client.search("fox").unwrap(), vec![PathBuf::from("/a.md")]);
+        assert_eq!(server.join().unwrap(), "token:3f9a max:1 fox\n");
+        assert_eq!(parse_truncated("truncated at 50"), Some(50));
+    }
+
+    #[test]
+    fn error_lines_become_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client::connect(listener.local_addr().unwrap()).unwrap();
+        let server = thread::spawn(move || {
+            for response in [&b"error: empty: no results\n"[..], b"error: parse: bad\n"] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut query = String::new();
+
+                BufReader::new(&stream).read_line(&mut query).unwrap();
+                stream.write_all(response).unwrap();
+            }
+        });
+
+        assert!(client.search("fox").unwrap().is_empty());
+        assert_eq!(
+            client.query("@on soon").unwrap_err().to_string(),
+            "parse: bad"
+        );
+        server.join().unwrap();
+        assert_eq!(
+            parse_error("error: database: locked"),
+            Some(("database", "locked"))
+        );
+        assert_eq!(parse_error("/notes/error: a.md"), None);
+    }
+
+    #[test]
+    fn tokens_come_before_the_query() {
+        let client = Client::connect("127.0.0.1:1").unwrap();
+
+        assert_eq!(client.request("@stats"), "@stats\n");
+        assert_eq!(
+            client.with_token("abc").request("@stats"),
+            "token:abc @stats\n"
+        );
+    }
+
+    #[test]
+    fn search_sends_the_query_and_splits_the_response() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = Client::connect(listener.local_addr().unwrap()).unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut query = [0; 64];
+            let size = stream.read(&mut query).unwrap();
+
+            stream.write_all(b"/a.md\n/b.md\n").unwrap();
+            String::from_utf8_lossy(&query[..size]).to_string()
+        });
+        let results = client.search("fox").unwrap();
+
+        assert_eq!(server.join().unwrap(), "fox\n");
+        assert_eq!(results, vec![PathBuf::from("/a.md"), PathBuf::from("/b.md")]);
+    }
+}
@@ -0,0 +1,132 @@
+use notify_rust::Notification;
+use regex::Regex;
+use std::process::Command;
+use std::thread;
+use tracing::{error, info, warn};
+
+use crate::config::RankingConfig;
+use crate::normalizer::Normalizer;
+use crate::query::{query_stems, run_search, Cancellation, SearchCache};
+use crate::store::{IndexChange, Store};
+use crate::subscriptions::StandingQuery;
+
+// A query from the `alerts` block, with what to do when a file starts
+// matching it.
+struct Alert {
+    name: String,
+    query: StandingQuery,
+    command: Option<String>,
+}
+
+// The configured alert queries.
+pub struct Alerts {
+    alerts: Vec<Alert>,
+}
+
+impl Alerts {
+    // Read the `alerts` block, a list of queries, each with an optional
+    // command to run instead of the desktop notification.  Files that
+    // already match aren't news, so we only alert on what changes after
+    // this.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        alerts_info: &gjson::Value,
+        punc: &Regex,
+        accents: &Regex,
+        stemmer: &dyn Normalizer,
+        store: &Store,
+        ranking: &RankingConfig,
+        cache: &mut SearchCache,
+    ) -> Alerts {
+        let mut alerts = Vec::<Alert>::new();
+
+        for alert in alerts_info.array() {
+            let name = alert.get("query").to_string();
+            let stems = query_stems(&name, punc, accents, stemmer);
+            let command = alert.get("command");
+
+            if stems.is_empty() {
+                warn!("Ignoring alert with nothing to search for: '{}'", name);
+                continue;
+            }
+
+            let serps = match run_search(
+                &name,
+                punc,
+                accents,
+                stemmer,
+                store,
+                ranking,
+                cache,
+                &Cancellation::never(),
+            ) {
+                Ok((serps, _sorted)) => serps,
+                Err(e) => {
+                    error!("Unable to search for alert '{}': {}", name, e);
+                    continue;
+                }
+            };
+
+            info!("Alerting on {}", name);
+            alerts.push(Alert {
+                name,
+                query: StandingQuery::new(stems, serps.into_keys().collect()),
+                command: if command.exists() {
+                    Some(command.to_string())
+                } else {
+                    None
+                },
+            });
+        }
+
+        Alerts { alerts }
+    }
+
+    // Raise every alert whose query a freshly indexed file now matches.
+    pub fn check(&mut self, change: &IndexChange) {
+        for alert in self.alerts.iter_mut() {
+            if alert.query.starts_matching(change) {
+                raise(&alert.name, alert.command.as_deref(), &change.path);
+            }
+        }
+    }
+}
+
+// Pop up a desktop notification, or run the alert's command with the path
+// as its argument.  Either can take a while, so neither holds up indexing.
+fn raise(name: &str, command: Option<&str>, path: &str) {
+    let name = name.to_string();
+    let command = command.map(|c| c.to_string());
+    let path = path.to_string();
+
+    info!("Alert '{}' for {}", name, path);
+    thread::spawn(move || match command {
+        Some(command) => {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} \"$1\"", command))
+                .arg("intern")
+                .arg(&path)
+                .status();
+
+            match status {
+                Ok(status) if !status.success() => {
+                    warn!("Alert command '{}' failed: {}", command, status)
+                }
+                Ok(_) => (),
+                Err(e) => error!("Unable to run alert command '{}': {}", command, e),
+            }
+        }
+        None => {
+            let shown = Notification::new()
+                .appname("intern")
+                .summary(&format!("INTERN: {}", name))
+                .body(&path)
+                .show();
+
+            if let Err(e) = shown {
+                error!("Unable to show notification: {}", e);
+            }
+        }
+    });
+}
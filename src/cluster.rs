@@ -0,0 +1,243 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::store::Store;
+
+// How many of its most distinctive stems stand for a file, which keeps the
+// vectors small enough to hold every file's at once.
+const CLUSTER_STEMS: usize = 25;
+
+// How many rounds of k-means to run, at most, if the clusters haven't
+// settled before then.
+const MAX_ROUNDS: usize = 20;
+
+// How many of a cluster's heaviest stems make up its label.
+const LABEL_STEMS: usize = 3;
+
+// A file's stems and their TF-IDF weights, scaled to unit length, so that
+// the dot product of two vectors is their cosine similarity.
+type Vector = HashMap<u32, f32>;
+
+// A group of files about the same things, with a label made of the stems
+// they have most in common.
+#[derive(Debug)]
+pub struct Cluster {
+    pub label: String,
+    pub files: Vec<u32>,
+}
+
+// Group the indexed files by TF-IDF similarity, with spherical k-means, and
+// store the clusters, replacing any from before.  Without a count, there
+// are about as many clusters as the square root of half the files.
+pub fn cluster_files(store: &Store, count: Option<usize>) -> Result<Vec<Cluster>, Error> {
+    let vectors = file_vectors(store)?;
+    let count = count
+        .unwrap_or_else(|| (vectors.len() as f32 / 2.0).sqrt().round() as usize)
+        .clamp(1, vectors.len().max(1));
+    let (assignments, centroids) = k_means(&vectors, count);
+    let stems = store
+        .select_all_stems()?
+        .into_iter()
+        .map(|(stem, id)| (id, stem))
+        .collect::<HashMap<_, _>>();
+    let mut clusters = centroids
+        .iter()
+        .map(|centroid| Cluster {
+            label: label(centroid, &stems),
+            files: Vec::new(),
+        })
+        .collect::<Vec<_>>();
+
+    for ((file, _), cluster) in vectors.iter().zip(assignments) {
+        clusters[cluster].files.push(*file);
+    }
+
+    clusters.retain(|c| !c.files.is_empty());
+    clusters.sort_by_key(|c| Reverse(c.files.len()));
+    store.replace_clusters(&clusters)?;
+    Ok(clusters)
+}
+
+// Describe every file by its most distinctive stems.  Stems that only one
+// file uses say a lot about that file, but nothing about what it has in
+// common with others, so they don't count.
+fn file_vectors(store: &Store) -> Result<Vec<(u32, Vector)>, Error> {
+    let total_files: f32 =
+        store.query_row("SELECT COUNT(*) FROM monitored_file", [], |row| row.get(0))?;
+    let mut stmt = store.prepare(
+        "SELECT stem, COUNT(DISTINCT file) FROM file_reverse_index
+           WHERE file IN (SELECT id FROM monitored_file)
+           GROUP BY stem HAVING COUNT(DISTINCT file) > 1",
+    )?;
+    let file_counts = stmt
+        .query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, f32>(1)?)))?
+        .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+    let mut stmt = store.prepare(
+        "SELECT file, stem, COUNT(*) FROM file_reverse_index
+           WHERE file IN (SELECT id FROM monitored_file)
+           GROUP BY file, stem ORDER BY file",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, u32>(0)?,
+            row.get::<_, u32>(1)?,
+            row.get::<_, f32>(2)?,
+        ))
+    })?;
+    let mut vectors = Vec::<(u32, Vector)>::new();
+    let mut weights = Vec::<(u32, f32)>::new();
+    let mut current = None;
+
+    for row in rows {
+        let (file, stem, frequency) = row?;
+
+        if current != Some(file) {
+            if let Some(done) = current {
+                vectors.push((done, unit_vector(&mut weights)));
+            }
+
+            current = Some(file);
+        }
+
+        if let Some(files) = file_counts.get(&stem) {
+            weights.push((stem, frequency * (total_files / files).ln()));
+        }
+    }
+
+    if let Some(done) = current {
+        vectors.push((done, unit_vector(&mut weights)));
+    }
+
+    // Files with nothing in common with any other file can't join anything.
+    vectors.retain(|(_, vector)| !vector.is_empty());
+    Ok(vectors)
+}
+
+// Keep the heaviest weights, scaled to unit length, emptying the list for
+// the next file.
+fn unit_vector(weights: &mut Vec<(u32, f32)>) -> Vector {
+    weights.sort_by(|a, b| b.1.total_cmp(&a.1));
+    weights.truncate(CLUSTER_STEMS);
+    weights.retain(|(_, weight)| *weight > 0.0);
+
+    let length = weights.iter().map(|(_, w)| w * w).sum::<f32>().sqrt();
+
+    weights
+        .drain(..)
+        .map(|(stem, w)| (stem, w / length))
+        .collect()
+}
+
+// Assign each vector to one of `count` clusters, starting from vectors
+// spread evenly through the list, so that the same index always clusters
+// the same way, and returning the final centroids, too.
+fn k_means(vectors: &[(u32, Vector)], count: usize) -> (Vec<usize>, Vec<Vector>) {
+    let mut centroids = (0..count)
+        .filter_map(|i| vectors.get(i * vectors.len() / count))
+        .map(|(_, vector)| vector.clone())
+        .collect::<Vec<_>>();
+    let mut assignments = vec![usize::MAX; vectors.len()];
+
+    for _ in 0..MAX_ROUNDS {
+        let mut changed = false;
+
+        for ((_, vector), assigned) in vectors.iter().zip(assignments.iter_mut()) {
+            let nearest = nearest(vector, &centroids);
+
+            changed |= *assigned != nearest;
+            *assigned = nearest;
+        }
+
+        if !changed {
+            break;
+        }
+
+        for (cluster, centroid) in centroids.iter_mut().enumerate() {
+            let mut sum = Vector::new();
+
+            for ((_, vector), _) in vectors
+                .iter()
+                .zip(&assignments)
+                .filter(|(_, assigned)| **assigned == cluster)
+            {
+                for (stem, weight) in vector {
+                    *sum.entry(*stem).or_insert(0.0) += weight;
+                }
+            }
+
+            // A cluster that lost all its files keeps its old centroid.
+            if !sum.is_empty() {
+                let length = sum.values().map(|w| w * w).sum::<f32>().sqrt();
+
+                sum.values_mut().for_each(|w| *w /= length);
+                *centroid = sum;
+            }
+        }
+    }
+
+    (assignments, centroids)
+}
+
+// The centroid most similar to a vector, breaking ties by order.
+fn nearest(vector: &Vector, centroids: &[Vector]) -> usize {
+    let similarity = |centroid: &Vector| {
+        vector
+            .iter()
+            .map(|(stem, weight)| weight * centroid.get(stem).unwrap_or(&0.0))
+            .sum::<f32>()
+    };
+
+    centroids
+        .iter()
+        .map(similarity)
+        .enumerate()
+        .fold(
+            (0, f32::MIN),
+            |best, (i, s)| if s > best.1 { (i, s) } else { best },
+        )
+        .0
+}
+
+fn label(centroid: &Vector, stems: &HashMap<u32, String>) -> String {
+    let mut weights = centroid.iter().collect::<Vec<_>>();
+
+    weights.sort_by(|a, b| b.1.total_cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    weights
+        .iter()
+        .take(LABEL_STEMS)
+        .filter_map(|(stem, _)| stems.get(stem).cloned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vector(weights: &[(u32, f32)]) -> Vector {
+        unit_vector(&mut weights.to_vec())
+    }
+
+    #[test]
+    fn similar_vectors_share_a_cluster() {
+        let vectors = [
+            (1, vector(&[(1, 3.0), (2, 1.0)])),
+            (2, vector(&[(3, 2.0), (4, 2.0)])),
+            (3, vector(&[(1, 2.0), (2, 2.0)])),
+            (4, vector(&[(3, 1.0), (4, 3.0), (1, 0.1)])),
+        ];
+        let (assignments, centroids) = k_means(&vectors, 2);
+
+        assert_eq!(assignments[0], assignments[2]);
+        assert_eq!(assignments[1], assignments[3]);
+        assert_ne!(assignments[0], assignments[1]);
+        assert_eq!(
+            label(
+                &centroids[assignments[0]],
+                &HashMap::from([(1, "garden".to_string()), (2, "soil".to_string())])
+            ),
+            "garden soil"
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+// How long to wait, by default, for more events on a path before acting on
+// them, in milliseconds.
+const DEFAULT_WINDOW_MS: u64 = 500;
+
+// The latest event for a path, and when anything last happened to it.
+struct PendingEvent {
+    name: &'static str,
+    last: Instant,
+}
+
+// Collects filesystem events, so that a single save, which can show up as
+// a notice, a write, and a change of permissions, only gets indexed once.
+// Each path keeps its latest event, except that the watcher's early
+// notices and changes to permissions never replace a change to the file
+// itself, and a path is ready once its events stop for a while.  Some
+// kinds of events can wait longer than others, or not count at all.
+pub struct EventCoalescer {
+    window: Duration,
+    windows: HashMap<String, Duration>,
+    watcher_delay: Duration,
+    ignored: HashSet<String>,
+    pending: HashMap<PathBuf, PendingEvent>,
+}
+
+impl EventCoalescer {
+    // Configure from the `coalesceWindow` item, in milliseconds, the
+    // `eventWindows` item, with windows for kinds of events, like `create`
+    // or `write`, that override it, and the `ignoreEvents` list of kinds to
+    // drop.  Notices wait longer, since the watcher holds back the event
+    // that follows them until the file has been quiet for its delay.
+    pub fn new(config: &gjson::Value, watcher_delay: Duration) -> EventCoalescer {
+        let window_info = config.get("coalesceWindow");
+        let mut windows = HashMap::<String, Duration>::new();
+
+        config.get("eventWindows").each(|kind, window| {
+            windows.insert(kind.to_string(), Duration::from_millis(window.u64()));
+            true
+        });
+
+        EventCoalescer {
+            window: Duration::from_millis(if window_info.exists() {
+                window_info.u64()
+            } else {
+                DEFAULT_WINDOW_MS
+            }),
+            windows,
+            watcher_delay,
+            ignored: config
+                .get("ignoreEvents")
+                .array()
+                .iter()
+                .map(|kind| kind.to_string())
+                .collect(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // Note an event for a path, unless it's a kind we ignore.
+    pub fn add(&mut self, name: &'static str, path: PathBuf, now: Instant) {
+        if self.ignored.contains(kind(name)) {
+            return;
+        }
+
+        let event = self
+            .pending
+            .entry(path)
+            .or_insert(PendingEvent { name, last: now });
+
+        if !is_minor(name) || is_minor(event.name) {
+            event.name = name;
+        }
+
+        event.last = now;
+    }
+
+    // Take the events whose paths have been quiet long enough, oldest
+    // first.
+    pub fn ready(&mut self, now: Instant) -> Vec<(&'static str, PathBuf)> {
+        let mut ready = self
+            .pending
+            .iter()
+            .filter(|(_, event)| {
+                now.saturating_duration_since(event.last) >= self.wait(event.name)
+            })
+            .map(|(path, event)| (event.last, path.to_path_buf()))
+            .collect::<Vec<_>>();
+
+        ready.sort();
+        ready
+            .into_iter()
+            .filter_map(|(_, path)| self.pending.remove(&path).map(|e| (e.name, path)))
+            .collect()
+    }
+
+    // How long a path waits after an event of its kind.
+    fn wait(&self, name: &str) -> Duration {
+        let window = self.windows.get(kind(name)).copied().unwrap_or(self.window);
+
+        if is_notice(name) {
+            self.watcher_delay + window
+        } else {
+            window
+        }
+    }
+
+    // How many paths are waiting.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Whether nothing's waiting.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+// The kind of change an event is about, which is the same for a notice as
+// for the event it announces, so that `notice write` and `notify write`
+// are both a `write`.
+fn kind(name: &str) -> &str {
+    name.rsplit(' ').next().unwrap_or(name)
+}
+
+// Whether an event only announces that another is on its way.
+fn is_notice(name: &str) -> bool {
+    name.starts_with("notice")
+}
+
+// Whether an event says less about a file than the others, since it's
+// either a notice or only about permissions.
+fn is_minor(name: &str) -> bool {
+    is_notice(name) || name == "chmod"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coalescer() -> EventCoalescer {
+        EventCoalescer::new(
+            &gjson::parse(r#"{"coalesceWindow": 100}"#),
+            Duration::from_secs(2),
+        )
+    }
+
+    #[test]
+    fn one_save_is_one_event() {
+        let mut events = coalescer();
+        let start = Instant::now();
+        let path = PathBuf::from("/notes/a.md");
+
+        events.add("notice write", path.clone(), start);
+        assert!(events.ready(start + Duration::from_secs(1)).is_empty());
+        events.add("notify write", path.clone(), start + Duration::from_secs(2));
+        events.add("chmod", path.clone(), start + Duration::from_secs(2));
+        events.add("notice write", path.clone(), start + Duration::from_secs(2));
+        assert!(events.ready(start + Duration::from_secs(2)).is_empty());
+        assert_eq!(
+            events.ready(start + Duration::from_secs(3)),
+            vec![("notify write", path)]
+        );
+        assert!(events.is_empty());
+    }
+
+    #[test]
+    fn notices_go_through_eventually() {
+        let mut events = coalescer();
+        let start = Instant::now();
+        let removed = PathBuf::from("/notes/gone.md");
+        let written = PathBuf::from("/notes/b.md");
+
+        events.add("notice remove", removed.clone(), start);
+        events.add("create", written.clone(), start + Duration::from_millis(50));
+        assert_eq!(
+            events.ready(start + Duration::from_millis(150)),
+            vec![("create", written)]
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events.ready(start + Duration::from_millis(2100)),
+            vec![("notice remove", removed)]
+        );
+    }
+    #[test]
+    fn kinds_of_events_can_differ() {
+        let mut events = EventCoalescer::new(
+            &gjson::parse(
+                r#"{"eventWindows": {"create": 1000}, "ignoreEvents": ["chmod"]}"#,
+            ),
+            Duration::from_secs(2),
+        );
+        let start = Instant::now();
+        let created = PathBuf::from("/notes/new.md");
+        let written = PathBuf::from("/notes/a.md");
+
+        events.add("chmod", PathBuf::from("/notes/b.md"), start);
+        events.add("create", created.clone(), start);
+        events.add("notify write", written.clone(), start);
+        assert_eq!(
+            events.ready(start + Duration::from_millis(600)),
+            vec![("notify write", written)]
+        );
+        assert_eq!(
+            events.ready(start + Duration::from_millis(1000)),
+            vec![("create", created)]
+        );
+        assert!(events.is_empty());
+    }
+}
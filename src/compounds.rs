@@ -0,0 +1,249 @@
+use std::collections::HashSet;
+use std::fs;
+use std::sync::OnceLock;
+use tracing::{error, info};
+
+// The shortest part of a compound that counts as a word of its own, so
+// that short dictionary entries don't chop words into nonsense.
+const MIN_PART_CHARS: usize = 3;
+
+// The dictionary for splitting compounds, if there is one.
+static COMPOUNDS: OnceLock<Option<Compounds>> = OnceLock::new();
+
+// The words that compounds can be made of, lowercase, and the letters that
+// can join them, like the "s" in "Arbeitszimmer."
+pub struct Compounds {
+    words: HashSet<String>,
+    linking: Vec<String>,
+}
+
+// Read the optional `compounds` block, with a `dictionary` file of words,
+// one to a line, and a `linking` list of the letters that can join them.
+// Without a dictionary, compounds stay whole.
+pub fn set_compounds(config: &gjson::Value) {
+    let info = config.get("compounds");
+    let dictionary = info.get("dictionary");
+    let compounds = if !dictionary.exists() {
+        None
+    } else {
+        match fs::read_to_string(dictionary.str()) {
+            Ok(text) => {
+                let linking = info.get("linking");
+                let compounds =
+                    Compounds::new(text.lines(), linking.array().iter().map(|l| l.str()));
+
+                info!(
+                    "Splitting compounds into {} dictionary words",
+                    compounds.words.len()
+                );
+                Some(compounds)
+            }
+            Err(e) => {
+                error!("Unable to read compounds from {}: {}", dictionary.str(), e);
+                None
+            }
+        }
+    };
+
+    COMPOUNDS.get_or_init(|| compounds);
+}
+
+// Follow each compound word with its parts, and each part that's a
+// compound itself with its own parts, so that searching for "Dampf" finds
+// "Donaudampfschiff."  Like hyphenated words, only the index needs these.
+pub fn with_compound_parts(words: Vec<(usize, &str)>) -> Vec<(usize, &str)> {
+    match COMPOUNDS.get_or_init(|| None) {
+        Some(compounds) => compounds.with_parts(words),
+        None => words,
+    }
+}
+
+impl Compounds {
+    // Gather the dictionary words, in lowercase, leaving out the ones too
+    // short to be parts, along with the linking letters.
+    pub fn new<'a>(
+        words: impl Iterator<Item = &'a str>,
+        linking: impl Iterator<Item = &'a str>,
+    ) -> Compounds {
+        Compounds {
+            words: words
+                .map(|w| w.trim().to_lowercase())
+                .filter(|w| w.chars().count() >= MIN_PART_CHARS)
+                .collect(),
+            linking: linking
+                .map(|l| l.trim().to_lowercase())
+                .filter(|l| !l.is_empty())
+                .collect(),
+        }
+    }
+
+    // Add the parts of every compound in a list of words, with their
+    // offsets.
+    pub fn with_parts<'a>(&self, words: Vec<(usize, &'a str)>) -> Vec<(usize, &'a str)> {
+        let mut all = Vec::<(usize, &str)>::with_capacity(words.len());
+
+        for (offset, word) in words {
+            all.push((offset, word));
+            self.add_parts(offset, word, &mut all);
+        }
+
+        all
+    }
+
+    // Add the parts of one word, and of its parts, after it.
+    fn add_parts<'a>(
+        &self,
+        offset: usize,
+        word: &'a str,
+        all: &mut Vec<(usize, &'a str)>,
+    ) {
+        for (start, end) in self.split(word) {
+            let part = &word[start..end];
+
+            all.push((offset + start, part));
+            self.add_parts(offset + start, part, all);
+        }
+    }
+
+    // Split a word into as few dictionary words as it takes, with linking
+    // letters allowed between them, as the byte ranges of the parts, or
+    // nothing, if it isn't a compound of the words we know.
+    fn split(&self, word: &str) -> Vec<(usize, usize)> {
+        // Compare in lowercase, remembering where each of the word's
+        // characters starts in the lowercase version.
+        let mut lower = String::with_capacity(word.len());
+        let mut starts = Vec::<(usize, usize)>::new();
+
+        for (start, c) in word.char_indices() {
+            starts.push((start, lower.len()));
+            lower.extend(c.to_lowercase());
+        }
+
+        starts.push((word.len(), lower.len()));
+
+        let count = starts.len() - 1;
+
+        if count < 2 * MIN_PART_CHARS {
+            return Vec::new();
+        }
+
+        // The fewest parts that cover the word from each character on,
+        // with where the first of them ends and where the next one starts.
+        let mut best = vec![None::<(usize, usize, usize)>; count + 1];
+
+        best[count] = Some((0, count, count));
+        for from in (0..count).rev() {
+            for to in (from + MIN_PART_CHARS)..=count {
+                // The whole word doesn't count as one of its own parts.
+                if from == 0 && to == count {
+                    continue;
+                }
+
+                if !self.words.contains(&lower[starts[from].1..starts[to].1]) {
+                    continue;
+                }
+
+                for next in self.next_parts(&lower, &starts, to) {
+                    let parts = match best[next] {
+                        Some((parts, _, _)) => parts + 1,
+                        None => continue,
+                    };
+
+                    if best[from].is_none_or(|(fewest, _, _)| parts < fewest) {
+                        best[from] = Some((parts, to, next));
+                    }
+                }
+            }
+        }
+
+        let mut parts = Vec::<(usize, usize)>::new();
+        let mut from = 0;
+
+        while from < count {
+            let (_, to, next) = match best[from] {
+                Some(best) => best,
+                None => return Vec::new(),
+            };
+
+            parts.push((starts[from].0, starts[to].0));
+            from = next;
+        }
+
+        parts
+    }
+
+    // Where the next part can start after a part that ends at a character,
+    // which is right there, or after any linking letters, but never with
+    // only linking letters left.
+    fn next_parts(
+        &self,
+        lower: &str,
+        starts: &[(usize, usize)],
+        to: usize,
+    ) -> Vec<usize> {
+        let count = starts.len() - 1;
+        let mut next = vec![to];
+
+        if to == count {
+            return next;
+        }
+
+        for linking in &self.linking {
+            let rest = &lower[starts[to].1..];
+
+            if rest.starts_with(linking.as_str()) && rest.len() > linking.len() {
+                let end = starts[to].1 + linking.len();
+
+                if let Some(at) = starts.iter().position(|&(_, l)| l == end) {
+                    next.push(at);
+                }
+            }
+        }
+
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compounds_split_into_known_words() {
+        let compounds = Compounds::new(
+            [
+                "Donau",
+                "dampf",
+                "schiff",
+                "dampfschiff",
+                "arbeit",
+                "zimmer",
+                "ab",
+            ]
+            .iter()
+            .copied(),
+            ["s"].iter().copied(),
+        );
+        let words = compounds
+            .with_parts(vec![(4, "Donaudampfschiff"), (21, "Arbeitszimmer")])
+            .into_iter()
+            .map(|(offset, word)| format!("{}:{}", offset, word))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            words,
+            [
+                "4:Donaudampfschiff",
+                "4:Donau",
+                "9:dampfschiff",
+                "9:dampf",
+                "14:schiff",
+                "21:Arbeitszimmer",
+                "21:Arbeit",
+                "28:zimmer"
+            ]
+        );
+        assert_eq!(compounds.with_parts(vec![(0, "Dampfer")]), [(0, "Dampfer")]);
+        assert_eq!(compounds.with_parts(vec![(0, "schiffs")]), [(0, "schiffs")]);
+    }
+}
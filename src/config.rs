@@ -0,0 +1,160 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+// How much a file's length counts against it, without a
+// `lengthNormalization` item, which is what BM25 usually uses.
+const DEFAULT_LENGTH_NORMALIZATION: f32 = 0.75;
+
+// Settings that adjust how we score search results.
+#[derive(Debug)]
+pub struct RankingConfig {
+    pub recency_boost: f32,
+    pub folder_boosts: Vec<(Gitignore, f32)>,
+    pub length_normalization: f32,
+}
+
+impl RankingConfig {
+    pub fn new(config: &gjson::Value) -> RankingConfig {
+        let mut folder_boosts = Vec::<(Gitignore, f32)>::new();
+
+        config.get("folderBoosts").each(|pattern, boost| {
+            match folder_pattern(pattern.str()) {
+                Some(_) if boost.f32() <= 0.0 => {
+                    warn!("Ignoring boost for {}, which isn't positive", pattern)
+                }
+                Some(matcher) => folder_boosts.push((matcher, boost.f32())),
+                None => warn!("Can't boost {}", pattern),
+            }
+
+            true
+        });
+
+        let length = config.get("lengthNormalization");
+        let length_normalization = if !length.exists() {
+            DEFAULT_LENGTH_NORMALIZATION
+        } else if (0.0..=1.0).contains(&length.f32()) {
+            length.f32()
+        } else {
+            warn!(
+                "Ignoring lengthNormalization {}, which isn't from 0 to 1",
+                length
+            );
+            DEFAULT_LENGTH_NORMALIZATION
+        };
+
+        RankingConfig {
+            recency_boost: config.get("recencyBoost").f32(),
+            folder_boosts,
+            length_normalization,
+        }
+    }
+
+    // Multiply together the boosts for every folder or glob that a file
+    // falls under, which is one, if it's under none of them.
+    pub fn folder_boost(&self, path: &str) -> f32 {
+        let path = Path::new(path);
+
+        if !path.is_absolute() {
+            return 1.0;
+        }
+
+        self.folder_boosts
+            .iter()
+            .filter(|(matcher, _)| {
+                matcher.matched_path_or_any_parents(path, false).is_ignore()
+            })
+            .map(|(_, boost)| boost)
+            .product()
+    }
+}
+
+// Read a `folderBoosts` key, which takes the same syntax as an ignore file
+// at the top of the filesystem, like the `exclude` item, except that a
+// leading `~` means the home folder.
+fn folder_pattern(pattern: &str) -> Option<Gitignore> {
+    let pattern = match pattern.strip_prefix('~') {
+        Some(rest) => format!("{}{}", dirs::home_dir()?.display(), rest),
+        None => pattern.to_string(),
+    };
+    let mut builder = GitignoreBuilder::new("/");
+
+    builder.add_line(None, &pattern).ok()?;
+    builder.build().ok()
+}
+
+// Limits how quickly we index files, so that a full reindex doesn't
+// saturate the disk.
+#[derive(Debug)]
+pub struct Throttle {
+    gap: Duration,
+    last: Instant,
+}
+
+impl Throttle {
+    // Configure from the `throttle` block, taking the slower of the
+    // files-per-second limit and the explicit sleep.
+    pub fn new(throttle_info: &gjson::Value) -> Throttle {
+        let per_second = throttle_info.get("filesPerSecond").f64();
+        let sleep = Duration::from_millis(throttle_info.get("sleep").u64());
+        let rate_gap = if per_second > 0.0 {
+            Duration::from_secs_f64(1.0 / per_second)
+        } else {
+            Duration::from_secs(0)
+        };
+
+        Throttle {
+            gap: std::cmp::max(rate_gap, sleep),
+            last: Instant::now(),
+        }
+    }
+
+    // Pause, if we're about to index files faster than allowed.
+    pub fn wait(&mut self) {
+        let elapsed = self.last.elapsed();
+
+        if elapsed < self.gap {
+            std::thread::sleep(self.gap - elapsed);
+        }
+
+        self.last = Instant::now();
+    }
+}
+
+// Extract information from application configuration file at:
+//   ~/.config/intern/intern.json
+pub fn find_paths() -> (PathBuf, PathBuf, PathBuf) {
+    let app = "intern";
+    let mut config_path = dirs::config_dir().expect("Can't access configuration folder.");
+    config_path.push(app);
+    config_path.push(format!("{}.json", app));
+
+    let mut db_path = dirs::config_dir().unwrap();
+    db_path.push(app);
+    db_path.push(format!("{}.sqlite3", app));
+
+    let mut log_path = dirs::config_dir().unwrap();
+    log_path.push("intern");
+
+    (config_path, db_path, log_path)
+}
+
+// The characters that separate words.
+pub fn punctuation() -> Regex {
+    Regex::new(r"[\x00-\x26\x28-\x2F\x3A-\x40\x5B-\x60\x7B-\x7F]+").unwrap()
+}
+
+// The accents to strip from words before stemming, which are the
+// combining marks left over once we decompose the words.  With the
+// `accentSensitive` option, we strip nothing, so that "résumé" and "resume"
+// are different words.
+pub fn accents(config: &gjson::Value) -> Regex {
+    if config.get("accentSensitive").bool() {
+        // A character class that nothing belongs to.
+        Regex::new(r"[^\s\S]").unwrap()
+    } else {
+        Regex::new(r"\p{Mn}+").unwrap()
+    }
+}
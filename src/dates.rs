@@ -0,0 +1,272 @@
+use chrono::{Days, Local, Months, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+// The timezone from the configuration, if any, that days start and end in.
+// Without one, we go by the system's.
+static TIMEZONE: OnceLock<Tz> = OnceLock::new();
+
+// A span of time that a relative date can count back in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Unit {
+    Day,
+    Week,
+    Month,
+    Year,
+}
+
+// Read the `timezone` setting, an IANA name like `America/New_York`, for
+// the days that date queries mean.
+pub fn set_timezone(timezone_info: &gjson::Value) {
+    if !timezone_info.exists() {
+        return;
+    }
+
+    match timezone_info.str().parse::<Tz>() {
+        Ok(tz) => {
+            info!("Dates are in {}", tz);
+            TIMEZONE.get_or_init(|| tz);
+        }
+        Err(e) => warn!("Using the system timezone: {}", e),
+    }
+}
+
+// The current date, wherever the user is.
+pub fn today() -> NaiveDate {
+    match TIMEZONE.get() {
+        Some(tz) => Utc::now().with_timezone(tz).date_naive(),
+        None => Local::now().date_naive(),
+    }
+}
+
+// The Unix timestamp of the midnight that starts a date.
+pub fn day_timestamp(date: NaiveDate) -> i64 {
+    match TIMEZONE.get() {
+        Some(tz) => day_start(tz, date),
+        None => day_start(&Local, date),
+    }
+}
+
+// Render a Unix timestamp in the user's time.
+pub fn format_timestamp(timestamp: i64) -> String {
+    let time = match TIMEZONE.get() {
+        Some(tz) => tz
+            .timestamp_opt(timestamp, 0)
+            .earliest()
+            .map(|t| t.format("%F %T").to_string()),
+        None => Local
+            .timestamp_opt(timestamp, 0)
+            .earliest()
+            .map(|t| t.format("%F %T").to_string()),
+    };
+
+    time.unwrap_or_else(|| timestamp.to_string())
+}
+
+// Interpret a date query, `@on` with a date, `@ago` with a relative date,
+// or `@between` with two dates, as the days it covers, from the first up
+// to, but not including, the second.
+pub fn date_window(query: &str, today: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+    let query = query.trim();
+    let (command, text) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+
+    match command {
+        "@on" => {
+            let day = parse_date(text)?;
+
+            Some((day, day.succ_opt()?))
+        }
+        "@ago" => relative_window(text, today),
+        "@between" => between_window(text),
+        _ => None,
+    }
+}
+
+// Interpret a relative date, like "3 days", "2 weeks ago", "yesterday", or
+// a bare number of days, as the days it covers, counting back from today.
+// The result runs from the first day up to, but not including, the second,
+// and spans one of whatever unit the query counts in, so "1 week" means
+// the seven days before today.
+pub fn relative_window(text: &str, today: NaiveDate) -> Option<(NaiveDate, NaiveDate)> {
+    let words = text
+        .split_whitespace()
+        .map(|w| w.to_lowercase())
+        .filter(|w| w != "ago")
+        .collect::<Vec<_>>();
+    let (count, unit) = match words.iter().map(|w| w.as_str()).collect::<Vec<_>>()[..] {
+        [] | ["today"] => (0, Unit::Day),
+        ["yesterday"] => (1, Unit::Day),
+        [count] => (count.parse().ok()?, Unit::Day),
+        [count, unit] => (parse_count(count)?, parse_unit(unit)?),
+        _ => return None,
+    };
+    let start = back(today, count, unit)?;
+
+    Some((start, forward(start, unit)?))
+}
+
+// Interpret a date like "2024-05-01".
+pub fn parse_date(text: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(text.trim(), "%F").ok()
+}
+
+// Interpret a pair of dates, like "2024-05-01 2024-05-07", as the days
+// from the first through the second, in either order.
+pub fn between_window(text: &str) -> Option<(NaiveDate, NaiveDate)> {
+    let dates = text
+        .split_whitespace()
+        .map(parse_date)
+        .collect::<Option<Vec<_>>>()?;
+
+    match dates[..] {
+        [first, second] => {
+            let (start, end) = if first <= second {
+                (first, second)
+            } else {
+                (second, first)
+            };
+
+            Some((start, end.checked_add_days(Days::new(1))?))
+        }
+        _ => None,
+    }
+}
+
+// The first moment of a date in a timezone.  Where a change to daylight
+// saving time skips midnight, the day starts when the clocks resume.
+fn day_start<Z: TimeZone>(zone: &Z, date: NaiveDate) -> i64 {
+    let mut time = date.and_time(NaiveTime::MIN);
+
+    for _ in 0..24 {
+        if let Some(start) = zone.from_local_datetime(&time).earliest() {
+            return start.timestamp();
+        }
+
+        time += chrono::Duration::hours(1);
+    }
+
+    date.and_time(NaiveTime::MIN).and_utc().timestamp()
+}
+
+// Read how many units back to go, allowing "a week" or "last month".
+fn parse_count(word: &str) -> Option<u32> {
+    match word {
+        "a" | "an" | "last" => Some(1),
+        _ => word.parse().ok(),
+    }
+}
+
+// Read a unit of time, in the singular or the plural.
+fn parse_unit(word: &str) -> Option<Unit> {
+    match word.strip_suffix('s').unwrap_or(word) {
+        "day" => Some(Unit::Day),
+        "week" => Some(Unit::Week),
+        "month" => Some(Unit::Month),
+        "year" => Some(Unit::Year),
+        _ => None,
+    }
+}
+
+// The date some number of units before another.
+fn back(date: NaiveDate, count: u32, unit: Unit) -> Option<NaiveDate> {
+    match unit {
+        Unit::Day => date.checked_sub_days(Days::new(count.into())),
+        Unit::Week => date.checked_sub_days(Days::new(u64::from(count) * 7)),
+        Unit::Month => date.checked_sub_months(Months::new(count)),
+        Unit::Year => date.checked_sub_months(Months::new(count.checked_mul(12)?)),
+    }
+}
+
+// The date one unit after another.
+fn forward(date: NaiveDate, unit: Unit) -> Option<NaiveDate> {
+    match unit {
+        Unit::Day => date.checked_add_days(Days::new(1)),
+        Unit::Week => date.checked_add_days(Days::new(7)),
+        Unit::Month => date.checked_add_months(Months::new(1)),
+        Unit::Year => date.checked_add_months(Months::new(12)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, "%F").unwrap()
+    }
+
+    #[test]
+    fn relative_dates_count_back_from_today() {
+        let today = day("2024-05-15");
+
+        assert_eq!(
+            relative_window("", today),
+            Some((day("2024-05-15"), day("2024-05-16")))
+        );
+        assert_eq!(
+            relative_window("yesterday", today),
+            Some((day("2024-05-14"), day("2024-05-15")))
+        );
+        assert_eq!(
+            relative_window("3", today),
+            Some((day("2024-05-12"), day("2024-05-13")))
+        );
+        assert_eq!(
+            relative_window("3 Days ago", today),
+            Some((day("2024-05-12"), day("2024-05-13")))
+        );
+        assert_eq!(
+            relative_window("2 weeks", today),
+            Some((day("2024-05-01"), day("2024-05-08")))
+        );
+        assert_eq!(
+            relative_window("last month", today),
+            Some((day("2024-04-15"), day("2024-05-15")))
+        );
+        assert_eq!(relative_window("3 fortnights", today), None);
+        assert_eq!(relative_window("soon", today), None);
+    }
+
+    #[test]
+    fn days_start_at_local_midnight() {
+        let new_york: Tz = "America/New_York".parse().unwrap();
+        let sao_paulo: Tz = "America/Sao_Paulo".parse().unwrap();
+
+        // Midnight in New York is four hours into the day in UTC, in May.
+        assert_eq!(day_start(&new_york, day("2024-05-01")), 1714536000);
+        // Brazil used to start daylight saving time at midnight.
+        assert_eq!(day_start(&sao_paulo, day("2018-11-04")), 1541300400);
+        assert_eq!(day_start(&Utc, day("2024-05-01")), 1714521600);
+    }
+
+    #[test]
+    fn date_queries_pick_their_parser() {
+        let today = day("2024-05-15");
+
+        assert_eq!(
+            date_window("@on 2024-05-01", today),
+            Some((day("2024-05-01"), day("2024-05-02")))
+        );
+        assert_eq!(
+            date_window("@ago 2 days", today),
+            Some((day("2024-05-13"), day("2024-05-14")))
+        );
+        assert_eq!(
+            date_window("@between 2024-05-01 2024-05-03", today),
+            Some((day("2024-05-01"), day("2024-05-04")))
+        );
+        assert_eq!(date_window("@on", today), None);
+        assert_eq!(date_window("@soon 2024-05-01", today), None);
+    }
+
+    #[test]
+    fn between_covers_both_days() {
+        assert_eq!(
+            between_window("2024-05-07 2024-05-01"),
+            Some((day("2024-05-01"), day("2024-05-08")))
+        );
+        assert_eq!(between_window("2024-05-01"), None);
+        assert_eq!(between_window("2024-05-01 tomorrow"), None);
+    }
+}
@@ -0,0 +1,44 @@
+use std::{fmt, io};
+
+// What can go wrong while keeping the index up to date, so that a problem
+// with one file costs us that file, rather than the whole daemon.
+#[derive(Debug)]
+pub enum Error {
+    // The filesystem wouldn't give up a file or folder.
+    Io(io::Error),
+    // The database refused a read or a write.
+    Database(rusqlite::Error),
+}
+
+impl Error {
+    // A word for what went wrong, for telling failures apart at a glance.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Io(_) => "io",
+            Error::Database(_) => "database",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Database(e) => write!(f, "database: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Database(e)
+    }
+}
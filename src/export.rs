@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::error::Error;
+use crate::json;
+use crate::store::Store;
+
+// The shapes the term weights can take on their way out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    // One row for each file and stem, with a header, for `read.csv` and
+    // pandas.
+    Csv,
+    // One object for each file, a line at a time, for `stream_in` and
+    // `read_json(lines=True)`.
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(Format::Csv),
+            "json" | "jsonl" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+// Write out the term-document matrix, as how often each stem appears in
+// each file and its TF-IDF weight there, the same weight `@terms` uses.
+// Files come out in order of their paths and stems in order within them,
+// a file at a time, so that the whole matrix never has to fit in memory.
+pub fn export_weights<W: Write>(
+    store: &Store,
+    format: Format,
+    out: &mut W,
+) -> Result<(), Error> {
+    let total_files: f32 =
+        store.query_row("SELECT COUNT(*) FROM monitored_file", [], |row| row.get(0))?;
+    let mut stmt = store.prepare(
+        "SELECT stem, COUNT(DISTINCT file) FROM file_reverse_index
+           WHERE file IN (SELECT id FROM monitored_file)
+           GROUP BY stem",
+    )?;
+    let file_counts = stmt
+        .query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, f32>(1)?)))?
+        .collect::<rusqlite::Result<HashMap<_, _>>>()?;
+    let mut stmt = store.prepare(
+        "SELECT f.path, s.id, s.stem, COUNT(*)
+           FROM file_reverse_index i
+           JOIN word_stem s ON s.id = i.stem
+           JOIN monitored_file f ON f.id = i.file
+           GROUP BY f.path, s.id
+           ORDER BY f.path, s.stem",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, u32>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, u32>(3)?,
+        ))
+    })?;
+    let mut current = None::<String>;
+
+    if format == Format::Csv {
+        writeln!(out, "path,stem,frequency,weight")?;
+    }
+
+    for row in rows {
+        let (path, id, stem, frequency) = row?;
+        let files = file_counts.get(&id).copied().unwrap_or(1.0);
+        let weight = frequency as f32 * (total_files / files).ln();
+
+        match format {
+            Format::Csv => writeln!(
+                out,
+                "{},{},{},{:.6}",
+                csv_field(&path),
+                csv_field(&stem),
+                frequency,
+                weight
+            )?,
+            Format::Json => {
+                if current.as_ref() != Some(&path) {
+                    if current.is_some() {
+                        writeln!(out, "}}}}")?;
+                    }
+
+                    write!(out, r#"{{"path":{},"terms":{{"#, json::quote(&path))?;
+                    current = Some(path);
+                } else {
+                    write!(out, ",")?;
+                }
+
+                write!(
+                    out,
+                    r#"{}:{{"frequency":{},"weight":{:.6}}}"#,
+                    json::quote(&stem),
+                    frequency,
+                    weight
+                )?;
+            }
+        }
+    }
+
+    if current.is_some() {
+        writeln!(out, "}}}}")?;
+    }
+
+    out.flush().map_err(Error::from)
+}
+
+// Quote a CSV field, if it needs it, doubling any quotes inside.
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::accents;
+    use crate::store::IndexTuple;
+    use crate::testing::open_store;
+    use std::path::Path;
+
+    #[test]
+    fn weights_come_out_by_file() {
+        let store = open_store();
+
+        let stems = store
+            .insert_bulk_stems(vec!["fox".to_string(), "dog".to_string()])
+            .unwrap();
+        let words = [("/a, b.md", "fox"), ("/a, b.md", "fox"), ("/c.md", "dog")];
+        let files = ["/a, b.md", "/c.md"]
+            .iter()
+            .map(|p| (*p, store.insert_file(Path::new(p), &0).unwrap().id))
+            .collect::<HashMap<_, _>>();
+
+        store
+            .insert_bulk_word_tuples(
+                words
+                    .iter()
+                    .enumerate()
+                    .map(|(offset, (path, stem))| IndexTuple {
+                        file: files[path],
+                        stem: stems[*stem],
+                        offset: offset as u32,
+                        byte_offset: 0,
+                        word: stem.to_string(),
+                    })
+                    .collect(),
+                &accents(&gjson::parse("{}")),
+            )
+            .unwrap();
+
+        let mut csv = Vec::<u8>::new();
+        let mut json = Vec::<u8>::new();
+
+        export_weights(&store, Format::Csv, &mut csv).unwrap();
+        export_weights(&store, Format::Json, &mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "path,stem,frequency,weight\n\
+             \"/a, b.md\",fox,2,1.386294\n\
+             /c.md,dog,1,0.693147\n"
+        );
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            concat!(
+                r#"{"path":"/a, b.md","terms":{"fox":{"frequency":2,"weight":1.386294}}}"#,
+                "\n",
+                r#"{"path":"/c.md","terms":{"dog":{"frequency":1,"weight":0.693147}}}"#,
+                "\n",
+            )
+        );
+    }
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, SecondsFormat};
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::lsp::{file_uri, percent_encode};
+use crate::store::Store;
+use crate::users::{visible, User};
+
+// How many files a feed lists without a `max` parameter or `maxResults`.
+pub const DEFAULT_FEED_SIZE: usize = 50;
+
+// The files that most recently changed on disk or in the index, newest
+// first, with when that was, limited to what the user can read and, for a
+// feed that follows a search, what matches it.
+pub fn recent_files(
+    store: &Store,
+    user: Option<&User>,
+    matching: Option<&HashSet<String>>,
+    limit: usize,
+) -> rusqlite::Result<Vec<(String, u64)>> {
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT path, MAX(COALESCE(modified, 0), COALESCE(indexed_at, 0)) AS changed
+           FROM monitored_file WHERE {} ORDER BY changed DESC, path",
+        store.in_folders("path")
+    ))?;
+    let mut files = Vec::<(String, u64)>::new();
+
+    for file in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (path, changed): (String, u64) = file?;
+
+        if !visible(user, &path) || matching.is_some_and(|m| !m.contains(&path)) {
+            continue;
+        }
+
+        files.push((path, changed));
+
+        if files.len() >= limit {
+            break;
+        }
+    }
+
+    Ok(files)
+}
+
+// Write an Atom feed of changed files, where each file's entry links to
+// it and changes its ID whenever the file changes, so that feed readers
+// show it again.
+pub fn atom(query: &str, files: &[(String, u64)]) -> String {
+    let title = if query.is_empty() {
+        "INTERN: Recent changes".to_string()
+    } else {
+        format!("INTERN: Recent changes matching {}", query)
+    };
+    let updated = files.iter().map(|(_, changed)| *changed).max().unwrap_or(0);
+    let mut feed = format!(
+        concat!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n",
+            "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n",
+            "  <title>{}</title>\n  <id>{}</id>\n",
+            "  <updated>{}</updated>\n  <author><name>INTERN</name></author>\n"
+        ),
+        escape(&title),
+        format!("urn:intern:feed:{}", percent_encode(query)).trim_end_matches(':'),
+        timestamp(updated)
+    );
+
+    for (path, changed) in files {
+        let name = Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.to_string());
+        let link = escape(&file_uri(path));
+
+        feed.push_str(&format!(
+            concat!(
+                "  <entry>\n    <title>{}</title>\n",
+                "    <link href=\"{}\"/>\n    <id>{}#{}</id>\n",
+                "    <updated>{}</updated>\n    <summary>{}</summary>\n",
+                "  </entry>\n"
+            ),
+            escape(&name),
+            link,
+            link,
+            changed,
+            timestamp(*changed),
+            escape(path)
+        ));
+    }
+
+    feed.push_str("</feed>\n");
+    feed
+}
+
+// Write a time in seconds since the epoch the way Atom wants it.
+fn timestamp(time: u64) -> String {
+    DateTime::from_timestamp(time as i64, 0)
+        .unwrap_or_default()
+        .to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+// Escape text for XML.
+fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c if (c as u32) < 0x20 && c != '\n' && c != '\t' => (),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open_store;
+
+    #[test]
+    fn feeds_list_recent_changes() {
+        let store = open_store();
+
+        for (path, modified) in [("/notes/old.md", 100), ("/notes/R&D <2>.md", 300)] {
+            store.insert_file(Path::new(path), &modified).unwrap();
+        }
+
+        let old = store.select_file("/notes/old.md").unwrap().unwrap();
+
+        store.record_indexed(old.id).unwrap();
+
+        let files = recent_files(&store, None, None, 10).unwrap();
+
+        assert_eq!(files[0].0, "/notes/old.md");
+        assert_eq!(files[1], ("/notes/R&D <2>.md".to_string(), 300));
+        assert_eq!(recent_files(&store, None, None, 1).unwrap().len(), 1);
+
+        let matching = HashSet::from(["/notes/R&D <2>.md".to_string()]);
+        let feed = atom(
+            "R&D",
+            &recent_files(&store, None, Some(&matching), 10).unwrap(),
+        );
+
+        assert!(feed.contains("<title>INTERN: Recent changes matching R&amp;D</title>"));
+        assert!(feed.contains("<title>R&amp;D &lt;2&gt;.md</title>"));
+        assert!(feed.contains("<link href=\"file:///notes/R%26D%20%3C2%3E.md\"/>"));
+        assert!(feed.contains("<updated>1970-01-01T00:05:00Z</updated>"));
+        assert!(!feed.contains("old.md"));
+    }
+}
@@ -0,0 +1,381 @@
+use ignore::gitignore::{gitconfig_excludes_path, Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+// The ignore files we read in each folder, in increasing precedence, so
+// that an `.internignore` can overrule version control about what's worth
+// searching.  Mercurial's syntax defaults to regular expressions, but in
+// practice these are lists of globs, so all of them get the gitignore
+// treatment.
+const IGNORE_FILES: [&str; 3] = [".hgignore", ".gitignore", ".internignore"];
+
+// Version control metadata, which is never worth indexing.
+const VCS_FOLDERS: [&str; 2] = [".git", ".hg"];
+
+// Where git keeps a repository's own exclusions, under its `.git` folder.
+const GIT_EXCLUDE: &str = "info/exclude";
+
+// The machine's own list of excludes, for an administrator to keep paths
+// out of every user's index.
+const SYSTEM_EXCLUDES: &str = "/etc/intern/excludes";
+
+// Empty files that keep a whole folder out of the index, as Spotlight
+// honors `.noindex` and Android's media scanner honors `.nomedia`.
+const MARKERS: [&str; 2] = [".noindex", ".nomedia"];
+
+// Decides what to leave alone, the same way for the startup scan, the
+// watches, and filesystem events.  Ignore files apply to the folder they
+// sit in and everything below it, with deeper files taking precedence, as
+// git does it.  Each folder's rules load the first time we need them.
+// Repositories also get git's global `core.excludesFile` and their own
+// `.git/info/exclude`, with lower precedence than their ignore files.
+// A folder with a marker file in it is ignored outright, whatever the
+// rules say, and so are the excludes, which apply everywhere.
+#[derive(Default)]
+pub struct Ignores {
+    roots: Vec<PathBuf>,
+    folders: HashMap<PathBuf, Option<Gitignore>>,
+    marked: HashMap<PathBuf, bool>,
+    excludes: Option<Gitignore>,
+    global: Option<PathBuf>,
+}
+
+impl Ignores {
+    pub fn new() -> Ignores {
+        Ignores {
+            global: gitconfig_excludes_path(),
+            ..Ignores::default()
+        }
+    }
+
+    // Start honoring ignore files in a watched folder.  Rules above the
+    // folder don't apply, since we don't know what they belong to.
+    pub fn add_root(&mut self, root: &Path) {
+        self.roots.push(root.to_path_buf());
+    }
+
+    // Exclude the paths and globs in the `exclude` list, along with the
+    // machine's own excludes, which come last, so that a user's `!` lines
+    // can't bring back what the administrator left out.
+    pub fn set_excludes(&mut self, config: &gjson::Value) {
+        let patterns = config
+            .array()
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>();
+
+        self.excludes = load_excludes(&patterns, Path::new(SYSTEM_EXCLUDES));
+    }
+
+    // Whether a path is in one of the watched folders.
+    pub fn is_watched(&self, path: &Path) -> bool {
+        self.roots.iter().any(|r| path.starts_with(r))
+    }
+
+    // If a path is one of our ignore files, or a repository's exclude file,
+    // forget its folder's rules, so that the next check reloads them, and
+    // likewise for a marker file.
+    pub fn refresh(&mut self, path: &Path) {
+        if is_marker(path) {
+            if let Some(dir) = path.parent() {
+                self.marked.remove(dir);
+            }
+        }
+
+        let is_rules = path
+            .file_name()
+            .is_some_and(|name| IGNORE_FILES.iter().any(|i| name == *i));
+        let dir = if path.ends_with(Path::new(VCS_FOLDERS[0]).join(GIT_EXCLUDE)) {
+            path.ancestors().nth(3)
+        } else if is_rules {
+            path.parent()
+        } else {
+            None
+        };
+
+        if let Some(dir) = dir {
+            debug!("Reloading ignore rules for {}", dir.display());
+            self.folders.remove(dir);
+        }
+    }
+
+    // Check whether a path, or any folder leading to it, is ignored.
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let root = match self
+            .roots
+            .iter()
+            .filter(|r| path.starts_with(r))
+            .max_by_key(|r| r.components().count())
+        {
+            Some(root) => root.to_path_buf(),
+            None => return false,
+        };
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        let mut folders = vec![root.to_path_buf()];
+        let mut ignored = false;
+
+        if relative
+            .components()
+            .any(|c| VCS_FOLDERS.iter().any(|v| c.as_os_str() == *v))
+            || self
+                .excludes
+                .as_ref()
+                .is_some_and(|e| e.matched_path_or_any_parents(path, is_dir).is_ignore())
+        {
+            return true;
+        }
+
+        for component in relative.parent().into_iter().flat_map(|p| p.components()) {
+            let folder = folders[folders.len() - 1].join(component);
+
+            folders.push(folder);
+        }
+
+        if folders.iter().any(|f| self.is_marked(f)) || (is_dir && self.is_marked(path)) {
+            return true;
+        }
+
+        for folder in folders {
+            if let Some(rules) = self.rules(&folder) {
+                let matched = rules.matched_path_or_any_parents(path, is_dir);
+
+                if matched.is_ignore() {
+                    ignored = true;
+                } else if matched.is_whitelist() {
+                    ignored = false;
+                }
+            }
+        }
+
+        ignored
+    }
+
+    // Every folder from a root down that isn't ignored, for watching one at
+    // a time, along with whether any ignore files turned up along the way.
+    pub fn included_folders(&mut self, root: &Path) -> (Vec<PathBuf>, bool) {
+        let mut included = Vec::<PathBuf>::new();
+        let mut folders = vec![root.to_path_buf()];
+        let mut has_rules = false;
+
+        while let Some(folder) = folders.pop() {
+            has_rules = has_rules || self.rules(&folder).is_some();
+
+            let entries = match fs::read_dir(&folder) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    warn!("Can't read {}: {}", folder.display(), e);
+                    continue;
+                }
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+
+                if path.is_dir() && !self.is_ignored(&path, true) {
+                    folders.push(path);
+                }
+            }
+
+            included.push(folder);
+        }
+
+        (included, has_rules)
+    }
+
+    // Whether a folder has a marker file in it, keeping it out of the index.
+    pub fn is_marked(&mut self, dir: &Path) -> bool {
+        *self
+            .marked
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| MARKERS.iter().any(|m| dir.join(m).is_file()))
+    }
+
+    // The rules from a folder's own ignore files, if it has any.
+    fn rules(&mut self, dir: &Path) -> Option<&Gitignore> {
+        let global = self.global.as_deref();
+
+        self.folders
+            .entry(dir.to_path_buf())
+            .or_insert_with(|| load_rules(dir, global))
+            .as_ref()
+    }
+}
+
+// Whether a path is a marker file, which keeps its folder out of the index.
+pub fn is_marker(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| MARKERS.iter().any(|m| name == *m))
+}
+
+// Build the excludes, which take the same syntax as an ignore file, as if
+// it were at the top of the filesystem, so that `/proc` means that folder,
+// and `*.pem` means those files anywhere.
+fn load_excludes(patterns: &[String], system: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new("/");
+
+    for pattern in patterns {
+        if let Err(e) = builder.add_line(None, pattern) {
+            warn!("Can't exclude {}: {}", pattern, e);
+        }
+    }
+
+    if system.is_file() {
+        if let Some(e) = builder.add(system) {
+            warn!("Problem reading {}: {}", system.display(), e);
+        }
+    }
+
+    match builder.build() {
+        Ok(excludes) if excludes.is_empty() => None,
+        Ok(excludes) => Some(excludes),
+        Err(e) => {
+            warn!("Can't use the excludes: {}", e);
+            None
+        }
+    }
+}
+
+// Read a folder's ignore files, starting with git's exclusions, if the
+// folder holds a repository.
+fn load_rules(dir: &Path, global: Option<&Path>) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found = false;
+    let git = dir.join(VCS_FOLDERS[0]);
+    let mut files = Vec::<PathBuf>::new();
+
+    if git.exists() {
+        files.extend(global.map(|g| g.to_path_buf()));
+        files.push(git.join(GIT_EXCLUDE));
+    }
+
+    files.extend(IGNORE_FILES.iter().map(|name| dir.join(name)));
+
+    for file in files {
+        if file.is_file() {
+            found = true;
+            if let Some(e) = builder.add(&file) {
+                warn!("Problem reading {}: {}", file.display(), e);
+            }
+        }
+    }
+
+    if !found {
+        return None;
+    }
+
+    match builder.build() {
+        Ok(rules) => Some(rules),
+        Err(e) => {
+            warn!("Can't use the ignore rules in {}: {}", dir.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn nested_ignore_files_apply_below_their_folder() {
+        let root = ScratchDir::new("ign");
+        let src = root.join("src");
+
+        fs::create_dir_all(src.join("build")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(src.join(".gitignore"), "build/\n!keep.log\n").unwrap();
+
+        let mut ignores = Ignores::new();
+
+        ignores.add_root(&root);
+        assert!(ignores.is_ignored(&root.join("a.log"), false));
+        assert!(ignores.is_ignored(&src.join("b.log"), false));
+        assert!(!ignores.is_ignored(&src.join("keep.log"), false));
+        assert!(ignores.is_ignored(&src.join("build/out.md"), false));
+        assert!(ignores.is_ignored(&root.join(".git/config"), false));
+        assert!(!ignores.is_ignored(&src.join("main.md"), false));
+        assert!(!ignores.is_ignored(Path::new("/elsewhere/a.log"), false));
+    }
+
+    #[test]
+    fn internignore_overrules_version_control() {
+        let root = ScratchDir::new("iign");
+
+        fs::write(root.join(".gitignore"), "*.pdf\ndrafts/\n").unwrap();
+        fs::write(root.join(".internignore"), "!*.pdf\n*.bak\n").unwrap();
+
+        let mut ignores = Ignores::new();
+
+        ignores.add_root(&root);
+        assert!(!ignores.is_ignored(&root.join("paper.pdf"), false));
+        assert!(ignores.is_ignored(&root.join("paper.bak"), false));
+        assert!(ignores.is_ignored(&root.join("drafts/a.md"), false));
+    }
+
+    #[test]
+    fn repositories_use_git_excludes() {
+        let root = ScratchDir::new("gign");
+        let repo = root.join("repo");
+        let global = root.join("global-ignore");
+
+        fs::create_dir_all(repo.join(".git/info")).unwrap();
+        fs::write(&global, "*.swp\n").unwrap();
+        fs::write(repo.join(".git/info/exclude"), "scratch/\n").unwrap();
+        fs::write(repo.join(".gitignore"), "!keep.swp\n").unwrap();
+
+        let mut ignores = Ignores::new();
+
+        ignores.global = Some(global);
+        ignores.add_root(&root);
+        assert!(ignores.is_ignored(&repo.join("a.md.swp"), false));
+        assert!(!ignores.is_ignored(&repo.join("keep.swp"), false));
+        assert!(ignores.is_ignored(&repo.join("scratch/a.md"), false));
+        assert!(!ignores.is_ignored(&root.join("outside.swp"), false));
+    }
+
+    #[test]
+    fn excludes_apply_everywhere() {
+        let root = ScratchDir::new("xign");
+        let system = root.join("excludes");
+
+        fs::create_dir_all(root.join("keys")).unwrap();
+        fs::write(&system, "*.pem\n").unwrap();
+        fs::write(root.join(".internignore"), "!*.pem\n").unwrap();
+
+        let mut ignores = Ignores::new();
+        let patterns = [format!("{}/keys", root.display()), "!*.pem".to_string()];
+
+        ignores.excludes = load_excludes(&patterns, &system);
+        ignores.add_root(&root);
+        assert!(ignores.is_ignored(&root.join("a.pem"), false));
+        assert!(ignores.is_ignored(&root.join("keys/a.md"), false));
+        assert!(!ignores.is_ignored(&root.join("a.md"), false));
+        assert!(load_excludes(&[], &root.join("missing")).is_none());
+    }
+
+    #[test]
+    fn marked_folders_are_ignored() {
+        let root = ScratchDir::new("mign");
+        let photos = root.join("photos");
+
+        fs::create_dir_all(photos.join("2020")).unwrap();
+        fs::write(root.join(".internignore"), "!*.jpg\n").unwrap();
+
+        let mut ignores = Ignores::new();
+
+        ignores.add_root(&root);
+        assert!(!ignores.is_ignored(&photos.join("2020/a.jpg"), false));
+        fs::write(photos.join(".noindex"), "").unwrap();
+        ignores.refresh(&photos.join(".noindex"));
+        assert!(ignores.is_ignored(&photos, true));
+        assert!(ignores.is_ignored(&photos.join("2020/a.jpg"), false));
+        assert!(!ignores.is_ignored(&root.join("a.jpg"), false));
+        fs::remove_file(photos.join(".noindex")).unwrap();
+        ignores.refresh(&photos.join(".noindex"));
+        assert!(!ignores.is_ignored(&photos.join("2020/a.jpg"), false));
+    }
+}
@@ -0,0 +1,1417 @@
+use notify::DebouncedEvent;
+use notify::DebouncedEvent::{
+    Chmod, Create, NoticeRemove, NoticeWrite, Remove, Rename, Rescan,
+    Write as NotifyWrite,
+};
+use notify::RecursiveMode;
+use regex::Regex;
+use rusqlite::params;
+use std::any::Any;
+use std::collections::HashSet;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::time::UNIX_EPOCH;
+use std::{fs, str};
+use tracing::{debug, error, info, instrument, trace, warn, Span};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::compounds::with_compound_parts;
+use crate::config::Throttle;
+use crate::error::Error;
+use crate::ignores::{is_marker, Ignores};
+use crate::links::{find_links, Link};
+use crate::media::media_text;
+use crate::metrics::METRICS;
+use crate::modeline::Modeline;
+use crate::normalizer::{language_normalizer, Normalizer};
+#[cfg(feature = "ocr")]
+use crate::ocr::recognize;
+#[cfg(feature = "semantic")]
+use crate::semantic::{passages, queue_passages};
+use crate::store::{path_text, record_index_change, ChangeKind, IndexTuple, Store};
+use crate::symbols::{Symbol, Symbols};
+use crate::table::Table;
+use crate::text::{content_hash, TextChunks, CHUNK_BYTES};
+use crate::trigrams::{fold, trigrams, trigrams_enabled};
+use crate::watch_registry::WatchRegistry;
+
+// Linux's inotify can't skip ignored subtrees when watching recursively,
+// so there we watch each included file individually.  The other platforms'
+// watchers (FSEvents, ReadDirectoryChangesW) are natively recursive and
+// cheap, so we watch the whole folder and filter events as they arrive.
+pub const WATCH_INCLUDED_FILES: bool = cfg!(target_os = "linux");
+
+// How many times a file can fail to index before we stop trying.
+pub const QUARANTINE_FAILURES: u32 = 3;
+
+// INTERN's own files, which are the database, the logs, and any backups.
+// Every pass through the index writes to them, so indexing them would feed
+// on itself.
+static OWN_ARTIFACTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// Folders that we poll, since their filesystems don't deliver reliable
+// events, where modification times can't be trusted either, so a file's
+// contents decide whether it changed.
+static COMPARED_FOLDERS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+// Numbers, dates, times, versions, and percentages, which the punctuation
+// would otherwise break into meaningless pieces.
+static NUMBERS: OnceLock<Regex> = OnceLock::new();
+
+// Hyphenated words, like "e-mail," along with the numbers, for keeping
+// whole with the `hyphenatedWords` option.
+static HYPHENATED: OnceLock<Regex> = OnceLock::new();
+
+// How to break text into words, from the configuration.
+static TOKENIZING: OnceLock<Tokenizing> = OnceLock::new();
+
+// Numbers, dates, times, versions, and percentages.
+const NUMBER_PATTERN: &str = r"\b[vV]?\d+(?:[.:/-]\d+)+\b|\b\d+(?:\.\d+)?%";
+
+// Words with hyphens between their parts, each of which can have
+// apostrophes inside it.
+const HYPHENATED_PATTERN: &str = r"\b\w+(?:['’]\w+)*(?:-\w+(?:['’]\w+)*)+\b";
+
+// The apostrophes that can join the parts of a word, like "don't."
+const APOSTROPHES: [char; 2] = ['\'', '’'];
+
+// The tag that marks a file whose words came from OCR, rather than text.
+const OCR_TAG: &str = "source=ocr";
+
+// The tokenizer settings, which are whether to keep hyphenated words
+// whole, whether to split words the way we used to, on ASCII punctuation,
+// rather than by Unicode's rules, and whether to add the words inside
+// identifiers, for source code.
+#[derive(Clone, Copy, Debug, Default)]
+struct Tokenizing {
+    hyphens: bool,
+    legacy: bool,
+    code: bool,
+}
+
+// Reduce a filesystem event to a name and the path it affects, or
+// nothing, for the events that don't involve indexing.
+pub fn describe_event(event: DebouncedEvent) -> Option<(&'static str, PathBuf)> {
+    match event {
+        Chmod(epath) => Some(("chmod", epath)),
+        Create(epath) => Some(("create", epath)),
+        DebouncedEvent::Error(event, _path) => {
+            debug!("error {:?} (unexpected)", event);
+            None
+        }
+        NoticeRemove(epath) => Some(("notice remove", epath)),
+        NoticeWrite(epath) => Some(("notice write", epath)),
+        NotifyWrite(epath) => Some(("notify write", epath)),
+        Remove(epath) => Some(("remove", epath)),
+        Rename(old, new) => {
+            debug!("{:?} => {:?}", old, new);
+            None
+        }
+        Rescan => {
+            debug!("rescan (unexpected)");
+            None
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn process_event(
+    event_name: &str,
+    epath: PathBuf,
+    store: &Store,
+    punc: &Regex,
+    acc: &Regex,
+    stem: &dyn Normalizer,
+    watches: &mut WatchRegistry,
+    ignores: &mut Ignores,
+    throttle: &mut Throttle,
+) {
+    let path = path_text(&epath);
+
+    // A changed ignore file changes what we ignore from here on, and a new
+    // marker file takes its folder out of the index.
+    ignores.refresh(&epath);
+    if is_marker(&epath) && epath.exists() {
+        if let Some(dir) = epath.parent() {
+            drop_marked_folder(store, dir);
+        }
+    }
+
+    if path.contains(".git")
+        || path.contains(".hg")
+        || path.ends_with(".svg")
+        || is_own_artifact(&epath)
+        || ignores.is_ignored(&epath, epath.is_dir())
+    {
+        return;
+    }
+
+    debug!("processing {} for {}", event_name, path);
+    METRICS.events_processed.fetch_add(1, Ordering::Relaxed);
+    if event_name.ends_with("remove") {
+        // The system discards the watch along with the file.
+        watches.dropped(&epath);
+
+        if !epath.exists() {
+            if let Err(e) = forget_file(store, &path) {
+                error!("Unable to remove {} from the index: {}", path, e);
+            }
+
+            return;
+        }
+    } else if WATCH_INCLUDED_FILES {
+        if let Err(e) = watches.watch(&epath, RecursiveMode::NonRecursive) {
+            warn!("Can't watch {}: {}", path, e);
+        }
+    }
+
+    process_file(
+        store,
+        &epath,
+        punc,
+        acc,
+        stem,
+        file_mod_time(&epath),
+        throttle,
+        false,
+    );
+}
+
+// Drop a file that no longer exists from the index, if it's there.
+fn forget_file(store: &Store, path: &str) -> Result<(), Error> {
+    if let Some(file) = store.select_file(path)? {
+        info!("Removing {} from the index", path);
+        store.delete_file(file.id, path)?;
+    }
+
+    Ok(())
+}
+
+// Drop whatever the index has from a folder with a marker file in it.
+fn drop_marked_folder(store: &Store, dir: &Path) {
+    match store.remove_files_in(dir) {
+        Ok(0) => (),
+        Ok(count) => info!("Dropped {} file(s) in marked {}", count, dir.display()),
+        Err(e) => error!("Unable to drop files in {}: {}", dir.display(), e),
+    }
+}
+
+// Never index the given files or anything in the given folders.
+pub fn exclude_own_artifacts(paths: &[&Path]) {
+    OWN_ARTIFACTS.lock().unwrap().extend(
+        paths
+            .iter()
+            .filter(|p| !p.as_os_str().is_empty())
+            .map(|p| p.to_path_buf()),
+    );
+}
+
+// Decide whether files in a folder changed by their contents, rather than
+// their modification times alone.
+pub fn compare_contents_in(folder: &Path) {
+    COMPARED_FOLDERS.lock().unwrap().push(folder.to_path_buf());
+}
+
+fn compares_contents(path: &Path) -> bool {
+    COMPARED_FOLDERS
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|folder| path.starts_with(folder))
+}
+
+// Check whether a path is one of our own, counting files that SQLite keeps
+// next to the database, like `intern.sqlite3-journal`.
+fn is_own_artifact(path: &Path) -> bool {
+    OWN_ARTIFACTS.lock().unwrap().iter().any(|artifact| {
+        path.starts_with(artifact)
+            || (path.parent() == artifact.parent()
+                && match (path.file_name(), artifact.file_name()) {
+                    (Some(name), Some(own)) => {
+                        name.to_string_lossy().starts_with(&*own.to_string_lossy())
+                    }
+                    _ => false,
+                })
+    })
+}
+
+// Iterate through the files in the folder, adding or indexing any files
+// that are new or updated since our last run.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip_all, fields(folder = %dir.display(), files))]
+pub fn process_folder(
+    store: &Store,
+    dir: &Path,
+    recursive: bool,
+    punc: &Regex,
+    acc: &Regex,
+    stem: &dyn Normalizer,
+    ignores: &mut Ignores,
+    throttle: &mut Throttle,
+) {
+    let mut files = Vec::<PathBuf>::new();
+    let mut marked = Vec::<PathBuf>::new();
+
+    list_files(dir, recursive, ignores, &mut files, &mut marked);
+    marked.iter().for_each(|m| drop_marked_folder(store, m));
+    Span::current().record("files", files.len());
+    for path in files {
+        let modified = file_mod_time(&path);
+
+        process_file(store, &path, punc, acc, stem, modified, throttle, false);
+    }
+}
+
+// A folder being indexed again, changed or not, even the files that failed
+// too often before, a file at a time, so that the daemon can go on
+// answering everyone else in between.  Files that have gone missing leave
+// the index as soon as it starts.
+pub struct Reindex {
+    files: Vec<PathBuf>,
+    done: usize,
+}
+
+impl Reindex {
+    #[instrument(level = "debug", skip_all, fields(folder = %dir.display()))]
+    pub fn new(store: &Store, dir: &Path, ignores: &mut Ignores) -> Reindex {
+        let mut files = Vec::<PathBuf>::new();
+        let mut marked = Vec::<PathBuf>::new();
+
+        if let Err(e) = store.purge_missing_files_in(dir) {
+            error!("Unable to remove missing files in {}: {}", dir.display(), e);
+        }
+
+        list_files(dir, true, ignores, &mut files, &mut marked);
+        marked.iter().for_each(|m| drop_marked_folder(store, m));
+        Reindex { files, done: 0 }
+    }
+
+    // How many files there are to index, in all.
+    pub fn total(&self) -> usize {
+        self.files.len()
+    }
+
+    // Index the next file, returning how many files were done before it
+    // and which one it was, or nothing, once they're all done.
+    pub fn next(
+        &mut self,
+        store: &Store,
+        punc: &Regex,
+        acc: &Regex,
+        stem: &dyn Normalizer,
+        throttle: &mut Throttle,
+    ) -> Option<(usize, &Path)> {
+        let done = self.done;
+        let path = self.files.get(done)?;
+        let modified = file_mod_time(path);
+
+        store.clear_failures(&path_text(path));
+        process_file(store, path, punc, acc, stem, modified, throttle, true);
+        self.done += 1;
+        Some((done, path))
+    }
+}
+
+// Collect the files in a folder that we index, leaving out our own files
+// and any that the ignore files rule out, along with the folders that a
+// marker file keeps out of the index.
+fn list_files(
+    dir: &Path,
+    recursive: bool,
+    ignores: &mut Ignores,
+    files: &mut Vec<PathBuf>,
+    marked: &mut Vec<PathBuf>,
+) {
+    if dir.is_dir() && ignores.is_marked(dir) {
+        marked.push(dir.to_path_buf());
+        return;
+    }
+
+    if !dir.is_dir() || is_own_artifact(dir) || ignores.is_ignored(dir, true) {
+        return;
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!("Can't read {}: {}", dir.display(), e);
+            return;
+        }
+    };
+
+    for entry in entries {
+        let entry_path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                error!("Can't read an entry in {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        let is_dir = entry_path.is_dir();
+
+        if is_dir && ignores.is_marked(&entry_path) {
+            marked.push(entry_path);
+            continue;
+        }
+
+        if is_own_artifact(&entry_path) || ignores.is_ignored(&entry_path, is_dir) {
+            continue;
+        }
+
+        if recursive && is_dir {
+            list_files(&entry_path, recursive, ignores, files, marked);
+        } else if is_dir {
+            // Should probably do something, but for now, it's just to prevent
+            // directories from falling through to be managed as normal files.
+        } else {
+            files.push(entry_path);
+        }
+    }
+}
+
+// Decide how to index a specific file, keeping track of files that fail
+// so that one pathological file can't take down the daemon over and over.
+#[allow(clippy::too_many_arguments)]
+fn process_file(
+    store: &Store,
+    path: &Path,
+    punc: &Regex,
+    acc: &Regex,
+    stem: &dyn Normalizer,
+    last_modified: u64,
+    throttle: &mut Throttle,
+    force: bool,
+) {
+    let path_str = path_text(path);
+
+    if store.is_quarantined(&path_str) {
+        trace!("skipping quarantined {}", path_str);
+        return;
+    }
+
+    let outcome = panic::catch_unwind(AssertUnwindSafe(|| {
+        index_if_changed(store, path, punc, acc, stem, last_modified, throttle, force)
+    }));
+    let failure = match outcome {
+        Ok(Ok(true)) => {
+            store.clear_failures(&path_str);
+            None
+        }
+        Ok(Ok(false)) => None,
+        Ok(Err(e)) => Some((e.kind(), e.to_string())),
+        Err(payload) => Some(("panic", panic_message(payload))),
+    };
+
+    if let Some((kind, e)) = failure {
+        error!("Failed to index {}: {}", path_str, e);
+        // Forget the modification time, so that we try again next time.
+        if let Err(e) = store.update_file_mod_time(&0, &path_str) {
+            error!("Unable to mark {} for reindexing: {}", path_str, e);
+        }
+
+        store.record_failure(&path_str, kind, &e);
+    }
+}
+
+// Index the file if it's new or changed, or if we're told to, reporting
+// whether we did.
+#[allow(clippy::too_many_arguments)]
+fn index_if_changed(
+    store: &Store,
+    path: &Path,
+    punc: &Regex,
+    acc: &Regex,
+    stem: &dyn Normalizer,
+    last_modified: u64,
+    throttle: &mut Throttle,
+    force: bool,
+) -> Result<bool, Error> {
+    let path_str = path_text(path);
+    let by_contents = compares_contents(path);
+    let mut hash = None;
+
+    store.restore_file(&path_str)?;
+
+    let (file_id, kind) = match store.select_file(&path_str)? {
+        Some(file) if force => {
+            store.update_file_mod_time(&last_modified, &path_str)?;
+            (file.id, ChangeKind::Updated)
+        }
+        // Network filesystems can move the time either way, without the
+        // file changing at all, so there the contents have the last word.
+        Some(file) if by_contents && file.modified != last_modified => {
+            let current = content_hash(path)?;
+
+            store.update_file_mod_time(&last_modified, &path_str)?;
+            if file.hash.as_ref() == Some(&current) {
+                return Ok(false);
+            }
+
+            hash = Some(current);
+            (file.id, ChangeKind::Updated)
+        }
+        // Update and index an existing file.
+        Some(file) if file.modified < last_modified => {
+            store.update_file_mod_time(&last_modified, &path_str)?;
+            (file.id, ChangeKind::Updated)
+        }
+        Some(_) => return Ok(false),
+        // Create and index a new file.
+        None => {
+            let file = store.insert_file(path, &last_modified)?;
+
+            (file.id, ChangeKind::Added)
+        }
+    };
+
+    throttle.wait();
+    index_file(store, path, file_id, kind, punc, acc, stem, last_modified)?;
+
+    // Only remember the contents once they're in the index, so that a file
+    // that fails to index gets another try.  Every file gets a fingerprint,
+    // not just the compared ones, so that `@duplicates` can find copies.
+    let hash = match hash {
+        Some(hash) => hash,
+        None => content_hash(path)?,
+    };
+
+    store.record_hash(file_id, &hash)?;
+    Ok(true)
+}
+
+// Break a chunk of a file into the words to index, with their offsets.
+fn chunk_words<'a>(
+    chunk: &'a str,
+    table: &mut Option<Table>,
+    punc: &Regex,
+    tokenizing: Tokenizing,
+) -> Vec<(usize, &'a str)> {
+    let words = with_compound_parts(with_hyphen_parts(match table {
+        // Only the cells worth searching, wherever they are.
+        Some(table) => table
+            .cells(chunk)
+            .into_iter()
+            .flat_map(|(start, end)| {
+                tokenize_with(&chunk[start..end], punc, tokenizing)
+                    .into_iter()
+                    .map(move |(offset, word)| (start + offset, word))
+            })
+            .collect(),
+        None => tokenize_with(chunk, punc, tokenizing),
+    }));
+
+    if tokenizing.code {
+        with_identifier_parts(words)
+    } else {
+        words
+    }
+}
+
+// The tokenizer settings for a file, which its modeline can choose,
+// falling back on the configured ones.
+fn file_tokenizing(path: &Path, modeline: &Modeline) -> Tokenizing {
+    let configured = *TOKENIZING.get_or_init(Tokenizing::default);
+    let tokenizer = match &modeline.tokenizer {
+        Some(tokenizer) => tokenizer,
+        None => return configured,
+    };
+
+    match tokenizer.as_str() {
+        "default" | "unicode" => Tokenizing::default(),
+        "code" => Tokenizing {
+            code: true,
+            ..Tokenizing::default()
+        },
+        "hyphenated" => Tokenizing {
+            hyphens: true,
+            ..Tokenizing::default()
+        },
+        "legacy" => Tokenizing {
+            legacy: true,
+            ..Tokenizing::default()
+        },
+        _ => {
+            warn!(
+                "Unknown tokenizer {} in the modeline of {}",
+                tokenizer,
+                path.display()
+            );
+            configured
+        }
+    }
+}
+
+// Count the words that indexing a text file would find in it, without
+// indexing it, or nothing, for a file that isn't text.
+pub fn count_words(path: &Path, punc: &Regex) -> io::Result<Option<usize>> {
+    let chunks = match TextChunks::open(path, CHUNK_BYTES)? {
+        Some(chunks) => chunks,
+        None => return Ok(None),
+    };
+    let mut table = Table::for_path(path);
+    let mut tokenizing = None;
+    let mut count = 0;
+
+    for chunk in chunks {
+        let chunk = chunk?;
+        let tokenizing = *tokenizing
+            .get_or_insert_with(|| file_tokenizing(path, &Modeline::read(&chunk)));
+
+        count += chunk_words(&chunk, &mut table, punc, tokenizing).len();
+    }
+
+    Ok(Some(count))
+}
+
+// Extract the message from a caught panic.
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.to_string()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Create the inverted index for the specified file, a chunk at a time, so
+// that huge files don't need to fit in memory.
+#[allow(clippy::too_many_arguments)]
+#[instrument(level = "debug", skip_all, fields(path = %path.display(), words))]
+pub fn index_file(
+    store: &Store,
+    path: &Path,
+    mut file_id: u32,
+    kind: ChangeKind,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    last_modified: u64,
+) -> Result<(), Error> {
+    let chunks = TextChunks::open(path, CHUNK_BYTES)?;
+    // Recordings, photos, and scans are binary, but can still have words
+    // in their metadata or in the pictures themselves.
+    let (described, recognized) = match chunks {
+        Some(_) => (None, None),
+        None => (media_text(path), recognize(path)?),
+    };
+    let ocr = recognized.is_some();
+    // Other binary files and the like just don't have any words to index.
+    let encoding = match &chunks {
+        Some(chunks) => chunks.encoding(),
+        None if ocr || described.is_some() => "UTF-8",
+        None => "binary",
+    };
+    let texts: Box<dyn Iterator<Item = io::Result<String>>> = match chunks {
+        Some(chunks) => Box::new(chunks),
+        None => Box::new(described.into_iter().chain(recognized).map(Ok)),
+    };
+    let mut table = Table::for_path(path);
+    let mut symbols = Symbols::for_path(path);
+    let mut definitions = Vec::<Symbol>::new();
+    let mut links = Vec::<Link>::new();
+    let mut to_embed = Vec::<String>::new();
+    let mut word_count = 0;
+    let mut byte_base = 0;
+    let mut word_end = 0;
+    let mut tags = None;
+    let mut settings = None;
+    let mut stems = HashSet::<String>::new();
+
+    // The file's whole index goes in at once, so that a crash partway
+    // through leaves the old one.
+    let transaction = store.begin()?;
+
+    // Delete any existing index.
+    if file_id > 0 {
+        store.clear_index_for(file_id)?;
+    } else {
+        file_id = store.insert_file(path, &last_modified)?.id;
+    }
+
+    // Every chunk adds to the stems, so read them once and keep up.
+    let mut all_stems = store.select_all_stems()?;
+
+    for chunk in texts {
+        let chunk = chunk?;
+        // Like the tags, a modeline has to be near the top.
+        let (tokenizing, own_stemmer, _) = settings.get_or_insert_with(|| {
+            let modeline = Modeline::read(&chunk);
+            let own_stemmer = modeline.language.as_deref().and_then(language_normalizer);
+            let language = own_stemmer.as_ref().and(modeline.language.clone());
+
+            (file_tokenizing(path, &modeline), own_stemmer, language)
+        });
+        let stemmer: &dyn Normalizer = match own_stemmer.as_deref() {
+            Some(own) => own,
+            None => stemmer,
+        };
+        let words = chunk_words(&chunk, &mut table, punc, *tokenizing);
+
+        // Front matter comes first, so the first chunk has any tags.
+        if tags.is_none() {
+            tags = Some(read_tags(path, &chunk));
+        }
+
+        if let Some(symbols) = &mut symbols {
+            definitions.extend(symbols.read(&chunk));
+        }
+
+        links.extend(find_links(path, &chunk));
+
+        to_embed.extend(passages(&chunk, to_embed.len()));
+
+        let word_stems = words
+            .iter()
+            .map(|(_, word)| stem_word(word, accents, stemmer))
+            .collect::<Vec<_>>();
+        let mut new_stems = word_stems
+            .iter()
+            .filter(|stem| !all_stems.contains_key(*stem))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        new_stems.sort();
+        new_stems.dedup();
+        all_stems.extend(store.insert_bulk_stems(new_stems)?);
+
+        if trigrams_enabled() {
+            let ids = &all_stems;
+            let grams = words
+                .iter()
+                .zip(&word_stems)
+                .flat_map(|((_, word), stem)| {
+                    trigrams(&fold(word, accents))
+                        .into_iter()
+                        .map(move |gram| (gram, ids[stem]))
+                })
+                .collect::<HashSet<_>>();
+
+            store.insert_trigrams(grams)?;
+        }
+
+        let new_index_tuples = words
+            .iter()
+            .zip(&word_stems)
+            .map(|((byte_offset, word), stem)| {
+                let start = byte_base + byte_offset;
+
+                // The parts of a word follow it, inside it, and take its
+                // place, rather than counting as words of their own.
+                if start + word.len() > word_end {
+                    word_count += 1;
+                    word_end = start + word.len();
+                }
+
+                IndexTuple {
+                    file: file_id,
+                    stem: all_stems[stem],
+                    offset: word_count - 1,
+                    byte_offset: start as u32,
+                    word: word.to_string(),
+                }
+            })
+            .collect();
+
+        store.insert_bulk_word_tuples(new_index_tuples, accents)?;
+        byte_base += chunk.len();
+        stems.extend(word_stems);
+    }
+
+    store.record_encoding(file_id, encoding)?;
+    store.record_word_count(file_id, word_count)?;
+    store.record_language(
+        file_id,
+        settings.and_then(|(_, _, language)| language).as_deref(),
+    )?;
+    definitions.extend(symbols.and_then(|mut s| s.finish()));
+    for symbol in &definitions {
+        store.execute(
+            "INSERT INTO file_symbol (file, symbol, kind, line) VALUES (?, ?, ?, ?)",
+            params![file_id, symbol.name, symbol.kind, symbol.line],
+        )?;
+    }
+
+    links.sort_by(|a, b| a.target().cmp(b.target()));
+    links.dedup();
+    for link in &links {
+        store.execute(
+            "INSERT INTO link (file_from, file_to) VALUES (?, ?)",
+            params![file_id, link.target()],
+        )?;
+    }
+
+    let mut tags = tags.unwrap_or_else(|| read_tags(path, ""));
+
+    if ocr {
+        tags.push(OCR_TAG.to_string());
+    }
+
+    for tag in &tags {
+        store.execute(
+            "INSERT INTO file_tag (file, tag) VALUES (?, ?)",
+            params![file_id, tag],
+        )?;
+    }
+
+    store.record_indexed(file_id)?;
+    transaction.commit()?;
+    Span::current().record("words", word_count);
+    METRICS.files_indexed.fetch_add(1, Ordering::Relaxed);
+    // Tags and definitions go in with the stems, marked so they can't
+    // collide with a real stem, so that cached searches for them notice
+    // when they change.
+    stems.extend(tags.iter().map(|t| format!("tag:{}", t)));
+    stems.extend(
+        definitions
+            .iter()
+            .map(|d| format!("def:{}", d.name.to_lowercase())),
+    );
+    queue_passages(&path_text(path), to_embed);
+    record_index_change(&path_text(path), kind, stems);
+    Ok(())
+}
+
+// Without OCR, there's no reading the words in an image.
+#[cfg(not(feature = "ocr"))]
+fn recognize(_path: &Path) -> io::Result<Option<String>> {
+    Ok(None)
+}
+
+// Without the semantic feature, there are no embeddings to make.
+#[cfg(not(feature = "semantic"))]
+fn passages(_text: &str, _before: usize) -> Vec<String> {
+    Vec::new()
+}
+
+#[cfg(not(feature = "semantic"))]
+fn queue_passages(_path: &str, _passages: Vec<String>) {}
+
+// Collect a file's tags, from the `tags` entry of any front matter at the
+// top of the file and from the `user.xdg.tags` extended attribute that
+// some file managers set, lowercased and without duplicates.
+fn read_tags(path: &Path, text: &str) -> Vec<String> {
+    let mut tags = front_matter_tags(text);
+
+    if let Ok(Some(value)) = xattr::get(path, "user.xdg.tags") {
+        tags.extend(split_tags(&String::from_utf8_lossy(&value)));
+    }
+
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+// Find the tags in YAML-style front matter, accepting `tags: a, b`,
+// `tags: [a, b]`, and a `tags:` line followed by a list of `- a` items.
+// Anything that doesn't close its front matter doesn't count, since a
+// leading `---` might just be a horizontal rule.
+fn front_matter_tags(text: &str) -> Vec<String> {
+    let mut lines = text.lines();
+    let mut tags = Vec::<String>::new();
+    let mut in_list = false;
+
+    if lines.next().map(|l| l.trim_end()) != Some("---") {
+        return tags;
+    }
+
+    for line in lines {
+        let trimmed = line.trim();
+
+        if trimmed == "---" || trimmed == "..." {
+            return tags;
+        }
+
+        if in_list {
+            if let Some(item) = trimmed.strip_prefix('-') {
+                tags.extend(split_tags(item));
+                continue;
+            }
+
+            in_list = false;
+        }
+
+        if let Some(value) = line.strip_prefix("tags:") {
+            let value = value.trim().trim_start_matches('[').trim_end_matches(']');
+
+            in_list = value.is_empty();
+            tags.extend(split_tags(value));
+        }
+    }
+
+    Vec::new()
+}
+
+// Break a list of tags on commas or whitespace, dropping any quotes.
+fn split_tags(list: &str) -> Vec<String> {
+    list.split(|c: char| c == ',' || c.is_whitespace())
+        .map(|t| t.trim_matches(|c| c == '"' || c == '\'').to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+// Read the tokenizer settings.  The `hyphenatedWords` setting indexes
+// words like "e-mail" as a whole, "email," as well as by their parts, and
+// `legacyTokenizer` goes back to splitting words only at ASCII punctuation
+// and whitespace, to match an index built that way.
+pub fn set_tokenizing(config: &gjson::Value) {
+    let tokenizing = Tokenizing {
+        hyphens: config.get("hyphenatedWords").bool(),
+        legacy: config.get("legacyTokenizer").bool(),
+        code: false,
+    };
+
+    if tokenizing.legacy {
+        info!("Splitting words at ASCII punctuation");
+    }
+
+    TOKENIZING.get_or_init(|| tokenizing);
+}
+
+// Split text into words, along with the byte offset where each starts.
+// Words break where Unicode's word segmentation rules (UAX #29) say they
+// do, except that numbers, dates, versions, and percentages, like
+// `2024-05-01`, `v1.2.3`, or `80%`, stay whole so that they can be
+// searched for.  Apostrophes only count inside a word, so "don't" stays
+// whole, but quotes around a word don't stick to it.
+pub fn tokenize<'a>(text: &'a str, punc: &Regex) -> Vec<(usize, &'a str)> {
+    tokenize_with(text, punc, *TOKENIZING.get_or_init(Tokenizing::default))
+}
+
+// Tokenize text with the given settings.
+fn tokenize_with<'a>(
+    text: &'a str,
+    punc: &Regex,
+    tokenizing: Tokenizing,
+) -> Vec<(usize, &'a str)> {
+    let whole = if tokenizing.hyphens {
+        HYPHENATED.get_or_init(|| {
+            Regex::new(&format!("{}|{}", NUMBER_PATTERN, HYPHENATED_PATTERN)).unwrap()
+        })
+    } else {
+        numbers()
+    };
+    let punc = if tokenizing.legacy { Some(punc) } else { None };
+    let mut words = Vec::<(usize, &str)>::new();
+    let mut start = 0;
+
+    for span in whole.find_iter(text) {
+        split_words(&mut words, text, start, span.start(), punc);
+        words.push((span.start(), span.as_str()));
+        start = span.end();
+    }
+
+    split_words(&mut words, text, start, text.len(), punc);
+    words
+}
+
+// Follow each hyphenated word with its parts, so that searching for "mail"
+// finds "e-mail," too.  Only the index needs these, since a query for the
+// whole word finds it either way.
+pub fn with_hyphen_parts(words: Vec<(usize, &str)>) -> Vec<(usize, &str)> {
+    let mut all = Vec::<(usize, &str)>::with_capacity(words.len());
+
+    for (offset, word) in words {
+        all.push((offset, word));
+        if is_hyphenated(word) {
+            let mut part_offset = offset;
+
+            for part in word.split('-') {
+                all.push((part_offset, part));
+                part_offset += part.len() + 1;
+            }
+        }
+    }
+
+    all
+}
+
+// Follow each identifier made of several words, like `parseHttpRequest`
+// or `MAX_RESULTS`, with those words, for files tokenized as code.
+fn with_identifier_parts(words: Vec<(usize, &str)>) -> Vec<(usize, &str)> {
+    let mut all = Vec::<(usize, &str)>::with_capacity(words.len());
+
+    for (offset, word) in words {
+        let parts = identifier_parts(word);
+
+        all.push((offset, word));
+        if parts.len() > 1 {
+            all.extend(
+                parts
+                    .into_iter()
+                    .map(|(start, part)| (offset + start, part)),
+            );
+        }
+    }
+
+    all
+}
+
+// The words in an identifier, split at underscores and where the case
+// changes, with their byte offsets.  Acronyms stay together, so
+// `HTTPServer` is "HTTP" and "Server."
+fn identifier_parts(word: &str) -> Vec<(usize, &str)> {
+    let chars = word.char_indices().collect::<Vec<_>>();
+    let mut parts = Vec::<(usize, &str)>::new();
+    let mut start = None::<usize>;
+
+    for (i, &(at, c)) in chars.iter().enumerate() {
+        if c == '_' {
+            if let Some(start) = start.take() {
+                parts.push((start, &word[start..at]));
+            }
+
+            continue;
+        }
+
+        let prev = if i > 0 { Some(chars[i - 1].1) } else { None };
+        let next = chars.get(i + 1).map(|&(_, c)| c);
+        let boundary = c.is_uppercase()
+            && prev.is_some_and(|p| {
+                p.is_lowercase()
+                    || p.is_numeric()
+                    || (p.is_uppercase() && next.is_some_and(char::is_lowercase))
+            });
+
+        match start {
+            Some(from) if boundary => {
+                parts.push((from, &word[from..at]));
+                start = Some(at);
+            }
+            Some(_) => (),
+            None => start = Some(at),
+        }
+    }
+
+    if let Some(start) = start {
+        parts.push((start, &word[start..]));
+    }
+
+    parts
+}
+
+// Whether a word has hyphens joining its parts, rather than being a date
+// or the like.
+fn is_hyphenated(word: &str) -> bool {
+    word.contains('-') && numbers().find(word).is_none_or(|n| n.len() != word.len())
+}
+
+// The numbers that stay whole.
+fn numbers() -> &'static Regex {
+    NUMBERS.get_or_init(|| Regex::new(NUMBER_PATTERN).unwrap())
+}
+
+// Add the words in part of some text, with their byte offsets in the
+// whole text, split by Unicode's rules or, given the punctuation, at the
+// punctuation and whitespace.
+fn split_words<'a>(
+    words: &mut Vec<(usize, &'a str)>,
+    text: &'a str,
+    start: usize,
+    end: usize,
+    punc: Option<&Regex>,
+) {
+    let base = text.as_ptr() as usize;
+    let part = &text[start..end];
+    let split: Box<dyn Iterator<Item = &str>> = match punc {
+        Some(punc) => Box::new(
+            punc.split(part)
+                .flat_map(|segment| segment.split_whitespace())
+                .filter(move |w| !punc.is_match(w)),
+        ),
+        None => Box::new(part.unicode_words()),
+    };
+
+    words.extend(
+        split
+            .map(|w| w.trim_matches(&APOSTROPHES[..]))
+            .filter(|w| !w.is_empty())
+            .map(|w| (w.as_ptr() as usize - base, w)),
+    );
+}
+
+// Get the modification time of a file.  Files from before 1970, which
+// turn up in old archives, count as 1970.
+pub fn file_mod_time(path: &Path) -> u64 {
+    match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+        Ok(time) => time.duration_since(UNIX_EPOCH).map_or(0, |t| t.as_secs()),
+        Err(e) => {
+            error!("{} for {}", e, path.display());
+            0
+        }
+    }
+}
+
+// Get the stem for the current word.  Hyphenated words stem as one word,
+// and a curly apostrophe counts the same as a straight one.
+pub fn stem_word(word: &str, accents: &Regex, stem: &dyn Normalizer) -> String {
+    let joined = if is_hyphenated(word) {
+        word.replace('-', "")
+    } else {
+        word.to_string()
+    };
+    let no_accents = strip_accents(&joined, accents)
+        .to_lowercase()
+        .replace(APOSTROPHES[1], "'");
+    stem.normalize(&no_accents).trim().to_string()
+}
+
+// Remove the accents from text, putting whatever's left back together,
+// so that the characters we keep look the same as they did.
+pub fn strip_accents(text: &str, accents: &Regex) -> String {
+    let nfd = text.nfd().collect::<String>();
+
+    accents.replace_all(&nfd, "").nfc().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{accents, punctuation, RankingConfig};
+    use crate::normalizer::normalizer;
+    use crate::query::{run_search, Cancellation, SearchCache};
+    use crate::testing::{open_store, ScratchDir};
+    use rust_stemmers::{Algorithm, Stemmer};
+
+    #[test]
+    fn database_failures_cost_only_the_file() {
+        let dir = ScratchDir::new("fail");
+        let path = dir.join("a.md");
+        let store = open_store();
+
+        store.execute("DROP TABLE file_reverse_index", []).unwrap();
+
+        let acc = accents(&gjson::parse("{}"));
+        let stemmer = Stemmer::create(Algorithm::English);
+        let mut throttle = Throttle::new(&gjson::parse("{}"));
+
+        fs::write(&path, "some words").unwrap();
+        process_file(
+            &store,
+            &path,
+            &punctuation(),
+            &acc,
+            &stemmer,
+            1,
+            &mut throttle,
+            false,
+        );
+
+        let error = store
+            .query_row("SELECT error FROM quarantine", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .unwrap();
+
+        assert!(error.starts_with("database: "));
+        let file = store.select_file(&path_text(&path)).unwrap().unwrap();
+
+        assert_eq!(file.modified, 0);
+    }
+
+    #[test]
+    fn own_artifacts_include_the_database_journal() {
+        exclude_own_artifacts(&[
+            Path::new("/home/u/.config/intern/intern.sqlite3"),
+            Path::new("/home/u/backups"),
+            Path::new(""),
+        ]);
+
+        assert!(is_own_artifact(Path::new("/home/u/.config/intern/intern.sqlite3")));
+        assert!(is_own_artifact(Path::new(
+            "/home/u/.config/intern/intern.sqlite3-journal"
+        )));
+        assert!(is_own_artifact(Path::new("/home/u/backups/intern-1.sqlite3")));
+        assert!(!is_own_artifact(Path::new("/home/u/backups-old/a.md")));
+        assert!(!is_own_artifact(Path::new("/home/u/notes/a.md")));
+    }
+
+    #[test]
+    fn polled_folders_reindex_only_changed_contents() {
+        let dir = ScratchDir::new("poll");
+        let path = dir.join("a.md");
+        let store = open_store();
+        let acc = accents(&gjson::parse("{}"));
+        let stemmer = Stemmer::create(Algorithm::English);
+        let mut throttle = Throttle::new(&gjson::parse("{}"));
+        let mut index = |modified| {
+            index_if_changed(
+                &store,
+                &path,
+                &punctuation(),
+                &acc,
+                &stemmer,
+                modified,
+                &mut throttle,
+                false,
+            )
+            .unwrap()
+        };
+
+        fs::write(&path, "some words").unwrap();
+        compare_contents_in(&dir);
+        assert!(index(10));
+        // Only the time moved, even backward.
+        assert!(!index(5));
+        fs::write(&path, "other words").unwrap();
+        assert!(index(6));
+    }
+
+    #[test]
+    fn word_parts_share_their_words_places() {
+        let dir = ScratchDir::new("parts");
+        let path = dir.join("a.rs");
+        let store = open_store();
+        let acc = accents(&gjson::parse("{}"));
+        let stemmer = Stemmer::create(Algorithm::English);
+
+        fs::write(&path, "// intern: tokenizer=code\nparseHttpRequest done\n").unwrap();
+        index_file(
+            &store,
+            &path,
+            0,
+            ChangeKind::Added,
+            &punctuation(),
+            &acc,
+            &stemmer,
+            1,
+        )
+        .unwrap();
+
+        let path = path_text(&path);
+        let words = store.words_between(&path, 0, 10).unwrap();
+        let offset = store
+            .query_row(
+                "SELECT offset FROM file_reverse_index WHERE word = 'Request'",
+                [],
+                |row| row.get::<_, u32>(0),
+            )
+            .unwrap();
+
+        assert_eq!(
+            words,
+            ["intern", "tokenizer", "code", "parseHttpRequest", "done"]
+        );
+        assert_eq!(offset, 3);
+        assert_eq!(
+            store
+                .query_row("SELECT words FROM monitored_file", [], |row| {
+                    row.get::<_, u32>(0)
+                })
+                .unwrap(),
+            5
+        );
+    }
+
+    #[test]
+    fn files_are_found_by_their_own_languages_words() {
+        let dir = ScratchDir::new("lang");
+        let path = dir.join("a.md");
+        let store = open_store();
+        let acc = accents(&gjson::parse("{}"));
+        let stemmer = normalizer(&gjson::parse("{}"), &acc);
+        let ranking = RankingConfig::new(&gjson::parse("{}"));
+        let mut cache = SearchCache::new(4);
+
+        fs::write(&path, "# intern: lang=de\nDie Kinder spielen im Garten\n").unwrap();
+        index_file(
+            &store,
+            &path,
+            0,
+            ChangeKind::Added,
+            &punctuation(),
+            &acc,
+            &*stemmer,
+            1,
+        )
+        .unwrap();
+
+        let search = |query: &str, cache: &mut SearchCache| {
+            let (found, _) = run_search(
+                query,
+                &punctuation(),
+                &acc,
+                &*stemmer,
+                &store,
+                &ranking,
+                cache,
+                &Cancellation::never(),
+            )
+            .unwrap();
+            found.into_keys().collect::<Vec<_>>()
+        };
+
+        assert_eq!(store.languages().unwrap(), ["de"]);
+        assert_eq!(search("spielen", &mut cache), [path_text(&path)]);
+        assert_eq!(search("spiel garten", &mut cache), [path_text(&path)]);
+    }
+
+    #[test]
+    fn tokenize_keeps_byte_offsets() {
+        let words = tokenize("Héllo, wörld!  again", &punctuation());
+
+        assert_eq!(words, vec![(0, "Héllo"), (8, "wörld"), (17, "again")]);
+    }
+
+    #[test]
+    fn tokenize_keeps_numbers_whole() {
+        let text = "Released v1.2.3 on 2024-05-01, at 10:30; 80% done.  Pi is 3.14.";
+        let words = tokenize(text, &punctuation())
+            .iter()
+            .map(|(_, w)| *w)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            words,
+            vec![
+                "Released",
+                "v1.2.3",
+                "on",
+                "2024-05-01",
+                "at",
+                "10:30",
+                "80%",
+                "done",
+                "Pi",
+                "is",
+                "3.14"
+            ]
+        );
+        assert_eq!(
+            tokenize("see 2024-05-01", &punctuation())[1],
+            (4, "2024-05-01")
+        );
+    }
+
+    fn words(text: &str, hyphens: bool, legacy: bool) -> Vec<&str> {
+        let tokenizing = Tokenizing {
+            hyphens,
+            legacy,
+            code: false,
+        };
+
+        tokenize_with(text, &punctuation(), tokenizing)
+            .iter()
+            .map(|(_, w)| *w)
+            .collect()
+    }
+
+    #[test]
+    fn tokenize_handles_apostrophes_and_hyphens() {
+        let text = "'Don't' e-mail the well-known cat’s";
+
+        for legacy in [false, true].iter().copied() {
+            assert_eq!(
+                words(text, false, legacy),
+                vec!["Don't", "e", "mail", "the", "well", "known", "cat’s"]
+            );
+            assert_eq!(
+                words(text, true, legacy),
+                vec!["Don't", "e-mail", "the", "well-known", "cat’s"]
+            );
+        }
+
+        let hyphenated = Tokenizing {
+            hyphens: true,
+            legacy: false,
+            code: false,
+        };
+
+        assert_eq!(
+            with_hyphen_parts(tokenize_with(text, &punctuation(), hyphenated))[1..4]
+                .to_vec(),
+            vec![(8, "e-mail"), (8, "e"), (10, "mail")]
+        );
+        assert_eq!(
+            with_hyphen_parts(vec![(0, "2024-05-01")]),
+            vec![(0, "2024-05-01")]
+        );
+    }
+
+    #[test]
+    fn tokenize_follows_unicode_word_boundaries() {
+        let text = "«Bonjour», dit-il — “Привет” 東京";
+
+        assert_eq!(
+            words(text, false, false),
+            vec!["Bonjour", "dit", "il", "Привет", "東", "京"]
+        );
+        assert_eq!(
+            words(text, false, true),
+            vec!["«Bonjour»", "dit", "il", "—", "“Привет”", "東京"]
+        );
+    }
+
+    #[test]
+    fn modelines_pick_the_tokenizer() {
+        let text = "// intern: tokenizer=code\nlet parseHTTPRequest = MAX_RESULTS;";
+        let path = Path::new("notes.rs");
+        let tokenizing = file_tokenizing(path, &Modeline::read(text));
+        let words = chunk_words(text, &mut None, &punctuation(), tokenizing);
+
+        assert_eq!(
+            words[4..].to_vec(),
+            vec![
+                (30, "parseHTTPRequest"),
+                (30, "parse"),
+                (35, "HTTP"),
+                (39, "Request"),
+                (49, "MAX_RESULTS"),
+                (49, "MAX"),
+                (53, "RESULTS")
+            ]
+        );
+        assert!(
+            file_tokenizing(path, &Modeline::read("intern: tokenizer=legacy")).legacy
+        );
+        assert_eq!(
+            identifier_parts("utf8Decode"),
+            vec![(0, "utf8"), (4, "Decode")]
+        );
+        assert_eq!(identifier_parts("__init__"), vec![(2, "init")]);
+    }
+
+    #[test]
+    fn stem_word_folds_case() {
+        let stemmer = Stemmer::create(Algorithm::English);
+
+        let acc = accents(&gjson::parse("{}"));
+
+        assert_eq!(stem_word("Foxes", &acc, &stemmer), "fox");
+        assert_eq!(stem_word("E-mails", &acc, &stemmer), "email");
+        assert_eq!(stem_word("don’t", &acc, &stemmer), "don't");
+    }
+
+    #[test]
+    fn accents_are_stripped_unless_configured() {
+        let stemmer = Stemmer::create(Algorithm::English);
+        let insensitive = accents(&gjson::parse("{}"));
+        let sensitive = accents(&gjson::parse(r#"{"accentSensitive": true}"#));
+
+        assert_eq!(stem_word("Café", &insensitive, &stemmer), "cafe");
+        assert_eq!(stem_word("cafe\u{301}", &insensitive, &stemmer), "cafe");
+        assert_eq!(stem_word("Café", &sensitive, &stemmer), "café");
+        assert_eq!(strip_accents("naïve résumé", &insensitive), "naive resume");
+    }
+
+    #[test]
+    fn front_matter_tags_accepts_each_form() {
+        let inline = "---\ntags: One, two\n---\nText";
+        let bracketed = "---\ntitle: x\ntags: [\"three\", four]\n---\n";
+        let listed = "---\ntags:\n  - five\n  - six\ndate: today\n---\n";
+
+        assert_eq!(front_matter_tags(inline), vec!["one", "two"]);
+        assert_eq!(front_matter_tags(bracketed), vec!["three", "four"]);
+        assert_eq!(front_matter_tags(listed), vec!["five", "six"]);
+    }
+
+    #[test]
+    fn front_matter_tags_needs_closed_front_matter() {
+        assert!(front_matter_tags("---\ntags: a\nNo end in sight").is_empty());
+        assert!(front_matter_tags("Text\n---\ntags: a\n---\n").is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use regex::Regex;
+use rusqlite::params;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::error::Error;
+use crate::indexer::{count_words, file_mod_time};
+use crate::store::Store;
+
+// How many files to check each time, without a `sample` item, which is few
+// enough that a check doesn't hold up indexing or queries.
+pub const DEFAULT_SAMPLE: usize = 20;
+
+// What a check found, where each kind of drift points at a different
+// problem.  A missing file means a missed removal, a stale one means a
+// missed write, and a file whose words don't match its index means the
+// index was cut short or damaged.
+#[derive(Debug, Default, PartialEq)]
+pub struct IntegrityCheck {
+    pub sampled: usize,
+    pub missing: usize,
+    pub stale: usize,
+    pub drifted: usize,
+}
+
+// Check a random sample of indexed files against the disk, reading each
+// one again to count its words, and store what we found for `@stats`.
+pub fn check_integrity(
+    store: &Store,
+    punc: &Regex,
+    sample: usize,
+) -> Result<IntegrityCheck, Error> {
+    let mut stmt = store.prepare(
+        "SELECT f.path, f.modified, COUNT(i.file)
+           FROM monitored_file f
+           LEFT JOIN file_reverse_index i ON i.file = f.id
+           WHERE f.id IN (
+             SELECT id FROM monitored_file WHERE modified > 0
+               ORDER BY RANDOM() LIMIT ?
+           )
+           GROUP BY f.id",
+    )?;
+    let files = stmt
+        .query_map(params![sample], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u64>(1)?,
+                row.get::<_, usize>(2)?,
+            ))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let mut check = IntegrityCheck::default();
+
+    for (path, modified, indexed) in files {
+        let location = store.locate_file(&path);
+
+        check.sampled += 1;
+        if !location.exists() {
+            warn!("Integrity check: {} is gone, but still indexed", path);
+            check.missing += 1;
+        } else if file_mod_time(&location) != modified {
+            warn!("Integrity check: {} changed without being indexed", path);
+            check.stale += 1;
+        } else {
+            match count_words(&location, punc) {
+                Ok(Some(words)) if words != indexed => {
+                    warn!(
+                        "Integrity check: {} has {} words, but {} indexed",
+                        path, words, indexed
+                    );
+                    check.drifted += 1;
+                }
+                Ok(_) => (),
+                Err(e) => warn!("Integrity check: can't read {}: {}", path, e),
+            }
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    store.execute(
+        "INSERT INTO integrity_check (checked_at, sampled, missing, stale, drifted)
+           VALUES (?, ?, ?, ?, ?)",
+        params![
+            now,
+            check.sampled,
+            check.missing,
+            check.stale,
+            check.drifted
+        ],
+    )?;
+    info!(
+        "Integrity check: {} sampled, {} missing, {} stale, {} drifted",
+        check.sampled, check.missing, check.stale, check.drifted
+    );
+    Ok(check)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{accents, punctuation};
+    use crate::store::IndexTuple;
+    use crate::testing::{open_store, ScratchDir};
+    use std::fs;
+
+    #[test]
+    fn drift_gets_noticed() {
+        let dir = ScratchDir::new("int");
+        let store = open_store();
+        let punc = punctuation();
+
+        let stems = store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+
+        for (name, text, words) in [("a.md", "fox", 1), ("b.md", "fox fox", 1)] {
+            let path = dir.join(name);
+
+            fs::write(&path, text).unwrap();
+
+            let file = store.insert_file(&path, &file_mod_time(&path)).unwrap();
+
+            store
+                .insert_bulk_word_tuples(
+                    (0..words)
+                        .map(|offset| IndexTuple {
+                            file: file.id,
+                            stem: stems["fox"],
+                            offset,
+                            byte_offset: 0,
+                            word: "fox".to_string(),
+                        })
+                        .collect(),
+                    &accents(&gjson::parse("{}")),
+                )
+                .unwrap();
+        }
+
+        store.insert_file(&dir.join("c.md"), &1).unwrap();
+        assert_eq!(
+            check_integrity(&store, &punc, 10).unwrap(),
+            IntegrityCheck {
+                sampled: 3,
+                missing: 1,
+                stale: 0,
+                drifted: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,218 @@
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use intern_client::{Client, LineMatch};
+use std::env;
+use std::io::{self, Stdout};
+use std::process::Command;
+use tui::backend::CrosstermBackend;
+use tui::layout::{Constraint, Direction, Layout, Rect};
+use tui::style::{Modifier, Style};
+use tui::text::{Span, Spans};
+use tui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use tui::{Frame, Terminal};
+
+use crate::text::read_text;
+
+type Screen = Terminal<CrosstermBackend<Stdout>>;
+
+// What the user has typed and what we found for it.
+struct Browser {
+    query: String,
+    results: Vec<LineMatch>,
+    selected: ListState,
+    error: Option<String>,
+}
+
+impl Browser {
+    // Search for the current query, keeping the selection in range.  The
+    // daemon caches recent searches, so retyping or backspacing over a
+    // query is cheap.
+    fn search(&mut self, client: &Client) {
+        if self.query.trim().is_empty() {
+            self.results.clear();
+            self.error = None;
+        } else {
+            match client.lines(&self.query) {
+                Ok(results) => {
+                    self.results = results;
+                    self.error = None;
+                }
+                Err(e) => self.error = Some(e.to_string()),
+            }
+        }
+
+        self.selected.select(if self.results.is_empty() {
+            None
+        } else {
+            Some(self.selected.selected().unwrap_or(0).min(self.results.len() - 1))
+        });
+    }
+
+    // Move the selection up or down the list, stopping at either end.
+    fn move_selection(&mut self, step: isize) {
+        if let Some(current) = self.selected.selected() {
+            let last = self.results.len() as isize - 1;
+            let next = (current as isize + step).clamp(0, last);
+
+            self.selected.select(Some(next as usize));
+        }
+    }
+
+    // The result under the selection, if any.
+    fn current(&self) -> Option<&LineMatch> {
+        self.selected.selected().and_then(|i| self.results.get(i))
+    }
+}
+
+// Run an interactive search screen against the daemon: type to search,
+// move through the results with the arrow keys to preview each match, and
+// press Enter to open the file in $EDITOR at the matching line.
+pub fn run(client: &Client) -> io::Result<()> {
+    let mut screen = open_screen()?;
+    let result = browse(client, &mut screen);
+
+    close_screen(&mut screen)?;
+    result
+}
+
+// Handle keystrokes until the user quits.
+fn browse(client: &Client, screen: &mut Screen) -> io::Result<()> {
+    let mut browser = Browser {
+        query: String::new(),
+        results: Vec::new(),
+        selected: ListState::default(),
+        error: None,
+    };
+
+    loop {
+        screen.draw(|f| draw(f, &mut browser))?;
+
+        if let Event::Key(KeyEvent { code, modifiers }) = event::read()? {
+            match code {
+                KeyCode::Esc => return Ok(()),
+                KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
+                KeyCode::Char(c) => {
+                    browser.query.push(c);
+                    browser.search(client);
+                }
+                KeyCode::Backspace => {
+                    browser.query.pop();
+                    browser.search(client);
+                }
+                KeyCode::Up => browser.move_selection(-1),
+                KeyCode::Down => browser.move_selection(1),
+                KeyCode::PageUp => browser.move_selection(-10),
+                KeyCode::PageDown => browser.move_selection(10),
+                KeyCode::Enter => {
+                    if let Some(found) = browser.current().cloned() {
+                        close_screen(screen)?;
+                        edit(&found);
+                        *screen = open_screen()?;
+                        browser.search(client);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+// Lay out the query box over the results, with the preview beside them.
+fn draw(f: &mut Frame<CrosstermBackend<Stdout>>, browser: &mut Browser) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(3), Constraint::Min(1)])
+        .split(f.size());
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(rows[1]);
+    let title = match &browser.error {
+        Some(e) => format!("Search ({})", e),
+        None => format!("Search ({} files)", browser.results.len()),
+    };
+    let query = Paragraph::new(browser.query.as_str())
+        .block(Block::default().borders(Borders::ALL).title(title));
+    let items = browser
+        .results
+        .iter()
+        .map(|r| ListItem::new(r.path.to_string_lossy().to_string()))
+        .collect::<Vec<_>>();
+    let list = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Results"))
+        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+
+    f.render_widget(query, rows[0]);
+    f.set_cursor(rows[0].x + 1 + browser.query.chars().count() as u16, rows[0].y + 1);
+    f.render_stateful_widget(list, columns[0], &mut browser.selected);
+    f.render_widget(preview(browser.current(), columns[1]), columns[1]);
+}
+
+// Show the lines around the selected match, highlighting the match.
+fn preview(found: Option<&LineMatch>, area: Rect) -> Paragraph<'static> {
+    let block = Block::default().borders(Borders::ALL).title("Preview");
+    let found = match found {
+        Some(found) => found,
+        None => return Paragraph::new("").block(block),
+    };
+    let text = match read_text(&found.path) {
+        Ok(Some((text, _encoding))) => text,
+        _ => String::new(),
+    };
+    let height = area.height.saturating_sub(2) as usize;
+    let first = found.line.saturating_sub(height / 2 + 1);
+    let lines = text
+        .lines()
+        .enumerate()
+        .skip(first)
+        .take(height)
+        .map(|(i, line)| {
+            let style = if i + 1 == found.line {
+                Style::default().add_modifier(Modifier::BOLD | Modifier::REVERSED)
+            } else {
+                Style::default()
+            };
+
+            Spans::from(Span::styled(line.to_string(), style))
+        })
+        .collect::<Vec<_>>();
+
+    Paragraph::new(lines).block(block)
+}
+
+// Open the file in the user's editor, at the matching line, which most
+// editors accept as a `+line` argument.
+fn edit(found: &LineMatch) {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let status = Command::new(&editor)
+        .arg(format!("+{}", found.line.max(1)))
+        .arg(&found.path)
+        .status();
+
+    if let Err(e) = status {
+        eprintln!("Unable to run {}: {}", editor, e);
+    }
+}
+
+// Take over the terminal.
+fn open_screen() -> io::Result<Screen> {
+    let mut stdout = io::stdout();
+
+    enable_raw_mode()?;
+    execute!(stdout, EnterAlternateScreen)?;
+    Terminal::new(CrosstermBackend::new(stdout))
+}
+
+// Give the terminal back, as we found it.
+fn close_screen(screen: &mut Screen) -> io::Result<()> {
+    disable_raw_mode()?;
+    execute!(screen.backend_mut(), LeaveAlternateScreen)?;
+    screen.show_cursor()
+}
@@ -0,0 +1,96 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::indexer::strip_accents;
+use crate::normalizer::Normalizer;
+
+// A dictionary of word forms, each with the word that it's a form of, so
+// that "universities" becomes "university" and leaves "universal" alone.
+// Words that the dictionary doesn't know stay as they are.
+pub struct Lemmatizer {
+    lemmas: HashMap<String, String>,
+}
+
+impl Lemmatizer {
+    // Read a dictionary with a word and one of its forms on each line,
+    // separated by a tab, like the lists at
+    // https://github.com/michmech/lemmatization-lists, normalizing both
+    // the way the indexer does.  A form listed more than once keeps the
+    // first word it's listed with.
+    pub fn load(path: &Path, accents: &Regex) -> io::Result<Lemmatizer> {
+        let text = fs::read_to_string(path)?;
+        let mut lemmas = HashMap::<String, String>::new();
+
+        for line in text.lines() {
+            let (lemma, form) = match line.split_once('\t') {
+                Some((lemma, form)) => (lemma.trim(), form.trim()),
+                None => continue,
+            };
+
+            if lemma.is_empty() || form.is_empty() {
+                continue;
+            }
+
+            lemmas
+                .entry(strip_accents(form, accents).to_lowercase())
+                .or_insert_with(|| strip_accents(lemma, accents).to_lowercase());
+        }
+
+        if lemmas.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "no word forms in the dictionary",
+            ));
+        }
+
+        Ok(Lemmatizer { lemmas })
+    }
+}
+
+impl Normalizer for Lemmatizer {
+    fn normalize(&self, word: &str) -> String {
+        self.lemmas
+            .get(word)
+            .cloned()
+            .unwrap_or_else(|| word.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::accents;
+    use crate::indexer::stem_word;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn forms_become_their_lemmas() {
+        let accents = accents(&gjson::parse("{}"));
+        let dir = ScratchDir::new("lemmas");
+        let path = dir.join("lemmas.tsv");
+
+        fs::write(
+            &path,
+            "university\tuniversities\nuniversité\tuniversités\nsee\tsaw\nsaw\tsaw\n",
+        )
+        .unwrap();
+
+        let lemmatizer = Lemmatizer::load(&path, &accents).unwrap();
+
+        assert_eq!(
+            stem_word("Universities", &accents, &lemmatizer),
+            "university"
+        );
+        assert_eq!(
+            stem_word("universités", &accents, &lemmatizer),
+            "universite"
+        );
+        assert_eq!(stem_word("universal", &accents, &lemmatizer), "universal");
+        assert_eq!(stem_word("saw", &accents, &lemmatizer), "see");
+        fs::write(&path, "no tabs here\n").unwrap();
+        assert!(Lemmatizer::load(&path, &accents).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+pub mod alerts;
+pub mod cluster;
+pub mod coalescer;
+pub mod compounds;
+pub mod config;
+pub mod dates;
+pub mod error;
+pub mod export;
+pub mod feed;
+pub mod ignores;
+pub mod indexer;
+pub mod integrity;
+pub mod interactive;
+pub mod json;
+#[cfg(feature = "lemmas")]
+pub mod lemmas;
+pub mod links;
+pub mod lsp;
+pub mod media;
+pub mod metrics;
+pub mod modeline;
+pub mod normalizer;
+#[cfg(feature = "ocr")]
+pub mod ocr;
+pub mod phonetic;
+pub mod presets;
+pub mod protocol;
+pub mod query;
+pub mod remote;
+pub mod result_cache;
+pub mod schedules;
+#[cfg(feature = "semantic")]
+pub mod semantic;
+pub mod server;
+pub mod store;
+pub mod subscriptions;
+pub mod symbols;
+pub mod table;
+#[cfg(test)]
+mod testing;
+pub mod text;
+pub mod traces;
+pub mod trigrams;
+pub mod users;
+pub mod watch_registry;
+pub mod web;
+pub mod webhooks;
@@ -0,0 +1,161 @@
+use regex::Regex;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+
+// Links between notes, like `[[Some Note]]` or `[[Some Note|what it says]]`,
+// which name a note, rather than giving its path.
+const WIKI_LINK_PATTERN: &str = r"\[\[([^\]|#]+)(?:[|#][^\]]*)?\]\]";
+
+// Markdown links, like `[what it says](../notes/note.md "title")`.
+const MARKDOWN_LINK_PATTERN: &str = r#"\[[^\]]*\]\(<?([^)\s>]+)>?(?:\s+"[^"]*")?\)"#;
+
+// Org links to files, like `[[file:note.org][what it says]]`.
+const ORG_LINK_PATTERN: &str = r"\[\[file:([^\]]+)\](?:\[[^\]]*\])?\]";
+
+static WIKI_LINKS: OnceLock<Regex> = OnceLock::new();
+static MARKDOWN_LINKS: OnceLock<Regex> = OnceLock::new();
+static ORG_LINKS: OnceLock<Regex> = OnceLock::new();
+
+// Where a note links to, as either a file's path, for an ordinary link, or
+// a note's name, for a wiki link, which means any file with that name,
+// whatever folder it's in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Link {
+    Path(String),
+    Name(String),
+}
+
+impl Link {
+    // What the index records the link as pointing to, which can't be
+    // mistaken for the other kind, since names don't have slashes.
+    pub fn target(&self) -> &str {
+        match self {
+            Link::Path(path) | Link::Name(path) => path,
+        }
+    }
+}
+
+// Find the links in some of a note's text, or nothing, if it isn't a
+// Markdown or Org file.  Links to web pages and to places in the same file
+// don't count.
+pub fn find_links(path: &Path, text: &str) -> Vec<Link> {
+    let base = path.parent().unwrap_or_else(|| Path::new("/"));
+
+    if is_markdown(path) {
+        let wiki = WIKI_LINKS.get_or_init(|| Regex::new(WIKI_LINK_PATTERN).unwrap());
+        let markdown =
+            MARKDOWN_LINKS.get_or_init(|| Regex::new(MARKDOWN_LINK_PATTERN).unwrap());
+
+        wiki.captures_iter(text)
+            .map(|c| Link::Name(note_name(&c[1])))
+            .chain(
+                markdown
+                    .captures_iter(text)
+                    .filter_map(|c| local_path(base, &c[1]))
+                    .map(Link::Path),
+            )
+            .collect()
+    } else if extension(path) == "org" {
+        ORG_LINKS
+            .get_or_init(|| Regex::new(ORG_LINK_PATTERN).unwrap())
+            .captures_iter(text)
+            // Org can point at a heading or line after a double colon.
+            .filter_map(|c| local_path(base, c[1].split("::").next()?))
+            .map(Link::Path)
+            .collect()
+    } else {
+        Vec::new()
+    }
+}
+
+// Whether a file is a Markdown note, which other notes can link to by name.
+pub fn is_markdown<P: AsRef<Path>>(path: P) -> bool {
+    matches!(extension(path.as_ref()).as_str(), "md" | "markdown")
+}
+
+fn extension(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+// The name a wiki link goes by, which is the name of the file without any
+// folder or extension, ignoring case.
+pub fn note_name(name: &str) -> String {
+    let name = name.trim();
+    let name = name.rsplit('/').next().unwrap_or(name);
+    let name = name
+        .strip_suffix(".md")
+        .or_else(|| name.strip_suffix(".markdown"))
+        .unwrap_or(name);
+
+    name.to_lowercase()
+}
+
+// Turn a link's target into the path of a file, relative to the folder the
+// note is in, or nothing, for a link somewhere else.
+fn local_path(base: &Path, target: &str) -> Option<String> {
+    let target = target.split('#').next()?;
+
+    if target.is_empty() || target.contains("://") || target.starts_with("mailto:") {
+        return None;
+    }
+
+    let target = target.replace("%20", " ");
+    let target = target.strip_prefix("file://").unwrap_or(&target);
+    let home = dirs::home_dir().unwrap_or_default();
+    let full = match target.strip_prefix("~/") {
+        Some(rest) => home.join(rest),
+        None => base.join(target),
+    };
+
+    Some(tidy(&full).to_string_lossy().into_owned())
+}
+
+// Resolve the `.` and `..` in a path, without going to the disk, since the
+// file might not exist yet.
+fn tidy(path: &Path) -> PathBuf {
+    let mut tidied = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                tidied.pop();
+            }
+            other => tidied.push(other),
+        }
+    }
+
+    tidied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notes_link_by_name_and_by_path() {
+        let note = Path::new("/notes/daily/today.md");
+        let text = "See [[Project X]] and [[ideas/Later.md|later]], \
+                    [the plan](../plan.md#goals), [me](mailto:me@example.com), \
+                    [a site](https://example.com), and [this](#top).";
+
+        assert_eq!(
+            find_links(note, text),
+            [
+                Link::Name("project x".to_string()),
+                Link::Name("later".to_string()),
+                Link::Path("/notes/plan.md".to_string()),
+            ]
+        );
+        assert_eq!(
+            find_links(
+                Path::new("/notes/today.org"),
+                "[[file:./work/plan.org::*Goals][plan]] [[Some heading]]"
+            ),
+            [Link::Path("/notes/work/plan.org".to_string())]
+        );
+        assert!(find_links(Path::new("/notes/a.txt"), "[[Project X]]").is_empty());
+    }
+}
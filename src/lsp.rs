@@ -0,0 +1,191 @@
+use crate::json;
+use intern_client::{Client, LineMatch};
+use std::io::{self, BufRead, Write};
+use tracing::{debug, error, info};
+
+// LSP's SymbolKind for a plain string, which is the closest thing it has
+// to a line of prose.
+const SYMBOL_KIND_STRING: u32 = 15;
+
+// What we tell the client we can do, which is only workspace symbols.
+const CAPABILITIES: &str = concat!(
+    r#"{"capabilities":{"workspaceSymbolProvider":true},"#,
+    r#""serverInfo":{"name":"intern"}}"#
+);
+
+// Speak enough of the Language Server Protocol over standard input and
+// output to answer `workspace/symbol` requests with full-text searches,
+// passed along to the daemon, so editors can search every indexed file
+// through their existing LSP plumbing.
+pub fn run(client: &Client) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+
+    info!("Serving LSP requests");
+    while let Some(message) = read_message(&mut input) {
+        let request = gjson::parse(&message);
+        let method = request.get("method");
+        let id = request.get("id");
+
+        debug!("LSP {}", method.str());
+        let response = match method.str() {
+            "initialize" => Some(result(&id, CAPABILITIES)),
+            "workspace/symbol" => {
+                let query = request.get("params.query");
+
+                Some(match client.lines(query.str()) {
+                    Ok(found) => result(&id, &symbols(&found)),
+                    Err(e) => failure(&id, -32603, &e.to_string()),
+                })
+            }
+            "shutdown" => Some(result(&id, "null")),
+            "exit" => break,
+            // Notifications don't get answers, even when we ignore them.
+            _ if !id.exists() => None,
+            _ => Some(failure(&id, -32601, "method not found")),
+        };
+
+        if let Some(response) = response {
+            if let Err(e) = write_message(&mut output, &response) {
+                error!("Unable to answer LSP client: {}", e);
+                break;
+            }
+        }
+    }
+}
+
+// Read one message, which is a JSON body following a block of headers,
+// returning nothing once the client hangs up.
+fn read_message(input: &mut impl BufRead) -> Option<String> {
+    let mut length = 0;
+
+    loop {
+        let mut header = String::new();
+
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+
+        let header = header.trim();
+
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                length = value.trim().parse::<usize>().ok()?;
+            }
+        }
+    }
+
+    let mut body = vec![0; length];
+
+    input.read_exact(&mut body).ok()?;
+    String::from_utf8(body).ok()
+}
+
+// Frame and send one message.
+fn write_message(output: &mut impl Write, body: &str) -> io::Result<()> {
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+// A successful response, given the result already rendered as JSON.
+fn result(id: &gjson::Value, json: &str) -> String {
+    format!(r#"{{"jsonrpc":"2.0","id":{},"result":{}}}"#, id.json(), json)
+}
+
+// An error response.
+fn failure(id: &gjson::Value, code: i32, message: &str) -> String {
+    format!(
+        r#"{{"jsonrpc":"2.0","id":{},"error":{{"code":{},"message":{}}}}}"#,
+        id.json(),
+        code,
+        json::quote(message)
+    )
+}
+
+// Render search results as SymbolInformation, naming each symbol after
+// the matching line and pointing at the start of that line.
+fn symbols(found: &[LineMatch]) -> String {
+    let symbols = found
+        .iter()
+        .map(|m| {
+            let path = m.path.to_string_lossy();
+            let position =
+                format!(r#"{{"line":{},"character":0}}"#, m.line.saturating_sub(1));
+            let location = format!(
+                r#"{{"uri":{},"range":{{"start":{},"end":{}}}}}"#,
+                json::quote(&file_uri(&path)),
+                position,
+                position
+            );
+
+            format!(
+                r#"{{"name":{},"kind":{},"containerName":{},"location":{}}}"#,
+                json::quote(m.text.trim()),
+                SYMBOL_KIND_STRING,
+                json::quote(&path),
+                location
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("[{}]", symbols.join(","))
+}
+
+// Turn a path into a `file://` URI.
+pub fn file_uri(path: &str) -> String {
+    format!("file://{}", percent_encode(path))
+}
+
+// Percent-encode anything in a path that isn't safe to leave as it is in a
+// URI.
+pub fn percent_encode(path: &str) -> String {
+    let mut encoded = String::new();
+
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'-' | b'.' | b'_' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    #[test]
+    fn read_message_uses_the_content_length() {
+        let mut input = io::Cursor::new(
+            "Content-Length: 2\r\nContent-Type: x\r\n\r\n{}Content-Length: 1\r\n\r\n[",
+        );
+
+        assert_eq!(read_message(&mut input).unwrap(), "{}");
+        assert_eq!(read_message(&mut input).unwrap(), "[");
+        assert!(read_message(&mut input).is_none());
+    }
+
+    #[test]
+    fn symbols_point_at_the_matching_line() {
+        let found = vec![LineMatch {
+            path: PathBuf::from("/my notes/a.md"),
+            line: 3,
+            text: "  a \"quoted\" fox".to_string(),
+        }];
+        let rendered = symbols(&found);
+        let parsed = gjson::parse(&rendered);
+
+        assert_eq!(parsed.get("0.name").str(), "a \"quoted\" fox");
+        assert_eq!(parsed.get("0.location.uri").str(), "file:///my%20notes/a.md");
+        assert_eq!(parsed.get("0.location.range.start.line").u32(), 2);
+    }
+}
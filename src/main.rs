@@ -1,80 +1,122 @@
-extern crate dirs;
-extern crate log;
-extern crate notify;
-extern crate regex;
-extern crate rusqlite;
-extern crate rust_stemmers;
-extern crate unicode_normalization;
-
-use chrono::{NaiveDateTime, Local};
-use log::{debug, error, info, trace, warn};
+use intern::alerts::Alerts;
+use intern::cluster::cluster_files;
+use intern::coalescer::EventCoalescer;
+use intern::compounds::set_compounds;
+use intern::config::{accents, find_paths, punctuation, RankingConfig, Throttle};
+use intern::dates::set_timezone;
+use intern::export::{export_weights, Format};
+use intern::ignores::Ignores;
+use intern::indexer::{
+    compare_contents_in, describe_event, exclude_own_artifacts, process_event,
+    process_folder, set_tokenizing, WATCH_INCLUDED_FILES,
+};
+use intern::integrity::{check_integrity, DEFAULT_SAMPLE};
+use intern::metrics::METRICS;
+use intern::normalizer::normalizer;
+use intern::presets::set_presets;
+use intern::query::{SearchCache, RESULT_CACHE_SIZE};
+use intern::remote::RemoteFolder;
+use intern::schedules::Schedules;
+#[cfg(feature = "semantic")]
+use intern::semantic::{set_semantic, Embeddings};
+use intern::server::{
+    handle_queries, sd_notify, set_max_results, set_query_log, watchdog_interval,
+    PendingRequests, Reindexing,
+};
+use intern::store::{
+    latest_backup_age, path_text, set_keep_deleted, take_index_changes, Store,
+};
+use intern::interactive;
+use intern::lsp;
+use intern::subscriptions::Subscriptions;
+#[cfg(feature = "ocr")]
+use intern::ocr::set_ocr;
+use intern::table::set_tabular_numbers;
+use intern::text::set_memory_map;
+use intern::trigrams::set_trigrams;
+use intern::traces::set_tracing;
+use intern::users::{set_multi_user, set_tokens};
+use intern::watch_registry::{self, WatchRegistry};
+use intern::web;
+use intern::webhooks::Webhooks;
+use intern_client::Client;
 use mio::net::TcpListener;
 use mio::{Events, Interest, Poll, Token};
-use notify::DebouncedEvent::{
-    Chmod, Create, Error, NoticeRemove, NoticeWrite, Remove, Rename, Rescan,
-    Write as NotifyWrite,
-};
-use notify::{watcher, INotifyWatcher, RecursiveMode, Watcher};
-use regex::Regex;
-use rusqlite::{params, params_from_iter, Connection, Statement};
-use rust_stemmers::{Algorithm, Stemmer};
-use std::collections::HashMap;
-use std::io::{Read, Write};
-use std::iter::FromIterator;
+use notify::DebouncedEvent::Error;
+use notify::{watcher, RecursiveMode};
+use std::env;
+use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
 use std::sync::mpsc::channel;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::{fs, io, str};
-use unicode_normalization::UnicodeNormalization;
-
-#[derive(Debug)]
-struct MonitoredFile {
-    id: u32,
-    modified: u64,
-    path: String,
-}
-
-#[derive(Debug)]
-struct WordStem {
-    id: u32,
-    stem: String,
-}
-
-#[derive(Debug)]
-struct IndexTuple {
-    id: u32,
-    file: u32,
-    stem: u32,
-    offset: u32,
-    word: String,
-}
-
-#[derive(Debug)]
-struct IgnoreFile<'a> {
-    path: String,
-    file: gitignore::File<'a>,
-}
-
-#[derive(Debug)]
-struct SearchResult {
-    path: String,
-    word: String,
-    stem: u32,
-    offset: u32,
-}
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, error, info, warn};
 
 fn main() {
-    let punc = Regex::new(r"[\x00-\x26\x28-\x2F\x3A-\x40\x5B-\x60\x7B-\x7F]+").unwrap();
-    let acc = Regex::new(r"\x{0300}-\x{035f}").unwrap();
-    let stem = Stemmer::create(Algorithm::English);
+    let punc = punctuation();
     let (config_path, db_path, log_path) = find_paths();
     let config_file = fs::read_to_string(config_path.as_path())
         .expect("Unable to read configuration file.");
     let config = gjson::parse(&config_file);
+    let acc = accents(&config);
+
+    set_tokenizing(&config);
+
+    match env::args().nth(1).as_deref() {
+        Some("lsp") => {
+            lsp::run(&connect_client(&config));
+            return;
+        }
+        Some("tui") => {
+            if let Err(e) = interactive::run(&connect_client(&config)) {
+                eprintln!("{}", e);
+            }
+
+            return;
+        }
+        Some("cluster") => {
+            let store = Store::open(db_path.as_path()).unwrap();
+            let count = env::args().nth(2).and_then(|n| n.parse().ok());
+
+            store.enforce_data_model();
+            match cluster_files(&store, count) {
+                Ok(clusters) => clusters
+                    .iter()
+                    .for_each(|c| println!("{}\t{}", c.files.len(), c.label)),
+                Err(e) => eprintln!("{}", e),
+            }
+
+            return;
+        }
+        Some("export") => {
+            let store = Store::open(db_path.as_path()).unwrap();
+            let name = env::args().nth(2).unwrap_or_else(|| "csv".to_string());
+
+            store.enforce_data_model();
+            match Format::from_name(&name) {
+                Some(format) => {
+                    let mut out = io::stdout().lock();
+
+                    if let Err(e) = export_weights(&store, format, &mut out) {
+                        eprintln!("{}", e);
+                    }
+                }
+                None => eprintln!("Can't export as {}, only csv or json", name),
+            }
+
+            return;
+        }
+        _ => (),
+    }
+
     let (tx, rx) = channel();
+    let (remote_tx, remote_rx) = channel();
     let check_period = config.get("period").u64();
-    let mut watcher = watcher(tx, Duration::from_secs(check_period)).unwrap();
-    let sqlite = Connection::open(db_path.as_path()).unwrap();
+    let mut watches = WatchRegistry::new(
+        watcher(tx, Duration::from_secs(check_period)).unwrap(),
+    );
+    let store = Store::open(db_path.as_path()).unwrap();
     let start = SystemTime::now();
     let server_info = config.get("server");
     let ip = server_info.get("address");
@@ -84,27 +126,97 @@ fn main() {
     let mut server_poll = Poll::new().unwrap();
     let mut events = Events::with_capacity(1024);
     let server_token: Token = Token(0);
+    // The HTTP server used to be just for metrics, so accept the old name.
+    let http_info = if config.get("http").exists() {
+        config.get("http")
+    } else {
+        config.get("metrics")
+    };
+    let http_listener = web::start_listener(&http_info);
 
     flexi_logger::Logger::try_with_str(config.get("logLevel").str())
         .unwrap()
         .format(flexi_logger::detailed_format)
         .log_to_file(
             flexi_logger::FileSpec::default()
-                .directory(log_path)
+                .directory(&log_path)
                 .basename("intern")
                 .suffix("log")
         )
         .print_message()
         .start()
         .unwrap();
-    enforce_data_model(&sqlite);
+    set_tracing(&config.get("tracing"));
+    store.enforce_data_model();
+    store.fill_word_norms(&acc);
     info!("INTERN reporting for duty");
 
-    let mut fileq = sqlite
-        .prepare("SELECT id, modified, path FROM monitored_file where path = ?")
-        .unwrap();
+    let run_id = store.start_run(&start);
+
+    let mut throttle = Throttle::new(&config.get("throttle"));
+    let mut polled_folders = Vec::<(String, bool, Duration, Instant)>::new();
+    let mut ignores = Ignores::new();
+    let mut all_folders = Vec::<(String, bool)>::new();
+    let rescan_interval = config.get("rescanInterval").u64();
+    let ranking = RankingConfig::new(&config);
+    let mut cache = SearchCache::new(RESULT_CACHE_SIZE);
+    let mut subscriptions = Subscriptions::new();
+    let mut pending = PendingRequests::new();
+    let mut reindexing = Reindexing::new();
+    let backup_info = config.get("backup");
+    let backup_interval = Duration::from_secs(backup_info.get("interval").u64());
+    let backup_dir = PathBuf::from(backup_info.get("destination").str());
+    let backup_retention = backup_info.get("retention").u64() as usize;
+    let integrity_info = config.get("integrityCheck");
+    let integrity_interval = Duration::from_secs(integrity_info.get("interval").u64());
+    let integrity_sample = if integrity_info.get("sample").exists() {
+        integrity_info.get("sample").u64() as usize
+    } else {
+        DEFAULT_SAMPLE
+    };
+
+    exclude_own_artifacts(&[&db_path, &log_path, &backup_dir]);
+    set_timezone(&config.get("timezone"));
+    set_memory_map(&config);
+    set_trigrams(&config);
+    set_compounds(&config);
+    set_tabular_numbers(&config);
+    #[cfg(feature = "ocr")]
+    set_ocr(&config);
+    #[cfg(feature = "semantic")]
+    set_semantic(&config);
+    set_multi_user(&config);
+    set_tokens(&config);
+    set_keep_deleted(&config);
+    set_query_log(&config);
+    set_max_results(&config);
+    set_presets(&config);
+    ignores.set_excludes(&config.get("exclude"));
+
+    let stem = normalizer(&config, &acc);
 
     for folder in config.get("folder").array() {
+        // Remote folders get indexed from their mirrors, which a background
+        // thread keeps up to date, rather than a watcher.
+        if let Some(remote) = RemoteFolder::new(&folder) {
+            let mirror = remote.mirror().to_path_buf();
+
+            all_folders.push((path_text(&mirror), remote.recurse()));
+            ignores.add_root(&mirror);
+            process_folder(
+                &store,
+                &mirror,
+                remote.recurse(),
+                &punc,
+                &acc,
+                &*stem,
+                &mut ignores,
+                &mut throttle,
+            );
+            remote.watch(remote_tx.clone());
+            continue;
+        }
+
         let recurse = folder.get("recurse").bool();
         let mode = if recurse {
             RecursiveMode::Recursive
@@ -113,930 +225,352 @@ fn main() {
         };
         let folder_name = folder.get("name");
         let path = folder_name.str();
-        let ignoregit = Path::new(path).join(".gitignore");
-        let ignorehg = Path::new(path).join(".hgignore");
-        let ignores = if ignoregit.exists() {
-            gitignore::File::new(&ignoregit)
-        } else {
-            // This will produce an error, if neither file exists.
-            gitignore::File::new(&ignorehg)
-        };
+        let polled = folder.get("watchMode").str() == "poll";
+
+        all_folders.push((path.to_string(), recurse));
+        ignores.add_root(Path::new(path));
+        if polled {
+            compare_contents_in(Path::new(path));
+        }
 
         process_folder(
-            &sqlite,
-            path,
+            &store,
+            Path::new(path),
             recurse,
             &punc,
             &acc,
-            &stem,
-            &mut fileq,
-            &Vec::<PathBuf>::new(),
+            &*stem,
+            &mut ignores,
+            &mut throttle,
         );
-        match &ignores {
-            Ok(ignore) => {
-                // Either un-watching or ignore status doesn't work as
-                // expected, so we flip the logic, only watching
-                // non-ignored (included) files.
-                watcher.watch(path, RecursiveMode::NonRecursive).unwrap();
-                ignore
-                    .included_files()
-                    .unwrap()
-                    .into_iter()
-                    .filter(|f|
-                        !f.to_str().unwrap().contains(".git") &&
-                        !f.to_str().unwrap().contains(".hg")
-                    )
-                    .for_each(|file| {
-                        watcher
-                            .watch(
-                                Path::new(file.to_str().unwrap()),
-                                RecursiveMode::NonRecursive,
-                            )
-                            .unwrap();
-                    });
-            }
-            // Not an error; just no ignore file
-            Err(_) => watcher.watch(path, mode).unwrap(),
-        }
-    }
-
-    server_poll
-        .registry()
-        .register(&mut server, server_token, Interest::READABLE)
-        .unwrap();
-    match SystemTime::now().duration_since(start) {
-        Ok(n) => info!("{} seconds to re-index", n.as_secs()),
-        Err(_) => panic!("Something bad"),
-    }
 
-    loop {
-        match rx.recv_timeout(Duration::from_millis(100)) {
-            Ok(event) => match event {
-                Chmod(epath) => process_event(
-                    "chmod",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                Create(epath) => process_event(
-                    "create",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                Error(event, _path) => debug!("error {:?} (unexpected)", event),
-                NoticeRemove(epath) => process_event(
-                    "notice remove",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                NoticeWrite(epath) => process_event(
-                    "notice write",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                NotifyWrite(epath) => process_event(
-                    "notify write",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                Remove(epath) => process_event(
-                    "remove",
-                    epath,
-                    &sqlite,
-                    &punc,
-                    &acc,
-                    &stem,
-                    &mut fileq,
-                    &mut watcher,
-                ),
-                Rename(old, new) => debug!("{:?} => {:?}", old, new),
-                Rescan => debug!("rescan {:?} (unexpected)", event),
-            },
-            Err(e) => {
-                if e != std::sync::mpsc::RecvTimeoutError::Timeout {
-                    debug!("watch error: {:#?}", e);
-                }
-            }
+        // Network filesystems don't deliver reliable events, so for those,
+        // we look for changes instead of waiting to hear about them.
+        if polled {
+            let interval = folder.get("pollInterval");
+            // Scanning a network share every few seconds would be too much,
+            // so the default is a minute.
+            let interval = if interval.exists() {
+                Duration::from_secs(interval.u64().max(1))
+            } else {
+                Duration::from_secs(60)
+            };
+
+            info!("Polling {} every {}s", path, interval.as_secs());
+            polled_folders.push((path.to_string(), recurse, interval, Instant::now()));
+            continue;
         }
 
-        server_poll
-            .poll(&mut events, Some(Duration::from_millis(100)))
-            .unwrap();
-        handle_queries(
-            &sqlite,
-            &events,
-            &server,
-            &server_poll,
-            server_token,
-            &punc,
-            &acc,
-            &stem,
-        );
-    }
-}
-
-fn process_event(
-    event_name: &str,
-    epath: PathBuf,
-    sqlite: &Connection,
-    punc: &Regex,
-    acc: &Regex,
-    stem: &Stemmer,
-    fileq: &mut Statement,
-    watcher: &mut INotifyWatcher,
-) {
-    let path = epath.to_str().unwrap();
-    let last_modified = file_mod_time(path);
-
-    if path.contains(".git")
-        || path.contains(".hg")
-        || path.ends_with(".svg")
-    {
-        return;
-    }
-
-    debug!("processing {} for {}", event_name, path);
-    match watcher.watch(path, RecursiveMode::NonRecursive) {
-        Ok(_) => (),
-        Err(e) => warn!("Can't watch {}: {}", path, e),
-    }
-
-    process_file(
-        sqlite,
-        path,
-        punc,
-        acc,
-        stem,
-        last_modified,
-        fileq,
-    );
-}
-
-// Iterate through the files in the folder, adding or indexing any files
-// that are new or updated since our last run.
-fn process_folder(
-    sqlite: &Connection,
-    path: &str,
-    recursive: bool,
-    punc: &Regex,
-    acc: &Regex,
-    stem: &Stemmer,
-    fileq: &mut Statement,
-    ignored: &Vec<PathBuf>,
-) {
-    let dir = Path::new(path);
-    let filename = dir.file_name().unwrap();
-    let gitignore = dir.join(".gitignore");
-    let hgignore = dir.join(".hgignore");
-    let mut ignores = Vec::<IgnoreFile>::new();
-
-    if !dir.is_dir() || filename == ".git" || filename == ".hg" {
-        return;
-    }
-
-    ignored.iter().for_each(|i| {
-        ignores.push(IgnoreFile {
-            path: String::from(i.as_path().to_str().unwrap()),
-            file: gitignore::File::new(i).unwrap(),
-        });
-    });
-
-    if gitignore.exists() {
-        ignores.push(IgnoreFile {
-            path: String::from(gitignore.as_path().to_str().unwrap()),
-            file: gitignore::File::new(&gitignore).unwrap(),
-        });
-    }
-
-    if hgignore.exists() {
-        ignores.push(IgnoreFile {
-            path: String::from(hgignore.as_path().to_str().unwrap()),
-            file: gitignore::File::new(&hgignore).unwrap(),
-        });
-    }
+        let included = if WATCH_INCLUDED_FILES && recurse {
+            Some(ignores.included_folders(Path::new(path)))
+        } else {
+            None
+        };
+        let watched = match included {
+            Some((folders, true)) => {
+                // Either un-watching or ignore status doesn't work as
+                // expected, so we flip the logic, only watching
+                // non-ignored (included) folders, without recursion.
+                let mut result = Ok(());
 
-    for entry in fs::read_dir(dir).expect("Cannot read directory") {
-        let entry = entry.expect("No entry");
-        let last_modified = file_mod_time(entry.path().to_str().unwrap());
-        let entry_path = entry.path();
-        let path_str = entry_path.to_str().unwrap();
+                for folder in folders {
+                    if result.is_err() {
+                        break;
+                    }
 
-        if recursive && entry.path().is_dir() {
-            process_folder(
-                sqlite,
-                path_str,
-                recursive,
-                punc,
-                acc,
-                stem,
-                fileq,
-                &ignores.iter().map(|i| PathBuf::from(&i.path)).collect(),
-            );
-        } else if entry.path().is_dir() {
-            // Should probably do something, but for now, it's just to prevent
-            // directories from falling through to be managed as normal files.
-        } else {
-            let mut ignore = false;
-            for item in &ignores {
-                ignore =
-                    ignore || item.file.is_excluded(Path::new(&path_str)).unwrap();
-            }
+                    result = watches.watch(&folder, RecursiveMode::NonRecursive);
+                }
 
-            if !ignore {
-                process_file(sqlite, path_str, punc, acc, stem, last_modified, fileq);
+                result
             }
-        }
-    }
-}
-
-// Decide how to index a specific file.
-fn process_file(
-    sqlite: &Connection,
-    path_str: &str,
-    punc: &Regex,
-    acc: &Regex,
-    stem: &Stemmer,
-    last_modified: u64,
-    fileq: &mut Statement,
-) {
-    let mod_time = select_file(fileq, path_str);
+            // Either no ignore files or a natively recursive watcher, which
+            // leaves filtering to process_event.
+            _ => watches.watch(Path::new(path), mode),
+        };
 
-    match mod_time {
-        Some(some_mod) => {
-            // Update and index an existing file.
-            let mtime = some_mod.unwrap();
-            if mtime.modified < last_modified {
-                update_file_mod_time(sqlite, &last_modified, path_str);
-                index_file(
-                    sqlite,
-                    path_str,
-                    mtime.id,
-                    punc,
-                    acc,
-                    stem,
-                    last_modified,
-                    fileq,
+        match watched {
+            Ok(_) => (),
+            Err(e) if watch_registry::is_limit_error(&e) => {
+                warn!(
+                    "Out of inotify watches at {}; polling every {} seconds instead",
+                    path, check_period
                 );
+                polled_folders.push((
+                    path.to_string(),
+                    recurse,
+                    Duration::from_secs(check_period),
+                    Instant::now(),
+                ));
             }
+            Err(e) => error!("Can't watch {}: {}", path, e),
         }
-        None => {
-            // Create and index a new file.
-            let mod_time = insert_file(sqlite, fileq, path_str, &last_modified);
-
-            index_file(
-                sqlite,
-                path_str,
-                mod_time.unwrap().unwrap().id,
-                punc,
-                acc,
-                stem,
-                last_modified,
-                fileq,
-            );
-        }
-    }
-}
-
-// Create the inverted index for the specified file.
-fn index_file(
-    sqlite: &Connection,
-    path: &str,
-    mut file_id: u32,
-    punc: &Regex,
-    accents: &Regex,
-    stemmer: &Stemmer,
-    last_modified: u64,
-    fileq: &mut Statement,
-) {
-    let text = fs::read_to_string(path).unwrap_or_else(|_| "".to_string());
-    let alpha_only = punc.replace_all(&text, " ");
-    let mut space_split = alpha_only.split_whitespace();
-    let mut word_count = 0;
-    let mut all_stems = select_all_stems(sqlite);
-    let mut new_stems = Vec::<String>::new();
-    let mut new_index_tuples = Vec::<IndexTuple>::new();
-
-    // Delete any existing index.
-    if file_id > 0 {
-        clear_index_for(sqlite, file_id);
-    } else {
-        let mod_time = insert_file(sqlite, fileq, path, &last_modified);
-
-        file_id = mod_time.unwrap().unwrap().id;
     }
 
-    space_split.filter(|w| !punc.is_match(w)).for_each(|word| {
-        let stem = stem_word(word, accents, stemmer);
-
-        // Add the stem to the to-be-created list if necessary.
-        if !all_stems.contains_key(&stem) {
-            new_stems.push(stem);
-        }
-    });
-
-    all_stems = insert_bulk_stems(sqlite, new_stems);
-    space_split = alpha_only.split_whitespace();
-    space_split.filter(|w| !punc.is_match(w)).for_each(|word| {
-        let stem = stem_word(word, accents, stemmer);
-        let stem_id = all_stems[&stem];
-        let tuple = IndexTuple {
-            id: 0,
-            file: file_id,
-            stem: stem_id,
-            offset: word_count,
-            word: word.to_string(),
-        };
-        new_index_tuples.push(tuple);
-        word_count += 1;
-    });
-
-    insert_bulk_word_tuples(sqlite, new_index_tuples);
-}
-
-// Ensure the required tables are available.
-fn enforce_data_model(sqlite: &Connection) {
-    sqlite
-        .execute(
-            "CREATE TABLE IF NOT EXISTS monitored_file (
-              id INTEGER PRIMARY KEY,
-              path TEXT NOT NULL,
-              modified INTEGER
-            )",
-            [],
-        )
-        .unwrap();
-    sqlite
-        .execute(
-            "CREATE TABLE IF NOT EXISTS word_stem (
-              id INTEGER PRIMARY KEY,
-              stem TEXT NOT NULL
-            )",
-            [],
-        )
-        .unwrap();
-    sqlite
-        .execute(
-            "CREATE TABLE IF NOT EXISTS file_reverse_index (
-              id INTEGER PRIMARY KEY,
-              file INTEGER NOT NULL,
-              stem INTEGER NOT NULL,
-              offset INTEGER NOT NULL,
-              word TEXT NOT NULL,
-              FOREIGN KEY(file) REFERENCES monitored_file(id),
-              FOREIGN KEY(stem) REFERENCES word_stem(id)
-            )",
-            [],
-        )
+    server_poll
+        .registry()
+        .register(&mut server, server_token, Interest::READABLE)
         .unwrap();
-}
-
-// Extract information from application configuration file at:
-//   ~/.config/intern/intern.json
-fn find_paths() -> (PathBuf, PathBuf, PathBuf) {
-    let app = "intern";
-    let mut config_path = dirs::config_dir().expect("Can't access configuration folder.");
-    config_path.push(app);
-    config_path.push(format!("{}.json", app));
-
-    let mut db_path = dirs::config_dir().unwrap();
-    db_path.push(app);
-    db_path.push(format!("{}.sqlite3", app));
-
-    let mut log_path = dirs::config_dir().unwrap();
-    log_path.push("intern");
-
-    (config_path, db_path, log_path)
-}
-
-// Get the modification time of a file.
-fn file_mod_time(path: &str) -> u64 {
-    let mut time: u64 = 0;
-
-    match fs::metadata(path) {
-        Ok(metadata) => time = metadata
-            .modified()
-            .unwrap()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-        Err(e) => error!("{} for {}", e, path),
+    match SystemTime::now().duration_since(start) {
+        Ok(n) => {
+            info!("{} seconds to re-index", n.as_secs());
+            store.record_scan_time(run_id, n);
+        }
+        Err(_) => panic!("Something bad"),
     }
 
-    time
-}
-
-// Get the stem for the current word.
-fn stem_word(word: &str, accents: &Regex, stem: &Stemmer) -> String {
-    let nfd = word.to_string().nfd().collect::<String>();
-    let no_accents = accents.replace_all(&nfd, "").to_lowercase();
-    stem.stem(&no_accents).trim().to_string()
-}
-
-// Retrieve file information.
-fn select_file(
-    fileq: &mut Statement,
-    path_str: &str,
-) -> Option<Result<MonitoredFile, rusqlite::Error>> {
-    let mod_times = fileq
-        .query_map(params![path_str], |row| {
-            Ok(MonitoredFile {
-                id: row.get(0).unwrap(),
-                modified: row.get(1).unwrap(),
-                path: row.get(2).unwrap(),
-            })
-        })
-        .unwrap();
-
-    mod_times.last()
-}
-
-// Retrieve all stem information.
-fn select_all_stems(sqlite: &Connection) -> HashMap<String, u32> {
-    let mut result = HashMap::new();
-    let mut stemq = sqlite.prepare("SELECT id, stem FROM word_stem").unwrap();
-    let stem_iter = stemq
-        .query_map([], |row| {
-            Ok(WordStem {
-                id: row.get(0).unwrap(),
-                stem: row.get(1).unwrap(),
-            })
-        })
-        .unwrap();
-
-    for stem in stem_iter {
-        let raw_stem = stem.unwrap();
-
-        result.insert(raw_stem.stem.to_string(), raw_stem.id);
+    // The startup scan catches up with whatever changed while we were
+    // away, which isn't news to anybody waiting on changes.
+    for change in take_index_changes() {
+        cache.invalidate(&change.path, &change.stems);
     }
 
-    result
-}
-
-// Return all files modified during the 24 hours after day_start and send
-// the resulting list back to the specified client, rather than returning.
-fn select_files_by_day(
-    day_start: i64,
-    sqlite: &Connection,
-    mut client: mio::net::TcpStream,
-) {
-    let day_end = day_start + 86400;
-    let select = format!(
-        "SELECT path FROM monitored_file WHERE modified >= {} AND modified <= {} ORDER BY modified",
-        day_start,
-        day_end
+    let mut alerts = Alerts::new(
+        &config.get("alerts"),
+        &punc,
+        &acc,
+        &*stem,
+        &store,
+        &ranking,
+        &mut cache,
     );
-    match sqlite.prepare(select.as_str()) {
-        Ok(mut stmt) => {
-            let file_rows = stmt.query_map([], |row| {
-                Ok(row.get(0))
-            }).unwrap();
-            let mut files = Vec::<String>::new();
-
-            file_rows.for_each(|f| files.push(f.unwrap().unwrap()));
-            debug!("{:#?}", files);
-            files.push("".to_string()); // To ensure we retain the last character
-            client.write_all(files.join("\n").as_bytes()).unwrap();
-        },
-        Err(e) => error!("Unable to aggregate results: {}", e),
-    }
-}
-
-// Add a file to be indexed.
-fn insert_file(
-    sqlite: &Connection,
-    fileq: &mut Statement,
-    path_str: &str,
-    last_modified: &u64,
-) -> Option<Result<MonitoredFile, rusqlite::Error>> {
-    sqlite
-        .execute(
-            "INSERT
-               INTO monitored_file (path, modified)
-               VALUES (?, ?)
-            ",
-            params![path_str, last_modified],
-        )
-        .unwrap();
-    select_file(fileq, path_str)
-}
-
-// Insert a group of stems.
-fn insert_bulk_stems(sqlite: &Connection, stems: Vec<String>) -> HashMap<String, u32> {
-    let placeholders = stems.iter().map(|_| "(?)").collect::<Vec<_>>().join(", ");
-    let query = format!("INSERT INTO word_stem (stem) VALUES {}", placeholders);
-
-    if stems.is_empty() {
-        return select_all_stems(sqlite);
-    }
-
-    sqlite
-        .execute(&query, params_from_iter(stems.iter()))
-        .unwrap();
-    select_all_stems(sqlite)
-}
-
-// Index a file's file-stem-position tuples.
-fn insert_bulk_word_tuples(sqlite: &Connection, mut words: Vec<IndexTuple>) {
-    let mut remainder = Vec::<IndexTuple>::new();
-    let max_values = 8192;
-
-    if words.is_empty() {
-        return;
-    }
-
+    let mut webhooks = Webhooks::new(
+        &config.get("webhooks"),
+        &punc,
+        &acc,
+        &*stem,
+        &store,
+        &ranking,
+        &mut cache,
+    );
+    let schedules = Schedules::new(&config.get("schedules"));
+    #[cfg(feature = "semantic")]
+    let embeddings = Embeddings::start();
+    let watchdog_period = watchdog_interval();
+    let mut last_watchdog = Instant::now();
+    let stats_period = Duration::from_secs(60);
+    let mut last_stats = Instant::now();
+    let rewatch_period = Duration::from_secs(60);
+    let mut last_rewatch = Instant::now();
+    let mut last_rescan = Instant::now();
+    let mut last_backup =
+        latest_backup_age(&backup_dir).and_then(|age| Instant::now().checked_sub(age));
+    let mut last_integrity = Instant::now();
+    let mut paused = false;
+    let mut coalescer = EventCoalescer::new(&config, Duration::from_secs(check_period));
+
+    sd_notify("READY=1");
     loop {
-        if words.len() > max_values {
-            remainder = words.split_off(max_values);
-        }
-
-        let placeholders = words
-            .iter()
-            .map(|_| "(?,?,?,?)")
-            .collect::<Vec<_>>()
-            .join(", ");
-        let query = format!(
-            "INSERT INTO file_reverse_index (file,stem,offset,word) VALUES {}",
-            placeholders
-        );
-        let mut values = Vec::<String>::new();
-
-        for word in words {
-            values.push(word.file.to_string());
-            values.push(word.stem.to_string());
-            values.push(word.offset.to_string());
-            values.push(word.word.to_string());
+        if let Some(period) = watchdog_period {
+            if last_watchdog.elapsed() >= period {
+                sd_notify("WATCHDOG=1");
+                last_watchdog = Instant::now();
+            }
         }
 
-        match sqlite.execute(&query, params_from_iter(values.iter())) {
-            Ok(_) => (),
-            Err(e) => panic!("Error:  {}", e),
+        if last_stats.elapsed() >= stats_period {
+            store.save_stats(run_id);
+            last_stats = Instant::now();
+            schedules.run_due(&store, &punc, &acc, &*stem, &ranking, &mut cache);
         }
 
-        words = remainder;
-        remainder = Vec::<IndexTuple>::new();
-        if words.is_empty() {
-            break;
+        if last_rewatch.elapsed() >= rewatch_period {
+            for epath in watches.restore() {
+                coalescer.add("rewatch", epath, Instant::now());
+            }
+            last_rewatch = Instant::now();
         }
-    }
-}
-
-// Update file's last modification time.
-fn update_file_mod_time(sqlite: &Connection, last_modified: &u64, path_str: &str) {
-    sqlite
-        .execute(
-            "UPDATE monitored_file
-               SET modified = ?1
-               WHERE path = ?2
-            ",
-            params![last_modified, path_str],
-        )
-        .unwrap();
-}
-
-// Wipe index information for a file.
-fn clear_index_for(sqlite: &Connection, file_id: u32) {
-    sqlite
-        .execute(
-            "DELETE FROM file_reverse_index WHERE file = ?",
-            params![file_id],
-        )
-        .unwrap();
-}
 
-// Retrieve stem information from the index.
-fn search_index(sqlite: &Connection, stems: Vec<WordStem>) -> Vec<SearchResult> {
-    let mut result = Vec::<SearchResult>::new();
-    let placeholders = stems.iter().map(|_| "(?)").collect::<Vec<_>>().join(", ");
-    let query = format!(
-        "SELECT f.path, i.word, i.stem, i.offset FROM file_reverse_index i JOIN monitored_file f ON f.id = i.file WHERE i.stem IN ({}) ORDER BY f.path, i.stem, i.offset",
-        placeholders
-    );
-    let ids = stems.iter().map(|s| s.id);
-    let mut stemq = sqlite.prepare(&query).unwrap();
-    let index_entries = stemq
-        .query_map(params_from_iter(ids), |row| {
-            Ok(SearchResult {
-                path: row.get(0).unwrap(),
-                word: row.get(1).unwrap(),
-                stem: row.get(2).unwrap(),
-                offset: row.get(3).unwrap(),
-            })
-        })
-        .unwrap();
+        for (path, recurse, interval, last_poll) in &mut polled_folders {
+            if paused || last_poll.elapsed() < *interval {
+                continue;
+            }
 
-    index_entries.for_each(|ie| result.push(ie.unwrap()));
-    result
-}
+            process_folder(
+                &store,
+                Path::new(path),
+                *recurse,
+                &punc,
+                &acc,
+                &*stem,
+                &mut ignores,
+                &mut throttle,
+            );
 
-// Organize a list sorted by file, stem, and offset
-//
-// Note that some of this code is clunky, copying data back and forth
-// between objects, to make sure that we don't violate Rust's ownership
-// rules.
-fn collate_search(
-    search: Vec<SearchResult>,
-    stem_ids: Vec<u32>,
-) -> HashMap<String, HashMap<u32, Vec<SearchResult>>> {
-    let mut result = HashMap::<String, HashMap<u32, Vec<SearchResult>>>::new();
-    let mut by_stem = Vec::<SearchResult>::new();
-    let mut by_file = HashMap::<u32, Vec<SearchResult>>::new();
-    let mut last_stem = 0;
-    let mut last_file = "";
+            // Without events, the only way to notice a file going away is
+            // to look for it.
+            if let Err(e) = store.purge_missing_files_in(Path::new(path)) {
+                error!("Unable to purge missing files in {}: {}", path, e);
+            }
 
-    search.iter().for_each(|sr| {
-        // We don't actually want special behavior on the first run,
-        // so we fake having a previous run with these conditions.
-        if last_file.is_empty() {
-            last_file = &sr.path;
+            *last_poll = Instant::now();
         }
 
-        if last_stem == 0 {
-            last_stem = sr.stem;
-        }
+        if !paused
+            && rescan_interval > 0
+            && last_rescan.elapsed() >= Duration::from_secs(rescan_interval)
+        {
+            info!("Rescanning all folders");
+            for (path, recurse) in &all_folders {
+                process_folder(
+                    &store,
+                    Path::new(path),
+                    *recurse,
+                    &punc,
+                    &acc,
+                    &*stem,
+                    &mut ignores,
+                    &mut throttle,
+                );
+            }
 
-        // Reset the stem list when the stem or file changes.
-        if sr.stem != last_stem || sr.path != last_file {
-            let mut stems = Vec::<SearchResult>::new();
+            if let Err(e) = store.purge_missing_files() {
+                error!("Unable to purge missing files: {}", e);
+            }
 
-            by_stem.iter().for_each(|s| {
-                stems.push(SearchResult {
-                    path: s.path.to_string(),
-                    word: s.word.to_string(),
-                    stem: s.stem,
-                    offset: s.offset,
-                })
-            });
-            by_file.insert(last_stem, stems);
-            by_stem = Vec::<SearchResult>::new();
-            last_stem = sr.stem;
+            last_rescan = Instant::now();
         }
 
-        // Reset the file list when the file changes.
-        if sr.path != last_file {
-            let mut files = HashMap::<u32, Vec<SearchResult>>::new();
-            let mut all_found = true;
-
-            by_file.keys().for_each(|k| {
-                let mut stems = Vec::<SearchResult>::new();
+        if backup_info.exists()
+            && last_backup.is_none_or(|last| last.elapsed() >= backup_interval)
+        {
+            store.backup(&backup_dir, backup_retention);
+            last_backup = Some(Instant::now());
+        }
 
-                by_file[k].iter().for_each(|s| {
-                    stems.push(SearchResult {
-                        path: s.path.to_string(),
-                        word: s.word.to_string(),
-                        stem: s.stem,
-                        offset: s.offset,
-                    });
-                });
-                files.insert(*k, stems);
-            });
-            stem_ids
-                .iter()
-                .for_each(|s| all_found &= files.contains_key(s));
-            if all_found {
-                result.insert(last_file.to_string(), files);
+        if !paused
+            && !integrity_interval.is_zero()
+            && last_integrity.elapsed() >= integrity_interval
+        {
+            if let Err(e) = check_integrity(&store, &punc, integrity_sample) {
+                error!("Unable to check the index's integrity: {}", e);
             }
 
-            by_file = HashMap::<u32, Vec<SearchResult>>::new();
-            last_file = &sr.path;
+            last_integrity = Instant::now();
         }
 
-        by_stem.push(SearchResult {
-            path: sr.path.to_string(),
-            word: sr.word.to_string(),
-            stem: sr.stem,
-            offset: sr.offset,
-        });
-    });
-
-    result
-}
-
-// Sort search results for relevance, returning the ordered file names.
-fn sort_search_results(
-    search: &HashMap<String, HashMap<u32, Vec<SearchResult>>>,
-    query: Vec::<&str>,
-) -> Vec<String> {
-    let mut result = Vec::<String>::new();
-    let mut ranking = HashMap::<String, f32>::new();
-
-    // Each time a literal search term appears in the file, rather than
-    // just the stem, increase the score.
-    search.keys().for_each(|k| {
-        let mut score = 1.0;
-        let stems = &search[k];
-        let _offsets = Vec::<Vec::<u32>>::new();
-        let stem_keys = Vec::from_iter(stems.keys());
-
-        for s in 1..stem_keys.len() - 1 {
-            let offsets = &stems[stem_keys[s]];
-            let compare = &stems[stem_keys[s + 1]];
-            let mut oi = 0;
-            let mut ci = 0;
-
-            while oi < offsets.len() && ci < compare.len() {
-                let offset = offsets[oi].offset;
-                let comp = compare[ci].offset;
-                if offset > comp {
-                    ci += 1;
-                    continue;
-                };
-
-                let diff = comp - offset;
-
-                if diff < 2 {
-                    score += 3.0;
-                } else if diff < 7 {
-                    score += 2.0;
-                } else if diff <= 20 {
-                    score += 1.0;
+        match rx.recv_timeout(Duration::from_millis(100)) {
+            Ok(Error(e, epath)) => {
+                warn!("watch error {:?} for {:?}", e, epath);
+                if let Some(epath) = epath {
+                    watches.dropped(&epath);
                 }
-
-                oi += 1;
             }
-        }
-
-        stems.keys().for_each(|s| {
-            let words = &stems[s];
-
-            words.iter().map(|w| w.word.to_string()).for_each(|w|
-                if query.contains(&w.as_str()) {
-                    score *= 1.1;
+            Ok(event) => {
+                if let Some((event_name, epath)) = describe_event(event) {
+                    coalescer.add(event_name, epath, Instant::now());
                 }
-            );
-        });
-        ranking.insert(k.to_string(), score);
-    });
-    // Sort the files by their scores.
-    ranking.keys().for_each(|k| result.push(k.to_string()));
-    result.sort_by(|a,b| if ranking[a] > ranking[b] {
-            std::cmp::Ordering::Greater
-        } else if ranking[a] < ranking[b] {
-            std::cmp::Ordering::Less
-        } else {
-            std::cmp::Ordering::Equal
-        });
-    // We need an empty, because something about the response to
-    // the client cuts off the final characters.
-    result.push("".to_string());
-
-    result
-}
-
-// Accept requests for searches and return any search results.
-fn handle_queries(
-    sqlite: &Connection,
-    events: &Events,
-    server: &TcpListener,
-    server_poll: &Poll,
-    server_token: Token,
-    punc: &Regex,
-    accents: &Regex,
-    stemmer: &Stemmer,
-) {
-    for _event in events.iter() {
-        let (mut client, _addr) = match server.accept() {
-            Ok((client, _addr)) => (client, _addr),
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                break;
             }
             Err(e) => {
-                debug!("{:?}", e);
-                return;
-            }
-        };
-        let mut buffer = [0; 4096];
-
-        server_poll
-            .registry()
-            .register(
-                &mut client,
-                server_token,
-                Interest::READABLE.add(Interest::WRITABLE),
-            )
-            .unwrap();
-        match client.read(&mut buffer) {
-            Ok(_) => {
-                let query = str::from_utf8(&buffer).unwrap();
-
-                if query.starts_with("@on") {
-                    respond_to_today(query, sqlite, client);
-                } else if query.starts_with("@ago") {
-                    respond_to_ago(query, sqlite, client);
-                } else {
-                    respond_to_search(query, punc, accents, stemmer, sqlite, client);
+                if e != std::sync::mpsc::RecvTimeoutError::Timeout {
+                    debug!("watch error: {:#?}", e);
                 }
             }
-            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e) => debug!("{:#?}", e),
         }
-    }
-}
-
-// Return files modified on the specified date
-fn respond_to_today(
-    raw_query: &str,
-    sqlite: &Connection,
-    client: mio::net::TcpStream,
-) {
-    let query_string = raw_query
-        .trim_matches(char::from(0))
-        .replace("@on", "")
-        .replace("\n", "");
-    let query = format!("{} 00:00:00", query_string);
-    let mut day_start = Local::today().and_hms(0, 0, 0).timestamp();
 
-    match NaiveDateTime::parse_from_str(&query, "%F %T") {
-        Ok(date) => day_start = date.timestamp(),
-        Err(e) => warn!("Can't parse '{}': {}", query_string, e),
-    }
-
-    select_files_by_day(day_start, sqlite, client);
-}
+        for (event_name, epath) in remote_rx.try_iter() {
+            coalescer.add(event_name, epath, Instant::now());
+        }
 
-// Return files modified on the specified date
-fn respond_to_ago(
-    raw_query: &str,
-    sqlite: &Connection,
-    client: mio::net::TcpStream,
-) {
-    let query_string = raw_query
-        .trim_matches(char::from(0))
-        .replace("@ago", "")
-        .replace("\n", "");
-    let today = Local::today().and_hms(0, 0, 0);
-    let days_ago = match query_string.parse() {
-        Ok(n) => n,
-        Err(e) => {
-            warn!("Using today: {}", e);
-            0
+        // While paused, events pile up, keeping only the latest for each
+        // path, since we reindex from whatever is on disk at resume.
+        if !paused && !coalescer.is_empty() {
+            for (event_name, epath) in coalescer.ready(Instant::now()) {
+                process_event(
+                    event_name,
+                    epath,
+                    &store,
+                    &punc,
+                    &acc,
+                    &*stem,
+                    &mut watches,
+                    &mut ignores,
+                    &mut throttle,
+                );
+            }
         }
-    };
-    let day_start = (today + chrono::Duration::days(-days_ago)).timestamp();
 
-    select_files_by_day(day_start, sqlite, client);
-}
+        // A folder that someone asked to index again gets a slice of the
+        // loop at a time, so that nothing else waits for all of it.
+        if !paused {
+            reindexing.advance(
+                &store,
+                &punc,
+                &acc,
+                &*stem,
+                &mut throttle,
+                &mut pending,
+                &server_poll,
+                server_token,
+            );
+        }
 
-// Find and return search results to client
-fn respond_to_search(
-    query: &str,
-    punc: &Regex,
-    accents: &Regex,
-    stemmer: &Stemmer,
-    sqlite: &Connection,
-    mut client: mio::net::TcpStream,
-) {
-    let alpha_only = punc.replace_all(query, " ");
-    let space_split = alpha_only.split_whitespace();
-    let all_stems = select_all_stems(sqlite);
-    let mut new_stems = Vec::<WordStem>::new();
-    let mut stem_ids = Vec::<u32>::new();
+        // Embeddings come back from their own thread whenever they're
+        // ready, long after their files' keywords went in.
+        #[cfg(feature = "semantic")]
+        embeddings.exchange(&store);
 
-    space_split.filter(|w| !punc.is_match(w)).for_each(|word| {
-        let stem = stem_word(word, accents, stemmer);
-        let id = if all_stems.contains_key(&stem) {
-            all_stems[&stem]
-        } else {
-            0
-        };
+        for change in take_index_changes() {
+            cache.invalidate(&change.path, &change.stems);
+            subscriptions.notify(&change);
+            alerts.check(&change);
+            webhooks.check(&change);
+        }
 
-        new_stems.push(WordStem { id, stem });
-        if !stem_ids.contains(&id) && id > 0 {
-            stem_ids.push(id);
+        METRICS
+            .watch_count
+            .store(watches.active_count() as i64, Ordering::Relaxed);
+        server_poll
+            .poll(&mut events, Some(Duration::from_millis(100)))
+            .unwrap();
+        handle_queries(
+            &store,
+            &events,
+            &server,
+            &server_poll,
+            server_token,
+            &punc,
+            &acc,
+            &*stem,
+            run_id,
+            &mut paused,
+            coalescer.len(),
+            &ranking,
+            &mut cache,
+            &mut subscriptions,
+            &mut pending,
+            &mut ignores,
+            &mut reindexing,
+        );
+        if let Some(listener) = &http_listener {
+            web::serve(
+                listener,
+                &db_path,
+                &store,
+                &punc,
+                &acc,
+                &*stem,
+                run_id,
+                &ranking,
+                &mut cache,
+            );
         }
-    });
+    }
+}
 
-    let search_results = search_index(sqlite, new_stems);
-    let serps = collate_search(search_results, stem_ids);
-    let sorted = sort_search_results(
-        &serps,
-        alpha_only.split_whitespace().collect()
+// Find the daemon described by the `server` block of the configuration,
+// presenting the token in `INTERN_TOKEN`, if there is one.
+fn connect_client(config: &gjson::Value) -> Client {
+    let server_info = config.get("server");
+    let address = format!(
+        "{}:{}",
+        server_info.get("address").str(),
+        server_info.get("port").u32()
     );
 
-    debug!("{:#?}", serps);
-    client.write_all(sorted.join("\n").as_bytes()).unwrap();
+    let client = Client::connect(address).expect("Unable to find the INTERN daemon.");
+
+    match env::var("INTERN_TOKEN") {
+        Ok(token) => client.with_token(&token),
+        Err(_) => client,
+    }
 }
@@ -0,0 +1,206 @@
+use encoding_rs::{UTF_16BE, UTF_16LE};
+use exif::{Context, Exif, In, Reader, Tag, Value};
+use id3::TagLike;
+use regex::bytes::Regex;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::debug;
+
+use crate::text::CHUNK_BYTES;
+
+// Audio files that carry ID3 tags, by extension.
+const TAGGED_AUDIO: [&str; 1] = ["mp3"];
+
+// Images that can carry EXIF data, by extension.
+const PHOTOS: [&str; 9] = [
+    "avif", "heic", "heif", "jpeg", "jpg", "png", "tif", "tiff", "webp",
+];
+
+// The tags that Windows uses for a photo's title, comment, author,
+// keywords, and subject, in UTF-16.
+const WINDOWS_TAGS: [u16; 5] = [0x9c9b, 0x9c9c, 0x9c9d, 0x9c9e, 0x9c9f];
+
+// The names of places in a photo's XMP packet, which photo managers fill in
+// from the GPS coordinates, as either attributes or elements.
+const XMP_PLACE_PATTERN: &str = concat!(
+    r"(?:photoshop:(?:City|State|Country)|Iptc4xmpCore:(?:Location|CountryName))",
+    r#"(?:="([^"]*)"|>([^<]*)<)"#,
+);
+
+static XMP_PLACES: OnceLock<Regex> = OnceLock::new();
+
+// Describe a recording or a photo in words, from the title, artist, album,
+// and comments of its ID3 tag, or the description, author, comments, and
+// place names in its EXIF and XMP data, or nothing, for anything else.
+pub fn media_text(path: &Path) -> Option<String> {
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let fields = if TAGGED_AUDIO.contains(&extension.as_str()) {
+        audio_fields(path)
+    } else if PHOTOS.contains(&extension.as_str()) {
+        photo_fields(path)
+    } else {
+        return None;
+    };
+    let text = fields
+        .iter()
+        .map(|f| f.trim_matches(|c: char| c == '\0' || c.is_whitespace()))
+        .filter(|f| !f.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+// The text frames of an ID3 tag worth searching, from either version.
+fn audio_fields(path: &Path) -> Vec<String> {
+    let tag = match id3::v1v2::read_from_path(path) {
+        Ok(tag) => tag,
+        Err(e) => {
+            debug!("no ID3 tag in {}: {}", path.display(), e);
+            return Vec::new();
+        }
+    };
+    let composer = tag.get("TCOM").and_then(|f| f.content().text());
+    let mut fields = [
+        tag.title(),
+        tag.artist(),
+        tag.album_artist(),
+        tag.album(),
+        composer,
+    ]
+    .iter()
+    .flatten()
+    .map(|f| f.to_string())
+    .collect::<Vec<_>>();
+
+    fields.extend(tag.genre_parsed().map(|g| g.into_owned()));
+    fields.extend(tag.comments().map(|c| c.text.clone()));
+    fields.extend(tag.lyrics().map(|l| l.text.clone()));
+    fields
+}
+
+// The descriptive EXIF fields of a photo, along with any place names.
+fn photo_fields(path: &Path) -> Vec<String> {
+    let mut fields = match read_exif(path) {
+        Ok(exif) => exif_fields(&exif),
+        Err(e) => {
+            debug!("no EXIF data in {}: {}", path.display(), e);
+            Vec::new()
+        }
+    };
+
+    fields.extend(xmp_places(path));
+    fields
+}
+
+fn read_exif(path: &Path) -> Result<Exif, exif::Error> {
+    Reader::new().read_from_container(&mut BufReader::new(File::open(path)?))
+}
+
+fn exif_fields(exif: &Exif) -> Vec<String> {
+    let little_endian = exif.little_endian();
+    let tags = [Tag::ImageDescription, Tag::Artist, Tag::UserComment]
+        .iter()
+        .copied()
+        .chain(WINDOWS_TAGS.iter().map(|t| Tag(Context::Tiff, *t)))
+        .chain(Some(Tag::GPSAreaInformation));
+
+    tags.filter_map(|tag| exif.get_field(tag, In::PRIMARY))
+        .filter_map(|field| match &field.value {
+            Value::Ascii(lines) => Some(
+                lines
+                    .iter()
+                    .map(|l| String::from_utf8_lossy(l).into_owned())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            ),
+            // Windows always writes its tags little-endian.
+            Value::Byte(bytes) => {
+                Some(UTF_16LE.decode_without_bom_handling(bytes).0.into_owned())
+            }
+            Value::Undefined(bytes, _) => coded_text(bytes, little_endian),
+            _ => None,
+        })
+        .collect()
+}
+
+// Read the text of a comment or area name, which starts with eight bytes
+// naming its character code.  Anything but Unicode is usually ASCII, or
+// close enough.
+fn coded_text(bytes: &[u8], little_endian: bool) -> Option<String> {
+    if bytes.len() < 8 {
+        return None;
+    }
+
+    let (code, text) = bytes.split_at(8);
+    let text = match (code, little_endian) {
+        (b"UNICODE\0", true) => UTF_16LE.decode_without_bom_handling(text).0,
+        (b"UNICODE\0", false) => UTF_16BE.decode_without_bom_handling(text).0,
+        _ => String::from_utf8_lossy(text),
+    };
+
+    Some(text.into_owned())
+}
+
+// Find the place names in a photo's XMP packet, which is plain XML, near
+// the start of the file.
+fn xmp_places(path: &Path) -> Vec<String> {
+    let mut bytes = Vec::<u8>::new();
+    let places = XMP_PLACES.get_or_init(|| Regex::new(XMP_PLACE_PATTERN).unwrap());
+
+    if let Ok(file) = File::open(path) {
+        let _ = file.take(CHUNK_BYTES as u64).read_to_end(&mut bytes);
+    }
+
+    places
+        .captures_iter(&bytes)
+        .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+        .map(|m| String::from_utf8_lossy(m.as_bytes()).into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use std::fs;
+
+    #[test]
+    fn recordings_and_photos_describe_themselves() {
+        let dir = ScratchDir::new("media");
+        let song = dir.join("song.mp3");
+        let photo = dir.join("photo.jpg");
+        let mut tag = id3::Tag::new();
+
+        fs::write(&song, "").unwrap();
+        tag.set_title("Giant Steps");
+        tag.set_artist("John Coltrane");
+        tag.write_to_path(&song, id3::Version::Id3v24).unwrap();
+        fs::write(
+            &photo,
+            "\u{ff}<x:xmpmeta><rdf:Description photoshop:City=\"Lisbon\">\
+             <photoshop:Country>Portugal</photoshop:Country>",
+        )
+        .unwrap();
+        assert_eq!(media_text(&song).unwrap(), "Giant Steps\nJohn Coltrane");
+        assert_eq!(media_text(&photo).unwrap(), "Lisbon\nPortugal");
+        assert_eq!(media_text(&dir.join("notes.md")), None);
+    }
+
+    #[test]
+    fn comments_name_their_character_code() {
+        assert_eq!(coded_text(b"ASCII\0\0\0Porto", true).unwrap(), "Porto");
+        assert_eq!(coded_text(b"UNICODE\0P\0o\0", true).unwrap(), "Po");
+        assert_eq!(coded_text(b"UNICODE\0\0P\0o", false).unwrap(), "Po");
+        assert_eq!(coded_text(b"short", true), None);
+    }
+}
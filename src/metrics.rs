@@ -0,0 +1,128 @@
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+use std::fs;
+
+// Process-wide counters for the metrics endpoint.  These are atomics
+// rather than locals in main, so that the indexing code can bump them
+// without threading another parameter through every call.
+pub struct Metrics {
+    pub files_indexed: AtomicU64,
+    pub events_processed: AtomicU64,
+    pub queries_served: AtomicU64,
+    pub query_micros: AtomicU64,
+    pub query_buckets: [AtomicU64; 8],
+    pub slow_queries: AtomicU64,
+    pub index_rows: AtomicU64,
+    pub watch_count: AtomicI64,
+}
+
+const QUERY_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+pub static METRICS: Metrics = Metrics {
+    files_indexed: AtomicU64::new(0),
+    events_processed: AtomicU64::new(0),
+    queries_served: AtomicU64::new(0),
+    query_micros: AtomicU64::new(0),
+    query_buckets: [
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+        AtomicU64::new(0),
+    ],
+    slow_queries: AtomicU64::new(0),
+    index_rows: AtomicU64::new(0),
+    watch_count: AtomicI64::new(0),
+};
+
+// Add a query's duration to the latency histogram.
+pub fn record_query_time(elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+
+    METRICS.queries_served.fetch_add(1, Ordering::Relaxed);
+    METRICS
+        .query_micros
+        .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    for (i, bound) in QUERY_BUCKETS.iter().enumerate() {
+        if seconds <= *bound {
+            METRICS.query_buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// How many rows searches have read from the index so far, which a query
+// can compare before and after, to see how many it read.
+pub fn index_rows_read() -> u64 {
+    METRICS.index_rows.load(Ordering::Relaxed)
+}
+
+// Produce the metrics in Prometheus' text exposition format.
+pub fn render_metrics(db_path: &Path) -> String {
+    let mut out = String::new();
+    // Recent writes sit in the write-ahead log until SQLite copies them
+    // into the database proper.
+    let wal_path = format!("{}-wal", db_path.display());
+    let db_size = [db_path, Path::new(&wal_path)]
+        .iter()
+        .map(|p| fs::metadata(p).map(|m| m.len()).unwrap_or(0))
+        .sum::<u64>();
+    let count = METRICS.queries_served.load(Ordering::Relaxed);
+
+    out.push_str("# HELP intern_files_indexed_total Files indexed since startup.\n");
+    out.push_str("# TYPE intern_files_indexed_total counter\n");
+    out.push_str(&format!(
+        "intern_files_indexed_total {}\n",
+        METRICS.files_indexed.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP intern_events_processed_total Filesystem events processed.\n");
+    out.push_str("# TYPE intern_events_processed_total counter\n");
+    out.push_str(&format!(
+        "intern_events_processed_total {}\n",
+        METRICS.events_processed.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP intern_watches Paths currently being watched.\n");
+    out.push_str("# TYPE intern_watches gauge\n");
+    out.push_str(&format!(
+        "intern_watches {}\n",
+        METRICS.watch_count.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP intern_database_bytes Size of the index database.\n");
+    out.push_str("# TYPE intern_database_bytes gauge\n");
+    out.push_str(&format!("intern_database_bytes {}\n", db_size));
+    out.push_str("# HELP intern_query_duration_seconds Time spent answering queries.\n");
+    out.push_str("# TYPE intern_query_duration_seconds histogram\n");
+    for (i, bound) in QUERY_BUCKETS.iter().enumerate() {
+        out.push_str(&format!(
+            "intern_query_duration_seconds_bucket{{le=\"{}\"}} {}\n",
+            bound,
+            METRICS.query_buckets[i].load(Ordering::Relaxed)
+        ));
+    }
+    out.push_str(&format!(
+        "intern_query_duration_seconds_bucket{{le=\"+Inf\"}} {}\n",
+        count
+    ));
+    out.push_str(&format!(
+        "intern_query_duration_seconds_sum {}\n",
+        METRICS.query_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+    ));
+    out.push_str(&format!("intern_query_duration_seconds_count {}\n", count));
+    out.push_str("# HELP intern_slow_queries_total Queries slower than slowQueryMs.\n");
+    out.push_str("# TYPE intern_slow_queries_total counter\n");
+    out.push_str(&format!(
+        "intern_slow_queries_total {}\n",
+        METRICS.slow_queries.load(Ordering::Relaxed)
+    ));
+    out.push_str("# HELP intern_index_rows_read_total Index rows read by searches.\n");
+    out.push_str("# TYPE intern_index_rows_read_total counter\n");
+    out.push_str(&format!(
+        "intern_index_rows_read_total {}\n",
+        index_rows_read()
+    ));
+
+    out
+}
@@ -0,0 +1,78 @@
+use regex::Regex;
+use std::sync::OnceLock;
+use tracing::debug;
+
+// How many lines at the top of a file can hold its modeline.
+const MODELINE_LINES: usize = 5;
+
+// The modeline, anywhere in a line, so that it can sit in whatever comment
+// the file's format has.
+static MODELINE: OnceLock<Regex> = OnceLock::new();
+
+// The settings that a file chooses for itself with a modeline, a comment
+// like `# intern: lang=de tokenizer=code` in one of its first few lines,
+// so that one file can differ from its folder without more configuration.
+#[derive(Debug, Default, PartialEq)]
+pub struct Modeline {
+    pub language: Option<String>,
+    pub tokenizer: Option<String>,
+}
+
+impl Modeline {
+    // Read the modeline from the start of a file's text, which is nothing
+    // but defaults, without one.  Settings we don't know are left out.
+    pub fn read(text: &str) -> Modeline {
+        let pattern = MODELINE.get_or_init(|| {
+            Regex::new(r"\bintern:((?:[ \t]+[A-Za-z]+=[\w.-]+)+)").unwrap()
+        });
+        let mut modeline = Modeline::default();
+        let settings = match text
+            .lines()
+            .take(MODELINE_LINES)
+            .find_map(|line| pattern.captures(line))
+        {
+            Some(captures) => captures[1].to_string(),
+            None => return modeline,
+        };
+
+        for setting in settings.split_whitespace() {
+            let (key, value) = match setting.split_once('=') {
+                Some((key, value)) => (key, value.to_lowercase()),
+                None => continue,
+            };
+
+            match key {
+                "lang" => modeline.language = Some(value),
+                "tokenizer" => modeline.tokenizer = Some(value),
+                _ => debug!("Ignoring the {} setting in a modeline", key),
+            }
+        }
+
+        modeline
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modelines_choose_settings() {
+        let modeline = Modeline::read("#!/bin/sh\n# intern: lang=DE tokenizer=code\n");
+
+        assert_eq!(modeline.language.as_deref(), Some("de"));
+        assert_eq!(modeline.tokenizer.as_deref(), Some("code"));
+        assert_eq!(
+            Modeline::read("<!-- intern: tokenizer=legacy color=blue -->"),
+            Modeline {
+                language: None,
+                tokenizer: Some("legacy".to_string()),
+            }
+        );
+        assert_eq!(
+            Modeline::read("1\n2\n3\n4\n5\n// intern: lang=fr\n"),
+            Modeline::default()
+        );
+        assert_eq!(Modeline::read("The intern: a person"), Modeline::default());
+    }
+}
@@ -0,0 +1,248 @@
+use regex::Regex;
+use rust_stemmers::{Algorithm, Stemmer};
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use tracing::{error, info, warn};
+
+#[cfg(feature = "lemmas")]
+use crate::lemmas::Lemmatizer;
+
+// Whatever turns a word into the form that the index keeps, so that the
+// forms of a word find each other.  Words arrive lowercase and, unless the
+// configuration keeps them, without their accents.
+pub trait Normalizer {
+    fn normalize(&self, word: &str) -> String;
+}
+
+// A normalizer that the indexer can share between files.
+type Shared = Arc<dyn Normalizer + Send + Sync>;
+
+// The configured language and dictionaries, for the files whose modelines
+// ask for a language of their own.
+static LANGUAGES: OnceLock<Languages> = OnceLock::new();
+
+// The normalizers that modelines have asked for so far, by language, so
+// that each dictionary loads only once, with nothing for a language that
+// there's no way to normalize.
+static MODELINE_NORMALIZERS: Mutex<Vec<(String, Option<Shared>)>> =
+    Mutex::new(Vec::new());
+
+// The language to normalize words in, and the dictionaries of word forms,
+// by language.
+struct Languages {
+    language: String,
+    lemmas: Vec<(String, String)>,
+    accents: Regex,
+}
+
+// Snowball stemming, which chops off endings by rule, so that it handles
+// any word, but sometimes lumps different words together, like
+// "universities" and "universal" as "univers."
+impl Normalizer for Stemmer {
+    fn normalize(&self, word: &str) -> String {
+        self.stem(word).to_string()
+    }
+}
+
+// Read the `language` item, which names the language to stem words in,
+// like `"french"` or `"fr"`, and defaults to English, and the `lemmas`
+// block, which can name a dictionary of word forms for a language, to look
+// its words up in instead.
+pub fn normalizer(config: &gjson::Value, accents: &Regex) -> Box<dyn Normalizer> {
+    let language = config.get("language");
+    let language = if language.exists() {
+        language_name(language.str())
+    } else {
+        "english".to_string()
+    };
+    let mut lemmas = Vec::<(String, String)>::new();
+
+    config.get("lemmas").each(|language, path| {
+        lemmas.push((language_name(language.str()), path.str().to_string()));
+        true
+    });
+
+    let languages = Languages {
+        language,
+        lemmas,
+        accents: accents.clone(),
+    };
+    let normalizer = languages.normalizer(&languages.language);
+
+    LANGUAGES.get_or_init(|| languages);
+    normalizer
+}
+
+// The normalizer for the language that a file's modeline names, or
+// nothing, for the configured language, which the indexer already has, or
+// for a language with neither a stemmer nor a dictionary.
+pub fn language_normalizer(language: &str) -> Option<Shared> {
+    let languages = LANGUAGES.get()?;
+    let language = language_name(language);
+
+    if language == languages.language {
+        return None;
+    }
+
+    let mut normalizers = MODELINE_NORMALIZERS.lock().unwrap();
+
+    if let Some((_, normalizer)) = normalizers.iter().find(|(l, _)| *l == language) {
+        return normalizer.clone();
+    }
+
+    let known = algorithm(&language).is_some() || languages.lemmas(&language).is_some();
+    let normalizer = if known {
+        Some(Shared::from(languages.normalizer(&language)))
+    } else {
+        warn!(
+            "There's no stemmer for {}, so ignoring it in modelines",
+            language
+        );
+        None
+    };
+
+    normalizers.push((language, normalizer.clone()));
+    normalizer
+}
+
+impl Languages {
+    // The dictionary of word forms for a language, if there is one.
+    fn lemmas(&self, language: &str) -> Option<&str> {
+        self.lemmas
+            .iter()
+            .find(|(l, _)| l == language)
+            .map(|(_, path)| path.as_str())
+    }
+
+    // Look a language's words up in its dictionary, if it has one that we
+    // can read, or stem them, falling back on English for a language that
+    // Snowball doesn't know.
+    fn normalizer(&self, language: &str) -> Box<dyn Normalizer + Send + Sync> {
+        if let Some(path) = self.lemmas(language) {
+            let path = Path::new(path);
+
+            match load_lemmas(path, &self.accents) {
+                Ok(lemmatizer) => {
+                    info!("Looking up {} words in {}", language, path.display());
+                    return lemmatizer;
+                }
+                Err(e) => error!("Unable to read lemmas from {}: {}", path.display(), e),
+            }
+        }
+
+        let algorithm = algorithm(language).unwrap_or_else(|| {
+            warn!(
+                "There's no stemmer for {}, so stemming in English",
+                language
+            );
+            Algorithm::English
+        });
+
+        info!("Stemming words in {}", language);
+        Box::new(Stemmer::create(algorithm))
+    }
+}
+
+// The English name of a language, from its name or its two-letter code,
+// like "de" or "de-AT" for German, in lowercase.
+fn language_name(language: &str) -> String {
+    let language = language.trim().to_lowercase();
+    let code = language.split(['-', '_']).next().unwrap_or_default();
+    let name = match code {
+        "ar" => "arabic",
+        "da" => "danish",
+        "de" => "german",
+        "el" => "greek",
+        "en" => "english",
+        "es" => "spanish",
+        "fi" => "finnish",
+        "fr" => "french",
+        "hu" => "hungarian",
+        "it" => "italian",
+        "nb" | "nn" | "no" => "norwegian",
+        "nl" => "dutch",
+        "pt" => "portuguese",
+        "ro" => "romanian",
+        "ru" => "russian",
+        "sv" => "swedish",
+        "ta" => "tamil",
+        "tr" => "turkish",
+        _ => return language,
+    };
+
+    name.to_string()
+}
+
+// The Snowball stemmer for a language, by its English name.
+fn algorithm(language: &str) -> Option<Algorithm> {
+    let algorithm = match language {
+        "arabic" => Algorithm::Arabic,
+        "danish" => Algorithm::Danish,
+        "dutch" => Algorithm::Dutch,
+        "english" => Algorithm::English,
+        "finnish" => Algorithm::Finnish,
+        "french" => Algorithm::French,
+        "german" => Algorithm::German,
+        "greek" => Algorithm::Greek,
+        "hungarian" => Algorithm::Hungarian,
+        "italian" => Algorithm::Italian,
+        "norwegian" => Algorithm::Norwegian,
+        "portuguese" => Algorithm::Portuguese,
+        "romanian" => Algorithm::Romanian,
+        "russian" => Algorithm::Russian,
+        "spanish" => Algorithm::Spanish,
+        "swedish" => Algorithm::Swedish,
+        "tamil" => Algorithm::Tamil,
+        "turkish" => Algorithm::Turkish,
+        _ => return None,
+    };
+
+    Some(algorithm)
+}
+
+#[cfg(feature = "lemmas")]
+fn load_lemmas(
+    path: &Path,
+    accents: &Regex,
+) -> io::Result<Box<dyn Normalizer + Send + Sync>> {
+    Ok(Box::new(Lemmatizer::load(path, accents)?))
+}
+
+// Without the lemmas feature, there are no dictionaries to look in.
+#[cfg(not(feature = "lemmas"))]
+fn load_lemmas(
+    _path: &Path,
+    _accents: &Regex,
+) -> io::Result<Box<dyn Normalizer + Send + Sync>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "looking up lemmas needs the lemmas feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::accents;
+
+    #[test]
+    fn languages_pick_their_stemmers() {
+        let accents = accents(&gjson::parse("{}"));
+        let english = normalizer(&gjson::parse("{}"), &accents);
+        let french = normalizer(&gjson::parse(r#"{"language": "French"}"#), &accents);
+        let unknown = normalizer(&gjson::parse(r#"{"language": "klingon"}"#), &accents);
+        let missing = normalizer(
+            &gjson::parse(r#"{"lemmas": {"english": "/nonexistent/lemmas.txt"}}"#),
+            &accents,
+        );
+
+        assert_eq!(english.normalize("universities"), "univers");
+        assert_eq!(french.normalize("continuellement"), "continuel");
+        assert_eq!(unknown.normalize("universities"), "univers");
+        assert_eq!(missing.normalize("universities"), "univers");
+        assert_eq!(language_name("de-AT"), "german");
+        assert_eq!(language_name("French"), "french");
+        assert_eq!(language_name("klingon"), "klingon");
+    }
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use tracing::{debug, info};
+
+// The kinds of image that tesseract reads, by extension.
+const IMAGE_EXTENSIONS: [&str; 9] = [
+    "bmp", "gif", "jpeg", "jpg", "png", "pnm", "tif", "tiff", "webp",
+];
+
+// How finely to render PDF pages for tesseract, in dots per inch, which is
+// about what it needs to read ordinary print.
+const PDF_RESOLUTION: &str = "300";
+
+// The languages to read images in, if we read them at all.
+static OCR: OnceLock<Option<String>> = OnceLock::new();
+
+// Read the `ocr` item, which runs tesseract on images and scanned PDFs,
+// and the `ocrLanguages` item, which says what languages to expect, the
+// way tesseract's `-l` option does, like `eng+deu`.
+pub fn set_ocr(config: &gjson::Value) {
+    let languages = config.get("ocrLanguages");
+    let languages = if !config.get("ocr").bool() {
+        None
+    } else if languages.exists() {
+        Some(languages.to_string())
+    } else {
+        Some("eng".to_string())
+    };
+
+    if let Some(languages) = &languages {
+        info!("Reading text from images, in {}", languages);
+    }
+
+    OCR.get_or_init(|| languages);
+}
+
+// Read the text in an image, or a PDF that's only pictures of pages, or
+// nothing, for any other file or with OCR turned off.  PDFs with text of
+// their own aren't scans, so we leave them alone.
+pub fn recognize(path: &Path) -> io::Result<Option<String>> {
+    let languages = match OCR.get_or_init(|| None) {
+        Some(languages) => languages,
+        None => return Ok(None),
+    };
+    let extension = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    if IMAGE_EXTENSIONS.contains(&extension.as_str()) {
+        debug!("reading {} with tesseract", path.display());
+        tesseract(path, languages).map(Some)
+    } else if extension == "pdf" && !has_text(path)? {
+        debug!("reading scanned pages of {}", path.display());
+        read_pages(path, languages).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+// Render each page of a PDF to an image and read them in order.
+fn read_pages(path: &Path, languages: &str) -> io::Result<String> {
+    let dir = std::env::temp_dir().join(format!("intern-ocr-{}", std::process::id()));
+
+    fs::create_dir_all(&dir)?;
+
+    let pages = run(Command::new("pdftoppm")
+        .args(["-r", PDF_RESOLUTION, "-png"])
+        .arg(path)
+        .arg(dir.join("page")))
+    .and_then(|_| {
+        page_images(&dir)?
+            .iter()
+            .map(|page| tesseract(page, languages))
+            .collect::<io::Result<Vec<_>>>()
+    });
+
+    fs::remove_dir_all(&dir)?;
+    Ok(pages?.join("\n"))
+}
+
+// The page images that pdftoppm wrote, in page order, which is the order
+// of their names, since it pads the page numbers to the same width.
+fn page_images(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut pages = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    pages.sort();
+    Ok(pages)
+}
+
+// Whether a PDF has any text to extract without OCR.
+fn has_text(path: &Path) -> io::Result<bool> {
+    let text = run(Command::new("pdftotext").args(["-q"]).arg(path).arg("-"))?;
+
+    Ok(text.chars().any(char::is_alphanumeric))
+}
+
+fn tesseract(image: &Path, languages: &str) -> io::Result<String> {
+    run(Command::new("tesseract")
+        .arg(image)
+        .arg("stdout")
+        .args(["-l", languages]))
+}
+
+// Run a command, returning what it prints, or what it complains about, if
+// it fails.
+fn run(command: &mut Command) -> io::Result<String> {
+    let output = command.stdin(Stdio::null()).output()?;
+
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn nothing_gets_read_with_ocr_off() {
+        let dir = ScratchDir::new("scan");
+        let scan = dir.join("scan.png");
+
+        fs::write(&scan, b"\x89PNG\r\n\x1a\n").unwrap();
+        assert!(recognize(&scan).unwrap().is_none());
+    }
+}
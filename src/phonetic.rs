@@ -0,0 +1,71 @@
+// The phonetic key that `@sounds-like` compares, as the American Soundex
+// code for a stem, like `N320` for the stem of "Nietzsche", which it
+// shares with "Nitsche" and "Neetsha", or nothing, for a stem that doesn't
+// start with a letter from A to Z.  Letters outside that range don't count.
+pub fn soundex(stem: &str) -> Option<String> {
+    if !stem.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut letters = stem
+        .chars()
+        .filter(char::is_ascii_alphabetic)
+        .map(|c| c.to_ascii_uppercase());
+    let first = letters.next()?;
+    let mut key = first.to_string();
+    let mut last = code(first);
+
+    for letter in letters {
+        let digit = code(letter);
+
+        // Vowels separate letters with the same code, but H and W don't.
+        match digit {
+            Some(d) if digit != last => key.push(d),
+            _ => (),
+        }
+
+        if !matches!(letter, 'H' | 'W') {
+            last = digit;
+        }
+
+        if key.len() == 4 {
+            break;
+        }
+    }
+
+    Some(format!("{:0<4}", key))
+}
+
+// The digit for a consonant, or nothing, for a vowel, H, W, or Y.
+fn code(letter: char) -> Option<char> {
+    match letter {
+        'B' | 'F' | 'P' | 'V' => Some('1'),
+        'C' | 'G' | 'J' | 'K' | 'Q' | 'S' | 'X' | 'Z' => Some('2'),
+        'D' | 'T' => Some('3'),
+        'L' => Some('4'),
+        'M' | 'N' => Some('5'),
+        'R' => Some('6'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_sound_alike() {
+        for name in ["nietzsch", "nitsch", "neetsha", "nietsch"] {
+            assert_eq!(soundex(name).as_deref(), Some("N320"), "{}", name);
+        }
+
+        assert_eq!(soundex("robert").as_deref(), Some("R163"));
+        assert_eq!(soundex("rupert").as_deref(), Some("R163"));
+        assert_eq!(soundex("ashcraft").as_deref(), Some("A261"));
+        assert_eq!(soundex("tymczak").as_deref(), Some("T522"));
+        assert_eq!(soundex("pfister").as_deref(), Some("P236"));
+        assert_eq!(soundex("lee").as_deref(), Some("L000"));
+        assert_eq!(soundex("2024"), None);
+        assert_eq!(soundex("ébène"), None);
+    }
+}
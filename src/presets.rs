@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::{info, warn};
+
+use crate::query::CollatedResults;
+
+// How a preset orders what it finds, which is by relevance, like any other
+// search, unless it says otherwise.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PresetSort {
+    #[default]
+    Relevance,
+    Modified,
+    Path,
+}
+
+// A search saved in the configuration, with the terms it starts from, the
+// folders and extensions it's limited to, if any, and how it's sorted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preset {
+    pub terms: String,
+    pub folders: Vec<PathBuf>,
+    pub extensions: Vec<String>,
+    pub sort: PresetSort,
+}
+
+// The presets, by name.
+static PRESETS: OnceLock<HashMap<String, Preset>> = OnceLock::new();
+
+// Read the `presets` block, which maps each name to a preset, like
+// `"work-notes": {"terms": "tag:work", "folders": ["/home/me/notes"],
+// "extensions": ["md"], "sort": "modified"}`, where every item is
+// optional.
+pub fn set_presets(config: &gjson::Value) {
+    let mut presets = HashMap::<String, Preset>::new();
+
+    config.get("presets").each(|name, preset| {
+        presets.insert(name.to_string(), read_preset(&name.to_string(), &preset));
+        true
+    });
+
+    if !presets.is_empty() {
+        info!("{} search preset(s)", presets.len());
+    }
+
+    PRESETS.get_or_init(|| presets);
+}
+
+// Read one preset from the configuration.
+fn read_preset(name: &str, config: &gjson::Value) -> Preset {
+    let sort = match config.get("sort").str() {
+        "" | "relevance" => PresetSort::Relevance,
+        "modified" => PresetSort::Modified,
+        "path" => PresetSort::Path,
+        other => {
+            warn!("Preset {} can't sort by {}", name, other);
+            PresetSort::Relevance
+        }
+    };
+
+    Preset {
+        terms: config.get("terms").to_string(),
+        folders: config
+            .get("folders")
+            .array()
+            .iter()
+            .map(|f| PathBuf::from(f.to_string()))
+            .collect(),
+        extensions: config
+            .get("extensions")
+            .array()
+            .iter()
+            .map(|e| e.str().trim_start_matches('.').to_lowercase())
+            .collect(),
+        sort,
+    }
+}
+
+// Find a preset by its name.
+pub fn preset(name: &str) -> Option<&'static Preset> {
+    PRESETS.get_or_init(HashMap::new).get(name)
+}
+
+impl Preset {
+    // The search to run, as the preset's terms followed by any others.
+    pub fn query(&self, extra: &str) -> String {
+        format!("{} {}", self.terms, extra).trim().to_string()
+    }
+
+    // Whether a file is in the preset's folders and has one of its
+    // extensions, where no folders or no extensions means any.
+    fn covers(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        (self.folders.is_empty() || self.folders.iter().any(|f| path.starts_with(f)))
+            && (self.extensions.is_empty() || self.extensions.contains(&extension))
+    }
+
+    // Narrow a search's ranked files to what the preset covers, and put
+    // them in the preset's order.
+    pub fn arrange(&self, search: &CollatedResults, sorted: Vec<String>) -> Vec<String> {
+        let mut files = sorted
+            .into_iter()
+            .filter(|p| !p.is_empty() && self.covers(p))
+            .collect::<Vec<_>>();
+        let modified = |path: &String| {
+            search
+                .get(path)
+                .and_then(|stems| stems.values().flatten().map(|w| w.modified).next())
+                .unwrap_or(0)
+        };
+
+        match self.sort {
+            PresetSort::Relevance => (),
+            PresetSort::Modified => files.sort_by_key(|p| std::cmp::Reverse(modified(p))),
+            PresetSort::Path => files.sort(),
+        }
+
+        files
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::SearchResult;
+
+    #[test]
+    fn presets_narrow_and_sort() {
+        let config = gjson::parse(
+            r#"{"terms": "tag:work", "folders": ["/notes/work"],
+                "extensions": [".MD"], "sort": "modified"}"#,
+        );
+        let preset = read_preset("work-notes", &config);
+        let mut search = CollatedResults::new();
+
+        for (path, modified) in [
+            ("/notes/work/old.md", 100),
+            ("/notes/work/new.md", 200),
+            ("/notes/work/data.csv", 300),
+            ("/notes/home/list.md", 400),
+        ] {
+            let found = SearchResult {
+                path: path.to_string(),
+                word: "budget".to_string(),
+                stem: 1,
+                offset: 0,
+                byte_offset: None,
+                modified,
+                words: 0,
+            };
+
+            search.insert(path.to_string(), HashMap::from([(1, vec![found])]));
+        }
+
+        let sorted = search.keys().cloned().collect::<Vec<_>>();
+
+        assert_eq!(preset.query("budget"), "tag:work budget");
+        assert_eq!(preset.extensions, ["md"]);
+        assert_eq!(
+            preset.arrange(&search, sorted),
+            ["/notes/work/new.md", "/notes/work/old.md"]
+        );
+        assert_eq!(
+            read_preset("odd", &gjson::parse(r#"{"sort": "size"}"#)).sort,
+            PresetSort::Relevance
+        );
+    }
+}
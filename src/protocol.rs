@@ -0,0 +1,291 @@
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+
+use crate::json;
+
+// How a client wants its responses written, which is lines of UTF-8 text
+// with bare newlines, unless it asks for something else.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ResponseFormat {
+    pub crlf: bool,
+    pub encoding: &'static Encoding,
+    pub json: bool,
+}
+
+impl Default for ResponseFormat {
+    fn default() -> ResponseFormat {
+        ResponseFormat {
+            crlf: false,
+            encoding: UTF_8,
+            json: false,
+        }
+    }
+}
+
+impl ResponseFormat {
+    // Write out a response's lines, one to a line, or as a JSON object on
+    // one line, like `{"lines":["/notes/a.md"]}`.
+    pub fn lines(&self, lines: &[String]) -> Vec<u8> {
+        if self.json {
+            let quoted = lines.iter().map(|l| json::quote(l)).collect::<Vec<_>>();
+
+            self.encode(&format!("{{\"lines\":[{}]}}\n", quoted.join(",")))
+        } else {
+            self.encode(&lines.iter().map(|l| format!("{}\n", l)).collect::<String>())
+        }
+    }
+
+    // Write out why there's no answer, like `error: parse: can't group by
+    // foo`, or `{"error":"parse","message":"can't group by foo"}` in JSON.
+    pub fn error(&self, kind: &str, message: &str) -> Vec<u8> {
+        if self.json {
+            self.encode(&format!(
+                "{{\"error\":{},\"message\":{}}}\n",
+                json::quote(kind),
+                json::quote(message)
+            ))
+        } else {
+            self.encode(&format!(
+                "error: {}: {}\n",
+                kind,
+                message.replace('\n', " ")
+            ))
+        }
+    }
+
+    // Write out lines of text, each ending in a newline, the way the client
+    // asked.  Characters that the encoding can't represent become numeric
+    // character references, like `&#8364;`.
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let text = if self.crlf {
+            text.replace('\n', "\r\n")
+        } else {
+            text.to_string()
+        };
+
+        // The Encoding Standard only decodes UTF-16, so encoding_rs would
+        // write UTF-8, instead.
+        if self.encoding == UTF_16LE {
+            text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        } else if self.encoding == UTF_16BE {
+            text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+        } else {
+            self.encoding.encode(&text).0.into_owned()
+        }
+    }
+}
+
+// The options at the start of a request, after any token, that change how
+// it gets answered, rather than what it asks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RequestOptions {
+    pub max: Option<usize>,
+    pub format: ResponseFormat,
+}
+
+// A client that said `@hello`, which keeps its connection open for more
+// requests, each answered as though it started with the same token and
+// options, unless it says otherwise.  The session lasts until the client
+// hangs up or subscribes, since a subscription takes over the connection,
+// though its notifications keep the session's options.  The version of
+// the protocol that the client expects only goes in the log, since
+// there's only the one.  A client can send its next requests without
+// waiting for answers, and they wait their turn in `unread`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Session {
+    pub name: String,
+    pub version: Option<String>,
+    pub token: Option<String>,
+    pub options: RequestOptions,
+    pub unread: Vec<u8>,
+}
+
+impl Session {
+    // Start a session from an `@hello` request, like `@hello vscode-plugin
+    // json eol:crlf max:100 v2`, with the token and options it came with,
+    // where `json` or `text` is short for the `format:` option, or explain
+    // what it asked for that we can't do.
+    pub fn hello(
+        request: &str,
+        token: Option<&str>,
+        options: RequestOptions,
+    ) -> Result<Session, String> {
+        let mut words = request.trim_start_matches("@hello").split_whitespace();
+        let mut session = Session {
+            name: words.next().ok_or("@hello needs a name")?.to_string(),
+            token: token.map(str::to_string),
+            options,
+            ..Session::default()
+        };
+
+        for word in words {
+            let version = word.strip_prefix('v').unwrap_or_default();
+
+            if !version.is_empty() && version.chars().all(|c| c.is_ascii_digit()) {
+                session.version = Some(word.to_string());
+                continue;
+            }
+
+            let option = match word {
+                "json" | "text" => format!("format:{}", word),
+                _ => word.to_string(),
+            };
+
+            match split_options(&option, session.options)? {
+                (options, "") => session.options = options,
+                _ => return Err(format!("@hello doesn't know what {} means", word)),
+            }
+        }
+
+        Ok(session)
+    }
+}
+
+// Clean up a request as it arrives, since some clients end it with a
+// carriage return as well as a newline, or pad it out with NULs.
+pub fn clean_request(request: &str) -> String {
+    request
+        .trim_matches(|c| c == char::from(0) || c == '\r')
+        .to_string()
+}
+
+// Separate a request from the options at its start, in any order, which
+// are `max:` and the most results it can have, like `max:50`, `eol:crlf`
+// or `eol:lf` for its line endings, `encoding:` and the label of an
+// encoding, like `encoding:utf-16le` or `encoding:windows-1252`, and
+// `format:json` or `format:text` for how the lines get written, or
+// explain which option doesn't make sense.  Options it doesn't have keep
+// their defaults.
+pub fn split_options(
+    request: &str,
+    defaults: RequestOptions,
+) -> Result<(RequestOptions, &str), String> {
+    let mut options = defaults;
+    let mut rest = request;
+
+    loop {
+        let (option, remainder) = rest.split_once(' ').unwrap_or((rest, ""));
+
+        if let Some(max) = option.strip_prefix("max:") {
+            options.max = Some(
+                max.parse()
+                    .map_err(|_| format!("can't limit results to {}", max))?,
+            );
+        } else if let Some(eol) = option.strip_prefix("eol:") {
+            options.format.crlf = match eol.to_lowercase().as_str() {
+                "crlf" => true,
+                "lf" => false,
+                _ => return Err(format!("unknown line ending {}", eol)),
+            };
+        } else if let Some(label) = option.strip_prefix("encoding:") {
+            options.format.encoding = Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| format!("unknown encoding {}", label))?;
+        } else if let Some(format) = option.strip_prefix("format:") {
+            options.format.json = match format.to_lowercase().as_str() {
+                "json" => true,
+                "text" => false,
+                _ => return Err(format!("unknown response format {}", format)),
+            };
+        } else {
+            return Ok((options, rest));
+        }
+
+        rest = remainder.trim_start();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::WINDOWS_1252;
+
+    #[test]
+    fn options_come_in_any_order() {
+        let defaults = RequestOptions::default();
+        let (options, query) =
+            split_options("eol:CRLF max:5 encoding:latin1 fox", defaults).unwrap();
+
+        assert_eq!(query, "fox");
+        assert_eq!(options.max, Some(5));
+        assert!(options.format.crlf);
+        assert_eq!(options.format.encoding, WINDOWS_1252);
+        assert_eq!(
+            split_options("@stats", defaults).unwrap(),
+            (RequestOptions::default(), "@stats")
+        );
+        assert!(split_options("eol:cr fox", defaults).is_err());
+        assert!(split_options("encoding:klingon fox", defaults).is_err());
+        assert_eq!(clean_request("\0\0@stats\r\0"), "@stats");
+    }
+
+    #[test]
+    fn sessions_remember_options() {
+        let options = RequestOptions {
+            max: Some(5),
+            ..RequestOptions::default()
+        };
+        let session = Session::hello(
+            "@hello vscode-plugin json eol:crlf v2",
+            Some("3f9a"),
+            options,
+        )
+        .unwrap();
+
+        assert_eq!(session.name, "vscode-plugin");
+        assert_eq!(session.version.as_deref(), Some("v2"));
+        assert_eq!(session.token.as_deref(), Some("3f9a"));
+        assert_eq!(session.options.max, Some(5));
+        assert!(session.options.format.crlf);
+        assert!(session.options.format.json);
+        assert_eq!(
+            split_options("max:9 fox", session.options).unwrap().0.max,
+            Some(9)
+        );
+        assert!(Session::hello("@hello", None, options).is_err());
+        assert!(Session::hello("@hello emacs eol:cr", None, options).is_err());
+        assert!(Session::hello("@hello emacs xml", None, options).is_err());
+        assert!(
+            !Session::hello("@hello emacs format:json text", None, options)
+                .unwrap()
+                .options
+                .format
+                .json
+        );
+    }
+
+    #[test]
+    fn responses_get_encoded() {
+        let windows = ResponseFormat {
+            crlf: true,
+            encoding: UTF_16LE,
+            json: false,
+        };
+        let latin = ResponseFormat {
+            crlf: false,
+            encoding: WINDOWS_1252,
+            json: false,
+        };
+        let json = ResponseFormat {
+            json: true,
+            ..ResponseFormat::default()
+        };
+
+        assert_eq!(windows.encode("é\n"), b"\xe9\0\r\0\n\0");
+        assert_eq!(latin.encode("café €\n"), b"caf\xe9 \x80\n");
+        assert_eq!(
+            ResponseFormat::default().encode("café\n"),
+            "café\n".as_bytes()
+        );
+        assert_eq!(
+            json.lines(&["/a \"b\".md".to_string()]),
+            b"{\"lines\":[\"/a \\\"b\\\".md\"]}\n"
+        );
+        assert_eq!(
+            json.error("empty", "nothing found"),
+            b"{\"error\":\"empty\",\"message\":\"nothing found\"}\n"
+        );
+        assert_eq!(
+            ResponseFormat::default().error("parse", "two\nlines"),
+            b"error: parse: two lines\n"
+        );
+    }
+}
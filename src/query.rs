@@ -0,0 +1,1349 @@
+use regex::Regex;
+use rusqlite::{params, params_from_iter};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::iter::{self, FromIterator};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::str;
+use tracing::debug;
+
+use crate::config::RankingConfig;
+use crate::indexer::{stem_word, tokenize};
+use crate::normalizer::{language_normalizer, Normalizer};
+use crate::result_cache::ResultCache;
+use crate::store::{Store, WordStem};
+use crate::text::read_text;
+use crate::trigrams::{fold, trigrams};
+
+// Days for the recency boost to fall to half its strength.
+const RECENCY_HALF_LIFE: f32 = 30.0;
+
+// How quickly repeated matches stop adding to a file's score, and the
+// extra weight of the literal search term over other words with its stem.
+const TERM_SATURATION: f32 = 1.2;
+
+const PROXIMITY_SATURATION: f32 = 3.0;
+
+const LITERAL_BONUS: f32 = 0.1;
+
+// How many of a file's stems to search for when looking for similar
+// files, and how many of those files to return.
+const SIMILAR_STEMS: usize = 10;
+
+const SIMILAR_RESULTS: usize = 25;
+
+// Score multipliers for search terms in a file's name or folders.
+const FILENAME_BOOST: f32 = 2.0;
+
+const FOLDER_BOOST: f32 = 1.5;
+
+// How many words to show before and after a match, when its line can't
+// come from the file.
+const SNIPPET_BEFORE: u32 = 5;
+
+const SNIPPET_AFTER: u32 = 10;
+
+// How many recent searches to keep results for.
+pub const RESULT_CACHE_SIZE: usize = 128;
+
+// How many search results to work through between checks on whether the
+// search is still wanted, since checking can take a system call.
+const CANCEL_CHECK_INTERVAL: usize = 4096;
+
+// Search results, grouped by file and then by stem.
+pub type CollatedResults = HashMap<String, HashMap<u32, Vec<SearchResult>>>;
+
+// Recent searches, with their collated and ranked results.
+pub type SearchCache = ResultCache<(CollatedResults, Vec<String>)>;
+
+#[derive(Clone, Debug)]
+pub struct SearchResult {
+    pub path: String,
+    pub word: String,
+    pub stem: u32,
+    pub offset: u32,
+    pub byte_offset: Option<u32>,
+    pub modified: u64,
+    // How many words the whole file has, or zero, if we don't know.
+    pub words: u32,
+}
+
+// Whether whoever asked for a search still wants the answer, so that a
+// client that hangs up mid-search, like a search box that starts over on
+// every keystroke, doesn't leave us collating results nobody will read.
+// Once cancelled, a search stays cancelled.
+pub struct Cancellation<'a> {
+    check: Option<Box<dyn Fn() -> bool + 'a>>,
+    cancelled: Cell<bool>,
+}
+
+impl<'a> Cancellation<'a> {
+    // A search that runs to the end, for when nobody can hang up.
+    pub fn never() -> Cancellation<'a> {
+        Cancellation {
+            check: None,
+            cancelled: Cell::new(false),
+        }
+    }
+
+    // A search that stops once the check says it's no longer wanted.
+    pub fn new(check: impl Fn() -> bool + 'a) -> Cancellation<'a> {
+        Cancellation {
+            check: Some(Box::new(check)),
+            cancelled: Cell::new(false),
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        if !self.cancelled.get() && self.check.as_ref().is_some_and(|check| check()) {
+            debug!("Search cancelled");
+            self.cancelled.set(true);
+        }
+
+        self.cancelled.get()
+    }
+
+    // Check whether the search is still wanted every so often, going by how
+    // many results we've worked through so far.
+    pub fn is_cancelled_after(&self, count: usize) -> bool {
+        self.cancelled.get()
+            || (count.is_multiple_of(CANCEL_CHECK_INTERVAL) && self.is_cancelled())
+    }
+}
+
+// Organize a list sorted by file, stem, and offset by file and then by
+// stem, keeping only the files that have every one of the stems.  Each
+// result moves straight into its place, so only the file names get copied,
+// once per file.  A cancelled search collates to nothing.
+pub fn collate_search(
+    search: Vec<SearchResult>,
+    stem_ids: Vec<u32>,
+    cancel: &Cancellation,
+) -> CollatedResults {
+    let mut result = CollatedResults::new();
+
+    for (count, sr) in search.into_iter().enumerate() {
+        if cancel.is_cancelled_after(count) {
+            return CollatedResults::new();
+        }
+
+        if !result.contains_key(&sr.path) {
+            result.insert(sr.path.to_string(), HashMap::new());
+        }
+
+        result
+            .get_mut(&sr.path)
+            .unwrap()
+            .entry(sr.stem)
+            .or_default()
+            .push(sr);
+    }
+
+    result.retain(|_, stems| stem_ids.iter().all(|s| stems.contains_key(s)));
+    result
+}
+
+// Sort search results for relevance, returning the ordered file names.
+pub fn sort_search_results(
+    search: &CollatedResults,
+    query: Vec::<&str>,
+    config: &RankingConfig,
+) -> Vec<String> {
+    let mut result = Vec::<String>::new();
+    let ranking = rank_search_results(search, &query, config);
+
+    // Sort the files by their scores, best first, falling back to the
+    // path when scores tie, so that the order is stable.
+    ranking.keys().for_each(|k| result.push(k.to_string()));
+    result.sort_by(|a, b| ranking[b].total_cmp(&ranking[a]).then_with(|| a.cmp(b)));
+    // We need an empty, because something about the response to
+    // the client cuts off the final characters.
+    result.push("".to_string());
+
+    result
+}
+
+// Score each file in the search results for relevance.
+pub fn rank_search_results(
+    search: &CollatedResults,
+    query: &[&str],
+    config: &RankingConfig,
+) -> HashMap<String, f32> {
+    explain_ranking(search, query, config)
+        .into_iter()
+        .map(|(path, breakdown)| (path, breakdown.score))
+        .collect()
+}
+
+// How a file's score came together, for `@explain`.  The score starts at
+// one and adds the proximity of the stems to each other, then each stem's
+// frequency, multiplying by that stem's bonus for the literal search term
+// along the way, and multiplies the total by the boosts for the path, the
+// folder the file is in, and how recent the file is.  The proximity and
+// frequencies saturate more slowly for files longer than the others that
+// matched, by the length factor.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScoreBreakdown {
+    pub proximity: f32,
+    pub terms: f32,
+    pub literal: f32,
+    pub path: f32,
+    pub folder: f32,
+    pub recency: f32,
+    pub length: f32,
+    pub score: f32,
+}
+
+// Score each file in the search results for relevance, keeping track of
+// what each part of the score contributed.
+pub fn explain_ranking(
+    search: &CollatedResults,
+    query: &[&str],
+    config: &RankingConfig,
+) -> HashMap<String, ScoreBreakdown> {
+    let mut ranking = HashMap::<String, ScoreBreakdown>::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let file_words = |stems: &HashMap<u32, Vec<SearchResult>>| {
+        stems
+            .values()
+            .flatten()
+            .map(|w| w.words)
+            .next()
+            .unwrap_or(0)
+    };
+    // Lengths only count against files compared to the others that
+    // matched, so the files we don't know the lengths of don't count.
+    let lengths = search
+        .values()
+        .map(file_words)
+        .filter(|&words| words > 0)
+        .collect::<Vec<_>>();
+    let average_words =
+        lengths.iter().map(|&w| w as f32).sum::<f32>() / lengths.len().max(1) as f32;
+
+    search.keys().for_each(|k| {
+        let mut score = 1.0;
+        let mut breakdown = ScoreBreakdown {
+            literal: 1.0,
+            recency: 1.0,
+            length: 1.0,
+            ..ScoreBreakdown::default()
+        };
+        let stems = &search[k];
+        let mut stem_keys = Vec::from_iter(stems.keys());
+        let words_in_file = file_words(stems);
+
+        // Like BM25, a file longer than average needs more matches to
+        // score as well as a shorter one, and a shorter file needs fewer.
+        if words_in_file > 0 && average_words > 0.0 {
+            let b = config.length_normalization;
+
+            breakdown.length = 1.0 - b + b * words_in_file as f32 / average_words;
+        }
+
+        // Compare the stems in a fixed order, so that scores don't depend
+        // on how the HashMap happens to iterate.
+        stem_keys.sort();
+        for s in 0..stem_keys.len().saturating_sub(1) {
+            let offsets = &stems[stem_keys[s]];
+            let compare = &stems[stem_keys[s + 1]];
+            let mut oi = 0;
+            let mut ci = 0;
+            let mut proximity = 0.0;
+
+            while oi < offsets.len() && ci < compare.len() {
+                let offset = offsets[oi].offset;
+                let comp = compare[ci].offset;
+                if offset > comp {
+                    ci += 1;
+                    continue;
+                };
+
+                let diff = comp - offset;
+
+                if diff < 2 {
+                    proximity += 3.0;
+                } else if diff < 7 {
+                    proximity += 2.0;
+                } else if diff <= 20 {
+                    proximity += 1.0;
+                }
+
+                oi += 1;
+            }
+
+            let proximity =
+                saturate_for_length(proximity, PROXIMITY_SATURATION, breakdown.length);
+
+            breakdown.proximity += proximity;
+            score += proximity;
+        }
+
+        // Each stem counts for more the more often it appears, and more
+        // again when the literal search term appears, rather than just the
+        // stem, but with diminishing returns, so that files matching every
+        // term a few times beat files repeating one term hundreds of times.
+        stems.values().for_each(|words| {
+            let literal = words
+                .iter()
+                .filter(|w| query.contains(&w.word.as_str()))
+                .count();
+            let bonus = 1.0 + LITERAL_BONUS * saturate(literal as f32, TERM_SATURATION);
+
+            let terms = saturate_for_length(
+                words.len() as f32,
+                TERM_SATURATION,
+                breakdown.length,
+            );
+
+            breakdown.terms += terms;
+            breakdown.literal *= bonus;
+            score += terms;
+            score *= bonus;
+        });
+
+        breakdown.path = path_boost(k, query);
+        score *= breakdown.path;
+        breakdown.folder = config.folder_boost(k);
+        score *= breakdown.folder;
+
+        // Favor recently modified files, with the boost halving every
+        // RECENCY_HALF_LIFE days since the last change.
+        if config.recency_boost > 0.0 {
+            let modified = stems
+                .values()
+                .flatten()
+                .map(|w| w.modified)
+                .next()
+                .unwrap_or(0);
+            let age_days = now.saturating_sub(modified) as f32 / 86400.0;
+            let freshness = 0.5f32.powf(age_days / RECENCY_HALF_LIFE);
+
+            breakdown.recency = 1.0 + config.recency_boost * freshness;
+            score *= breakdown.recency;
+        }
+
+        breakdown.score = score;
+        ranking.insert(k.to_string(), breakdown);
+    });
+
+    ranking
+}
+
+// Scale a count so that the first occurrence counts as one, and each
+// additional occurrence counts for less, approaching k + 1 in total, like
+// BM25's term frequency component.
+pub fn saturate(count: f32, k: f32) -> f32 {
+    saturate_for_length(count, k, 1.0)
+}
+
+// Scale a count the way saturate does, for a file whose length, compared
+// to the others, makes counts saturate more slowly or more quickly.
+fn saturate_for_length(count: f32, k: f32, length: f32) -> f32 {
+    count * (k + 1.0) / (count + k * length)
+}
+
+// Boost files whose names, or failing that whose folders, include the
+// search terms, since a note titled after a topic is probably about it.
+fn path_boost(path: &str, query: &[&str]) -> f32 {
+    let path = Path::new(path);
+    let words_in = |text: &str| {
+        text.to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| !w.is_empty())
+            .map(|w| w.to_string())
+            .collect::<Vec<_>>()
+    };
+    let name_words = words_in(&path.file_name().unwrap_or_default().to_string_lossy());
+    let dir_words = words_in(&path.parent().map_or("".into(), |p| p.to_string_lossy()));
+    let mut boost = 1.0;
+
+    query.iter().map(|q| q.to_lowercase()).for_each(|term| {
+        if name_words.contains(&term) {
+            boost *= FILENAME_BOOST;
+        } else if dir_words.contains(&term) {
+            boost *= FOLDER_BOOST;
+        }
+    });
+
+    boost
+}
+
+// Run a search, returning the results collated by file and stem, along
+// with the ranked file names, or nothing at all, if the search gets
+// cancelled, which leaves the cache alone.  A database that can't answer
+// is an error, rather than a search that found nothing.
+#[allow(clippy::too_many_arguments)]
+pub fn run_search(
+    query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+) -> rusqlite::Result<(CollatedResults, Vec<String>)> {
+    // Pull out any `tag:` and `def:` filters before punctuation breaks
+    // them up.
+    let tags = filter_values(query, "tag:");
+    let defs = filter_values(query, "def:");
+    let exact = filter_values(query, "word:");
+    // Exact words still rank by their stems, like the rest.
+    let words = query_words(query, punc)
+        .into_iter()
+        .chain(exact.iter().cloned())
+        .collect::<Vec<_>>();
+    let tokens = words.iter().map(String::as_str).collect::<Vec<_>>();
+    // The same words find different files for a token limited to folders.
+    let scope = Some(store.in_folders("path")).filter(|s| s != "1");
+    let key = tokens
+        .iter()
+        .map(|w| w.to_string())
+        .chain(tags.iter().map(|t| format!("tag:{}", t)))
+        .chain(defs.iter().map(|d| format!("def:{}", d)))
+        .chain(exact.iter().map(|w| format!("word:{}", w)))
+        .chain(scope)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    if let Some(cached) = cache.get(&key) {
+        debug!("cached results for {}", key);
+        return Ok(cached);
+    }
+
+    let all_stems = store.select_all_stems()?;
+    // Files whose modelines chose another language have their words
+    // normalized that way, so each word can match any language's stem.
+    let own_stemmers = store
+        .languages()?
+        .iter()
+        .filter_map(|language| language_normalizer(language))
+        .collect::<Vec<_>>();
+    let mut new_stems = Vec::<WordStem>::new();
+    let mut groups = Vec::<HashSet<u32>>::new();
+
+    tokens.iter().for_each(|word| {
+        let mut group = HashSet::<u32>::new();
+        let mut stems = iter::once(stemmer)
+            .chain(own_stemmers.iter().map(|s| &**s as &dyn Normalizer))
+            .map(|normalizer| stem_word(word, accents, normalizer))
+            .collect::<Vec<_>>();
+
+        stems.sort();
+        stems.dedup();
+        for stem in stems {
+            let id = all_stems.get(&stem).copied().unwrap_or(0);
+
+            if id > 0 {
+                group.insert(id);
+            }
+
+            // Stems that nothing has yet still go in, so that the cache
+            // knows to forget the search once something does.
+            new_stems.push(WordStem { id, stem });
+        }
+
+        if !group.is_empty() {
+            groups.push(group);
+        }
+    });
+
+    let query_stems = new_stems
+        .iter()
+        .map(|s| s.stem.to_string())
+        .chain(tags.iter().map(|t| format!("tag:{}", t)))
+        .chain(defs.iter().map(|d| format!("def:{}", d)))
+        .collect();
+    let mut filtered = None::<HashSet<String>>;
+
+    if !tags.is_empty() {
+        filtered = Some(store.files_with_tags(&tags)?);
+    }
+
+    if !defs.is_empty() {
+        let defining = store.files_defining(&defs)?;
+
+        filtered = Some(match filtered {
+            Some(tagged) => tagged.intersection(&defining).cloned().collect(),
+            None => defining,
+        });
+    }
+
+    if !exact.is_empty() {
+        let exactly = store.files_with_words(&exact, accents)?;
+
+        filtered = Some(match filtered {
+            Some(others) => others.intersection(&exactly).cloned().collect(),
+            None => exactly,
+        });
+    }
+
+    let serps = match &filtered {
+        // With only filters to go on, every file that passes them matches
+        // equally.
+        Some(filtered) if new_stems.is_empty() => filtered
+            .iter()
+            .map(|p| (p.to_string(), HashMap::new()))
+            .collect(),
+        _ => {
+            let search_results = store.search_index(new_stems, cancel)?;
+            let mut serps = collate_search(search_results, Vec::new(), cancel);
+
+            serps.retain(|_, found| {
+                groups
+                    .iter()
+                    .all(|group| found.keys().any(|stem| group.contains(stem)))
+            });
+
+            if let Some(filtered) = &filtered {
+                serps.retain(|path, _| filtered.contains(path));
+            }
+
+            serps
+        }
+    };
+
+    if cancel.is_cancelled() {
+        return Ok((CollatedResults::new(), Vec::new()));
+    }
+
+    let sorted = sort_search_results(&serps, tokens, ranking);
+
+    debug!("{:#?}", serps);
+    cache.insert(
+        &key,
+        (serps.clone(), sorted.clone()),
+        query_stems,
+        serps.keys().map(|p| p.to_string()).collect(),
+    );
+    Ok((serps, sorted))
+}
+
+// Run a search where each word matches any stem that sounds like it, as
+// `@sounds-like` does, keeping only the files that match every word in
+// one way or another.  Phonetic searches are rare enough that they skip
+// the cache.
+#[allow(clippy::too_many_arguments)]
+pub fn run_phonetic_search(
+    query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cancel: &Cancellation,
+) -> rusqlite::Result<(CollatedResults, Vec<String>)> {
+    let words = query_words(query, punc);
+    let mut groups = Vec::<HashSet<u32>>::new();
+    let mut stems = Vec::<WordStem>::new();
+
+    for word in &words {
+        let stem = stem_word(word, accents, stemmer);
+        let alike = store.stems_sounding_like(&stem)?;
+
+        groups.push(alike.iter().map(|s| s.id).collect());
+        stems.extend(alike);
+    }
+
+    if stems.is_empty() || groups.iter().any(HashSet::is_empty) {
+        return Ok((CollatedResults::new(), vec![String::new()]));
+    }
+
+    let search_results = store.search_index(stems, cancel)?;
+    let mut serps = collate_search(search_results, Vec::new(), cancel);
+
+    serps.retain(|_, found| {
+        groups
+            .iter()
+            .all(|group| found.keys().any(|stem| group.contains(stem)))
+    });
+
+    if cancel.is_cancelled() {
+        return Ok((CollatedResults::new(), Vec::new()));
+    }
+
+    let tokens = words.iter().map(String::as_str).collect::<Vec<_>>();
+    let sorted = sort_search_results(&serps, tokens, ranking);
+
+    Ok((serps, sorted))
+}
+
+// Run a search for words containing each of the query's terms anywhere in
+// them, as `@contains` does, ignoring case and, usually, accents, keeping
+// only the files that have every term.  The trigram index narrows down the
+// stems to look at, and then each word found has to really contain a term.
+pub fn run_substring_search(
+    query: &str,
+    accents: &Regex,
+    store: &Store,
+    ranking: &RankingConfig,
+    cancel: &Cancellation,
+) -> rusqlite::Result<(CollatedResults, Vec<String>)> {
+    let needles = query
+        .split_whitespace()
+        .map(|n| fold(n, accents))
+        .collect::<Vec<_>>();
+    let mut stems = Vec::<WordStem>::new();
+
+    for needle in &needles {
+        let ids = store.stems_with_trigrams(&trigrams(needle))?;
+
+        if ids.is_empty() {
+            return Ok((CollatedResults::new(), vec![String::new()]));
+        }
+
+        stems.extend(ids.into_iter().map(|id| WordStem {
+            id,
+            stem: String::new(),
+        }));
+    }
+
+    let search_results = store
+        .search_index(stems, cancel)?
+        .into_iter()
+        .filter(|found| {
+            let word = fold(&found.word, accents);
+
+            needles.iter().any(|n| word.contains(n))
+        })
+        .collect();
+    let mut serps = collate_search(search_results, Vec::new(), cancel);
+
+    serps.retain(|_, found| {
+        let words = found
+            .values()
+            .flatten()
+            .map(|w| fold(&w.word, accents))
+            .collect::<HashSet<_>>();
+
+        needles.iter().all(|n| words.iter().any(|w| w.contains(n)))
+    });
+
+    if cancel.is_cancelled() {
+        return Ok((CollatedResults::new(), Vec::new()));
+    }
+
+    let tokens = needles.iter().map(String::as_str).collect::<Vec<_>>();
+    let sorted = sort_search_results(&serps, tokens, ranking);
+
+    Ok((serps, sorted))
+}
+
+// The words a query searches for, leaving out its filters.
+pub fn query_words(query: &str, punc: &Regex) -> Vec<String> {
+    let words = query
+        .split_whitespace()
+        .filter(|t| filter_prefix(t).is_none())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    tokenize(&words, punc)
+        .into_iter()
+        .map(|(_, w)| w.to_string())
+        .collect()
+}
+
+// The filter a search term starts with, like `tag:`, `def:`, or `word:`,
+// if any.
+fn filter_prefix(term: &str) -> Option<&'static str> {
+    ["tag:", "def:", "word:"]
+        .iter()
+        .find(|f| {
+            term.get(..f.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(f))
+        })
+        .copied()
+}
+
+// The values of one kind of filter in a query, like the tags from its
+// `tag:` terms, lowercased and without duplicates.
+pub fn filter_values(query: &str, filter: &str) -> Vec<String> {
+    let mut values = query
+        .split_whitespace()
+        .filter(|t| filter_prefix(t) == Some(filter))
+        .map(|t| t[filter.len()..].to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect::<Vec<_>>();
+
+    values.sort();
+    values.dedup();
+    values
+}
+
+// The stems a query searches for, with its filters marked the way the
+// indexer reports them, for comparing against a freshly indexed file.
+pub fn query_stems(
+    query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+) -> HashSet<String> {
+    query
+        .split_whitespace()
+        .flat_map(|term| match filter_prefix(term) {
+            // A file can only have the exact word if it has the word's stem.
+            Some("word:") => vec![stem_word(&term[5..], accents, stemmer)],
+            Some(prefix) => {
+                vec![format!("{}{}", prefix, term[prefix.len()..].to_lowercase())]
+            }
+            None => tokenize(term, punc)
+                .iter()
+                .map(|(_, w)| stem_word(w, accents, stemmer))
+                .collect(),
+        })
+        .filter(|s| !s.is_empty() && s != "tag:" && s != "def:")
+        .collect()
+}
+
+// Find the stems that best characterize a file, by TF-IDF, returning the
+// stem ID, the stem, its weight, and how often it appears in the file.
+pub fn top_file_stems(
+    store: &Store,
+    path: &str,
+    count: usize,
+) -> rusqlite::Result<Vec<(u32, String, f32, u32)>> {
+    let total_files: f32 =
+        store.query_row("SELECT COUNT(*) FROM monitored_file", [], |row| row.get(0))?;
+    let mut stmt = store.prepare(
+        "SELECT s.id, s.stem, COUNT(*),
+                (SELECT COUNT(DISTINCT d.file)
+                   FROM file_reverse_index d
+                   WHERE d.stem = s.id AND d.file IN (SELECT id FROM monitored_file))
+           FROM file_reverse_index i
+           JOIN word_stem s ON s.id = i.stem
+           JOIN monitored_file f ON f.id = i.file
+           WHERE f.path = ?
+           GROUP BY s.id",
+    )?;
+    let mut stems = stmt
+        .query_map(params![path], |row| {
+            let frequency: u32 = row.get(2)?;
+            let files: f32 = row.get(3)?;
+            let weight = frequency as f32 * (total_files / files.max(1.0)).ln();
+
+            Ok((row.get(0)?, row.get(1)?, weight, frequency))
+        })?
+        .collect::<rusqlite::Result<Vec<(u32, String, f32, u32)>>>()?;
+
+    stems.sort_by(|a, b| b.2.total_cmp(&a.2).then_with(|| a.1.cmp(&b.1)));
+    stems.truncate(count);
+    Ok(stems)
+}
+
+// Pick the match with the most distinct search terms close by, preferring
+// the earliest in the file if there's a tie.
+pub fn best_match(stems: &HashMap<u32, Vec<SearchResult>>) -> Option<&SearchResult> {
+    let window = 10;
+    let mut best = None::<&SearchResult>;
+    let mut best_count = 0;
+
+    stems.values().flatten().for_each(|candidate| {
+        let count = stems
+            .values()
+            .filter(|matches| {
+                matches
+                    .iter()
+                    .any(|m| m.offset.abs_diff(candidate.offset) <= window)
+            })
+            .count();
+
+        if count > best_count
+            || (count == best_count && best.is_none_or(|b| candidate.offset < b.offset))
+        {
+            best_count = count;
+            best = Some(candidate);
+        }
+    });
+
+    best
+}
+
+// Find the line containing a match, returning the line's number and its
+// text.  The match's byte offset leads straight there, but files indexed
+// before we tracked those need their words counted the same way that
+// index_file does, which tables, missing their numbers, throw off.
+pub fn find_line(
+    path: &Path,
+    found: &SearchResult,
+    punc: &Regex,
+) -> Option<(usize, String)> {
+    let (text, _encoding) = read_text(path).ok()??;
+
+    if let Some(byte_offset) = found.byte_offset {
+        let line_no = text.get(..byte_offset as usize)?.matches('\n').count();
+
+        return text
+            .lines()
+            .nth(line_no)
+            .map(|line| (line_no + 1, line.trim_end().to_string()));
+    }
+
+    let mut word_count = 0;
+
+    for (line_no, line) in text.lines().enumerate() {
+        word_count += tokenize(line, punc).len() as u32;
+        if word_count > found.offset {
+            return Some((line_no + 1, line.trim_end().to_string()));
+        }
+    }
+
+    None
+}
+
+// Put the words around a match back together from the index, for files
+// whose lines can't be read, like photos with their descriptions, scans,
+// or files that are gone.  The index keeps each word the way it was
+// written, so acronyms and names look right, even if the punctuation
+// between the words is lost.
+pub fn index_snippet(store: &Store, found: &SearchResult) -> Option<String> {
+    let first = found.offset.saturating_sub(SNIPPET_BEFORE);
+    let words = store
+        .words_between(&found.path, first, found.offset + SNIPPET_AFTER)
+        .ok()?;
+
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+// Gather the spellings of indexed words, like "NASA" and "nasa," into one
+// entry for each word, ignoring case, spelled the way that it appears most
+// often, and counting every spelling, most common first.
+pub fn common_spellings(spellings: Vec<(String, u32)>) -> Vec<(String, u32)> {
+    let mut words = HashMap::<String, (String, u32, u32)>::new();
+
+    for (spelling, count) in spellings {
+        let entry = words
+            .entry(spelling.to_lowercase())
+            .or_insert_with(|| (spelling.to_string(), 0, 0));
+
+        if count > entry.1 || (count == entry.1 && spelling < entry.0) {
+            entry.0 = spelling;
+            entry.1 = count;
+        }
+
+        entry.2 += count;
+    }
+
+    let mut words = words.into_iter().collect::<Vec<_>>();
+
+    words.sort_by(|a, b| b.1 .2.cmp(&a.1 .2).then_with(|| a.0.cmp(&b.0)));
+    words
+        .into_iter()
+        .map(|(_, (spelling, _, total))| (spelling, total))
+        .collect()
+}
+
+// Read one line of a file, counting from one, with its number.
+pub fn read_line(path: &Path, line_no: u32) -> Option<(usize, String)> {
+    let (text, _encoding) = read_text(path).ok()??;
+    let line_no = (line_no as usize).max(1);
+
+    text.lines()
+        .nth(line_no - 1)
+        .map(|line| (line_no, line.trim_end().to_string()))
+}
+
+// Find files similar to the specified file, best first, by searching for
+// its most distinctive stems.  Unlike a normal search, files don't need to
+// contain every stem, but score higher the more of the important ones
+// they share.
+pub fn similar_files(store: &Store, path: &str) -> rusqlite::Result<Vec<String>> {
+    let stems = top_file_stems(store, path, SIMILAR_STEMS)?;
+    let weights = stems
+        .iter()
+        .map(|(id, _, weight, _)| (*id, *weight))
+        .collect::<HashMap<_, _>>();
+    let mut scores = HashMap::<String, f32>::new();
+    let placeholders = stems.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+    let query = format!(
+        "SELECT f.path, i.stem, COUNT(*)
+           FROM file_reverse_index i JOIN monitored_file f ON f.id = i.file
+           WHERE i.stem IN ({}) AND f.path != ? AND {}
+           GROUP BY f.path, i.stem",
+        placeholders,
+        store.in_folders("f.path")
+    );
+    let mut values = stems.iter().map(|s| s.0.to_string()).collect::<Vec<_>>();
+
+    values.push(path.to_string());
+    if !stems.is_empty() {
+        let mut stmt = store.prepare_uncached(&query)?;
+        let rows = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, u32>(2)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (file, stem, count) = row?;
+
+            *scores.entry(file).or_insert(0.0) +=
+                weights[&stem] * saturate(count as f32, TERM_SATURATION);
+        }
+    }
+
+    let mut result = scores.keys().map(|k| k.to_string()).collect::<Vec<_>>();
+
+    result.sort_by(|a, b| scores[b].total_cmp(&scores[a]).then_with(|| a.cmp(b)));
+    result.truncate(SIMILAR_RESULTS);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{accents, punctuation};
+    use crate::store::IndexTuple;
+    use crate::testing::open_store;
+    use rust_stemmers::Stemmer;
+
+    fn result(path: &str, word: &str, stem: u32, offset: u32) -> SearchResult {
+        SearchResult {
+            path: path.to_string(),
+            word: word.to_string(),
+            stem,
+            offset,
+            byte_offset: None,
+            modified: 0,
+            words: 0,
+        }
+    }
+
+    fn no_boosts() -> RankingConfig {
+        RankingConfig {
+            recency_boost: 0.0,
+            folder_boosts: Vec::new(),
+            length_normalization: 0.0,
+        }
+    }
+
+    #[test]
+    fn collate_keeps_the_last_file() {
+        let search = vec![
+            result("/a.md", "fox", 1, 3),
+            result("/b.md", "fox", 1, 5),
+        ];
+        let collated = collate_search(search, vec![1], &Cancellation::never());
+
+        assert!(collated.contains_key("/a.md"));
+        assert!(collated.contains_key("/b.md"));
+    }
+
+    #[test]
+    fn collate_requires_every_stem() {
+        let search = vec![
+            result("/a.md", "fox", 1, 3),
+            result("/a.md", "dog", 2, 9),
+            result("/b.md", "fox", 1, 5),
+            result("/c.md", "dog", 2, 1),
+        ];
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+
+        assert_eq!(collated.len(), 1);
+        assert_eq!(collated["/a.md"][&1][0].offset, 3);
+        assert_eq!(collated["/a.md"][&2][0].offset, 9);
+    }
+
+    #[test]
+    fn collate_groups_by_file_and_stem() {
+        let search = vec![
+            result("/a.md", "fox", 1, 3),
+            result("/a.md", "foxes", 1, 8),
+            result("/a.md", "dog", 2, 9),
+            result("/b.md", "dog", 2, 1),
+            result("/b.md", "fox", 1, 5),
+        ];
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+        let words = |path: &str, stem: u32| {
+            collated[path][&stem]
+                .iter()
+                .map(|r| (r.word.as_str(), r.offset))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(collated.len(), 2);
+        assert_eq!(collated["/a.md"].len(), 2);
+        assert_eq!(words("/a.md", 1), vec![("fox", 3), ("foxes", 8)]);
+        assert_eq!(words("/a.md", 2), vec![("dog", 9)]);
+        assert_eq!(words("/b.md", 1), vec![("fox", 5)]);
+        assert_eq!(words("/b.md", 2), vec![("dog", 1)]);
+        assert!(collate_search(Vec::new(), vec![1], &Cancellation::never()).is_empty());
+    }
+
+    #[test]
+    fn cancelled_searches_stop_early() {
+        let checks = Cell::new(0);
+        let cancel = Cancellation::new(|| {
+            checks.set(checks.get() + 1);
+            true
+        });
+        let search = (0..CANCEL_CHECK_INTERVAL as u32 * 3)
+            .map(|n| result("/a.md", "fox", 1, n))
+            .collect::<Vec<_>>();
+
+        assert!(collate_search(search, vec![1], &cancel).is_empty());
+        assert!(cancel.is_cancelled());
+        assert_eq!(checks.get(), 1);
+    }
+
+    #[test]
+    fn sort_puts_the_best_match_first() {
+        let search = vec![
+            result("/far.md", "fox", 1, 0),
+            result("/far.md", "dog", 2, 50),
+            result("/near.md", "fox", 1, 0),
+            result("/near.md", "dog", 2, 1),
+        ];
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+        let sorted = sort_search_results(&collated, vec!["fox", "dog"], &no_boosts());
+
+        assert_eq!(sorted, vec!["/near.md", "/far.md", ""]);
+    }
+
+    #[test]
+    fn sort_breaks_ties_by_path() {
+        let search = vec![
+            result("/a.md", "fox", 1, 0),
+            result("/b.md", "fox", 1, 0),
+            result("/c.md", "fox", 1, 0),
+        ];
+        let collated = collate_search(search, vec![1], &Cancellation::never());
+        let sorted = sort_search_results(&collated, vec!["fox"], &no_boosts());
+
+        assert_eq!(sorted, vec!["/a.md", "/b.md", "/c.md", ""]);
+    }
+
+    #[test]
+    fn literal_matches_outrank_stem_matches() {
+        let search = vec![
+            result("/a.md", "foxes", 1, 0),
+            result("/b.md", "fox", 1, 0),
+        ];
+        let collated = collate_search(search, vec![1], &Cancellation::never());
+        let sorted = sort_search_results(&collated, vec!["fox"], &no_boosts());
+
+        assert_eq!(sorted, vec!["/b.md", "/a.md", ""]);
+    }
+
+    #[test]
+    fn balanced_matches_beat_repetition() {
+        let mut search = vec![
+            result("/balanced.md", "fox", 1, 0),
+            result("/balanced.md", "fox", 1, 30),
+            result("/balanced.md", "dog", 2, 10),
+            result("/balanced.md", "dog", 2, 40),
+        ];
+
+        (0..500).for_each(|i| search.push(result("/spam.md", "fox", 1, i)));
+        search.push(result("/spam.md", "dog", 2, 1000));
+
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+        let sorted = sort_search_results(&collated, vec!["fox", "dog"], &no_boosts());
+
+        assert_eq!(sorted, vec!["/balanced.md", "/spam.md", ""]);
+    }
+
+    #[test]
+    fn file_names_boost_the_score() {
+        let search = vec![
+            result("/notes/a.md", "fox", 1, 0),
+            result("/notes/fox.md", "fox", 1, 0),
+        ];
+        let collated = collate_search(search, vec![1], &Cancellation::never());
+        let sorted = sort_search_results(&collated, vec!["fox"], &no_boosts());
+
+        assert_eq!(sorted, vec!["/notes/fox.md", "/notes/a.md", ""]);
+    }
+
+    #[test]
+    fn folders_boost_the_score() {
+        let search = vec![
+            result("/notes/archive/a.md", "fox", 1, 0),
+            result("/notes/b.md", "fox", 1, 0),
+            result("/notes/active/c.md", "fox", 1, 0),
+            result("/notes/active/d.draft.md", "fox", 1, 0),
+        ];
+        let collated = collate_search(search, vec![1], &Cancellation::never());
+        let ranking = RankingConfig::new(&gjson::parse(
+            r#"{"folderBoosts": {"/notes/active": 2, "/notes/archive/": 0.5,
+                                 "*.draft.md": 1.5, "/notes/b.md": -1}}"#,
+        ));
+        let sorted = sort_search_results(&collated, vec!["fox"], &ranking);
+
+        assert_eq!(ranking.folder_boosts.len(), 3);
+        assert!((ranking.folder_boost("/notes/active/d.draft.md") - 3.0).abs() < 0.0001);
+        assert_eq!(
+            sorted,
+            vec![
+                "/notes/active/d.draft.md",
+                "/notes/active/c.md",
+                "/notes/b.md",
+                "/notes/archive/a.md",
+                ""
+            ]
+        );
+    }
+
+    #[test]
+    fn explanations_add_up() {
+        let search = vec![
+            result("/notes/fox.md", "fox", 1, 0),
+            result("/notes/fox.md", "dog", 2, 1),
+        ];
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+        let explained = explain_ranking(&collated, &["fox", "dog"], &no_boosts());
+        let breakdown = &explained["/notes/fox.md"];
+        let close = |a: f32, b: f32| (a - b).abs() < 0.0001;
+
+        assert!(close(breakdown.proximity, 2.0));
+        assert!(close(breakdown.terms, 2.0));
+        assert!(close(breakdown.literal, 1.21));
+        assert!(close(breakdown.path, 2.0));
+        assert!(close(breakdown.folder, 1.0));
+        assert!(close(breakdown.recency, 1.0));
+        assert!(close(breakdown.length, 1.0));
+        assert!(close(breakdown.score, 11.88));
+    }
+
+    #[test]
+    fn short_files_beat_long_ones() {
+        let mut search = Vec::new();
+
+        for (path, words) in [("/log.txt", 9000), ("/note.md", 120), ("/old.md", 0)] {
+            for (word, stem, offset) in [("fox", 1, 0), ("dog", 2, 3), ("fox", 1, 8)] {
+                search.push(SearchResult {
+                    words,
+                    ..result(path, word, stem, offset)
+                });
+            }
+        }
+
+        let collated = collate_search(search, vec![1, 2], &Cancellation::never());
+        let ranking = RankingConfig::new(&gjson::parse("{}"));
+        let explained = explain_ranking(&collated, &["fox", "dog"], &ranking);
+
+        assert_eq!(
+            sort_search_results(&collated, vec!["fox", "dog"], &ranking),
+            vec!["/note.md", "/old.md", "/log.txt", ""]
+        );
+        assert!(explained["/log.txt"].length > 1.0);
+        assert!(explained["/note.md"].length < 1.0);
+        assert_eq!(explained["/old.md"].length, 1.0);
+        assert!(explain_ranking(&collated, &["fox", "dog"], &no_boosts())
+            .values()
+            .all(|s| s.length == 1.0));
+    }
+
+    #[test]
+    fn sounds_like_matches_every_word() {
+        let store = open_store();
+        let stemmer = Stemmer::create(rust_stemmers::Algorithm::English);
+        let accents = accents(&gjson::parse("{}"));
+
+        let stems = store
+            .insert_bulk_stems(vec!["nietzsch".into(), "nitsch".into(), "wrote".into()])
+            .unwrap();
+        let mut tuples = Vec::new();
+
+        for (path, words) in [
+            ("/a.md", ["nietzsche", "wrote"]),
+            ("/b.md", ["nitsche", "nitsche"]),
+            ("/c.md", ["nitsche", "wrote"]),
+        ] {
+            let file = store.insert_file(Path::new(path), &0).unwrap().id;
+
+            for (offset, word) in words.iter().enumerate() {
+                tuples.push(IndexTuple {
+                    file,
+                    stem: stems[&stem_word(word, &accents, &stemmer)],
+                    offset: offset as u32,
+                    byte_offset: 0,
+                    word: word.to_string(),
+                });
+            }
+        }
+
+        store.insert_bulk_word_tuples(tuples, &accents).unwrap();
+
+        // Both files match as well as each other.
+        let (_serps, mut sorted) = run_phonetic_search(
+            "Neetsha wrote",
+            &punctuation(),
+            &accents,
+            &stemmer,
+            &store,
+            &no_boosts(),
+            &Cancellation::never(),
+        )
+        .unwrap();
+
+        sorted.sort();
+        assert_eq!(sorted, vec!["", "/a.md", "/c.md"]);
+    }
+
+    #[test]
+    fn database_failures_are_errors() {
+        let store = open_store();
+        let stemmer = Stemmer::create(rust_stemmers::Algorithm::English);
+        let accents = accents(&gjson::parse("{}"));
+        let mut cache = SearchCache::new(4);
+
+        store
+            .insert_bulk_stems(vec!["fox".into(), "work".into()])
+            .unwrap();
+        store.execute("DROP TABLE file_reverse_index", []).unwrap();
+
+        let search = |query: &str, cache: &mut SearchCache| {
+            run_search(
+                query,
+                &punctuation(),
+                &accents,
+                &stemmer,
+                &store,
+                &no_boosts(),
+                cache,
+                &Cancellation::never(),
+            )
+        };
+
+        assert!(search("fox", &mut cache).is_err());
+        assert!(search("word:fox", &mut cache).is_err());
+        assert!(top_file_stems(&store, "/a.md", 10).is_err());
+        assert!(similar_files(&store, "/a.md").is_err());
+        store.execute("DROP TABLE file_tag", []).unwrap();
+        assert!(search("tag:work", &mut cache).is_err());
+        store.execute("DROP TABLE file_symbol", []).unwrap();
+        assert!(search("def:Fox", &mut cache).is_err());
+    }
+
+    #[test]
+    fn contains_finds_words_by_any_part() {
+        let store = open_store();
+        let accents = accents(&gjson::parse("{}"));
+        let mut tuples = Vec::new();
+        let mut grams = HashSet::new();
+
+        let stems = store
+            .insert_bulk_stems(vec!["miscellan".into(), "cell".into(), "sell".into()])
+            .unwrap();
+
+        for (path, words) in [
+            ("/a.md", [("Miscellaneous", "miscellan"), ("cells", "cell")]),
+            ("/b.md", [("Cell", "cell"), ("selling", "sell")]),
+        ] {
+            let file = store.insert_file(Path::new(path), &0).unwrap().id;
+
+            for (offset, &(word, stem)) in words.iter().enumerate() {
+                tuples.push(IndexTuple {
+                    file,
+                    stem: stems[stem],
+                    offset: offset as u32,
+                    byte_offset: 0,
+                    word: word.to_string(),
+                });
+                grams.extend(
+                    trigrams(&fold(word, &accents))
+                        .into_iter()
+                        .map(|g| (g, stems[stem])),
+                );
+            }
+        }
+
+        store.insert_bulk_word_tuples(tuples, &accents).unwrap();
+        store.insert_trigrams(grams).unwrap();
+
+        // The files, leaving out the order of files that tie.
+        let contains = |query| {
+            let (serps, _sorted) = run_substring_search(
+                query,
+                &accents,
+                &store,
+                &no_boosts(),
+                &Cancellation::never(),
+            )
+            .unwrap();
+            let mut files = serps.into_keys().collect::<Vec<_>>();
+
+            files.sort();
+            files
+        };
+
+        assert_eq!(contains("ELLANE"), ["/a.md"]);
+        assert_eq!(contains("ell"), ["/a.md", "/b.md"]);
+        assert_eq!(contains("ell lling"), ["/b.md"]);
+        assert!(contains("cellz").is_empty());
+    }
+
+    #[test]
+    fn display_keeps_the_original_case() {
+        let store = open_store();
+        let accents = accents(&gjson::parse("{}"));
+
+        let file = store.insert_file(Path::new("/scan.png"), &0).unwrap().id;
+        let stems = store
+            .insert_bulk_stems(vec!["nasa".into(), "launch".into(), "artemi".into()])
+            .unwrap();
+        let tuples = [
+            ("NASA", "nasa"),
+            ("launched", "launch"),
+            ("Artemis", "artemi"),
+        ]
+        .iter()
+        .enumerate()
+        .map(|(offset, &(word, stem))| IndexTuple {
+            file,
+            stem: stems[stem],
+            offset: offset as u32,
+            byte_offset: 0,
+            word: word.to_string(),
+        })
+        .collect();
+
+        store.insert_bulk_word_tuples(tuples, &accents).unwrap();
+
+        let found = result("/scan.png", "Artemis", stems["artemi"], 2);
+
+        assert_eq!(
+            index_snippet(&store, &found).as_deref(),
+            Some("NASA launched Artemis")
+        );
+        assert_eq!(index_snippet(&store, &result("/gone.md", "x", 1, 0)), None);
+        assert_eq!(
+            common_spellings(vec![
+                ("nasa".to_string(), 2),
+                ("NASA".to_string(), 5),
+                ("Nasal".to_string(), 3),
+                ("nasal".to_string(), 3),
+            ]),
+            [("NASA".to_string(), 7), ("Nasal".to_string(), 6)]
+        );
+    }
+
+    #[test]
+    fn query_stems_keeps_tags_apart() {
+        let stemmer = Stemmer::create(rust_stemmers::Algorithm::English);
+        let query = "Running, foxes tag:Work tag: DEF:Handle_Queries def: word:Jumped";
+        let accents = accents(&gjson::parse("{}"));
+        let stems = query_stems(query, &punctuation(), &accents, &stemmer);
+
+        assert_eq!(stems.len(), 5);
+        assert!(stems.contains("run"));
+        assert!(stems.contains("fox"));
+        assert!(stems.contains("tag:work"));
+        assert!(stems.contains("def:handle_queries"));
+        assert!(stems.contains("jump"));
+        assert_eq!(filter_values(query, "def:"), ["handle_queries"]);
+        assert_eq!(filter_values(query, "word:"), ["jumped"]);
+        assert_eq!(query_words(query, &punctuation()), ["Running", "foxes"]);
+    }
+}
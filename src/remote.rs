@@ -0,0 +1,303 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use tracing::{debug, info, warn};
+
+// How often to check a remote folder for changes, by default, in seconds.
+// There's nothing like inotify over SSH, so all we can do is look.
+const DEFAULT_POLL_SECONDS: u64 = 300;
+
+// A folder on another machine, from a `folder` entry with a name like
+// `sftp://user@host:2222/home/user/notes`.  We copy its files into a local
+// mirror and index that, so that search results are files we can open,
+// and the indexer never has to know the difference.
+pub struct RemoteFolder {
+    url: String,
+    destination: String,
+    port: Option<u16>,
+    path: String,
+    recurse: bool,
+    mirror: PathBuf,
+    interval: Duration,
+}
+
+impl RemoteFolder {
+    // Read a `folder` entry, or nothing, if it isn't remote.  The optional
+    // `pollInterval` item says how often to check for changes, in seconds.
+    pub fn new(folder: &gjson::Value) -> Option<RemoteFolder> {
+        let url = folder.get("name").to_string();
+        let (destination, port, path) = split_url(&url)?;
+        let host = destination.rsplit('@').next().unwrap_or_default();
+        let mut mirror = dirs::cache_dir()?;
+        let interval = folder.get("pollInterval");
+
+        mirror.push("intern");
+        mirror.push("remote");
+        mirror.push(match port {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        });
+        mirror.push(path.trim_start_matches('/'));
+        Some(RemoteFolder {
+            url,
+            destination,
+            port,
+            path,
+            recurse: folder.get("recurse").bool(),
+            mirror,
+            interval: Duration::from_secs(if interval.exists() {
+                interval.u64().max(1)
+            } else {
+                DEFAULT_POLL_SECONDS
+            }),
+        })
+    }
+
+    // Where the folder's files get copied to.
+    pub fn mirror(&self) -> &Path {
+        &self.mirror
+    }
+
+    pub fn recurse(&self) -> bool {
+        self.recurse
+    }
+
+    // Keep the mirror up to date in the background, since copying files
+    // over the network can take a while, sending an event for each file
+    // that arrives or goes away, the way the watcher would.
+    pub fn watch(self, sender: Sender<(&'static str, PathBuf)>) {
+        info!("Checking {} every {}s", self.url, self.interval.as_secs());
+        thread::spawn(move || loop {
+            match self.sync() {
+                Ok(events) => {
+                    for event in events {
+                        if sender.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+                Err(e) => warn!("Can't check {}: {}", self.url, e),
+            }
+
+            thread::sleep(self.interval);
+        });
+    }
+
+    // Copy whatever changed since the last time, and delete whatever went
+    // away, returning the events for the indexer.
+    fn sync(&self) -> io::Result<Vec<(&'static str, PathBuf)>> {
+        let remote = self.list()?;
+        let mut events = Vec::<(&'static str, PathBuf)>::new();
+        let mut wanted = HashSet::<PathBuf>::new();
+
+        for (path, modified) in &remote {
+            let relative = match path.strip_prefix(&self.path) {
+                Some(relative) => relative.trim_start_matches('/'),
+                None => continue,
+            };
+            let local = self.mirror.join(relative);
+
+            // A file we can't copy, this time, keeps whatever copy the
+            // mirror has, rather than holding up every file after it.
+            if modification_time(&local) != Some(*modified) {
+                debug!("fetching {}", path);
+                match self.fetch(path, &local, *modified) {
+                    Ok(()) => events.push(("create", local.clone())),
+                    Err(e) => warn!("Skipping {} until the next check: {}", path, e),
+                }
+            }
+
+            wanted.insert(local);
+        }
+
+        for local in mirrored_files(&self.mirror) {
+            if !wanted.contains(&local) {
+                debug!("{} went away", local.display());
+                fs::remove_file(&local)?;
+                events.push(("remove", local));
+            }
+        }
+
+        Ok(events)
+    }
+
+    // List the remote files with their modification times, using `find`,
+    // with NULs between entries, since file names can have anything else.
+    fn list(&self) -> io::Result<HashMap<String, u64>> {
+        let depth = if self.recurse { "" } else { "-maxdepth 1 " };
+        let output = self
+            .ssh(&format!(
+                "find {} {}-type f -printf '%T@ %p\\0'",
+                quote(&self.path),
+                depth
+            ))
+            .stdout(Stdio::piped())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(io::Error::other(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+
+        Ok(parse_listing(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    // Copy a remote file into the mirror, by way of a file next to the
+    // mirror, so that the indexer never sees half a file, and give the copy
+    // the original's modification time.
+    fn fetch(&self, remote: &str, local: &Path, modified: u64) -> io::Result<()> {
+        let partial = self.mirror.with_extension("partial");
+
+        if let Some(parent) = local.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let output = self
+            .ssh(&format!("cat -- {}", quote(remote)))
+            .stdout(File::create(&partial)?)
+            .output()?;
+
+        if !output.status.success() {
+            fs::remove_file(&partial)?;
+            return Err(io::Error::other(format!(
+                "can't copy {}: {}",
+                remote,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        File::options()
+            .write(true)
+            .open(&partial)?
+            .set_modified(UNIX_EPOCH + Duration::from_secs(modified))?;
+        fs::rename(&partial, local)
+    }
+
+    // Run a command on the remote machine, with whatever keys and settings
+    // the user has for SSH, but never stopping to ask for a password.
+    fn ssh(&self, command: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+
+        ssh.args(["-o", "BatchMode=yes"]);
+        if let Some(port) = self.port {
+            ssh.arg("-p").arg(port.to_string());
+        }
+
+        ssh.arg(&self.destination)
+            .arg(command)
+            .stdin(Stdio::null())
+            .stderr(Stdio::piped());
+        ssh
+    }
+}
+
+// Break an `sftp://` URL into where to connect, any port, and the folder.
+// A destination that starts with a hyphen would look like an option to
+// SSH, so that's no URL at all.
+fn split_url(url: &str) -> Option<(String, Option<u16>, String)> {
+    let rest = url.strip_prefix("sftp://")?;
+    let (authority, path) = rest.split_at(rest.find('/')?);
+    let (destination, port) = match authority.rsplit_once(':') {
+        Some((destination, port)) => (destination, Some(port.parse().ok()?)),
+        None => (authority, None),
+    };
+
+    if destination.is_empty()
+        || destination.ends_with('@')
+        || destination.starts_with('-')
+    {
+        None
+    } else {
+        Some((
+            destination.to_string(),
+            port,
+            path.trim_end_matches('/').to_string(),
+        ))
+    }
+}
+
+// Read the output of `find`, a modification time and a path for each file,
+// with fractions of a second that we don't keep.
+fn parse_listing(listing: &str) -> HashMap<String, u64> {
+    listing
+        .split('\0')
+        .filter_map(|entry| {
+            let (time, path) = entry.split_once(' ')?;
+            let seconds = time.split('.').next()?.parse().ok()?;
+
+            Some((path.to_string(), seconds))
+        })
+        .collect()
+}
+
+// Quote a path for the remote shell.
+fn quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+// When a mirrored file was last modified, going by the remote file it's a
+// copy of, or nothing, if we don't have it.
+fn modification_time(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+// Every file in the mirror, however deep.
+fn mirrored_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::<PathBuf>::new();
+
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+
+        if path.is_dir() {
+            files.extend(mirrored_files(&path));
+        } else {
+            files.push(path);
+        }
+    }
+
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_url_finds_the_host_and_folder() {
+        assert_eq!(
+            split_url("sftp://me@notes.example.com:2222/home/me/notes/"),
+            Some((
+                "me@notes.example.com".to_string(),
+                Some(2222),
+                "/home/me/notes".to_string()
+            ))
+        );
+        assert_eq!(
+            split_url("sftp://server/srv/wiki"),
+            Some(("server".to_string(), None, "/srv/wiki".to_string()))
+        );
+        assert_eq!(split_url("/home/me/notes"), None);
+        assert_eq!(split_url("sftp://server"), None);
+        assert_eq!(split_url("sftp://server:ssh/notes"), None);
+        assert_eq!(split_url("sftp://-oProxyCommand=sh/notes"), None);
+    }
+
+    #[test]
+    fn listings_keep_whole_seconds() {
+        let listing = "1697500000.1234567890 /notes/a.md\0\
+                       1697400000.0000000000 /notes/with space.md\0";
+        let files = parse_listing(listing);
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["/notes/a.md"], 1697500000);
+        assert_eq!(files["/notes/with space.md"], 1697400000);
+    }
+}
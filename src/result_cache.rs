@@ -0,0 +1,86 @@
+use std::collections::{HashMap, HashSet};
+
+// Remembers recent search results, so that a client searching as the user
+// types doesn't send every keystroke to the database.  Each entry knows
+// the stems it searched for and the files it found, so that reindexing a
+// file can throw out exactly the entries it might change.
+pub struct ResultCache<T> {
+    entries: HashMap<String, Entry<T>>,
+    capacity: usize,
+    clock: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    stems: HashSet<String>,
+    paths: HashSet<String>,
+    used: u64,
+}
+
+impl<T: Clone> ResultCache<T> {
+    pub fn new(capacity: usize) -> ResultCache<T> {
+        ResultCache {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    // Look up the results for a query, if we still have them.
+    pub fn get(&mut self, key: &str) -> Option<T> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.used = self.clock;
+                Some(entry.value.clone())
+            }
+            None => None,
+        }
+    }
+
+    // Save the results for a query, dropping the least recently used entry
+    // if we're full.
+    pub fn insert(
+        &mut self,
+        key: &str,
+        value: T,
+        stems: HashSet<String>,
+        paths: HashSet<String>,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(key) {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(k, _)| k.to_string());
+
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.clock += 1;
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                stems,
+                paths,
+                used: self.clock,
+            },
+        );
+    }
+
+    // Forget any results that a change to the file's index could affect,
+    // either because the file was in them or because it now contains a
+    // stem they searched for.
+    pub fn invalidate(&mut self, path: &str, stems: &HashSet<String>) {
+        self.entries.retain(|_, entry| {
+            !entry.paths.contains(path) && entry.stems.is_disjoint(stems)
+        });
+    }
+}
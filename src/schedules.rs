@@ -0,0 +1,262 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{debug, error, info, warn};
+
+use crate::config::RankingConfig;
+use crate::dates::format_timestamp;
+use crate::json;
+use crate::normalizer::Normalizer;
+use crate::query::{run_search, Cancellation, SearchCache};
+use crate::store::Store;
+use crate::webhooks::{post, split_url};
+
+// How often a scheduled query runs, in seconds, without an `interval`,
+// which is once a day.
+const DEFAULT_INTERVAL: u64 = 24 * 60 * 60;
+
+// A query from the `schedules` block, with how often to run it and where
+// to send the files that newly match it.
+struct ScheduledQuery {
+    query: String,
+    interval: u64,
+    digest: Option<PathBuf>,
+    webhook: Option<String>,
+}
+
+// The configured scheduled queries.
+pub struct Schedules {
+    queries: Vec<ScheduledQuery>,
+}
+
+impl Schedules {
+    // Read the `schedules` block, a list of queries, each with an optional
+    // `interval` in seconds, and a `digest` file to append to, a `webhook`
+    // to call, or both.
+    pub fn new(schedules_info: &gjson::Value) -> Schedules {
+        let mut queries = Vec::<ScheduledQuery>::new();
+
+        for schedule in schedules_info.array() {
+            let query = schedule.get("query").to_string();
+            let interval = schedule.get("interval");
+            let digest = schedule.get("digest");
+            let webhook = schedule.get("webhook");
+            let scheduled = ScheduledQuery {
+                query,
+                interval: if interval.exists() {
+                    interval.u64()
+                } else {
+                    DEFAULT_INTERVAL
+                },
+                digest: digest.exists().then(|| PathBuf::from(digest.to_string())),
+                webhook: webhook.exists().then(|| webhook.to_string()),
+            };
+
+            if scheduled.query.trim().is_empty() || scheduled.interval == 0 {
+                warn!("Ignoring a schedule without a query or an interval");
+                continue;
+            } else if scheduled.digest.is_none() && scheduled.webhook.is_none() {
+                warn!(
+                    "Ignoring schedule for '{}' with nowhere to send it",
+                    scheduled.query
+                );
+                continue;
+            } else if scheduled
+                .webhook
+                .as_deref()
+                .is_some_and(|u| split_url(u).is_none())
+            {
+                warn!(
+                    "Ignoring schedule for '{}' with an unusable URL",
+                    scheduled.query
+                );
+                continue;
+            }
+
+            info!(
+                "Running '{}' every {}s",
+                scheduled.query, scheduled.interval
+            );
+            queries.push(scheduled);
+        }
+
+        Schedules { queries }
+    }
+
+    // Run every scheduled query that's due, sending along the files that
+    // match it now, but didn't the last time it ran.
+    #[allow(clippy::too_many_arguments)]
+    pub fn run_due(
+        &self,
+        store: &Store,
+        punc: &Regex,
+        accents: &Regex,
+        stemmer: &dyn Normalizer,
+        ranking: &RankingConfig,
+        cache: &mut SearchCache,
+    ) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        for scheduled in &self.queries {
+            let last = match store.last_scheduled_run(&scheduled.query) {
+                Ok(last) => last,
+                Err(e) => {
+                    error!("Unable to check schedule for '{}': {}", scheduled.query, e);
+                    continue;
+                }
+            };
+
+            if last
+                .as_ref()
+                .is_some_and(|(ran_at, _)| now < ran_at + scheduled.interval)
+            {
+                continue;
+            }
+
+            let sorted = match run_search(
+                &scheduled.query,
+                punc,
+                accents,
+                stemmer,
+                store,
+                ranking,
+                cache,
+                &Cancellation::never(),
+            ) {
+                Ok((_serps, sorted)) => sorted,
+                Err(e) => {
+                    error!("Unable to run schedule for '{}': {}", scheduled.query, e);
+                    continue;
+                }
+            };
+            let matches = sorted
+                .into_iter()
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>();
+
+            if let Err(e) = store.record_scheduled_run(&scheduled.query, now, &matches) {
+                error!("Unable to record schedule for '{}': {}", scheduled.query, e);
+                continue;
+            }
+
+            // The first run only finds out what already matches.
+            let Some((_, before)) = last else {
+                info!(
+                    "Scheduled '{}' from {} files",
+                    scheduled.query,
+                    matches.len()
+                );
+                continue;
+            };
+            let fresh = newly_matching(&before, matches);
+
+            if fresh.is_empty() {
+                debug!("Nothing new for '{}'", scheduled.query);
+                continue;
+            }
+
+            info!("{} file(s) new for '{}'", fresh.len(), scheduled.query);
+            scheduled.send(now, &fresh);
+        }
+    }
+}
+
+impl ScheduledQuery {
+    // Append the new files to the digest, and call the webhook with them,
+    // which can take a while, so it doesn't hold up indexing.
+    fn send(&self, now: u64, fresh: &[String]) {
+        if let Some(digest) = &self.digest {
+            if let Err(e) = append_digest(digest, &self.query, now, fresh) {
+                error!("Unable to write digest {}: {}", digest.display(), e);
+            }
+        }
+
+        if let Some(url) = &self.webhook {
+            let url = url.to_string();
+            let body = format!(
+                r#"{{"query":{},"ran":{},"files":[{}]}}"#,
+                json::quote(&self.query),
+                json::quote(&format_timestamp(now as i64)),
+                fresh
+                    .iter()
+                    .map(|p| json::quote(p))
+                    .collect::<Vec<_>>()
+                    .join(",")
+            );
+
+            thread::spawn(move || match post(&url, &body) {
+                Ok(status) if (200..300).contains(&status) => {
+                    debug!("{} answered {}", url, status)
+                }
+                Ok(status) => warn!("Webhook {} answered {}", url, status),
+                Err(e) => error!("Unable to call webhook {}: {}", url, e),
+            });
+        }
+    }
+}
+
+// The files that match now, but didn't before, in their current order.
+fn newly_matching(before: &[String], now: Vec<String>) -> Vec<String> {
+    let before = before.iter().collect::<HashSet<_>>();
+
+    now.into_iter().filter(|p| !before.contains(p)).collect()
+}
+
+// Add a run's new files to a digest, one per line, with when the query ran
+// and the query, separated by tabs.
+fn append_digest(
+    digest: &Path,
+    query: &str,
+    now: u64,
+    fresh: &[String],
+) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(digest)?;
+    let ran = format_timestamp(now as i64);
+
+    for path in fresh {
+        writeln!(file, "{}\t{}\t{}", ran, query, path)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{open_store, ScratchDir};
+    use std::fs;
+
+    #[test]
+    fn only_new_matches_go_in_the_digest() {
+        let store = open_store();
+        let dir = ScratchDir::new("digest");
+        let digest = dir.join("digest.tsv");
+        let before = vec!["/notes/a.md".to_string(), "/notes/b.md".to_string()];
+        let now = vec!["/notes/c.md".to_string(), "/notes/a.md".to_string()];
+
+        assert_eq!(store.last_scheduled_run("invoice").unwrap(), None);
+        store.record_scheduled_run("invoice", 100, &before).unwrap();
+        assert_eq!(
+            store.last_scheduled_run("invoice").unwrap(),
+            Some((100, before.clone()))
+        );
+
+        let fresh = newly_matching(&before, now);
+
+        assert_eq!(fresh, ["/notes/c.md"]);
+        append_digest(&digest, "invoice", 200, &fresh).unwrap();
+        append_digest(&digest, "invoice", 300, &fresh).unwrap();
+
+        let lines = fs::read_to_string(&digest).unwrap();
+
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.ends_with("\tinvoice\t/notes/c.md\n"));
+    }
+}
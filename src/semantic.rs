@@ -0,0 +1,367 @@
+use rusqlite::params;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use tracing::{info, warn};
+
+use crate::error::Error;
+use crate::json;
+use crate::store::Store;
+use crate::webhooks::split_url;
+
+// How long to wait on the embedding service, which can be slow to load its
+// model the first time.
+const EMBEDDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How much text goes into each embedding, about a long paragraph, since
+// most models only read a few hundred words at a time.
+const PASSAGE_BYTES: usize = 2000;
+
+// How many passages of any one file to embed, so that one huge file
+// doesn't tie up the service for hours.
+const MAX_PASSAGES: usize = 64;
+
+// How much semantic similarity counts for, against keyword scores, unless
+// the configuration says otherwise.
+const DEFAULT_WEIGHT: f32 = 0.5;
+
+// How many results `@semantic` returns, since every file is at least a
+// little similar to any query.
+pub const SEMANTIC_RESULTS: usize = 20;
+
+// The service that turns text into embeddings, which speaks the OpenAI
+// embeddings API, as Ollama, llama.cpp, and most local servers do.
+pub struct Embedder {
+    url: String,
+    model: String,
+    pub weight: f32,
+}
+
+// The embedding service, if we use one.
+static SEMANTIC: OnceLock<Option<Embedder>> = OnceLock::new();
+
+// Read the `semantic` block, with the `url` of the embedding service, the
+// `model` to ask it for, and the `weight` of semantic similarity against
+// keyword scores, from zero to one.
+pub fn set_semantic(config: &gjson::Value) {
+    let semantic = config.get("semantic");
+    let embedder = if semantic.get("url").exists() {
+        let weight = semantic.get("weight");
+
+        Some(Embedder {
+            url: semantic.get("url").to_string(),
+            model: semantic.get("model").to_string(),
+            weight: if weight.exists() {
+                weight.f32().clamp(0.0, 1.0)
+            } else {
+                DEFAULT_WEIGHT
+            },
+        })
+    } else {
+        None
+    };
+
+    if let Some(embedder) = &embedder {
+        info!(
+            "Embedding passages with {} from {}",
+            embedder.model, embedder.url
+        );
+    }
+
+    SEMANTIC.get_or_init(|| embedder);
+}
+
+// The embedding service, or nothing, if it isn't configured.
+pub fn embedder() -> Option<&'static Embedder> {
+    SEMANTIC.get_or_init(|| None).as_ref()
+}
+
+impl Embedder {
+    // Turn some text into a vector of unit length, so that the dot product
+    // of two vectors is their cosine similarity.
+    pub fn embed(&self, text: &str) -> io::Result<Vec<f32>> {
+        let body = format!(
+            r#"{{"model":{},"input":{}}}"#,
+            json::quote(&self.model),
+            json::quote(text)
+        );
+        let response = post(&self.url, &body)?;
+        let vector = gjson::get(&response, "data.0.embedding")
+            .array()
+            .iter()
+            .map(|v| v.f32())
+            .collect::<Vec<_>>();
+        let length = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+
+        if length == 0.0 {
+            Err(io::Error::new(io::ErrorKind::InvalidData, "no embedding"))
+        } else {
+            Ok(vector.iter().map(|v| v / length).collect())
+        }
+    }
+}
+
+// Break the next chunk of a file into passages to embed, at whitespace,
+// given how many passages came before it, or nothing, without an
+// embedding service.
+pub fn passages(text: &str, before: usize) -> Vec<String> {
+    let mut passages = Vec::<String>::new();
+    let mut rest = text.trim();
+
+    if embedder().is_none() {
+        return passages;
+    }
+
+    while !rest.is_empty() && before + passages.len() < MAX_PASSAGES {
+        let mut end = rest.len().min(PASSAGE_BYTES);
+
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        if end < rest.len() {
+            end = rest[..end].rfind(char::is_whitespace).unwrap_or(end);
+        }
+
+        passages.push(rest[..end].to_string());
+        rest = rest[end..].trim_start();
+    }
+
+    passages
+}
+
+// The passages of freshly indexed files, by path, waiting for the main
+// loop to hand them to the embedding thread.
+static WAITING: Mutex<Vec<(String, Vec<String>)>> = Mutex::new(Vec::new());
+
+// Set a file's passages aside to embed, once its keywords are in the index.
+pub fn queue_passages(path: &str, passages: Vec<String>) {
+    if !passages.is_empty() {
+        WAITING.lock().unwrap().push((path.to_string(), passages));
+    }
+}
+
+// The thread that embeds passages, so that a slow service never holds up
+// indexing or queries, along with the vectors that it sends back.
+pub struct Embeddings {
+    sender: Sender<(String, Vec<String>)>,
+    receiver: Receiver<(String, Vec<Vec<f32>>)>,
+}
+
+impl Embeddings {
+    // Start the thread.  A file that the service can't embed keeps
+    // whatever passages came back before it failed, so that a service
+    // that's down doesn't hold up every other file.
+    pub fn start() -> Embeddings {
+        let (sender, passages) = channel::<(String, Vec<String>)>();
+        let (vectors, receiver) = channel::<(String, Vec<Vec<f32>>)>();
+
+        thread::spawn(move || {
+            for (path, passages) in passages {
+                let mut embedded = Vec::<Vec<f32>>::new();
+
+                for passage in &passages {
+                    match embedder().map(|e| e.embed(passage)) {
+                        Some(Ok(vector)) => embedded.push(vector),
+                        Some(Err(e)) => {
+                            warn!("Unable to embed a passage of {}: {}", path, e);
+                            break;
+                        }
+                        None => break,
+                    }
+                }
+
+                if vectors.send((path, embedded)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Embeddings { sender, receiver }
+    }
+
+    // Hand the waiting passages to the thread and store whatever vectors
+    // it has finished, without waiting for any more.
+    pub fn exchange(&self, store: &Store) {
+        for waiting in std::mem::take(&mut *WAITING.lock().unwrap()) {
+            self.sender.send(waiting).unwrap();
+        }
+
+        for (path, vectors) in self.receiver.try_iter() {
+            if let Err(e) = store_embeddings(store, &path, &vectors) {
+                warn!("Unable to store the embeddings of {}: {}", path, e);
+            }
+        }
+    }
+}
+
+// Replace a file's vectors, unless it left the index while they were on
+// their way.
+fn store_embeddings(
+    store: &Store,
+    path: &str,
+    vectors: &[Vec<f32>],
+) -> Result<(), Error> {
+    let transaction = store.begin()?;
+    let file_id = match store.select_file(path)? {
+        Some(file) => file.id,
+        None => return Ok(()),
+    };
+
+    store.execute("DELETE FROM file_vector WHERE file = ?", params![file_id])?;
+    for (number, vector) in vectors.iter().enumerate() {
+        let bytes = vector
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+
+        store.execute(
+            "INSERT INTO file_vector (file, passage, vector) VALUES (?, ?, ?)",
+            params![file_id, number, bytes],
+        )?;
+    }
+
+    transaction.commit()?;
+    Ok(())
+}
+
+// Score every embedded file by its passage most similar to a query's
+// vector.
+pub fn similarities(
+    store: &Store,
+    query: &[f32],
+) -> rusqlite::Result<HashMap<String, f32>> {
+    let mut scores = HashMap::<String, f32>::new();
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT f.path, v.vector FROM file_vector v
+           JOIN monitored_file f ON f.id = v.file WHERE {}",
+        store.in_folders("f.path")
+    ))?;
+    let rows = stmt.query_map([], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+    })?;
+
+    for row in rows {
+        let (path, bytes) = row?;
+        let similarity = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .zip(query)
+            .map(|(a, b)| a * b)
+            .sum::<f32>();
+        let score = scores.entry(path).or_insert(f32::MIN);
+
+        *score = score.max(similarity);
+    }
+
+    Ok(scores)
+}
+
+// Send a JSON body to a URL and return the body of the answer.  Asking
+// with HTTP/1.0 keeps the server from chunking it.
+fn post(url: &str, body: &str) -> io::Result<String> {
+    let (host, path) = split_url(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an http URL"))?;
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, EMBEDDING_TIMEOUT)?;
+    let request = format!(
+        concat!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\n",
+            "Content-Length: {}\r\n\r\n{}"
+        ),
+        path,
+        host,
+        body.len(),
+        body
+    );
+    let mut response = String::new();
+
+    stream.set_read_timeout(Some(EMBEDDING_TIMEOUT))?;
+    stream.set_write_timeout(Some(EMBEDDING_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    stream.read_to_string(&mut response)?;
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+
+    match head.split_whitespace().nth(1) {
+        Some("200") => Ok(body.to_string()),
+        _ => Err(io::Error::other(
+            head.lines().next().unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::open_store;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn embeddings_come_back_unit_length() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let embedder = Embedder {
+            url: format!("http://{}/v1/embeddings", listener.local_addr().unwrap()),
+            model: "mini".to_string(),
+            weight: DEFAULT_WEIGHT,
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::<u8>::new();
+            let mut buffer = [0; 512];
+
+            while !request.ends_with(b"}") {
+                let size = stream.read(&mut buffer).unwrap();
+
+                request.extend_from_slice(&buffer[..size]);
+            }
+
+            stream
+                .write_all(b"HTTP/1.0 200 OK\r\n\r\n{\"data\":[{\"embedding\":[3,4]}]}")
+                .unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+
+        assert_eq!(embedder.embed("tomatoes").unwrap(), [0.6, 0.8]);
+        assert!(server
+            .join()
+            .unwrap()
+            .ends_with(r#"{"model":"mini","input":"tomatoes"}"#));
+    }
+
+    #[test]
+    fn late_vectors_replace_old_ones() {
+        let store = open_store();
+        let vectors = |store: &Store| {
+            store
+                .query_row("SELECT COUNT(*) FROM file_vector", [], |row| {
+                    row.get::<_, u32>(0)
+                })
+                .unwrap()
+        };
+
+        store
+            .insert_file(std::path::Path::new("/notes/a.md"), &0)
+            .unwrap();
+        store_embeddings(&store, "/notes/a.md", &[vec![0.6, 0.8], vec![1.0, 0.0]])
+            .unwrap();
+        assert_eq!(vectors(&store), 2);
+        store_embeddings(&store, "/notes/a.md", &[vec![0.0, 1.0]]).unwrap();
+        assert_eq!(vectors(&store), 1);
+        store_embeddings(&store, "/notes/gone.md", &[vec![0.0, 1.0]]).unwrap();
+        assert_eq!(vectors(&store), 1);
+    }
+}
@@ -0,0 +1,2160 @@
+use chrono::Days;
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token};
+use regex::Regex;
+use rusqlite::params;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{fmt, io, mem, str, thread};
+use tracing::{debug, debug_span, error, field, info, trace, warn};
+
+use crate::config::{RankingConfig, Throttle};
+use crate::dates::{date_window, day_timestamp, format_timestamp, today};
+use crate::error::Error;
+use crate::ignores::Ignores;
+use crate::indexer::{stem_word, tokenize, Reindex, QUARANTINE_FAILURES};
+use crate::metrics::{index_rows_read, record_query_time, METRICS};
+use crate::normalizer::Normalizer;
+use crate::presets::preset;
+use crate::protocol::{
+    clean_request, split_options, RequestOptions, ResponseFormat, Session,
+};
+use crate::query::{
+    best_match, common_spellings, explain_ranking, filter_values, find_line,
+    index_snippet, query_stems, query_words, rank_search_results, read_line,
+    run_phonetic_search, run_search, run_substring_search, similar_files, top_file_stems,
+    Cancellation, SearchCache,
+};
+#[cfg(feature = "semantic")]
+use crate::semantic::{embedder, similarities, SEMANTIC_RESULTS};
+use crate::store::{LoggedQuery, Store};
+use crate::subscriptions::{StandingQuery, Subscriptions};
+use crate::text::source_offsets;
+use crate::trigrams::trigrams_enabled;
+use crate::users::{authorize, multi_user, split_token, visible, User};
+
+// Send a state notification to the service manager, if we were started
+// by one that asked for them (e.g., systemd with Type=notify).
+#[cfg(unix)]
+pub fn sd_notify(state: &str) {
+    let socket_path = match std::env::var_os("NOTIFY_SOCKET") {
+        Some(p) => p,
+        None => return,
+    };
+    let socket = match std::os::unix::net::UnixDatagram::unbound() {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("Can't create notification socket: {}", e);
+            return;
+        }
+    };
+    let path = socket_path.to_string_lossy();
+    let result = if let Some(name) = path.strip_prefix('@') {
+        send_abstract_notification(&socket, name, state)
+    } else {
+        socket.send_to(state.as_bytes(), Path::new(&socket_path))
+    };
+
+    match result {
+        Ok(_) => trace!("notified service manager: {}", state),
+        Err(e) => warn!("Can't notify service manager at {}: {}", path, e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn sd_notify(_state: &str) {}
+
+// Abstract socket names (starting with @) only exist on Linux.
+#[cfg(target_os = "linux")]
+fn send_abstract_notification(
+    socket: &std::os::unix::net::UnixDatagram,
+    name: &str,
+    state: &str,
+) -> io::Result<usize> {
+    use std::os::linux::net::SocketAddrExt;
+
+    let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+    socket.send_to_addr(state.as_bytes(), &addr)
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn send_abstract_notification(
+    _socket: &std::os::unix::net::UnixDatagram,
+    name: &str,
+    _state: &str,
+) -> io::Result<usize> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("abstract socket @{} not supported", name),
+    ))
+}
+
+// Figure out how often the service manager wants to hear from us, if at
+// all.  We ping at half the requested interval, as systemd recommends.
+pub fn watchdog_interval() -> Option<Duration> {
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+
+    if usec == 0 {
+        None
+    } else {
+        Some(Duration::from_micros(usec / 2))
+    }
+}
+
+// The longest request we'll wait for, so that a client can't have us
+// buffer without end.
+const MAX_REQUEST_BYTES: usize = 65536;
+
+// How long to keep trying to send a response to a client that isn't
+// reading it.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How long to spend reindexing in each pass through the main loop, before
+// getting back to everything else.
+const REINDEX_SLICE: Duration = Duration::from_millis(100);
+
+// How many queries `@history` lists, unless it says otherwise.
+const HISTORY_LENGTH: usize = 25;
+
+// How long to keep logged queries, in days, unless the configuration says
+// otherwise.
+const DEFAULT_QUERY_LOG_DAYS: u64 = 30;
+
+// How long to keep logged queries, in seconds, if we log them at all, which
+// we don't by default, since queries say a lot about what's on someone's
+// mind.
+static QUERY_LOG: OnceLock<Option<u64>> = OnceLock::new();
+
+// How long a query can take before we log it as slow, if we watch for slow
+// queries at all, which, like logging queries, we don't by default.
+static SLOW_QUERY: OnceLock<Option<Duration>> = OnceLock::new();
+
+// Read the `queryLog` item, which keeps every query, with when it came in
+// and how long it took, in the `query_log` table, the `queryLogDays` item,
+// how many days to keep them, and the `slowQueryMs` item, how many
+// milliseconds a query can take before it goes in the log as slow.
+pub fn set_query_log(config: &gjson::Value) {
+    let slow = config.get("slowQueryMs");
+
+    if slow.exists() {
+        info!("Logging queries slower than {} ms", slow.u64());
+    }
+
+    SLOW_QUERY.get_or_init(|| slow.exists().then(|| Duration::from_millis(slow.u64())));
+
+    let days = config.get("queryLogDays");
+    let days = if days.exists() {
+        days.u64()
+    } else {
+        DEFAULT_QUERY_LOG_DAYS
+    };
+    let keep = if config.get("queryLog").bool() {
+        info!("Logging queries for {} days", days);
+        Some(days * 24 * 60 * 60)
+    } else {
+        None
+    };
+
+    QUERY_LOG.get_or_init(|| keep);
+}
+
+// The most results a response can have, unless a query asks for more or
+// fewer, if there's a limit at all, which there isn't by default.
+static MAX_RESULTS: OnceLock<Option<usize>> = OnceLock::new();
+
+// Read the `maxResults` item, past which responses get cut short.
+pub fn set_max_results(config: &gjson::Value) {
+    let max = config.get("maxResults");
+
+    if max.exists() {
+        info!("Returning at most {} results", max.u64());
+    }
+
+    MAX_RESULTS.get_or_init(|| max.exists().then(|| max.u64() as usize));
+}
+
+// The most results a response can have, without a query saying otherwise.
+pub fn max_results() -> Option<usize> {
+    *MAX_RESULTS.get_or_init(|| None)
+}
+
+// Cut a response short at the most results it can have, ending it with a
+// line saying so, so that the client knows that there's more.
+fn truncate_results(mut lines: Vec<String>, max: Option<usize>) -> Vec<String> {
+    match max {
+        Some(max) if lines.iter().filter(|l| !l.is_empty()).count() > max => {
+            lines.retain(|l| !l.is_empty());
+            lines.truncate(max);
+            lines.push(format!("truncated at {}", max));
+            lines
+        }
+        _ => lines,
+    }
+}
+
+// Add a query to the query log, if we keep one, except for the requests to
+// see the log itself, and warn about it, if it was slow, with how many rows
+// it read from the index, to show whether the time went to the search.
+pub fn log_query(
+    store: &Store,
+    query: &str,
+    user: Option<&User>,
+    elapsed: Duration,
+    results: usize,
+    rows: u64,
+) {
+    let query = query.trim_matches(char::from(0)).trim();
+
+    if SLOW_QUERY
+        .get_or_init(|| None)
+        .is_some_and(|slow| elapsed >= slow)
+    {
+        METRICS.slow_queries.fetch_add(1, Ordering::Relaxed);
+        warn!(
+            "Slow query, {} ms, {} results, {} index rows: {}",
+            elapsed.as_millis(),
+            results,
+            rows,
+            query
+        );
+    }
+
+    let keep = match QUERY_LOG.get_or_init(|| None) {
+        Some(_) if query.is_empty() || query.starts_with("@history") => return,
+        Some(keep) => keep,
+        None => return,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let entry = LoggedQuery {
+        at: now,
+        uid: user.map(|u| u.uid()),
+        query: query.to_string(),
+        micros: elapsed.as_micros() as u64,
+        results,
+    };
+
+    if let Err(e) = store.log_query(&entry, now.saturating_sub(*keep)) {
+        warn!("Unable to log a query: {}", e);
+    }
+}
+
+// Why a request has no answer, which the client hears about as an
+// `error: kind: message` line in place of a response.
+#[derive(Debug)]
+pub enum QueryError {
+    // The request didn't make sense, like a date that isn't one.
+    Parse(String),
+    // The database couldn't answer.
+    Database(rusqlite::Error),
+    // The request made sense, but nothing matched it.
+    Empty,
+    // In multi-user mode, the request isn't for whoever sent it.
+    Denied(String),
+    // The request needs something we don't have, like an embedding service.
+    Unavailable(String),
+}
+
+impl QueryError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QueryError::Parse(_) => "parse",
+            QueryError::Database(_) => "database",
+            QueryError::Empty => "empty",
+            QueryError::Denied(_) => "denied",
+            QueryError::Unavailable(_) => "unavailable",
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Parse(message)
+            | QueryError::Denied(message)
+            | QueryError::Unavailable(message) => write!(f, "{}", message),
+            QueryError::Database(e) => write!(f, "{}", e),
+            QueryError::Empty => write!(f, "no results"),
+        }
+    }
+}
+
+impl From<rusqlite::Error> for QueryError {
+    fn from(e: rusqlite::Error) -> QueryError {
+        QueryError::Database(e)
+    }
+}
+
+// What a request gets back, one line at a time.
+type Response = Result<Vec<String>, QueryError>;
+
+// A client, its request, whether it's still sending, its session, if it
+// said `@hello`, and whatever it sent after the request.
+type Request = (TcpStream, String, bool, Option<Session>, Vec<u8>);
+
+// Connections whose requests haven't entirely arrived, yet.  A request
+// ends with a newline, or when the client stops sending.  Connections
+// with sessions come back here after each response, to wait for the next,
+// unless they already sent it, which makes them ready.
+#[derive(Default)]
+pub struct PendingRequests {
+    clients: HashMap<Token, (TcpStream, Vec<u8>)>,
+    sessions: HashMap<Token, Session>,
+    ready: Vec<Token>,
+    last_token: usize,
+}
+
+impl PendingRequests {
+    pub fn new() -> PendingRequests {
+        PendingRequests::default()
+    }
+
+    // Start waiting on a new connection's request.
+    fn add(
+        &mut self,
+        mut client: TcpStream,
+        server_poll: &Poll,
+        server_token: Token,
+    ) -> Token {
+        let mut token = server_token;
+
+        while token == server_token || self.clients.contains_key(&token) {
+            self.last_token = self.last_token.wrapping_add(1);
+            token = Token(self.last_token);
+        }
+
+        server_poll
+            .registry()
+            .register(&mut client, token, Interest::READABLE)
+            .unwrap();
+        self.clients.insert(token, (client, Vec::new()));
+        token
+    }
+
+    // Read whatever a connection has sent, returning the connection and the
+    // cleaned up request, once all of it has arrived, along with
+    // whether the client is still sending, which it is if the request ended
+    // with a newline.
+    fn read(&mut self, token: Token, server_poll: &Poll) -> Option<Request> {
+        let (client, buffer) = self.clients.get_mut(&token)?;
+        let mut chunk = [0; 4096];
+        let mut sending = true;
+        let finished = loop {
+            // A session may have sent this request along with its last.
+            if buffer.contains(&b'\n') {
+                break true;
+            }
+
+            match client.read(&mut chunk) {
+                Ok(0) => {
+                    sending = false;
+                    break !buffer.is_empty();
+                }
+                Ok(size) => {
+                    buffer.extend_from_slice(&chunk[..size]);
+                    if buffer.contains(&b'\n') {
+                        break true;
+                    } else if buffer.len() > MAX_REQUEST_BYTES {
+                        warn!("Dropping a request over {} bytes", MAX_REQUEST_BYTES);
+                        break false;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return None,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    debug!("{:?}", e);
+                    break false;
+                }
+            }
+        };
+        let (mut client, buffer) = self.clients.remove(&token)?;
+        let session = self.sessions.remove(&token);
+
+        if let Err(e) = server_poll.registry().deregister(&mut client) {
+            debug!("{:?}", e);
+        }
+
+        if !finished {
+            return None;
+        }
+
+        let end = buffer
+            .iter()
+            .position(|b| *b == b'\n')
+            .unwrap_or(buffer.len());
+        let request = String::from_utf8_lossy(&buffer[..end]);
+        let unread = buffer.get(end + 1..).unwrap_or_default().to_vec();
+
+        Some((client, clean_request(&request), sending, session, unread))
+    }
+
+    // The next request from a session that sent it along with an earlier
+    // one, if there is one.
+    fn next_ready(&mut self, server_poll: &Poll) -> Option<Request> {
+        while let Some(token) = self.ready.pop() {
+            if let Some(request) = self.read(token, server_poll) {
+                return Some(request);
+            }
+        }
+
+        None
+    }
+
+    // Be done with a client that got its response, ending the response
+    // with a blank line and waiting for its next request, if it has a
+    // session, or hanging up, otherwise.
+    fn finish(
+        &mut self,
+        mut client: TcpStream,
+        session: Option<Session>,
+        format: &ResponseFormat,
+        server_poll: &Poll,
+        server_token: Token,
+    ) {
+        if let Some(mut session) = session {
+            send(&mut client, &format.encode("\n"));
+
+            let token = self.add(client, server_poll, server_token);
+            let unread = mem::take(&mut session.unread);
+
+            if unread.contains(&b'\n') {
+                self.ready.push(token);
+            }
+
+            if let Some((_, buffer)) = self.clients.get_mut(&token) {
+                *buffer = unread;
+            }
+            self.sessions.insert(token, session);
+        }
+    }
+}
+
+// A folder that a client asked to index again, with the client, for as
+// long as it keeps reading how far along the reindex is.
+struct ReindexJob {
+    reindex: Reindex,
+    client: Option<TcpStream>,
+    session: Option<Session>,
+    format: ResponseFormat,
+}
+
+impl ReindexJob {
+    // Tell the client how the reindex is going, letting it go, rather than
+    // waiting for it, once it stops reading.
+    fn tell(&mut self, line: String) {
+        if let Some(client) = &mut self.client {
+            if let Err(e) = client.write_all(&self.format.lines(&[line])) {
+                debug!("Dropping a reindex's client: {:?}", e);
+                self.client = None;
+            }
+        }
+    }
+}
+
+// The folders waiting to be indexed again, which the main loop works
+// through a slice at a time, so that everyone else still gets answered.
+#[derive(Default)]
+pub struct Reindexing {
+    jobs: VecDeque<ReindexJob>,
+}
+
+impl Reindexing {
+    pub fn new() -> Reindexing {
+        Reindexing::default()
+    }
+
+    // Queue a folder to index again, for a client waiting on it.
+    fn add(
+        &mut self,
+        reindex: Reindex,
+        client: TcpStream,
+        session: Option<Session>,
+        format: ResponseFormat,
+    ) {
+        self.jobs.push_back(ReindexJob {
+            reindex,
+            client: Some(client),
+            session,
+            format,
+        });
+    }
+
+    // Index files from the oldest folder in the queue for a slice of time,
+    // sending each client a line as each of its files is done, and one
+    // with how many files there were, once they all are.
+    #[allow(clippy::too_many_arguments)]
+    pub fn advance(
+        &mut self,
+        store: &Store,
+        punc: &Regex,
+        accents: &Regex,
+        stemmer: &dyn Normalizer,
+        throttle: &mut Throttle,
+        pending: &mut PendingRequests,
+        server_poll: &Poll,
+        server_token: Token,
+    ) {
+        let started = Instant::now();
+
+        while started.elapsed() < REINDEX_SLICE {
+            let Some(job) = self.jobs.front_mut() else {
+                return;
+            };
+            let total = job.reindex.total();
+            let line = job
+                .reindex
+                .next(store, punc, accents, stemmer, throttle)
+                .map(|(done, path)| format!("{}/{}\t{}", done, total, path.display()));
+
+            if let Some(line) = line {
+                job.tell(line);
+                continue;
+            }
+
+            let mut job = self.jobs.pop_front().unwrap();
+
+            job.tell(format!("reindexed {} file(s)", total));
+            if let Some(client) = job.client {
+                let format = job.format;
+
+                pending.finish(client, job.session, &format, server_poll, server_token);
+            }
+        }
+    }
+}
+
+// Whoever sent a request, going by who owns the other end of the
+// connection, or nothing, if we can't tell.
+fn requester(client: &TcpStream) -> Option<User> {
+    User::connected(client.local_addr().ok()?, client.peer_addr().ok()?)
+}
+
+// Whether a client that sent its request and is waiting on the response has
+// hung up, which shows up as the end of the stream, or an error, where we'd
+// otherwise find nothing to read yet.
+fn hung_up(client: &TcpStream) -> bool {
+    let mut byte = [0; 1];
+
+    match client.peek(&mut byte) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(e) => !matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        ),
+    }
+}
+
+// Accept requests for searches and return any search results.
+#[allow(clippy::too_many_arguments)]
+pub fn handle_queries(
+    store: &Store,
+    events: &Events,
+    server: &TcpListener,
+    server_poll: &Poll,
+    server_token: Token,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    run_id: i64,
+    paused: &mut bool,
+    queued: usize,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    subscriptions: &mut Subscriptions,
+    pending: &mut PendingRequests,
+    ignores: &mut Ignores,
+    reindexing: &mut Reindexing,
+) {
+    let mut requests = Vec::<Request>::new();
+
+    for event in events.iter() {
+        if event.token() != server_token {
+            requests.extend(pending.read(event.token(), server_poll));
+            continue;
+        }
+
+        loop {
+            match server.accept() {
+                Ok((client, _addr)) => {
+                    let token = pending.add(client, server_poll, server_token);
+
+                    // The request may well have arrived with the connection.
+                    requests.extend(pending.read(token, server_poll));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    debug!("{:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    let mut requests = requests.into_iter();
+
+    while let Some((mut client, query, sending, mut session, unread)) =
+        requests.next().or_else(|| pending.next_ready(server_poll))
+    {
+        let started = Instant::now();
+
+        // Anything more that a session sent waits its turn.
+        if let Some(session) = &mut session {
+            session.unread = unread.clone();
+        }
+
+        let (token, query) = split_token(&query);
+        let token = token
+            .map(str::to_string)
+            .or_else(|| session.as_ref()?.token.clone());
+        let defaults = session
+            .as_ref()
+            .map_or_else(RequestOptions::default, |s| s.options);
+        let (options, query) = match split_options(query, defaults) {
+            Ok(split) => split,
+            Err(e) => {
+                let format = &defaults.format;
+
+                write_response(&mut client, Err(QueryError::Parse(e)), format);
+                record_query_time(started.elapsed());
+                pending.finish(client, session, format, server_poll, server_token);
+                continue;
+            }
+        };
+        let format = &options.format;
+        let rows = index_rows_read();
+        let span = debug_span!("query", query, results = field::Empty);
+        let _entered = span.enter();
+        let user = if multi_user() {
+            match requester(&client) {
+                Some(user) => Some(user),
+                None => {
+                    let unknown =
+                        QueryError::Denied("can't tell who's asking".to_string());
+
+                    write_response(&mut client, Err(unknown), format);
+                    record_query_time(started.elapsed());
+                    pending.finish(client, session, format, server_poll, server_token);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let user = match authorize(user, token.as_deref()) {
+            Ok(user) => user,
+            Err(e) => {
+                write_response(&mut client, Err(QueryError::Denied(e)), format);
+                record_query_time(started.elapsed());
+                pending.finish(client, session, format, server_poll, server_token);
+                continue;
+            }
+        };
+        let user = user.as_ref();
+        // Whatever answers the request only finds files in the token's folders.
+        let _limit = store.limit_to_folders(user.and_then(User::folders));
+
+        if query.starts_with("@hello") {
+            // The greeting goes out the way the new session asked.
+            let greeted = Session::hello(query, token.as_deref(), options);
+            let (response, format) = match greeted {
+                Ok(mut hello) => {
+                    let greeting = format!("hello {}", hello.name);
+                    let format = hello.options.format;
+
+                    hello.unread = unread;
+
+                    info!(
+                        "Session with {} {}",
+                        hello.name,
+                        hello.version.as_deref().unwrap_or_default()
+                    );
+                    session = Some(hello);
+                    (Ok(vec![greeting]), format)
+                }
+                Err(e) => (Err(QueryError::Parse(e)), *format),
+            };
+
+            write_response(&mut client, response, &format);
+            record_query_time(started.elapsed());
+            pending.finish(client, session, &format, server_poll, server_token);
+            continue;
+        }
+
+        if query.starts_with("@subscribe") {
+            respond_to_subscribe(
+                query,
+                punc,
+                accents,
+                stemmer,
+                store,
+                ranking,
+                cache,
+                subscriptions,
+                client,
+                user,
+                format,
+            );
+            record_query_time(started.elapsed());
+            continue;
+        }
+
+        if query.starts_with("@reindex") {
+            match start_reindex(query, store, ignores, *paused, user) {
+                Ok(reindex) => reindexing.add(reindex, client, session, *format),
+                Err(e) => {
+                    write_response(&mut client, Err(e), format);
+                    pending.finish(client, session, format, server_poll, server_token);
+                }
+            }
+            record_query_time(started.elapsed());
+            continue;
+        }
+
+        // A client that ended its request with a newline can hang up on us
+        // mid-search, but one that closed its side to end the request has
+        // already reached the end of its stream.
+        let cancel = if sending {
+            Cancellation::new(|| hung_up(&client))
+        } else {
+            Cancellation::never()
+        };
+        let response = if (query.starts_with("@frequencies")
+            || query.starts_with("@complete")
+            || query.starts_with("@bloat")
+            || query.starts_with("@purge")
+            || query.starts_with("@explain")
+            || query.starts_with("@stats"))
+            && user.is_some_and(|u| !u.sees_everything())
+        {
+            // These count words or files across the whole index, which
+            // would give away what's in files the asker can't see.
+            Err(QueryError::Denied(format!(
+                "{} covers files you can't see",
+                query.split_whitespace().next().unwrap_or_default()
+            )))
+        } else if (query.starts_with("@pause") || query.starts_with("@resume"))
+            && user.is_some_and(|u| !u.runs_the_daemon())
+        {
+            // Indexing stops for everybody, so it's up to whoever runs it.
+            Err(QueryError::Denied(
+                "only the daemon's owner can pause or resume indexing".to_string(),
+            ))
+        } else if query.starts_with("@on")
+            || query.starts_with("@ago")
+            || query.starts_with("@between")
+        {
+            respond_to_dates(query, store, user)
+        } else if query.starts_with("@modified-since") {
+            respond_to_modified_since(query, store, user)
+        } else if query.starts_with("@stale") {
+            respond_to_stale(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@stats") {
+            stats_lines(store, run_id)
+        } else if query.starts_with("@similar") {
+            respond_to_similar(query, store, user)
+        } else if query.starts_with("@semantic") {
+            respond_to_semantic(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@cluster") {
+            respond_to_cluster(query, store, user)
+        } else if query.starts_with("@duplicates") {
+            respond_to_duplicates(store, user)
+        } else if query.starts_with("@links") || query.starts_with("@backlinks") {
+            respond_to_links(query, store, user)
+        } else if query.starts_with("@terms") {
+            respond_to_terms(query, store, user)
+        } else if query.starts_with("@explain") {
+            respond_to_explain(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@frequencies") {
+            respond_to_frequencies(query, store)
+        } else if query.starts_with("@bloat") {
+            respond_to_bloat(query, store)
+        } else if query.starts_with("@complete") {
+            respond_to_complete(query, accents, store)
+        } else if query.starts_with("@group") {
+            respond_to_group(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@offsets") {
+            respond_to_offsets(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@lines") {
+            respond_to_lines(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@preset") {
+            respond_to_preset(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@contains") {
+            respond_to_contains(query, accents, store, ranking, &cancel, user)
+        } else if query.starts_with("@sounds-like") {
+            respond_to_sounds_like(
+                query, punc, accents, stemmer, store, ranking, &cancel, user,
+            )
+        } else if query.starts_with("@type") {
+            respond_to_type(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        } else if query.starts_with("@history") {
+            respond_to_history(query, store, user)
+        } else if query.starts_with("@errors") {
+            respond_to_errors(store, user)
+        } else if query.starts_with("@deleted") {
+            respond_to_deleted(store, user)
+        } else if query.starts_with("@purge") {
+            respond_to_purge(store)
+        } else if query.starts_with("@pause") {
+            *paused = true;
+            info!("Indexing paused");
+            Ok(vec!["paused".to_string()])
+        } else if query.starts_with("@resume") {
+            *paused = false;
+            info!("Indexing resumed");
+            Ok(vec![format!("resumed, {} queued", queued)])
+        } else {
+            respond_to_search(
+                query, punc, accents, stemmer, store, ranking, cache, &cancel, user,
+            )
+        };
+        let response =
+            response.map(|lines| truncate_results(lines, options.max.or(max_results())));
+        // A session's responses end with a blank line, so there can't be
+        // any others.
+        let response = match session {
+            Some(_) => response
+                .map(|lines| lines.into_iter().filter(|l| !l.is_empty()).collect()),
+            None => response,
+        };
+        let cancelled = cancel.is_cancelled();
+        let results = response
+            .as_ref()
+            .map_or(0, |lines| lines.iter().filter(|l| !l.is_empty()).count());
+
+        span.record("results", results);
+        drop(cancel);
+        if cancelled {
+            debug!("Not answering a client that hung up");
+        } else {
+            write_response(&mut client, response, format);
+            pending.finish(client, session, format, server_poll, server_token);
+        }
+
+        record_query_time(started.elapsed());
+        log_query(
+            store,
+            query,
+            user,
+            started.elapsed(),
+            results,
+            index_rows_read() - rows,
+        );
+    }
+}
+
+// Send a response, a line at a time, or the reason there isn't one, and an
+// empty response counts as having found nothing, in the format the client
+// asked for.  The client is no longer on the poll, so if it's slow to read,
+// we keep retrying for a while.
+pub fn write_response(
+    client: &mut TcpStream,
+    response: Response,
+    format: &ResponseFormat,
+) {
+    let bytes = match response {
+        Ok(lines) if !lines.is_empty() => format.lines(&lines),
+        Ok(_) => error_line(&QueryError::Empty, format),
+        Err(e) => {
+            if let QueryError::Database(_) = e {
+                error!("Unable to answer a request: {}", e);
+            }
+
+            error_line(&e, format)
+        }
+    };
+    send(client, &bytes);
+}
+
+// Send bytes to a client that's no longer on the poll, retrying for a
+// while, if it's slow to read them.
+fn send(client: &mut TcpStream, mut bytes: &[u8]) {
+    let started = Instant::now();
+
+    while !bytes.is_empty() {
+        match client.write(bytes) {
+            Ok(0) => break,
+            Ok(size) => bytes = &bytes[size..],
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                if started.elapsed() > WRITE_TIMEOUT {
+                    warn!("Giving up on a client that isn't reading its response");
+                    break;
+                }
+
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                debug!("{:?}", e);
+                break;
+            }
+        }
+    }
+}
+
+// Describe an error for the client.
+fn error_line(e: &QueryError, format: &ResponseFormat) -> Vec<u8> {
+    format.error(e.kind(), &e.to_string())
+}
+
+// Describe the current run, the last few runs before it, and the totals
+// across all runs, one line each.
+pub fn stats_lines(store: &Store, run_id: i64) -> Response {
+    let mut lines = Vec::<String>::new();
+
+    store.save_stats(run_id);
+
+    let mut stmt = store.prepare(
+        "SELECT id, started, updated, scan_seconds, files_indexed,
+                events_processed, queries_served
+           FROM stats ORDER BY id DESC LIMIT 10",
+    )?;
+    let runs = stmt.query_map([], |row| {
+        let id: i64 = row.get(0)?;
+        let started: i64 = row.get(1)?;
+        let updated: i64 = row.get(2)?;
+        let scan: Option<f64> = row.get(3)?;
+
+        Ok(format!(
+            "{} {}: up {}s, scan {}, {} files indexed, {} events, {} queries",
+            if id == run_id { "current" } else { "run" },
+            format_timestamp(started),
+            updated - started,
+            scan.map_or("unfinished".to_string(), |s| format!("{:.1}s", s)),
+            row.get::<_, i64>(4)?,
+            row.get::<_, i64>(5)?,
+            row.get::<_, i64>(6)?,
+        ))
+    })?;
+
+    for run in runs {
+        lines.push(run?);
+    }
+
+    let total = store.query_row(
+        "SELECT COUNT(*), AVG(scan_seconds), SUM(files_indexed),
+                SUM(events_processed), SUM(queries_served)
+           FROM stats",
+        [],
+        |row| {
+            Ok(format!(
+                "total: {} runs, average scan {:.1}s, {} files indexed, {} events, {} queries",
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<f64>>(1)?.unwrap_or(0.0),
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        },
+    )?;
+
+    lines.push(total);
+
+    // Files modified after they were indexed are waiting for the indexer,
+    // or it missed them.
+    let index = store.query_row(
+        "SELECT COUNT(*), MAX(indexed_at), SUM(modified > indexed_at)
+           FROM monitored_file",
+        [],
+        |row| {
+            Ok(format!(
+                "index: {} files, last indexed {}, {} changed since",
+                row.get::<_, i64>(0)?,
+                row.get::<_, Option<i64>>(1)?
+                    .map_or("never".to_string(), format_timestamp),
+                row.get::<_, Option<i64>>(2)?.unwrap_or(0),
+            ))
+        },
+    )?;
+
+    lines.push(index);
+
+    // Only once there's been an integrity check, though.
+    match store.query_row(
+        "SELECT checked_at, sampled, missing, stale, drifted
+           FROM integrity_check ORDER BY id DESC LIMIT 1",
+        [],
+        |row| {
+            Ok(format!(
+                "integrity: last checked {}, {} files sampled, {} missing, {} stale, \
+                 {} drifted",
+                format_timestamp(row.get(0)?),
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        },
+    ) {
+        Ok(integrity) => lines.push(integrity),
+        Err(rusqlite::Error::QueryReturnedNoRows) => (),
+        Err(e) => return Err(e.into()),
+    }
+
+    Ok(lines)
+}
+
+// Return the latest logged queries, newest first, each with when it ran,
+// how long it took, and how many results it found, only counting the
+// asker's own queries in multi-user mode.
+fn respond_to_history(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let count = raw_query
+        .replacen("@history", "", 1)
+        .trim()
+        .parse::<usize>()
+        .unwrap_or(HISTORY_LENGTH);
+
+    if QUERY_LOG.get_or_init(|| None).is_none() {
+        return Err(QueryError::Unavailable(
+            "@history needs queryLog turned on".to_string(),
+        ));
+    }
+
+    // In multi-user mode, people only get to see their own queries.
+    let uid = user.filter(|u| !u.sees_everything()).map(|u| u.uid());
+    Ok(store
+        .query_history(uid, count)?
+        .iter()
+        .map(|q| {
+            format!(
+                "{}\t{:.1} ms\t{} result(s)\t{}",
+                format_timestamp(q.at as i64),
+                q.micros as f64 / 1000.0,
+                q.results,
+                q.query
+            )
+        })
+        .collect())
+}
+
+// List the latest indexing errors, newest first, each with when it
+// happened, what kind of error it was, and whether the file has failed
+// often enough to be quarantined.
+fn respond_to_errors(store: &Store, user: Option<&User>) -> Response {
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT e.path, e.at, e.kind, q.failures, e.error
+           FROM index_error e
+           LEFT JOIN quarantine q ON q.path = e.path
+           WHERE {}
+           ORDER BY e.id DESC",
+        store.in_folders("e.path")
+    ))?;
+    let failures = stmt.query_map([], |row| {
+        let path: String = row.get(0)?;
+        let failures = row.get::<_, Option<u32>>(3)?.unwrap_or_default();
+
+        Ok((
+            path.clone(),
+            format!(
+                "{}\t{}\t{}{}\t{}",
+                path,
+                format_timestamp(row.get(1)?),
+                row.get::<_, String>(2)?,
+                if failures >= QUARANTINE_FAILURES {
+                    ", quarantined"
+                } else {
+                    ""
+                },
+                row.get::<_, String>(4)?,
+            ),
+        ))
+    })?;
+
+    Ok(failures
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .filter(|(path, _)| visible(user, path))
+        .map(|(_, line)| line)
+        .collect())
+}
+
+// Start indexing a folder all over again, once the request checks out.
+// The main loop does the work, a slice at a time, between everything else.
+fn start_reindex(
+    raw_query: &str,
+    store: &Store,
+    ignores: &mut Ignores,
+    paused: bool,
+    user: Option<&User>,
+) -> Result<Reindex, QueryError> {
+    let folder = Path::new(raw_query.trim_start_matches("@reindex").trim());
+
+    if folder.as_os_str().is_empty() {
+        return Err(QueryError::Parse("@reindex needs a folder".to_string()));
+    } else if !ignores.is_watched(folder) {
+        return Err(QueryError::Parse(format!(
+            "{} isn't watched",
+            folder.display()
+        )));
+    } else if user.is_some_and(|u| !u.sees_everything()) {
+        return Err(QueryError::Denied(
+            "@reindex covers files you can't see".to_string(),
+        ));
+    } else if paused {
+        return Err(QueryError::Unavailable("indexing is paused".to_string()));
+    }
+
+    info!("Reindexing {}", folder.display());
+    Ok(Reindex::new(store, folder, ignores))
+}
+
+// Return the files that have disappeared, but that the index remembers in
+// case they come back, with when they went.
+fn respond_to_deleted(store: &Store, user: Option<&User>) -> Response {
+    Ok(store
+        .deleted_files()?
+        .into_iter()
+        .filter(|(path, _)| visible(user, path))
+        .map(|(path, at)| format!("{}\t{}", path, format_timestamp(at as i64)))
+        .collect())
+}
+
+// Forget the deleted files for good, without waiting for them to expire.
+fn respond_to_purge(store: &Store) -> Response {
+    match store.purge_deleted(None) {
+        Ok(count) => Ok(vec![format!("purged {} deleted file(s)", count)]),
+        Err(Error::Database(e)) => Err(QueryError::Database(e)),
+        Err(e) => Err(QueryError::Unavailable(e.to_string())),
+    }
+}
+
+// Return the files modified during the days that an `@on`, `@ago`, or
+// `@between` query covers
+fn respond_to_dates(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let query = raw_query.trim();
+    let (start, end) = date_window(query, today()).ok_or_else(|| {
+        QueryError::Parse(format!("can't read the dates in '{}'", query))
+    })?;
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT path FROM monitored_file WHERE modified >= ? AND modified < ? AND {}
+           ORDER BY modified",
+        store.in_folders("path")
+    ))?;
+    let files = stmt
+        .query_map(params![day_timestamp(start), day_timestamp(end)], |row| {
+            row.get(0)
+        })?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    debug!("{:#?}", files);
+    Ok(files.into_iter().filter(|p| visible(user, p)).collect())
+}
+
+// Return the files that changed on disk, or in the index, after a time in
+// seconds since the epoch, so that sync tools can ask, instead of walking
+// every folder.
+fn respond_to_modified_since(
+    raw_query: &str,
+    store: &Store,
+    user: Option<&User>,
+) -> Response {
+    let since = raw_query
+        .trim_start_matches("@modified-since")
+        .trim()
+        .parse::<u64>()
+        .map_err(|_| {
+            QueryError::Parse("@modified-since needs a timestamp".to_string())
+        })?;
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT path FROM monitored_file WHERE (modified > ?1 OR indexed_at > ?1) AND {}
+           ORDER BY path",
+        store.in_folders("path")
+    ))?;
+    let files = stmt
+        .query_map(params![since], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    Ok(files.into_iter().filter(|p| visible(user, p)).collect())
+}
+
+// Run a search saved in the configuration, like `@preset work-notes budget`,
+// with any extra terms, limited to the preset's folders and extensions,
+// and in its order.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_preset(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@preset", "", 1);
+    let (name, extra) = query.trim().split_once(' ').unwrap_or((query.trim(), ""));
+    let preset = match preset(name) {
+        Some(preset) => preset,
+        None if name.is_empty() => {
+            return Err(QueryError::Parse("@preset needs a name".to_string()))
+        }
+        None => return Err(QueryError::Parse(format!("no preset named {}", name))),
+    };
+    let query = preset.query(extra);
+
+    if query.is_empty() {
+        return Err(QueryError::Parse(format!("preset {} has no terms", name)));
+    }
+
+    let (serps, sorted) = run_search(
+        &query, punc, accents, stemmer, store, ranking, cache, cancel,
+    )?;
+
+    Ok(preset
+        .arrange(&serps, sorted)
+        .into_iter()
+        .filter(|p| visible(user, p))
+        .collect())
+}
+
+// Find and return search results to client
+#[allow(clippy::too_many_arguments)]
+fn respond_to_search(
+    query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let (_serps, sorted) =
+        run_search(query, punc, accents, stemmer, store, ranking, cache, cancel)?;
+
+    Ok(sorted
+        .into_iter()
+        .filter(|p| !p.is_empty() && visible(user, p))
+        .collect())
+}
+
+// Run a search for words with the query's terms anywhere in them, like
+// `@contains ellanec`, with the trigram index turned on.
+fn respond_to_contains(
+    raw_query: &str,
+    accents: &Regex,
+    store: &Store,
+    ranking: &RankingConfig,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@contains", "", 1);
+
+    if !trigrams_enabled() {
+        return Err(QueryError::Unavailable(
+            "@contains needs the trigrams item".to_string(),
+        ));
+    } else if query.split_whitespace().next().is_none() {
+        return Err(QueryError::Parse(
+            "@contains needs text to find".to_string(),
+        ));
+    } else if query.split_whitespace().any(|n| n.chars().count() < 3) {
+        return Err(QueryError::Parse(
+            "@contains needs at least three characters".to_string(),
+        ));
+    }
+
+    let (_serps, sorted) = run_substring_search(&query, accents, store, ranking, cancel)?;
+
+    Ok(sorted
+        .into_iter()
+        .filter(|p| !p.is_empty() && visible(user, p))
+        .collect())
+}
+
+// Run a search for words that sound like the query's, like `@sounds-like
+// nitsche`, for names that nobody spells the same way twice.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_sounds_like(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@sounds-like", "", 1);
+
+    if query_words(&query, punc).is_empty() {
+        return Err(QueryError::Parse("@sounds-like needs words".to_string()));
+    }
+
+    let (_serps, sorted) =
+        run_phonetic_search(&query, punc, accents, stemmer, store, ranking, cancel)?;
+
+    Ok(sorted
+        .into_iter()
+        .filter(|p| !p.is_empty() && visible(user, p))
+        .collect())
+}
+
+// Rank files by how close they come to the meaning of a query, like
+// `@semantic growing vegetables`, blending the similarity of their
+// embeddings with their keyword scores, so that a file that uses the exact
+// words still does well.
+#[cfg(feature = "semantic")]
+#[allow(clippy::too_many_arguments)]
+fn respond_to_semantic(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@semantic", "", 1);
+    let query = query.trim();
+    let embedder = embedder().ok_or_else(|| {
+        QueryError::Unavailable("@semantic needs an embedding service".to_string())
+    })?;
+
+    if query.is_empty() {
+        return Err(QueryError::Parse("@semantic needs a query".to_string()));
+    }
+
+    let vector = embedder
+        .embed(query)
+        .map_err(|e| QueryError::Unavailable(format!("embedding service: {}", e)))?;
+    let meanings = similarities(store, &vector)?;
+    let (serps, _sorted) =
+        run_search(query, punc, accents, stemmer, store, ranking, cache, cancel)?;
+    let tokens = tokenize(query, punc);
+    let words = tokens.iter().map(|(_, w)| *w).collect::<Vec<_>>();
+    let keywords = rank_search_results(&serps, &words, ranking);
+    let best_keyword = keywords.values().copied().fold(0.0, f32::max);
+    let mut scores = HashMap::<String, f32>::new();
+
+    for (path, similarity) in meanings {
+        scores.insert(path, embedder.weight * similarity.max(0.0));
+    }
+
+    for (path, score) in keywords {
+        *scores.entry(path).or_insert(0.0) +=
+            (1.0 - embedder.weight) * score / best_keyword.max(f32::MIN_POSITIVE);
+    }
+
+    let mut ranked = scores
+        .into_iter()
+        .filter(|(path, _)| visible(user, path))
+        .collect::<Vec<_>>();
+
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(ranked
+        .into_iter()
+        .take(SEMANTIC_RESULTS)
+        .map(|(path, _)| path)
+        .collect())
+}
+
+// Without the semantic feature, there are no embeddings to compare.
+#[cfg(not(feature = "semantic"))]
+#[allow(clippy::too_many_arguments)]
+fn respond_to_semantic(
+    _raw_query: &str,
+    _punc: &Regex,
+    _accents: &Regex,
+    _stemmer: &dyn Normalizer,
+    _store: &Store,
+    _ranking: &RankingConfig,
+    _cache: &mut SearchCache,
+    _cancel: &Cancellation,
+    _user: Option<&User>,
+) -> Response {
+    Err(QueryError::Unavailable(
+        "@semantic needs the semantic feature".to_string(),
+    ))
+}
+
+// Find search results among the files of one type, going by extension,
+// like `@type mp3 coltrane`, or every file of that type, without any
+// search terms.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_type(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@type", "", 1);
+    let mut terms = query.split_whitespace();
+    let extension = match terms.next() {
+        Some(extension) => extension.trim_start_matches('.').to_lowercase(),
+        None => return Err(QueryError::Parse("@type needs a file type".to_string())),
+    };
+    let words = terms.collect::<Vec<_>>().join(" ");
+    let files = if words.is_empty() {
+        let mut stmt = store.prepare_uncached(&format!(
+            "SELECT path FROM monitored_file WHERE path LIKE ? AND {} ORDER BY path",
+            store.in_folders("path")
+        ))?;
+        let files = stmt
+            .query_map(params![format!("%.{}", extension)], |row| row.get(0))?
+            .collect::<Result<Vec<String>, _>>()?;
+
+        files
+    } else {
+        let (_serps, sorted) = run_search(
+            &words, punc, accents, stemmer, store, ranking, cache, cancel,
+        )?;
+
+        sorted
+    };
+
+    Ok(files
+        .into_iter()
+        .filter(|p| {
+            Path::new(p)
+                .extension()
+                .is_some_and(|e| e.to_string_lossy().to_lowercase() == extension)
+                && visible(user, p)
+        })
+        .collect())
+}
+
+// Return the files that nobody has modified in some number of days, oldest
+// first, limited to a folder, if the rest of the query is a path, or to the
+// files that match the rest of it, otherwise.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_stale(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@stale", "", 1);
+    let mut terms = query.split_whitespace();
+    let cutoff = terms
+        .next()
+        .and_then(|days| days.parse::<u64>().ok())
+        .and_then(|days| today().checked_sub_days(Days::new(days)))
+        .ok_or_else(|| QueryError::Parse("@stale needs a number of days".to_string()))?;
+    let rest = terms.collect::<Vec<_>>().join(" ");
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT path FROM monitored_file WHERE modified > 0 AND modified < ? AND {}
+           ORDER BY modified",
+        store.in_folders("path")
+    ))?;
+    let mut files = stmt
+        .query_map(params![day_timestamp(cutoff)], |row| row.get(0))?
+        .collect::<Result<Vec<String>, _>>()?;
+
+    if rest.starts_with('/') {
+        files.retain(|p| Path::new(p).starts_with(&rest));
+    } else if !rest.is_empty() {
+        let (_serps, sorted) =
+            run_search(&rest, punc, accents, stemmer, store, ranking, cache, cancel)?;
+
+        let matches = sorted.into_iter().collect::<HashSet<_>>();
+
+        files.retain(|p| matches.contains(p));
+    }
+
+    Ok(files.into_iter().filter(|p| visible(user, p)).collect())
+}
+
+// Keep the connection open as a standing query, pushing the path of each
+// file that starts matching it, once the daemon indexes that file.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_subscribe(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    subscriptions: &mut Subscriptions,
+    mut client: mio::net::TcpStream,
+    user: Option<&User>,
+    format: &ResponseFormat,
+) {
+    let query = raw_query.replacen("@subscribe", "", 1);
+    let stems = query_stems(&query, punc, accents, stemmer);
+
+    if stems.is_empty() {
+        let nothing = QueryError::Parse("nothing to subscribe to".to_string());
+
+        write_response(&mut client, Err(nothing), format);
+        return;
+    }
+
+    // Files that already match aren't news.
+    let serps = match run_search(
+        &query,
+        punc,
+        accents,
+        stemmer,
+        store,
+        ranking,
+        cache,
+        &Cancellation::never(),
+    ) {
+        Ok((serps, _sorted)) => serps,
+        Err(e) => {
+            write_response(&mut client, Err(QueryError::Database(e)), format);
+            return;
+        }
+    };
+
+    info!("Subscribing to {}", query.trim());
+    write_response(&mut client, Ok(vec!["subscribed".to_string()]), format);
+    subscriptions.add(
+        client,
+        StandingQuery::new(stems, serps.into_keys().collect()),
+        user.cloned(),
+        *format,
+    );
+}
+
+// Find files similar to the specified file.
+fn respond_to_similar(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let path = raw_query.replacen("@similar", "", 1).trim().to_string();
+
+    if path.is_empty() {
+        return Err(QueryError::Parse("@similar needs a file".to_string()));
+    }
+
+    // Someone who can't read the file doesn't get to hear what's in it, and
+    // that looks the same as a file that isn't in the index.
+    if !visible(user, &path) {
+        return Ok(Vec::new());
+    }
+
+    Ok(similar_files(store, &path)?
+        .into_iter()
+        .filter(|p| visible(user, p))
+        .collect())
+}
+
+// List the other files in the same cluster as the specified file, as of the
+// last time the files were clustered.
+fn respond_to_cluster(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let path = raw_query.replacen("@cluster", "", 1).trim().to_string();
+
+    if path.is_empty() {
+        return Err(QueryError::Parse("@cluster needs a file".to_string()));
+    } else if !visible(user, &path) {
+        return Ok(Vec::new());
+    }
+
+    Ok(store
+        .cluster_members(&path)?
+        .into_iter()
+        .filter(|p| visible(user, p))
+        .collect())
+}
+
+// List the groups of files with the same contents, one group to a line,
+// separated by tabs.  Someone who can only read one of the copies doesn't
+// hear about the others.
+fn respond_to_duplicates(store: &Store, user: Option<&User>) -> Response {
+    Ok(store
+        .duplicate_files()?
+        .into_iter()
+        .map(|group| {
+            group
+                .into_iter()
+                .filter(|p| visible(user, p))
+                .collect::<Vec<_>>()
+        })
+        .filter(|group| group.len() > 1)
+        .map(|group| group.join("\t"))
+        .collect())
+}
+
+// List the files that a note links to, with `@links`, or the notes that
+// link to a file, with `@backlinks`.
+fn respond_to_links(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let query = raw_query.trim();
+    let (command, path) = query.split_once(char::is_whitespace).unwrap_or((query, ""));
+    let path = path.trim();
+
+    if path.is_empty() {
+        return Err(QueryError::Parse(format!("{} needs a file", command)));
+    } else if !visible(user, path) {
+        return Ok(Vec::new());
+    }
+
+    let linked = if command == "@backlinks" {
+        store.backlinks_to(path)?
+    } else {
+        store.links_from(path)?
+    };
+
+    Ok(linked.into_iter().filter(|p| visible(user, p)).collect())
+}
+
+// List the stems that best characterize a file, most distinctive first,
+// with how often each appears in the file and its TF-IDF weight.  Since
+// paths can contain spaces, a count has to come at the end.
+fn respond_to_terms(raw_query: &str, store: &Store, user: Option<&User>) -> Response {
+    let query_string = raw_query.replacen("@terms", "", 1).trim().to_string();
+    let (path, limit) = match query_string.rsplit_once(char::is_whitespace) {
+        Some((path, count)) if count.parse::<usize>().is_ok() => {
+            (path.trim().to_string(), count.parse::<usize>().unwrap())
+        }
+        _ => (query_string, 10),
+    };
+
+    if path.is_empty() {
+        return Err(QueryError::Parse("@terms needs a file".to_string()));
+    } else if !visible(user, &path) {
+        return Ok(Vec::new());
+    }
+
+    Ok(top_file_stems(store, &path, limit)?
+        .iter()
+        .map(|(_, stem, weight, frequency)| {
+            format!("{}\t{}\t{:.3}", stem, frequency, weight)
+        })
+        .collect())
+}
+
+// Show how a query gets answered, to make sense of a file that didn't rank
+// where it should have.  The first line has the words searched for, then
+// each has a line with its stem and how many times and in how many files
+// that stem appears, then any filters get a line, and each file found gets
+// a line with its score and what went into it, best first.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_explain(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@explain", "", 1);
+    let query = query.trim();
+    let words = query_words(query, punc);
+
+    if query.is_empty() {
+        return Err(QueryError::Parse("@explain needs a query".to_string()));
+    }
+
+    let mut lines = vec![format!("terms\t{}", words.join(" "))];
+    let mut stmt = store.prepare_uncached(&format!(
+        "SELECT f.path, COUNT(*)
+           FROM file_reverse_index i
+           JOIN monitored_file f ON f.id = i.file
+           JOIN word_stem s ON s.id = i.stem
+           WHERE s.stem = ? AND {}
+           GROUP BY i.file",
+        store.in_folders("f.path")
+    ))?;
+
+    for word in &words {
+        let stem = stem_word(word, accents, stemmer);
+        let files = stmt
+            .query_map(params![stem], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        let (count, rows) = files
+            .iter()
+            .filter(|(path, _)| visible(user, path))
+            .fold((0, 0), |(count, rows), (_, n)| (count + 1, rows + n));
+
+        lines.push(format!("stem\t{}\t{}\t{}\t{}", word, stem, rows, count));
+    }
+
+    for filter in ["tag:", "def:", "word:"] {
+        lines.extend(
+            filter_values(query, filter)
+                .iter()
+                .map(|value| format!("{}\t{}", filter.trim_end_matches(':'), value)),
+        );
+    }
+
+    let (mut serps, _sorted) =
+        run_search(query, punc, accents, stemmer, store, ranking, cache, cancel)?;
+
+    serps.retain(|path, _| visible(user, path));
+
+    let tokens = words.iter().map(String::as_str).collect::<Vec<_>>();
+    let mut scores = explain_ranking(&serps, &tokens, ranking)
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    scores.sort_by(|a, b| b.1.score.total_cmp(&a.1.score).then_with(|| a.0.cmp(&b.0)));
+    lines.extend(scores.iter().map(|(path, s)| {
+        format!(
+            "file\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+            path,
+            s.score,
+            s.proximity,
+            s.terms,
+            s.literal,
+            s.path,
+            s.folder,
+            s.recency,
+            s.length
+        )
+    }));
+    Ok(lines)
+}
+
+// List the most common stems across the whole index, with how many times
+// each appears and in how many files, to help spot stopwords and noise.
+fn respond_to_frequencies(raw_query: &str, store: &Store) -> Response {
+    let limit = raw_query
+        .replacen("@frequencies", "", 1)
+        .trim()
+        .parse::<u32>()
+        .unwrap_or(25);
+    let mut stmt = store.prepare(
+        "SELECT s.stem, COUNT(*) AS n, COUNT(DISTINCT i.file)
+           FROM file_reverse_index i JOIN word_stem s ON s.id = i.stem
+           WHERE i.file IN (SELECT id FROM monitored_file)
+           GROUP BY i.stem
+           ORDER BY n DESC, s.stem
+           LIMIT ?",
+    )?;
+    let stems = stmt.query_map(params![limit], |row| {
+        Ok(format!(
+            "{}\t{}\t{}",
+            row.get::<_, String>(0)?,
+            row.get::<_, u32>(1)?,
+            row.get::<_, u32>(2)?
+        ))
+    })?;
+
+    Ok(stems.collect::<Result<Vec<_>, _>>()?)
+}
+
+// Report what takes up the most room in the index, as the stems and the
+// files with the most entries, and the extensions whose files add up to
+// the most, each line saying which it is, along with its share of the
+// entries.  Deleted files count as an extension of their own.
+fn respond_to_bloat(raw_query: &str, store: &Store) -> Response {
+    let limit = raw_query
+        .replacen("@bloat", "", 1)
+        .trim()
+        .parse::<usize>()
+        .unwrap_or(10);
+    let total: u64 =
+        store.query_row("SELECT COUNT(*) FROM file_reverse_index", [], |row| {
+            row.get(0)
+        })?;
+    let share = |rows: u64| rows as f64 * 100.0 / total.max(1) as f64;
+    let mut lines = Vec::<String>::new();
+    let mut stmt = store.prepare(
+        "SELECT s.stem, COUNT(*) AS n
+           FROM file_reverse_index i JOIN word_stem s ON s.id = i.stem
+           GROUP BY i.stem
+           ORDER BY n DESC, s.stem
+           LIMIT ?",
+    )?;
+    let stems = stmt.query_map(params![limit], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+    })?;
+
+    for stem in stems {
+        let (stem, rows) = stem?;
+
+        lines.push(format!("stem\t{}\t{}\t{:.1}%", stem, rows, share(rows)));
+    }
+
+    let mut stmt = store.prepare(
+        "SELECT f.path, COUNT(*) AS n
+           FROM file_reverse_index i JOIN monitored_file f ON f.id = i.file
+           GROUP BY i.file
+           ORDER BY n DESC, f.path",
+    )?;
+    let files = stmt
+        .query_map([], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, u64>(1)?))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut extensions = HashMap::<String, u64>::new();
+
+    for (path, rows) in &files {
+        let extension = Path::new(path)
+            .extension()
+            .map_or("(none)".to_string(), |e| e.to_string_lossy().to_lowercase());
+
+        *extensions.entry(extension).or_insert(0) += rows;
+    }
+
+    // Deleted files keep their entries until they're purged.
+    let live = files.iter().map(|(_, rows)| rows).sum::<u64>();
+
+    if total > live {
+        extensions.insert("(deleted)".to_string(), total - live);
+    }
+
+    lines.extend(
+        files.iter().take(limit).map(|(path, rows)| {
+            format!("file\t{}\t{}\t{:.1}%", path, rows, share(*rows))
+        }),
+    );
+
+    let mut extensions = extensions.into_iter().collect::<Vec<_>>();
+
+    extensions.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    lines.extend(extensions.iter().take(limit).map(|(extension, rows)| {
+        format!("extension\t{}\t{}\t{:.1}%", extension, rows, share(*rows))
+    }));
+    Ok(lines)
+}
+
+// Suggest completions for a partial search term, as the most common
+// indexed words starting with it, along with how often each appears, in
+// their most common spelling, so that acronyms and names keep their
+// capitals.
+fn respond_to_complete(raw_query: &str, accents: &Regex, store: &Store) -> Response {
+    let query_string = raw_query.replacen("@complete", "", 1);
+    let mut terms = query_string.split_whitespace();
+    let prefix = terms.next().unwrap_or("");
+    let limit = terms.next().and_then(|n| n.parse::<usize>().ok()).unwrap_or(10);
+
+    if prefix.is_empty() {
+        return Err(QueryError::Parse("@complete needs a prefix".to_string()));
+    }
+
+    let spellings = store.words_starting_with(prefix, accents)?;
+
+    Ok(common_spellings(spellings)
+        .into_iter()
+        .take(limit)
+        .map(|(word, count)| format!("{}\t{}", word, count))
+        .collect())
+}
+
+// Find search results and summarize them by folder, with the number of
+// matching files in each and the best score among them, best first.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_group(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query_string = raw_query.replacen("@group", "", 1);
+    let mut terms = query_string.split_whitespace();
+    let mut lines = Vec::<String>::new();
+
+    match terms.next() {
+        Some("dir") => {
+            let query = terms.collect::<Vec<_>>().join(" ");
+            let (mut serps, _sorted) = run_search(
+                &query, punc, accents, stemmer, store, ranking, cache, cancel,
+            )?;
+
+            serps.retain(|path, _| visible(user, path));
+
+            let words = tokenize(&query, punc)
+                .into_iter()
+                .map(|(_, w)| w)
+                .collect::<Vec<_>>();
+            let scores = rank_search_results(&serps, &words, ranking);
+            let mut buckets = HashMap::<String, (u32, f32)>::new();
+
+            scores.iter().for_each(|(path, score)| {
+                let dir = Path::new(path)
+                    .parent()
+                    .map_or("".to_string(), |p| p.to_string_lossy().to_string());
+                let bucket = buckets.entry(dir).or_insert((0, 0.0));
+
+                bucket.0 += 1;
+                bucket.1 = bucket.1.max(*score);
+            });
+
+            let mut dirs = buckets.into_iter().collect::<Vec<_>>();
+
+            dirs.sort_by(|a, b| b.1 .1.total_cmp(&a.1 .1).then_with(|| a.0.cmp(&b.0)));
+            dirs.iter().for_each(|(dir, (count, best))| {
+                lines.push(format!("{}\t{}\t{:.2}", dir, count, best));
+            });
+        }
+        Some(grouping) => {
+            return Err(QueryError::Parse(format!("can't group by {}", grouping)))
+        }
+        None => return Err(QueryError::Parse("@group needs a grouping".to_string())),
+    }
+
+    Ok(lines)
+}
+
+// Find search results and return the byte offset of every match, so that
+// an editor can jump straight to it, as `path:byte offset:word` lines.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_offsets(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@offsets", "", 1);
+    let (serps, sorted) = run_search(
+        &query, punc, accents, stemmer, store, ranking, cache, cancel,
+    )?;
+    let mut lines = Vec::<String>::new();
+
+    sorted
+        .iter()
+        .filter(|p| !p.is_empty() && visible(user, p))
+        .for_each(|path| {
+            let mut matches = serps[path].values().flatten().collect::<Vec<_>>();
+
+            matches.sort_by_key(|m| m.offset);
+
+            // The index counts bytes of the decoded text, but clients seek
+            // in the file.
+            let decoded = matches
+                .iter()
+                .filter_map(|m| m.byte_offset.map(|o| o as usize))
+                .collect::<Vec<_>>();
+            let mut on_disk = match source_offsets(&store.locate_file(path), &decoded) {
+                Ok(Some(offsets)) => offsets,
+                _ => decoded,
+            }
+            .into_iter();
+
+            for m in matches {
+                match m.byte_offset.and_then(|_| on_disk.next()) {
+                    Some(byte_offset) => {
+                        lines.push(format!("{}:{}:{}", path, byte_offset, m.word))
+                    }
+                    // Indexed before we tracked byte offsets.
+                    None => lines.push(format!("{}::{}", path, m.word)),
+                }
+            }
+        });
+    Ok(lines)
+}
+
+// Find search results and return them to the client in the style of grep,
+// as the line containing each file's best match.
+#[allow(clippy::too_many_arguments)]
+fn respond_to_lines(
+    raw_query: &str,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    store: &Store,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    cancel: &Cancellation,
+    user: Option<&User>,
+) -> Response {
+    let query = raw_query.replacen("@lines", "", 1);
+    let defs = filter_values(&query, "def:");
+    let (serps, sorted) = run_search(
+        &query, punc, accents, stemmer, store, ranking, cache, cancel,
+    )?;
+    let mut lines = Vec::<String>::new();
+
+    sorted
+        .iter()
+        .filter(|p| !p.is_empty() && visible(user, p))
+        .for_each(|path| {
+            let location = store.locate_file(path);
+            let line = match best_match(&serps[path]) {
+                Some(found) => find_line(&location, found, punc)
+                    .or_else(|| Some((0, index_snippet(store, found)?))),
+                // A search for definitions alone goes to the first one.
+                None => store
+                    .definition_line(path, &defs)
+                    .and_then(|line_no| read_line(&location, line_no)),
+            };
+
+            match line {
+                Some((line_no, text)) => {
+                    lines.push(format!("{}:{}:{}", path, line_no, text))
+                }
+                None => lines.push(format!("{}:0:", path)),
+            }
+        });
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{accents, punctuation};
+    use crate::testing::{open_store, ScratchDir};
+    use rust_stemmers::{Algorithm, Stemmer};
+    use std::fs;
+    use std::time::SystemTime;
+
+    #[test]
+    fn stats_report_database_errors() {
+        let store = open_store();
+
+        // Without the tables, there's nothing to report, but no panic.
+        assert!(matches!(
+            stats_lines(&store, 1),
+            Err(QueryError::Database(_))
+        ));
+
+        let run_id = store.start_run(&SystemTime::now());
+        let lines = stats_lines(&store, run_id).unwrap();
+
+        assert!(lines[0].starts_with("current "));
+        assert!(lines[1].starts_with("total: 1 runs"));
+    }
+
+    #[test]
+    fn sessions_can_send_requests_ahead() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender =
+            std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (client, _) = listener.accept().unwrap();
+        let poll = Poll::new().unwrap();
+        let server_token = Token(0);
+        let mut pending = PendingRequests::new();
+
+        client.set_nonblocking(true).unwrap();
+        sender.write_all(b"@hello emacs\n@stats\n@type").unwrap();
+
+        let token = pending.add(TcpStream::from_std(client), &poll, server_token);
+        let started = Instant::now();
+        let (client, request, _, _, unread) = loop {
+            match pending.read(token, &poll) {
+                Some(request) if request.4.ends_with(b"@type") => break request,
+                Some(_) => panic!("the request arrived in pieces"),
+                None if started.elapsed() < Duration::from_secs(5) => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                None => panic!("the request never arrived"),
+            }
+        };
+        let session = Session {
+            unread,
+            ..Session::default()
+        };
+
+        assert_eq!(request, "@hello emacs");
+        pending.finish(
+            client,
+            Some(session),
+            &ResponseFormat::default(),
+            &poll,
+            server_token,
+        );
+
+        let (_, request, _, session, unread) = pending.next_ready(&poll).unwrap();
+
+        assert_eq!(request, "@stats");
+        assert_eq!(unread, b"@type");
+        assert!(session.is_some());
+        assert!(pending.next_ready(&poll).is_none());
+    }
+
+    #[test]
+    fn reindexing_reports_each_file() {
+        let dir = ScratchDir::new("reindex");
+        let store = open_store();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut reader =
+            std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (client, _) = listener.accept().unwrap();
+        let poll = Poll::new().unwrap();
+        let mut pending = PendingRequests::new();
+        let mut reindexing = Reindexing::new();
+        let mut throttle = Throttle::new(&gjson::parse("{}"));
+        let acc = accents(&gjson::parse("{}"));
+        let stemmer = Stemmer::create(Algorithm::English);
+        let mut output = String::new();
+
+        fs::write(dir.join("a.md"), "apples").unwrap();
+        fs::write(dir.join("b.md"), "bananas").unwrap();
+        client.set_nonblocking(true).unwrap();
+
+        let reindex = Reindex::new(&store, &dir, &mut Ignores::new());
+
+        reindexing.add(
+            reindex,
+            TcpStream::from_std(client),
+            None,
+            ResponseFormat::default(),
+        );
+        while !reindexing.jobs.is_empty() {
+            reindexing.advance(
+                &store,
+                &punctuation(),
+                &acc,
+                &stemmer,
+                &mut throttle,
+                &mut pending,
+                &poll,
+                Token(0),
+            );
+        }
+        reader.read_to_string(&mut output).unwrap();
+
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("0/2\t"));
+        assert!(lines[1].starts_with("1/2\t"));
+        assert_eq!(lines[2], "reindexed 2 file(s)");
+        assert_eq!(
+            store
+                .query_row("SELECT COUNT(*) FROM monitored_file", [], |row| {
+                    row.get::<_, u32>(0)
+                })
+                .unwrap(),
+            2
+        );
+    }
+}
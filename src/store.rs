@@ -0,0 +1,2620 @@
+use chrono::Local;
+use regex::Regex;
+use rusqlite::{
+    params, params_from_iter, CachedStatement, Connection, OpenFlags, OptionalExtension,
+    Params, Row, Statement, ToSql, Transaction,
+};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, str};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::cluster::Cluster;
+use crate::error::Error;
+use crate::indexer::QUARANTINE_FAILURES;
+use crate::links::{is_markdown, note_name};
+use crate::metrics::METRICS;
+use crate::phonetic::soundex;
+use crate::query::{Cancellation, SearchResult};
+use crate::text::EMPTY_HASH;
+use crate::trigrams::fold;
+
+#[derive(Debug)]
+pub struct MonitoredFile {
+    pub id: u32,
+    pub modified: u64,
+    pub path: String,
+    pub hash: Option<String>,
+    // When the file was last indexed, in seconds since the epoch, which
+    // older versions didn't record.
+    pub indexed_at: Option<u64>,
+}
+
+// A query from the query log, with when it came in, in seconds since the
+// epoch, who asked, in multi-user mode, how long it took, and how many
+// results it found.
+#[derive(Debug, PartialEq)]
+pub struct LoggedQuery {
+    pub at: u64,
+    pub uid: Option<u32>,
+    pub query: String,
+    pub micros: u64,
+    pub results: usize,
+}
+
+#[derive(Debug)]
+pub struct WordStem {
+    pub id: u32,
+    pub stem: String,
+}
+
+#[derive(Debug)]
+pub struct IndexTuple {
+    pub file: u32,
+    pub stem: u32,
+    pub offset: u32,
+    pub byte_offset: u32,
+    pub word: String,
+}
+
+// How a file's place in the index changed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChangeKind {
+    Added,
+    Updated,
+    Removed,
+}
+
+impl ChangeKind {
+    // The name configurations and notifications use for the change.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChangeKind::Added => "add",
+            ChangeKind::Updated => "update",
+            ChangeKind::Removed => "remove",
+        }
+    }
+}
+
+// A file whose index just changed, with the stems it now contains, so
+// that anything depending on the index can catch up.
+#[derive(Debug)]
+pub struct IndexChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    pub stems: HashSet<String>,
+}
+
+// Changes to the index since the main loop last looked.
+static INDEX_CHANGES: Mutex<Vec<IndexChange>> = Mutex::new(Vec::new());
+
+// How long to hold on to the index for a file that disappears, in days,
+// unless the configuration says otherwise.
+const DEFAULT_KEEP_DELETED_DAYS: u64 = 7;
+
+// How long to hold on to the index for a file that disappears, in seconds,
+// in case it comes back, as files do when a sync tool replaces them.
+static KEEP_DELETED: OnceLock<u64> = OnceLock::new();
+
+// Read the `keepDeletedDays` item, where zero drops files from the index
+// as soon as they disappear.
+pub fn set_keep_deleted(config: &gjson::Value) {
+    let days = config.get("keepDeletedDays");
+    let days = if days.exists() {
+        days.u64()
+    } else {
+        DEFAULT_KEEP_DELETED_DAYS
+    };
+
+    KEEP_DELETED.get_or_init(|| days * 24 * 60 * 60);
+}
+
+fn keep_deleted() -> u64 {
+    *KEEP_DELETED.get_or_init(|| DEFAULT_KEEP_DELETED_DAYS * 24 * 60 * 60)
+}
+
+// How many of the latest indexing errors to keep, which is plenty to learn
+// why a file isn't searchable, without the table growing forever.
+const ERROR_HISTORY: u32 = 200;
+
+// How many prepared statements to keep around, which needs to cover every
+// fixed query we run, with some room to spare.
+const STATEMENT_CACHE_SIZE: usize = 64;
+
+// The index database.  Queries that we run over and over, like looking up
+// a file for every event, stay prepared in SQLite's statement cache, rather
+// than getting parsed again every time.  Queries whose text changes with
+// their arguments, like a search for some number of stems, skip the cache,
+// so that they don't push out the rest.
+//
+// Reads and writes go through separate connections, with the database in
+// write-ahead logging mode, so that a long search doesn't hold up indexing
+// or the other way around.  Each read sees whatever was last written.
+//
+// A request from a client whose token only sees some folders limits every
+// query that lists files to those folders, for as long as it takes to
+// answer, so that no response can stray outside them.
+pub struct Store {
+    sqlite: Connection,
+    reader: Option<Connection>,
+    folders: RefCell<Option<Vec<String>>>,
+}
+
+// Lifts a Store's limit to some folders when it goes away.
+pub struct FolderLimit<'a> {
+    store: &'a Store,
+}
+
+impl Drop for FolderLimit<'_> {
+    fn drop(&mut self) {
+        self.store.folders.replace(None);
+    }
+}
+
+impl Store {
+    // Open the database at the given path, creating it if necessary, along
+    // with a read-only connection for queries.
+    pub fn open(path: &Path) -> Result<Store, Error> {
+        let sqlite = Connection::open(path)?;
+
+        sqlite.query_row("PRAGMA journal_mode = WAL", [], |row| {
+            row.get::<_, String>(0)
+        })?;
+
+        let reader = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+
+        Ok(Store::new(sqlite, Some(reader)))
+    }
+
+    // Open a database that only lasts as long as the Store does, which
+    // nothing else can connect to, so it reads and writes the same way.
+    pub fn open_in_memory() -> Result<Store, Error> {
+        Ok(Store::new(Connection::open_in_memory()?, None))
+    }
+
+    fn new(sqlite: Connection, reader: Option<Connection>) -> Store {
+        sqlite.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+        if let Some(reader) = &reader {
+            reader.set_prepared_statement_cache_capacity(STATEMENT_CACHE_SIZE);
+        }
+
+        Store {
+            sqlite,
+            reader,
+            folders: RefCell::new(None),
+        }
+    }
+
+    // Limit the queries that list files to some folders, or none, until
+    // the limit goes away.
+    pub fn limit_to_folders(&self, folders: Option<&[PathBuf]>) -> FolderLimit<'_> {
+        self.folders.replace(folders.map(|folders| {
+            folders
+                .iter()
+                .map(|f| path_text(f).trim_end_matches('/').to_string())
+                .collect()
+        }));
+        FolderLimit { store: self }
+    }
+
+    // The SQL condition that keeps a column of paths inside the folders
+    // that queries are limited to, which is always true without a limit.
+    // The folders come from the configuration, rather than from clients,
+    // so quoting them is enough.  The range of paths after the folder's
+    // slash, up to the character after the slash, is everything in it.
+    pub fn in_folders(&self, column: &str) -> String {
+        let folders = self.folders.borrow();
+        let folders = match &*folders {
+            Some(folders) => folders,
+            None => return "1".to_string(),
+        };
+        let conditions = folders
+            .iter()
+            .map(|folder| {
+                let quoted = folder.replace('\'', "''");
+
+                format!(
+                    "{0} = '{1}' OR ({0} >= '{1}/' AND {0} < '{1}0')",
+                    column, quoted
+                )
+            })
+            .collect::<Vec<_>>();
+
+        if conditions.is_empty() {
+            "0".to_string()
+        } else {
+            format!("({})", conditions.join(" OR "))
+        }
+    }
+
+    // The connection for queries.
+    fn reader(&self) -> &Connection {
+        self.reader.as_ref().unwrap_or(&self.sqlite)
+    }
+
+    // Prepare a query through the cache.
+    pub fn prepare(&self, sql: &str) -> rusqlite::Result<CachedStatement<'_>> {
+        self.reader().prepare_cached(sql)
+    }
+
+    // Prepare a query whose text changes with its arguments, without the
+    // cache.
+    pub fn prepare_uncached(&self, sql: &str) -> rusqlite::Result<Statement<'_>> {
+        self.reader().prepare(sql)
+    }
+
+    // Run a statement that changes the database through the cache,
+    // returning how many rows changed.
+    pub fn execute<P: Params>(&self, sql: &str, params: P) -> rusqlite::Result<usize> {
+        self.sqlite.prepare_cached(sql)?.execute(params)
+    }
+
+    // Start a transaction, so that a group of changes, like a file's whole
+    // index, goes in all at once or not at all.  It rolls back unless it's
+    // committed.  The reader doesn't see the changes until then, so reading
+    // them back has to go through the writer.
+    pub fn begin(&self) -> rusqlite::Result<Transaction<'_>> {
+        self.sqlite.unchecked_transaction()
+    }
+
+    // Run a query through the cache, returning its first row.
+    pub fn query_row<T, P, F>(&self, sql: &str, params: P, f: F) -> rusqlite::Result<T>
+    where
+        P: Params,
+        F: FnOnce(&Row<'_>) -> rusqlite::Result<T>,
+    {
+        self.prepare(sql)?.query_row(params, f)
+    }
+
+    // Ensure the required tables are available.
+    pub fn enforce_data_model(&self) {
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS quarantine (
+                  path TEXT PRIMARY KEY,
+                  failures INTEGER NOT NULL,
+                  error TEXT NOT NULL,
+                  updated INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        // What each integrity check found, for `@stats`.
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS integrity_check (
+                  id INTEGER PRIMARY KEY,
+                  checked_at INTEGER NOT NULL,
+                  sampled INTEGER NOT NULL,
+                  missing INTEGER NOT NULL,
+                  stale INTEGER NOT NULL,
+                  drifted INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        // The latest indexing errors, oldest first, as a ring buffer.
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS index_error (
+                  id INTEGER PRIMARY KEY,
+                  path TEXT NOT NULL,
+                  kind TEXT NOT NULL,
+                  error TEXT NOT NULL,
+                  at INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS stats (
+                  id INTEGER PRIMARY KEY,
+                  started INTEGER NOT NULL,
+                  updated INTEGER NOT NULL,
+                  scan_seconds REAL,
+                  files_indexed INTEGER NOT NULL DEFAULT 0,
+                  events_processed INTEGER NOT NULL DEFAULT 0,
+                  queries_served INTEGER NOT NULL DEFAULT 0,
+                  slow_queries INTEGER NOT NULL DEFAULT 0
+                )",
+                [],
+            )
+            .unwrap();
+        self.add_column_if_missing("stats", "slow_queries", "INTEGER NOT NULL DEFAULT 0");
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS monitored_file (
+                  id INTEGER PRIMARY KEY,
+                  path TEXT NOT NULL,
+                  modified INTEGER
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS word_stem (
+                  id INTEGER PRIMARY KEY,
+                  stem TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_reverse_index (
+                  id INTEGER PRIMARY KEY,
+                  file INTEGER NOT NULL,
+                  stem INTEGER NOT NULL,
+                  offset INTEGER NOT NULL,
+                  word TEXT NOT NULL,
+                  FOREIGN KEY(file) REFERENCES monitored_file(id),
+                  FOREIGN KEY(stem) REFERENCES word_stem(id)
+                )",
+                [],
+            )
+            .unwrap();
+        self.add_column_if_missing("file_reverse_index", "byte_offset", "INTEGER");
+        self.add_column_if_missing("file_reverse_index", "word_norm", "TEXT");
+        self.add_column_if_missing("word_stem", "phonetic", "TEXT");
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS stem_trigram (
+                  trigram TEXT NOT NULL,
+                  stem INTEGER NOT NULL,
+                  PRIMARY KEY (trigram, stem)
+                ) WITHOUT ROWID",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS word_stem_phonetic ON word_stem (phonetic)",
+                [],
+            )
+            .unwrap();
+        self.add_column_if_missing("monitored_file", "raw_path", "BLOB");
+        self.add_column_if_missing("monitored_file", "encoding", "TEXT");
+        self.add_column_if_missing("monitored_file", "hash", "TEXT");
+        self.add_column_if_missing("monitored_file", "indexed_at", "INTEGER");
+        self.add_column_if_missing("monitored_file", "words", "INTEGER");
+        self.add_column_if_missing("monitored_file", "language", "TEXT");
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS monitored_file_hash ON monitored_file (hash)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS monitored_file_language
+                   ON monitored_file (language)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_tag (
+                  file INTEGER NOT NULL,
+                  tag TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_tag_tag ON file_tag (tag)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_symbol (
+                  file INTEGER NOT NULL,
+                  symbol TEXT NOT NULL COLLATE NOCASE,
+                  kind TEXT NOT NULL,
+                  line INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_symbol_symbol ON file_symbol (symbol)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS link (
+                  file_from INTEGER NOT NULL,
+                  file_to TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS link_file_to ON link (file_to)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS query_log (
+                  id INTEGER PRIMARY KEY,
+                  at INTEGER NOT NULL,
+                  uid INTEGER,
+                  query TEXT NOT NULL,
+                  micros INTEGER NOT NULL,
+                  results INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS query_log_at ON query_log (at)",
+                [],
+            )
+            .unwrap();
+        // When each scheduled query last ran, and the files it found then,
+        // one per line.
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS scheduled_run (
+                  query TEXT PRIMARY KEY,
+                  ran_at INTEGER NOT NULL,
+                  matches TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        // Files that have disappeared, set aside with their index, until
+        // they come back or we give up on them.
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS deleted_file (
+                  id INTEGER PRIMARY KEY,
+                  path TEXT NOT NULL UNIQUE,
+                  modified INTEGER,
+                  raw_path BLOB,
+                  encoding TEXT,
+                  hash TEXT,
+                  indexed_at INTEGER,
+                  deleted_at INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.add_column_if_missing("deleted_file", "words", "INTEGER");
+        self.add_column_if_missing("deleted_file", "language", "TEXT");
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_vector (
+                  file INTEGER NOT NULL,
+                  passage INTEGER NOT NULL,
+                  vector BLOB NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS cluster (
+                  id INTEGER PRIMARY KEY,
+                  label TEXT NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE TABLE IF NOT EXISTS file_cluster (
+                  file INTEGER PRIMARY KEY,
+                  cluster INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_cluster_cluster
+                   ON file_cluster (cluster)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_reverse_index_stem
+                   ON file_reverse_index (stem)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_reverse_index_file
+                   ON file_reverse_index (file)",
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE INDEX IF NOT EXISTS file_reverse_index_word_norm
+                   ON file_reverse_index (word_norm)",
+                [],
+            )
+            .unwrap();
+        self.enforce_unique_paths();
+        self.fill_phonetic_keys();
+        self.fill_word_counts();
+    }
+
+    // Count the words in files indexed before we kept track, going by the
+    // last position in each file's index, which is as good as a count.
+    fn fill_word_counts(&self) {
+        let filled = self
+            .sqlite
+            .execute(
+                "UPDATE monitored_file SET words = COALESCE(
+                   (SELECT MAX(offset) + 1 FROM file_reverse_index
+                      WHERE file = monitored_file.id), 0)
+                   WHERE words IS NULL",
+                [],
+            )
+            .unwrap();
+
+        if filled > 0 {
+            info!("Counted the words in {} file(s)", filled);
+        }
+    }
+
+    // Work out the phonetic keys for stems indexed before there were any,
+    // leaving an empty key for stems that can't have one, so that they
+    // only get checked once.
+    fn fill_phonetic_keys(&self) {
+        let mut stmt = self
+            .sqlite
+            .prepare("SELECT id, stem FROM word_stem WHERE phonetic IS NULL")
+            .unwrap();
+        let stems = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+
+        if stems.is_empty() {
+            return;
+        }
+
+        info!("Adding phonetic keys for {} stem(s)", stems.len());
+
+        let transaction = self.sqlite.unchecked_transaction().unwrap();
+
+        for (id, stem) in stems {
+            transaction
+                .execute(
+                    "UPDATE word_stem SET phonetic = ? WHERE id = ?",
+                    params![soundex(&stem).unwrap_or_default(), id],
+                )
+                .unwrap();
+        }
+
+        transaction.commit().unwrap();
+    }
+
+    // Work out the normalized words for index entries from before there were
+    // any, which needs the configuration's idea of accents, so it can't
+    // happen with the rest of the data model.
+    pub fn fill_word_norms(&self, accents: &Regex) {
+        let mut stmt = self
+            .sqlite
+            .prepare("SELECT id, word FROM file_reverse_index WHERE word_norm IS NULL")
+            .unwrap();
+        let words = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?))
+            })
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+
+        if words.is_empty() {
+            return;
+        }
+
+        info!("Adding normalized words for {} index entries", words.len());
+
+        let transaction = self.sqlite.unchecked_transaction().unwrap();
+
+        for (id, word) in words {
+            transaction
+                .execute(
+                    "UPDATE file_reverse_index SET word_norm = ? WHERE id = ?",
+                    params![fold(&word, accents), id],
+                )
+                .unwrap();
+        }
+
+        transaction.commit().unwrap();
+    }
+
+    // Add a column to an existing table, for databases from older versions.
+    fn add_column_if_missing(&self, table: &str, column: &str, definition: &str) {
+        let mut stmt = self
+            .sqlite
+            .prepare(&format!("SELECT name FROM pragma_table_info('{}')", table))
+            .unwrap();
+        let exists = stmt
+            .query_map([], |row| row.get::<_, String>(0))
+            .unwrap()
+            .any(|name| name.is_ok_and(|n| n == column));
+
+        if !exists {
+            info!("Adding {}.{} to the database", table, column);
+            self.sqlite
+                .execute(
+                    &format!(
+                        "ALTER TABLE {} ADD COLUMN {} {}",
+                        table, column, definition
+                    ),
+                    [],
+                )
+                .unwrap();
+        }
+    }
+
+    // Make sure that each path has only one row in monitored_file.  Older
+    // databases could pick up duplicates, when an event raced the startup
+    // scan, so we keep the newest row for each path, dropping the index
+    // information for the rest, before SQLite starts enforcing it.  There's
+    // no telling which row has the complete index, so the survivor gets
+    // indexed again.
+    fn enforce_unique_paths(&self) {
+        let exists = self
+            .sqlite
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master
+                   WHERE type = 'index' AND name = 'monitored_file_path'",
+                [],
+                |row| row.get::<_, u32>(0),
+            )
+            .unwrap()
+            > 0;
+
+        if exists {
+            return;
+        }
+
+        let stale = "SELECT id FROM monitored_file
+                       WHERE id NOT IN
+                         (SELECT MAX(id) FROM monitored_file GROUP BY path)";
+        let duplicates = self
+            .sqlite
+            .query_row(&format!("SELECT COUNT(*) FROM ({})", stale), [], |row| {
+                row.get::<_, u32>(0)
+            })
+            .unwrap();
+
+        if duplicates > 0 {
+            info!(
+                "Removing {} duplicate file(s) from the database",
+                duplicates
+            );
+            self.sqlite
+                .execute(
+                    "UPDATE monitored_file SET modified = 0
+                       WHERE path IN
+                         (SELECT path FROM monitored_file
+                            GROUP BY path HAVING COUNT(*) > 1)",
+                    [],
+                )
+                .unwrap();
+        }
+
+        for table in [
+            "file_reverse_index",
+            "file_tag",
+            "file_symbol",
+            "file_cluster",
+            "file_vector",
+        ] {
+            self.sqlite
+                .execute(
+                    &format!("DELETE FROM {} WHERE file IN ({})", table, stale),
+                    [],
+                )
+                .unwrap();
+        }
+
+        self.sqlite
+            .execute(
+                &format!("DELETE FROM link WHERE file_from IN ({})", stale),
+                [],
+            )
+            .unwrap();
+
+        self.sqlite
+            .execute(
+                &format!("DELETE FROM monitored_file WHERE id IN ({})", stale),
+                [],
+            )
+            .unwrap();
+        self.sqlite
+            .execute(
+                "CREATE UNIQUE INDEX monitored_file_path ON monitored_file (path)",
+                [],
+            )
+            .unwrap();
+    }
+
+    // Snapshot the database into the backup folder using SQLite's online
+    // backup, so we don't need to stop indexing, then drop the oldest
+    // snapshots beyond the number we're supposed to retain.
+    pub fn backup(&self, backup_dir: &Path, retention: usize) {
+        let name = format!("intern-{}.sqlite3", Local::now().format("%Y%m%d-%H%M%S"));
+        let destination = backup_dir.join(name);
+
+        if let Err(e) = fs::create_dir_all(backup_dir) {
+            error!("Can't create backup folder {:?}: {}", backup_dir, e);
+            return;
+        }
+
+        match self
+            .sqlite
+            .backup(rusqlite::DatabaseName::Main, &destination, None)
+        {
+            Ok(_) => info!("Backed up index to {:?}", destination),
+            Err(e) => {
+                error!("Can't back up index to {:?}: {}", destination, e);
+                return;
+            }
+        }
+
+        let mut backups = list_backups(backup_dir);
+
+        if retention > 0 && backups.len() > retention {
+            // The names sort by date, so the oldest come first.
+            backups.sort();
+            for old in &backups[..backups.len() - retention] {
+                match fs::remove_file(old) {
+                    Ok(_) => debug!("removed old backup {:?}", old),
+                    Err(e) => warn!("Can't remove old backup {:?}: {}", old, e),
+                }
+            }
+        }
+    }
+
+    // Retrieve file information.
+    pub fn select_file(&self, path_str: &str) -> Result<Option<MonitoredFile>, Error> {
+        let mut fileq = self.prepare(
+            "SELECT id, modified, path, hash, indexed_at FROM monitored_file
+               WHERE path = ?",
+        )?;
+        let mut mod_times = fileq.query_map(params![path_str], monitored_file)?;
+
+        mod_times.next().transpose().map_err(Error::from)
+    }
+
+    // Retrieve all stem information.
+    pub fn select_all_stems(&self) -> rusqlite::Result<HashMap<String, u32>> {
+        let mut result = HashMap::new();
+        let mut stemq = self.prepare("SELECT id, stem FROM word_stem")?;
+        let stem_iter = stemq.query_map([], |row| {
+            Ok(WordStem {
+                id: row.get(0)?,
+                stem: row.get(1)?,
+            })
+        })?;
+
+        for stem in stem_iter {
+            let raw_stem = stem?;
+
+            result.insert(raw_stem.stem.to_string(), raw_stem.id);
+        }
+
+        Ok(result)
+    }
+
+    // Add a file to be indexed, or catch up with it, if something else
+    // added it first.  New files get numbers that no deleted file still
+    // has, so that they can't pick up its index.
+    pub fn insert_file(
+        &self,
+        path: &Path,
+        last_modified: &u64,
+    ) -> Result<MonitoredFile, Error> {
+        let path_str = path_text(path);
+
+        self.execute(
+            "INSERT
+               INTO monitored_file (id, path, modified, raw_path)
+               VALUES (
+                 (SELECT COALESCE(MAX(id), 0) + 1 FROM
+                    (SELECT MAX(id) AS id FROM monitored_file
+                     UNION ALL SELECT MAX(id) FROM deleted_file)),
+                 ?, ?, ?
+               )
+               ON CONFLICT (path) DO UPDATE
+                 SET modified = excluded.modified, raw_path = excluded.raw_path
+            ",
+            params![path_str, last_modified, raw_path(path)],
+        )?;
+        // Through the writer, in case this is part of a transaction.
+        self.sqlite
+            .prepare_cached(
+                "SELECT id, modified, path, hash, indexed_at FROM monitored_file
+                   WHERE path = ?",
+            )?
+            .query_row(params![path_str], monitored_file)
+            .map_err(Error::from)
+    }
+
+    // Find the file on disk behind a path from the index, which only
+    // differs from the path itself when the original isn't UTF-8.
+    pub fn locate_file(&self, path: &str) -> PathBuf {
+        let raw = self
+            .query_row(
+                "SELECT raw_path FROM monitored_file WHERE path = ?",
+                params![path],
+                |row| row.get::<_, Option<Vec<u8>>>(0),
+            )
+            .unwrap_or(None);
+
+        disk_path(path, raw)
+    }
+
+    // Insert a group of stems, with their phonetic keys, returning their
+    // numbers, so that the indexer can add them to the ones it knows about
+    // without reading every stem again.
+    pub fn insert_bulk_stems(
+        &self,
+        stems: Vec<String>,
+    ) -> Result<HashMap<String, u32>, Error> {
+        let mut ids = HashMap::<String, u32>::with_capacity(stems.len());
+
+        // SQLite allows at most 32766 parameters in a statement, and each
+        // stem takes two.
+        for batch in stems.chunks(16383) {
+            let placeholders = batch
+                .iter()
+                .map(|_| "(?, ?)")
+                .collect::<Vec<_>>()
+                .join(", ");
+            let query = format!(
+                "INSERT INTO word_stem (stem, phonetic) VALUES {}",
+                placeholders
+            );
+            let values = batch
+                .iter()
+                .flat_map(|stem| [stem.to_string(), soundex(stem).unwrap_or_default()]);
+
+            self.sqlite.execute(&query, params_from_iter(values))?;
+
+            // Through the writer, in case this is part of a transaction.
+            let placeholders = batch.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+            let mut stmt = self.sqlite.prepare(&format!(
+                "SELECT stem, id FROM word_stem WHERE stem IN ({})",
+                placeholders
+            ))?;
+            let rows = stmt.query_map(params_from_iter(batch), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+
+            for row in rows {
+                let (stem, id) = row?;
+
+                ids.insert(stem, id);
+            }
+        }
+
+        Ok(ids)
+    }
+
+    // Note which stems have words with which trigrams, for `@contains`.
+    // Nothing ever comes out, since a stem that no file uses anymore just
+    // never matches.
+    pub fn insert_trigrams(&self, grams: HashSet<(String, u32)>) -> Result<(), Error> {
+        let grams = grams.into_iter().collect::<Vec<_>>();
+
+        // Each trigram takes two parameters, like each stem.
+        for batch in grams.chunks(16383) {
+            let placeholders = batch
+                .iter()
+                .map(|_| "(?, ?)")
+                .collect::<Vec<_>>()
+                .join(", ");
+            let query = format!(
+                "INSERT OR IGNORE INTO stem_trigram (trigram, stem) VALUES {}",
+                placeholders
+            );
+            let mut values = Vec::<&dyn ToSql>::new();
+
+            for (trigram, stem) in batch {
+                values.push(trigram);
+                values.push(stem);
+            }
+
+            self.sqlite.execute(&query, values.as_slice())?;
+        }
+
+        Ok(())
+    }
+
+    // Find the stems with words that have every one of some trigrams.
+    pub fn stems_with_trigrams(
+        &self,
+        grams: &HashSet<String>,
+    ) -> rusqlite::Result<Vec<u32>> {
+        let placeholders = grams.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!(
+            "SELECT stem FROM stem_trigram WHERE trigram IN ({})
+               GROUP BY stem HAVING COUNT(*) = ?",
+            placeholders
+        );
+        let mut stmt = self.prepare_uncached(&query)?;
+        let mut values = grams.iter().map(|g| g as &dyn ToSql).collect::<Vec<_>>();
+        let count = grams.len();
+
+        values.push(&count);
+
+        let stems = stmt
+            .query_map(values.as_slice(), |row| row.get(0))?
+            .collect();
+
+        stems
+    }
+
+    // Find the stems that sound like a stem, going by their phonetic keys,
+    // which is none at all, for a stem without a key.
+    pub fn stems_sounding_like(&self, stem: &str) -> rusqlite::Result<Vec<WordStem>> {
+        let Some(key) = soundex(stem) else {
+            return Ok(Vec::new());
+        };
+        let mut stmt =
+            self.prepare("SELECT id, stem FROM word_stem WHERE phonetic = ?")?;
+        let stems = stmt
+            .query_map(params![key], |row| {
+                Ok(WordStem {
+                    id: row.get(0)?,
+                    stem: row.get(1)?,
+                })
+            })?
+            .collect();
+
+        stems
+    }
+
+    // Index a file's file-stem-position tuples, along with each word in
+    // the form that exact and prefix matches compare.
+    pub fn insert_bulk_word_tuples(
+        &self,
+        mut words: Vec<IndexTuple>,
+        accents: &Regex,
+    ) -> Result<(), Error> {
+        let mut remainder = Vec::<IndexTuple>::new();
+        // SQLite allows at most 32766 parameters in a statement.
+        let max_values = 5000;
+
+        if words.is_empty() {
+            return Ok(());
+        }
+
+        loop {
+            if words.len() > max_values {
+                remainder = words.split_off(max_values);
+            }
+
+            let placeholders = words
+                .iter()
+                .map(|_| "(?,?,?,?,?,?)")
+                .collect::<Vec<_>>()
+                .join(", ");
+            let query = format!(
+                "INSERT INTO file_reverse_index (file,stem,offset,byte_offset,word,word_norm) VALUES {}",
+                placeholders
+            );
+            let mut values = Vec::<String>::new();
+
+            for word in words {
+                values.push(word.file.to_string());
+                values.push(word.stem.to_string());
+                values.push(word.offset.to_string());
+                values.push(word.byte_offset.to_string());
+                values.push(word.word.to_string());
+                values.push(fold(&word.word, accents));
+            }
+
+            self.sqlite
+                .execute(&query, params_from_iter(values.iter()))?;
+
+            words = remainder;
+            remainder = Vec::<IndexTuple>::new();
+            if words.is_empty() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Add a query to the query log, forgetting any logged before the given
+    // time.
+    pub fn log_query(
+        &self,
+        entry: &LoggedQuery,
+        forget_before: u64,
+    ) -> Result<(), Error> {
+        self.execute(
+            "INSERT INTO query_log (at, uid, query, micros, results)
+               VALUES (?, ?, ?, ?, ?)",
+            params![
+                entry.at,
+                entry.uid,
+                entry.query,
+                entry.micros,
+                entry.results
+            ],
+        )?;
+        self.execute("DELETE FROM query_log WHERE at < ?", params![forget_before])?;
+        Ok(())
+    }
+
+    // The most recent queries in the query log, newest first, only counting
+    // one user's, if given.
+    pub fn query_history(
+        &self,
+        uid: Option<u32>,
+        count: usize,
+    ) -> rusqlite::Result<Vec<LoggedQuery>> {
+        let mut stmt = self.prepare(
+            "SELECT at, uid, query, micros, results FROM query_log
+               WHERE ?1 IS NULL OR uid = ?1
+               ORDER BY at DESC, id DESC LIMIT ?2",
+        )?;
+        let entries = stmt
+            .query_map(params![uid, count], |row| {
+                Ok(LoggedQuery {
+                    at: row.get(0)?,
+                    uid: row.get(1)?,
+                    query: row.get(2)?,
+                    micros: row.get(3)?,
+                    results: row.get(4)?,
+                })
+            })?
+            .collect();
+
+        entries
+    }
+
+    // When a scheduled query last ran, with the files it found, if it ever
+    // has.
+    pub fn last_scheduled_run(
+        &self,
+        query: &str,
+    ) -> rusqlite::Result<Option<(u64, Vec<String>)>> {
+        self.query_row(
+            "SELECT ran_at, matches FROM scheduled_run WHERE query = ?",
+            params![query],
+            |row| {
+                let matches = row.get::<_, String>(1)?;
+
+                Ok((
+                    row.get(0)?,
+                    matches.lines().map(|l| l.to_string()).collect(),
+                ))
+            },
+        )
+        .optional()
+    }
+
+    // Remember when a scheduled query ran and what it found.
+    pub fn record_scheduled_run(
+        &self,
+        query: &str,
+        ran_at: u64,
+        matches: &[String],
+    ) -> rusqlite::Result<()> {
+        self.execute(
+            "INSERT INTO scheduled_run (query, ran_at, matches) VALUES (?1, ?2, ?3)
+               ON CONFLICT (query) DO UPDATE SET ran_at = ?2, matches = ?3",
+            params![query, ran_at, matches.join("\n")],
+        )?;
+        Ok(())
+    }
+
+    // Record the start of this run, returning its ID in the stats table.
+    pub fn start_run(&self, start: &SystemTime) -> i64 {
+        let started = start.duration_since(UNIX_EPOCH).unwrap().as_secs();
+
+        self.execute(
+            "INSERT INTO stats (started, updated) VALUES (?1, ?1)",
+            params![started],
+        )
+        .unwrap();
+        self.sqlite.last_insert_rowid()
+    }
+
+    // Record how long the startup scan took.
+    pub fn record_scan_time(&self, run_id: i64, elapsed: Duration) {
+        self.execute(
+            "UPDATE stats SET scan_seconds = ? WHERE id = ?",
+            params![elapsed.as_secs_f64(), run_id],
+        )
+        .unwrap();
+        self.save_stats(run_id);
+    }
+
+    // Copy the current process's counters into this run's stats.
+    pub fn save_stats(&self, run_id: i64) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let result = self.execute(
+            "UPDATE stats
+               SET updated = ?1, files_indexed = ?2, events_processed = ?3,
+                   queries_served = ?4, slow_queries = ?5
+               WHERE id = ?6
+            ",
+            params![
+                now,
+                METRICS.files_indexed.load(Ordering::Relaxed),
+                METRICS.events_processed.load(Ordering::Relaxed),
+                METRICS.queries_served.load(Ordering::Relaxed),
+                METRICS.slow_queries.load(Ordering::Relaxed),
+                run_id
+            ],
+        );
+
+        if let Err(e) = result {
+            error!("Unable to save statistics: {}", e);
+        }
+    }
+
+    // Check whether a file has failed to index too many times to try again.
+    pub fn is_quarantined(&self, path_str: &str) -> bool {
+        self.query_row(
+            "SELECT failures FROM quarantine WHERE path = ?",
+            params![path_str],
+            |row| row.get::<_, u32>(0),
+        )
+        .is_ok_and(|failures| failures >= QUARANTINE_FAILURES)
+    }
+
+    // Count a failure to index a file, with the most recent error, and add
+    // it to the latest errors.
+    pub fn record_failure(&self, path_str: &str, kind: &str, error: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let result = self.execute(
+            "INSERT INTO quarantine (path, failures, error, updated)
+               VALUES (?1, 1, ?2, ?3)
+               ON CONFLICT(path) DO UPDATE
+                 SET failures = failures + 1, error = ?2, updated = ?3
+            ",
+            params![path_str, error, now],
+        );
+        let remembered = self.remember_error(path_str, kind, error, now);
+
+        if let Err(e) = result.and(remembered) {
+            error!("Unable to record failure for {}: {}", path_str, e);
+        }
+    }
+
+    // Add an error to the latest ones, dropping the oldest past the limit.
+    fn remember_error(
+        &self,
+        path_str: &str,
+        kind: &str,
+        error: &str,
+        now: u64,
+    ) -> rusqlite::Result<usize> {
+        self.execute(
+            "INSERT INTO index_error (path, kind, error, at) VALUES (?, ?, ?, ?)",
+            params![path_str, kind, error, now],
+        )?;
+        self.execute(
+            "DELETE FROM index_error WHERE id <= (SELECT MAX(id) FROM index_error) - ?",
+            params![ERROR_HISTORY],
+        )
+    }
+
+    // Forget past failures of a file that now indexes successfully.
+    pub fn clear_failures(&self, path_str: &str) {
+        if let Err(e) =
+            self.execute("DELETE FROM quarantine WHERE path = ?", params![path_str])
+        {
+            error!("Unable to clear failures for {}: {}", path_str, e);
+        }
+    }
+
+    // Update file's last modification time.
+    pub fn update_file_mod_time(
+        &self,
+        last_modified: &u64,
+        path_str: &str,
+    ) -> Result<(), Error> {
+        self.execute(
+            "UPDATE monitored_file
+               SET modified = ?1
+               WHERE path = ?2
+            ",
+            params![last_modified, path_str],
+        )?;
+        Ok(())
+    }
+
+    // Note that a file's index is up to date as of now, in seconds since the
+    // epoch.
+    pub fn record_indexed(&self, file_id: u32) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.execute(
+            "UPDATE monitored_file SET indexed_at = ? WHERE id = ?",
+            params![now, file_id],
+        )?;
+        Ok(())
+    }
+
+    // Note how many words indexing a file found in it, for ranking.
+    pub fn record_word_count(&self, file_id: u32, words: u32) -> Result<(), Error> {
+        self.execute(
+            "UPDATE monitored_file SET words = ? WHERE id = ?",
+            params![words, file_id],
+        )?;
+        Ok(())
+    }
+
+    // Note the language that a file's modeline chose to normalize its words
+    // in, if it's not the configured one, so that searches can, too.
+    pub fn record_language(
+        &self,
+        file_id: u32,
+        language: Option<&str>,
+    ) -> Result<(), Error> {
+        self.execute(
+            "UPDATE monitored_file SET language = ? WHERE id = ?",
+            params![language, file_id],
+        )?;
+        Ok(())
+    }
+
+    // The languages that files in the index chose for themselves.
+    pub fn languages(&self) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.prepare(
+            "SELECT DISTINCT language FROM monitored_file
+               WHERE language IS NOT NULL ORDER BY language",
+        )?;
+        let languages = stmt.query_map([], |row| row.get(0))?.collect();
+
+        languages
+    }
+
+    // Note the encoding we read a file's text in.
+    pub fn record_encoding(&self, file_id: u32, encoding: &str) -> Result<(), Error> {
+        self.execute(
+            "UPDATE monitored_file SET encoding = ? WHERE id = ?",
+            params![encoding, file_id],
+        )?;
+        Ok(())
+    }
+
+    // Remember a fingerprint of a file's contents, for telling whether it
+    // changed when its modification time can't be trusted, and for finding
+    // copies.
+    pub fn record_hash(&self, file_id: u32, hash: &str) -> Result<(), Error> {
+        self.execute(
+            "UPDATE monitored_file SET hash = ? WHERE id = ?",
+            params![hash, file_id],
+        )?;
+        Ok(())
+    }
+
+    // Replace the clusters of similar files with a new set, all at once, so
+    // that queries never see half of each.
+    pub fn replace_clusters(&self, clusters: &[Cluster]) -> Result<(), Error> {
+        let transaction = self.sqlite.unchecked_transaction()?;
+
+        transaction.execute("DELETE FROM file_cluster", [])?;
+        transaction.execute("DELETE FROM cluster", [])?;
+        for (id, cluster) in clusters.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO cluster (id, label) VALUES (?, ?)",
+                params![id, cluster.label],
+            )?;
+            for file in &cluster.files {
+                transaction.execute(
+                    "INSERT INTO file_cluster (file, cluster) VALUES (?, ?)",
+                    params![file, id],
+                )?;
+            }
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    // The other files in the same cluster as a file, or nothing, if it
+    // isn't in one.
+    pub fn cluster_members(&self, path: &str) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT f.path FROM file_cluster fc
+               JOIN monitored_file f ON f.id = fc.file
+               WHERE fc.cluster =
+                 (SELECT fc2.cluster FROM file_cluster fc2
+                    JOIN monitored_file f2 ON f2.id = fc2.file
+                    WHERE f2.path = ?1)
+                 AND f.path != ?1 AND {}
+               ORDER BY f.path",
+            self.in_folders("f.path")
+        ))?;
+        let paths = stmt.query_map(params![path], |row| row.get(0))?.collect();
+
+        paths
+    }
+
+    // Find groups of files with the same contents, biggest groups first,
+    // leaving out empty files, which are all alike without being copies of
+    // each other.
+    pub fn duplicate_files(&self) -> rusqlite::Result<Vec<Vec<String>>> {
+        let mut groups = Vec::<Vec<String>>::new();
+        let mut last_hash = None::<String>;
+        let folders = self.in_folders("path");
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT hash, path FROM monitored_file
+               WHERE {0} AND hash IN
+                 (SELECT hash FROM monitored_file
+                    WHERE hash IS NOT NULL AND hash != ? AND {0}
+                    GROUP BY hash HAVING COUNT(*) > 1)
+               ORDER BY hash, path",
+            folders
+        ))?;
+        let files = stmt.query_map(params![EMPTY_HASH], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?;
+
+        for file in files {
+            let (hash, path) = file?;
+
+            match groups.last_mut() {
+                Some(group) if last_hash.as_ref() == Some(&hash) => group.push(path),
+                _ => groups.push(vec![path]),
+            }
+
+            last_hash = Some(hash);
+        }
+
+        groups.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+        Ok(groups)
+    }
+
+    // Drop files that no longer exist from the index, in case we missed the
+    // events for their removal.
+    pub fn purge_missing_files(&self) -> Result<(), Error> {
+        self.purge_missing(|_| true)
+    }
+
+    // Drop files that no longer exist in one folder from the index, for a
+    // folder we poll, rather than watch.
+    pub fn purge_missing_files_in(&self, folder: &Path) -> Result<(), Error> {
+        self.purge_missing(|path| path.starts_with(folder))
+    }
+
+    // Drop every file in a folder from the index, for a folder that's been
+    // marked to stay out of it, returning how many there were.
+    pub fn remove_files_in(&self, folder: &Path) -> Result<usize, Error> {
+        let mut removed = 0;
+
+        for (id, path, location) in self.file_locations()? {
+            if location.starts_with(folder) {
+                info!("Removing {} from the index", path);
+                self.remove_file(id, &path)?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    fn purge_missing(&self, within: impl Fn(&Path) -> bool) -> Result<(), Error> {
+        for (id, path, location) in self.file_locations()? {
+            if within(&location) && !location.exists() {
+                info!("Removing missing file {} from the index", path);
+                self.delete_file(id, &path)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Every indexed file, with its path and where it is on disk.
+    fn file_locations(&self) -> Result<Vec<(u32, String, PathBuf)>, Error> {
+        let mut stmt = self.prepare("SELECT id, path, raw_path FROM monitored_file")?;
+        let files = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, u32>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<Vec<u8>>>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(files
+            .into_iter()
+            .map(|(id, path, raw)| {
+                let location = disk_path(&path, raw);
+
+                (id, path, location)
+            })
+            .collect())
+    }
+
+    // Set aside a file that has disappeared, along with its index, so that
+    // it can come back without indexing it all over again, or drop it, if
+    // we don't keep deleted files.  Either way, searches stop finding it.
+    pub fn delete_file(&self, file_id: u32, path: &str) -> Result<(), Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        if keep_deleted() == 0 {
+            return self.remove_file(file_id, path);
+        }
+
+        // Only the latest file at any path can come back.
+        if let Some(earlier) = self
+            .query_row(
+                "SELECT id FROM deleted_file WHERE path = ?",
+                params![path],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()?
+        {
+            self.drop_deleted(earlier)?;
+        }
+
+        let transaction = self.sqlite.unchecked_transaction()?;
+
+        transaction.execute(
+            "INSERT INTO deleted_file
+               (id, path, modified, raw_path, encoding, hash, indexed_at, words,
+                language, deleted_at)
+               SELECT id, path, modified, raw_path, encoding, hash, indexed_at, words,
+                      language, ?
+                 FROM monitored_file WHERE id = ?",
+            params![now, file_id],
+        )?;
+        transaction
+            .execute("DELETE FROM monitored_file WHERE id = ?", params![file_id])?;
+        transaction.commit()?;
+        record_index_change(path, ChangeKind::Removed, HashSet::new());
+        self.purge_deleted(Some(now.saturating_sub(keep_deleted())))?;
+        Ok(())
+    }
+
+    // Bring back a deleted file that has reappeared, with its index, which
+    // is still good if the file hasn't changed, returning whether there was
+    // one to bring back.
+    pub fn restore_file(&self, path: &str) -> Result<bool, Error> {
+        let file_id = match self
+            .query_row(
+                "SELECT id FROM deleted_file WHERE path = ?",
+                params![path],
+                |row| row.get::<_, u32>(0),
+            )
+            .optional()?
+        {
+            Some(file_id) => file_id,
+            None => return Ok(false),
+        };
+        let transaction = self.sqlite.unchecked_transaction()?;
+
+        transaction.execute(
+            "INSERT INTO monitored_file
+               (id, path, modified, raw_path, encoding, hash, indexed_at, words,
+                language)
+               SELECT id, path, modified, raw_path, encoding, hash, indexed_at, words,
+                      language
+                 FROM deleted_file WHERE id = ?",
+            params![file_id],
+        )?;
+        transaction.execute("DELETE FROM deleted_file WHERE id = ?", params![file_id])?;
+        transaction.commit()?;
+        info!("Restoring {} to the index", path);
+        record_index_change(path, ChangeKind::Added, self.file_stems(file_id)?);
+        Ok(true)
+    }
+
+    // The deleted files, with when they disappeared, most recent first.
+    pub fn deleted_files(&self) -> rusqlite::Result<Vec<(String, u64)>> {
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT path, deleted_at FROM deleted_file WHERE {}
+               ORDER BY deleted_at DESC, path",
+            self.in_folders("path")
+        ))?;
+        let files = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+
+        files
+    }
+
+    // Give up on the files deleted at or before a time, or on all of them,
+    // and their indexes, returning how many there were.
+    pub fn purge_deleted(&self, before: Option<u64>) -> Result<usize, Error> {
+        let mut stmt = self.prepare(
+            "SELECT id FROM deleted_file WHERE ?1 IS NULL OR deleted_at <= ?1",
+        )?;
+        let files = stmt
+            .query_map(params![before], |row| row.get::<_, u32>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for file_id in &files {
+            self.drop_deleted(*file_id)?;
+        }
+
+        Ok(files.len())
+    }
+
+    fn drop_deleted(&self, file_id: u32) -> Result<(), Error> {
+        self.clear_index_for(file_id)?;
+        self.execute("DELETE FROM file_cluster WHERE file = ?", params![file_id])?;
+        self.execute("DELETE FROM deleted_file WHERE id = ?", params![file_id])?;
+        Ok(())
+    }
+
+    // Everything a file contributes to searches, the way the indexer
+    // reports it, with tags and definitions marked.
+    fn file_stems(&self, file_id: u32) -> Result<HashSet<String>, Error> {
+        let mut stmt = self.prepare(
+            "SELECT s.stem FROM file_reverse_index i JOIN word_stem s ON s.id = i.stem
+               WHERE i.file = ?1
+             UNION SELECT 'tag:' || tag FROM file_tag WHERE file = ?1
+             UNION SELECT 'def:' || LOWER(symbol) FROM file_symbol WHERE file = ?1",
+        )?;
+        let stems = stmt
+            .query_map(params![file_id], |row| row.get(0))?
+            .collect::<Result<HashSet<String>, _>>()?;
+
+        Ok(stems)
+    }
+
+    // Drop a file from the index entirely.  A file that's only changed
+    // stays in its cluster until the next time the files get clustered.
+    pub fn remove_file(&self, file_id: u32, path: &str) -> Result<(), Error> {
+        self.clear_index_for(file_id)?;
+        self.execute("DELETE FROM file_cluster WHERE file = ?", params![file_id])?;
+        self.execute("DELETE FROM monitored_file WHERE id = ?", params![file_id])?;
+        record_index_change(path, ChangeKind::Removed, HashSet::new());
+        Ok(())
+    }
+
+    // Wipe index information for a file.
+    pub fn clear_index_for(&self, file_id: u32) -> Result<(), Error> {
+        self.execute(
+            "DELETE FROM file_reverse_index WHERE file = ?",
+            params![file_id],
+        )?;
+        self.execute("DELETE FROM file_tag WHERE file = ?", params![file_id])?;
+        self.execute("DELETE FROM file_symbol WHERE file = ?", params![file_id])?;
+        self.execute("DELETE FROM link WHERE file_from = ?", params![file_id])?;
+        self.execute("DELETE FROM file_vector WHERE file = ?", params![file_id])?;
+        Ok(())
+    }
+
+    // Find the files that have every one of the given tags.
+    pub fn files_with_tags(&self, tags: &[String]) -> rusqlite::Result<HashSet<String>> {
+        let placeholders = tags.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!(
+            "SELECT f.path FROM file_tag t JOIN monitored_file f ON f.id = t.file
+               WHERE t.tag IN ({}) AND {}
+               GROUP BY f.path
+               HAVING COUNT(DISTINCT t.tag) = {}",
+            placeholders,
+            self.in_folders("f.path"),
+            tags.len()
+        );
+        let mut stmt = self.prepare_uncached(&query)?;
+        let paths = stmt
+            .query_map(params_from_iter(tags.iter()), |row| row.get(0))?
+            .collect();
+
+        paths
+    }
+
+    // Find the files that have every one of the given words, exactly, but
+    // ignoring case and, usually, accents, which the index of normalized
+    // words finds without reading every word.
+    pub fn files_with_words(
+        &self,
+        words: &[String],
+        accents: &Regex,
+    ) -> rusqlite::Result<HashSet<String>> {
+        let mut folded = words.iter().map(|w| fold(w, accents)).collect::<Vec<_>>();
+
+        folded.sort();
+        folded.dedup();
+
+        let placeholders = folded.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!(
+            "SELECT f.path FROM file_reverse_index i
+               JOIN monitored_file f ON f.id = i.file
+               WHERE i.word_norm IN ({}) AND {}
+               GROUP BY f.path
+               HAVING COUNT(DISTINCT i.word_norm) = {}",
+            placeholders,
+            self.in_folders("f.path"),
+            folded.len()
+        );
+        let mut stmt = self.prepare_uncached(&query)?;
+        let paths = stmt
+            .query_map(params_from_iter(folded.iter()), |row| row.get(0))?
+            .collect();
+
+        paths
+    }
+
+    // Find the files that define every one of the given symbols, ignoring
+    // case.
+    pub fn files_defining(
+        &self,
+        symbols: &[String],
+    ) -> rusqlite::Result<HashSet<String>> {
+        let placeholders = symbols.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let query = format!(
+            "SELECT f.path FROM file_symbol s JOIN monitored_file f ON f.id = s.file
+               WHERE s.symbol IN ({}) AND {}
+               GROUP BY f.path
+               HAVING COUNT(DISTINCT LOWER(s.symbol)) = {}",
+            placeholders,
+            self.in_folders("f.path"),
+            symbols.len()
+        );
+        let mut stmt = self.prepare_uncached(&query)?;
+        let paths = stmt
+            .query_map(params_from_iter(symbols.iter()), |row| row.get(0))?
+            .collect();
+
+        paths
+    }
+
+    // The line where a file defines the first of the given symbols.
+    pub fn definition_line(&self, path: &str, symbols: &[String]) -> Option<u32> {
+        symbols.iter().find_map(|symbol| {
+            self.query_row(
+                "SELECT s.line FROM file_symbol s JOIN monitored_file f ON f.id = s.file
+                   WHERE f.path = ? AND s.symbol = ?
+                   ORDER BY s.line LIMIT 1",
+                params![path, symbol],
+                |row| row.get(0),
+            )
+            .ok()
+        })
+    }
+
+    // The files that a note links to, with a wiki link standing for every
+    // note by that name, or for itself, in brackets, if there isn't one.
+    pub fn links_from(&self, path: &str) -> rusqlite::Result<Vec<String>> {
+        // Wiki links are names, rather than paths, until they're looked up.
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT DISTINCT l.file_to FROM link l JOIN monitored_file f
+               ON f.id = l.file_from
+               WHERE f.path = ? AND {} AND (l.file_to NOT LIKE '%/%' OR {})
+               ORDER BY l.file_to",
+            self.in_folders("f.path"),
+            self.in_folders("l.file_to")
+        ))?;
+        let targets = stmt
+            .query_map(params![path], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        let mut result = Vec::<String>::new();
+
+        for target in targets {
+            if target.contains('/') {
+                result.push(target);
+            } else {
+                match self.notes_named(&target)? {
+                    notes if notes.is_empty() => result.push(format!("[[{}]]", target)),
+                    notes => result.extend(notes),
+                }
+            }
+        }
+
+        result.sort();
+        result.dedup();
+        Ok(result)
+    }
+
+    // The notes that link to a file, by its path or, for a Markdown note,
+    // by its name.
+    pub fn backlinks_to(&self, path: &str) -> rusqlite::Result<Vec<String>> {
+        let name = if is_markdown(path) {
+            note_name(path)
+        } else {
+            path.to_string()
+        };
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT DISTINCT f.path FROM link l JOIN monitored_file f
+               ON f.id = l.file_from
+               WHERE l.file_to IN (?, ?) AND {}
+               ORDER BY f.path",
+            self.in_folders("f.path")
+        ))?;
+        let paths = stmt
+            .query_map(params![path, name], |row| row.get(0))?
+            .collect();
+
+        paths
+    }
+
+    // The Markdown notes that a wiki link's name could mean, in whatever
+    // folder.  LIKE ignores case, the way note names do, but needs its
+    // wildcards escaped.
+    fn notes_named(&self, name: &str) -> rusqlite::Result<Vec<String>> {
+        let escaped = name
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        let mut stmt = self.prepare_uncached(&format!(
+            "SELECT path FROM monitored_file
+               WHERE (path LIKE ?1 ESCAPE '\\' OR path LIKE ?2 ESCAPE '\\') AND {}
+               ORDER BY path",
+            self.in_folders("path")
+        ))?;
+        let paths = stmt
+            .query_map(
+                params![
+                    format!("%/{}.md", escaped),
+                    format!("%/{}.markdown", escaped)
+                ],
+                |row| row.get(0),
+            )?
+            .collect();
+
+        paths
+    }
+
+    // The words of a file from the index, as they appeared in it, between two
+    // positions, inclusive.  The parts of a word share its position, and
+    // the whole word is the longest.
+    pub fn words_between(
+        &self,
+        path: &str,
+        first: u32,
+        last: u32,
+    ) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.prepare(
+            "SELECT i.word, MAX(LENGTH(i.word)) FROM file_reverse_index i
+               JOIN monitored_file f ON f.id = i.file
+               WHERE f.path = ? AND i.offset BETWEEN ? AND ?
+               GROUP BY i.offset
+               ORDER BY i.offset",
+        )?;
+        let words = stmt
+            .query_map(params![path, first, last], |row| row.get(0))?
+            .collect();
+
+        words
+    }
+
+    // Every spelling of the indexed words that start with a prefix, ignoring
+    // case and, usually, accents, with how many times each appears.  This
+    // compares a range of normalized words, rather than using LIKE, so that
+    // it can use their index.
+    pub fn words_starting_with(
+        &self,
+        prefix: &str,
+        accents: &Regex,
+    ) -> rusqlite::Result<Vec<(String, u32)>> {
+        let first = fold(prefix, accents);
+        let last = format!("{}{}", first, char::MAX);
+        let mut stmt = self.prepare(
+            "SELECT word, COUNT(*) FROM file_reverse_index
+               WHERE word_norm >= ? AND word_norm < ?
+                 AND file IN (SELECT id FROM monitored_file)
+               GROUP BY word",
+        )?;
+        let words = stmt
+            .query_map(params![first, last], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+
+        words
+    }
+
+    // Retrieve stem information from the index, or nothing, once the search
+    // gets cancelled.
+    #[instrument(level = "debug", skip_all, fields(stems = stems.len()))]
+    pub fn search_index(
+        &self,
+        stems: Vec<WordStem>,
+        cancel: &Cancellation,
+    ) -> rusqlite::Result<Vec<SearchResult>> {
+        let mut result = Vec::<SearchResult>::new();
+        let placeholders = stems.iter().map(|_| "(?)").collect::<Vec<_>>().join(", ");
+        let query = format!(
+            "SELECT f.path, i.word, i.stem, i.offset, i.byte_offset, f.modified, COALESCE(f.words, 0) FROM file_reverse_index i JOIN monitored_file f ON f.id = i.file WHERE i.stem IN ({}) AND {} ORDER BY f.path, i.stem, i.offset",
+            placeholders,
+            self.in_folders("f.path")
+        );
+        let ids = stems.iter().map(|s| s.id);
+        let mut stemq = self.prepare_uncached(&query)?;
+        let index_entries = stemq.query_map(params_from_iter(ids), |row| {
+            Ok(SearchResult {
+                path: row.get(0)?,
+                word: row.get(1)?,
+                stem: row.get(2)?,
+                offset: row.get(3)?,
+                byte_offset: row.get(4)?,
+                modified: row.get(5)?,
+                words: row.get(6)?,
+            })
+        })?;
+
+        for (count, ie) in index_entries.enumerate() {
+            if cancel.is_cancelled_after(count) {
+                return Ok(Vec::new());
+            }
+
+            result.push(ie?);
+        }
+
+        METRICS
+            .index_rows
+            .fetch_add(result.len() as u64, Ordering::Relaxed);
+        Ok(result)
+    }
+}
+
+// Find the backups we've made in a folder.
+fn list_backups(backup_dir: &Path) -> Vec<PathBuf> {
+    match fs::read_dir(backup_dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                let name = p.file_name().unwrap_or_default().to_string_lossy();
+                name.starts_with("intern-") && name.ends_with(".sqlite3")
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+// How long ago we made the most recent backup, so that restarting the
+// daemon doesn't trigger a new backup every time.
+pub fn latest_backup_age(backup_dir: &Path) -> Option<Duration> {
+    list_backups(backup_dir)
+        .iter()
+        .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+        .filter_map(|t| SystemTime::now().duration_since(t).ok())
+        .min()
+}
+
+// Read a file's row from `monitored_file`.
+fn monitored_file(row: &Row<'_>) -> rusqlite::Result<MonitoredFile> {
+    Ok(MonitoredFile {
+        id: row.get(0)?,
+        modified: row.get(1)?,
+        path: row.get(2)?,
+        hash: row.get(3)?,
+        indexed_at: row.get(4)?,
+    })
+}
+
+// The text we store and report for a path.  Paths aren't necessarily
+// UTF-8, like Latin-1 names from old archives, so those get replacement
+// characters, with the original bytes kept alongside them.
+pub fn path_text(path: &Path) -> String {
+    path.to_string_lossy().to_string()
+}
+
+// The original bytes of a path that isn't valid UTF-8, or nothing, for the
+// usual path that the text already describes.
+#[cfg(unix)]
+fn raw_path(path: &Path) -> Option<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    match path.to_str() {
+        Some(_) => None,
+        None => Some(path.as_os_str().as_bytes().to_vec()),
+    }
+}
+
+#[cfg(not(unix))]
+fn raw_path(_path: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+// Rebuild a path from what we store for it.
+#[cfg(unix)]
+fn disk_path(path: &str, raw: Option<Vec<u8>>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+
+    match raw {
+        Some(bytes) => PathBuf::from(std::ffi::OsString::from_vec(bytes)),
+        None => PathBuf::from(path),
+    }
+}
+
+#[cfg(not(unix))]
+fn disk_path(path: &str, _raw: Option<Vec<u8>>) -> PathBuf {
+    PathBuf::from(path)
+}
+
+// Note that a file's index changed.
+pub fn record_index_change(path: &str, kind: ChangeKind, stems: HashSet<String>) {
+    INDEX_CHANGES.lock().unwrap().push(IndexChange {
+        path: path.to_string(),
+        kind,
+        stems,
+    });
+}
+
+// Collect the changes to the index since we last checked.
+pub fn take_index_changes() -> Vec<IndexChange> {
+    std::mem::take(&mut *INDEX_CHANGES.lock().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::accents;
+    use crate::testing::{open_store, ScratchDir};
+
+    fn add_file(store: &Store, path: &str) -> u32 {
+        store.insert_file(Path::new(path), &0).unwrap().id
+    }
+
+    #[test]
+    fn data_model_can_be_enforced_again() {
+        let store = open_store();
+
+        store.enforce_data_model();
+    }
+
+    #[test]
+    fn indexed_words_can_be_found_and_cleared() {
+        let store = open_store();
+        let file = add_file(&store, "/a.md");
+        let stems = store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+        let fox = WordStem {
+            id: stems["fox"],
+            stem: "fox".to_string(),
+        };
+
+        store
+            .insert_bulk_word_tuples(
+                vec![IndexTuple {
+                    file,
+                    stem: stems["fox"],
+                    offset: 0,
+                    byte_offset: 4,
+                    word: "Foxes".to_string(),
+                }],
+                &accents(&gjson::parse("{}")),
+            )
+            .unwrap();
+
+        let found = store
+            .search_index(vec![fox], &Cancellation::never())
+            .unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "/a.md");
+        assert_eq!(found[0].byte_offset, Some(4));
+
+        store.clear_index_for(file).unwrap();
+
+        let fox = WordStem {
+            id: stems["fox"],
+            stem: "fox".to_string(),
+        };
+
+        assert!(store
+            .search_index(vec![fox], &Cancellation::never())
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn prefixes_match_normalized_words() {
+        let store = open_store();
+        let accents = accents(&gjson::parse("{}"));
+        let file = add_file(&store, "/a.md");
+        let stems = store.insert_bulk_stems(vec!["menag".to_string()]).unwrap();
+        let tuples = ["Ménage", "menagerie", "MÉNAGE", "manage"]
+            .iter()
+            .enumerate()
+            .map(|(offset, word)| IndexTuple {
+                file,
+                stem: stems["menag"],
+                offset: offset as u32,
+                byte_offset: 0,
+                word: word.to_string(),
+            })
+            .collect();
+
+        store.insert_bulk_word_tuples(tuples, &accents).unwrap();
+
+        // An entry from before there were normalized words.
+        store
+            .execute(
+                "INSERT INTO file_reverse_index (file, stem, offset, word)
+                   VALUES (?, ?, 4, 'Menagerie')",
+                params![file, stems["menag"]],
+            )
+            .unwrap();
+        store.fill_word_norms(&accents);
+
+        let mut words = store.words_starting_with("MENAG", &accents).unwrap();
+
+        words.sort();
+        assert_eq!(
+            words,
+            [
+                ("Menagerie".to_string(), 1),
+                ("MÉNAGE".to_string(), 1),
+                ("Ménage".to_string(), 1),
+                ("menagerie".to_string(), 1),
+            ]
+        );
+
+        let plan = store
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT word FROM file_reverse_index
+                   WHERE word_norm >= 'a' AND word_norm < 'b'",
+                [],
+                |row| row.get::<_, String>(3),
+            )
+            .unwrap();
+
+        assert!(plan.contains("file_reverse_index_word_norm"), "{}", plan);
+        // Deleted files keep their index, but not their words.
+        store.delete_file(file, "/a.md").unwrap();
+        assert!(store
+            .words_starting_with("menag", &accents)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn exact_words_leave_out_the_rest_of_the_stem() {
+        let store = open_store();
+        let accents = accents(&gjson::parse("{}"));
+        let stems = store.insert_bulk_stems(vec!["run".to_string()]).unwrap();
+
+        for (path, word) in [("/a.md", "Running"), ("/b.md", "runs")] {
+            let file = add_file(&store, path);
+
+            store
+                .insert_bulk_word_tuples(
+                    vec![IndexTuple {
+                        file,
+                        stem: stems["run"],
+                        offset: 0,
+                        byte_offset: 0,
+                        word: word.to_string(),
+                    }],
+                    &accents,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            store
+                .files_with_words(&["running".to_string()], &accents)
+                .unwrap(),
+            HashSet::from(["/a.md".to_string()])
+        );
+        assert!(store
+            .files_with_words(&["running".to_string(), "runs".to_string()], &accents)
+            .unwrap()
+            .is_empty());
+
+        let plan = store
+            .query_row(
+                "EXPLAIN QUERY PLAN SELECT file FROM file_reverse_index
+                   WHERE word_norm IN ('running')",
+                [],
+                |row| row.get::<_, String>(3),
+            )
+            .unwrap();
+
+        assert!(plan.contains("file_reverse_index_word_norm"), "{}", plan);
+    }
+
+    #[test]
+    fn paths_stay_unique() {
+        let store = open_store();
+        let file = add_file(&store, "/a.md");
+
+        assert_eq!(add_file(&store, "/a.md"), file);
+
+        // A database from before paths had to be unique.
+        store.execute("DROP INDEX monitored_file_path", []).unwrap();
+        for _ in 0..2 {
+            store
+                .execute("INSERT INTO monitored_file (path) VALUES ('/a.md')", [])
+                .unwrap();
+        }
+        store
+            .execute(
+                "INSERT INTO file_tag (file, tag) VALUES (?, 'work')",
+                params![file],
+            )
+            .unwrap();
+        store.enforce_data_model();
+
+        let (count, newest, modified) = store
+            .query_row(
+                "SELECT COUNT(*), MAX(id), MAX(modified) FROM monitored_file",
+                [],
+                |row| {
+                    Ok((
+                        row.get::<_, u32>(0)?,
+                        row.get::<_, u32>(1)?,
+                        row.get::<_, Option<u64>>(2)?,
+                    ))
+                },
+            )
+            .unwrap();
+
+        assert_eq!(count, 1);
+        assert_eq!(modified, Some(0));
+        assert_eq!(add_file(&store, "/a.md"), newest);
+        assert!(store
+            .files_with_tags(&["work".to_string()])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn files_with_tags_requires_every_tag() {
+        let store = open_store();
+        let both = add_file(&store, "/both.md");
+        let one = add_file(&store, "/one.md");
+
+        for (file, tag) in [(both, "work"), (both, "urgent"), (one, "work")] {
+            store
+                .execute(
+                    "INSERT INTO file_tag (file, tag) VALUES (?, ?)",
+                    params![file, tag],
+                )
+                .unwrap();
+        }
+
+        let tags = ["urgent".to_string(), "work".to_string()];
+        let tagged = store.files_with_tags(&tags).unwrap();
+
+        assert_eq!(tagged, HashSet::from(["/both.md".to_string()]));
+    }
+
+    #[test]
+    fn folder_limits_narrow_what_queries_find() {
+        let store = open_store();
+        let stems = store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+        let mut tuples = Vec::<IndexTuple>::new();
+
+        for path in ["/home/me/work/a.md", "/home/me/workshop/b.md", "/c.md"] {
+            let file = add_file(&store, path);
+
+            store
+                .execute(
+                    "INSERT INTO file_tag (file, tag) VALUES (?, 'work')",
+                    params![file],
+                )
+                .unwrap();
+            tuples.push(IndexTuple {
+                file,
+                stem: stems["fox"],
+                offset: 0,
+                byte_offset: 0,
+                word: "fox".to_string(),
+            });
+        }
+
+        store
+            .insert_bulk_word_tuples(tuples, &accents(&gjson::parse("{}")))
+            .unwrap();
+
+        let fox = || WordStem {
+            id: stems["fox"],
+            stem: "fox".to_string(),
+        };
+        let folders = [PathBuf::from("/home/me/work/")];
+        let limit = store.limit_to_folders(Some(&folders));
+        let found = store
+            .search_index(vec![fox()], &Cancellation::never())
+            .unwrap();
+
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].path, "/home/me/work/a.md");
+        assert_eq!(
+            store.files_with_tags(&["work".to_string()]).unwrap(),
+            HashSet::from(["/home/me/work/a.md".to_string()])
+        );
+        drop(limit);
+        assert_eq!(
+            store
+                .search_index(vec![fox()], &Cancellation::never())
+                .unwrap()
+                .len(),
+            3
+        );
+
+        let _limit = store.limit_to_folders(Some(&[]));
+
+        assert_eq!(store.in_folders("path"), "0");
+    }
+
+    #[test]
+    fn files_defining_ignores_case() {
+        let store = open_store();
+        let both = add_file(&store, "/server.rs");
+        let one = add_file(&store, "/client.rs");
+        let definitions = [
+            (both, "handle_queries"),
+            (both, "Request"),
+            (one, "Request"),
+        ];
+
+        for (file, symbol) in definitions {
+            store
+                .execute(
+                    "INSERT INTO file_symbol (file, symbol, kind, line)
+                       VALUES (?, ?, 'fn', 1)",
+                    params![file, symbol],
+                )
+                .unwrap();
+        }
+
+        let symbols = ["handle_queries".to_string(), "request".to_string()];
+
+        assert_eq!(
+            store.files_defining(&symbols).unwrap(),
+            HashSet::from(["/server.rs".to_string()])
+        );
+        assert_eq!(store.definition_line("/client.rs", &symbols), Some(1));
+    }
+
+    #[test]
+    fn wiki_links_find_notes_by_name() {
+        let store = open_store();
+        let today = add_file(&store, "/notes/daily/today.md");
+        let plan = add_file(&store, "/notes/Project_X.md");
+
+        add_file(&store, "/notes/ProjectXX.md");
+        for (file, target) in [
+            (today, "project_x"),
+            (today, "someday"),
+            (plan, "/notes/daily/today.md"),
+        ] {
+            store
+                .execute(
+                    "INSERT INTO link (file_from, file_to) VALUES (?, ?)",
+                    params![file, target],
+                )
+                .unwrap();
+        }
+
+        assert_eq!(
+            store.links_from("/notes/daily/today.md").unwrap(),
+            ["/notes/Project_X.md", "[[someday]]"]
+        );
+        assert_eq!(
+            store.backlinks_to("/notes/Project_X.md").unwrap(),
+            ["/notes/daily/today.md"]
+        );
+        assert_eq!(
+            store.backlinks_to("/notes/daily/today.md").unwrap(),
+            ["/notes/Project_X.md"]
+        );
+    }
+
+    #[test]
+    fn duplicates_share_a_hash() {
+        let store = open_store();
+        let files = [
+            ("/notes/a.md", "1111"),
+            ("/backup/a.md", "1111"),
+            ("/notes/b.md", "2222"),
+            ("/notes/empty.md", EMPTY_HASH),
+            ("/backup/empty.md", EMPTY_HASH),
+        ];
+
+        for (path, hash) in files {
+            let file = add_file(&store, path);
+
+            store.record_hash(file, hash).unwrap();
+        }
+
+        add_file(&store, "/notes/unhashed.md");
+        assert_eq!(
+            store.duplicate_files().unwrap(),
+            [["/backup/a.md".to_string(), "/notes/a.md".to_string()]]
+        );
+    }
+
+    #[test]
+    fn clusters_replace_each_other() {
+        let store = open_store();
+        let files = ["/a.md", "/b.md", "/c.md"].map(|path| add_file(&store, path));
+        let cluster = |label: &str, files: &[u32]| Cluster {
+            label: label.to_string(),
+            files: files.to_vec(),
+        };
+
+        store
+            .replace_clusters(&[cluster("garden", &files), cluster("tax", &[])])
+            .unwrap();
+        assert_eq!(store.cluster_members("/b.md").unwrap(), ["/a.md", "/c.md"]);
+        store
+            .replace_clusters(&[
+                cluster("garden", &files[..2]),
+                cluster("tax", &files[2..]),
+            ])
+            .unwrap();
+        assert_eq!(store.cluster_members("/b.md").unwrap(), ["/a.md"]);
+        assert!(store.cluster_members("/c.md").unwrap().is_empty());
+        assert!(store.cluster_members("/d.md").unwrap().is_empty());
+    }
+
+    #[test]
+    fn query_history_forgets_old_queries() {
+        let store = open_store();
+        let entry = |at: u64, uid: Option<u32>, query: &str| LoggedQuery {
+            at,
+            uid,
+            query: query.to_string(),
+            micros: 1500,
+            results: 3,
+        };
+
+        store.log_query(&entry(100, None, "old"), 0).unwrap();
+        store
+            .log_query(&entry(200, Some(1000), "fox"), 150)
+            .unwrap();
+        store.log_query(&entry(300, Some(0), "dog"), 150).unwrap();
+        assert_eq!(
+            store.query_history(None, 10).unwrap(),
+            [entry(300, Some(0), "dog"), entry(200, Some(1000), "fox")]
+        );
+        assert_eq!(
+            store.query_history(Some(1000), 10).unwrap(),
+            [entry(200, Some(1000), "fox")]
+        );
+        assert_eq!(store.query_history(None, 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn indexing_time_is_recorded() {
+        let store = open_store();
+        let indexed = add_file(&store, "/a.md");
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        add_file(&store, "/b.md");
+        store.record_indexed(indexed).unwrap();
+
+        let mut stmt = store
+            .prepare("SELECT path FROM monitored_file WHERE indexed_at >= ?")
+            .unwrap();
+        let paths = stmt
+            .query_map(params![started], |row| row.get::<_, String>(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap();
+
+        let indexed_at = |path| store.select_file(path).unwrap().unwrap().indexed_at;
+
+        assert_eq!(paths, ["/a.md"]);
+        assert!(indexed_at("/a.md") >= Some(started));
+        assert_eq!(indexed_at("/b.md"), None);
+    }
+
+    #[test]
+    fn deleted_files_can_come_back() {
+        let store = open_store();
+        let file = add_file(&store, "/a.md");
+
+        store.record_hash(file, "abc").unwrap();
+        store.delete_file(file, "/a.md").unwrap();
+        assert!(store.select_file("/a.md").unwrap().is_none());
+        assert_eq!(store.deleted_files().unwrap()[0].0, "/a.md");
+        // A new file can't take the deleted file's place in the index.
+        assert_ne!(add_file(&store, "/b.md"), file);
+        assert!(store.restore_file("/a.md").unwrap());
+        assert!(!store.restore_file("/c.md").unwrap());
+
+        let restored = store.select_file("/a.md").unwrap().unwrap();
+
+        assert_eq!(restored.id, file);
+        assert_eq!(restored.hash.as_deref(), Some("abc"));
+        store.delete_file(file, "/a.md").unwrap();
+        assert_eq!(store.purge_deleted(None).unwrap(), 1);
+        assert!(store.deleted_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn restored_files_keep_their_word_counts() {
+        let store = open_store();
+        let file = add_file(&store, "/a.md");
+        let stems = store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+        let fox = WordStem {
+            id: stems["fox"],
+            stem: "fox".to_string(),
+        };
+
+        store
+            .insert_bulk_word_tuples(
+                vec![IndexTuple {
+                    file,
+                    stem: stems["fox"],
+                    offset: 0,
+                    byte_offset: 0,
+                    word: "fox".to_string(),
+                }],
+                &accents(&gjson::parse("{}")),
+            )
+            .unwrap();
+        store.record_word_count(file, 120).unwrap();
+        store.delete_file(file, "/a.md").unwrap();
+        assert!(store.restore_file("/a.md").unwrap());
+
+        let found = store
+            .search_index(vec![fox], &Cancellation::never())
+            .unwrap();
+
+        // Ranking weighs matches by the file's length.
+        assert_eq!(found[0].words, 120);
+        store.delete_file(file, "/a.md").unwrap();
+        assert_eq!(store.purge_deleted(None).unwrap(), 1);
+        assert!(store.deleted_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn marked_folders_leave_the_index() {
+        let store = open_store();
+
+        add_file(&store, "/notes/a.md");
+        add_file(&store, "/notes/photos/b.jpg");
+        add_file(&store, "/notes/photos2/c.jpg");
+        assert_eq!(
+            store.remove_files_in(Path::new("/notes/photos")).unwrap(),
+            1
+        );
+        assert!(store.select_file("/notes/photos/b.jpg").unwrap().is_none());
+        assert!(store.select_file("/notes/photos2/c.jpg").unwrap().is_some());
+        assert!(store.deleted_files().unwrap().is_empty());
+    }
+
+    #[test]
+    fn repeated_failures_quarantine_a_file() {
+        let store = open_store();
+
+        for _ in 1..QUARANTINE_FAILURES {
+            store.record_failure("/bad.md", "io", "broken");
+        }
+
+        assert!(!store.is_quarantined("/bad.md"));
+        store.record_failure("/bad.md", "io", "broken");
+        assert!(store.is_quarantined("/bad.md"));
+        store.clear_failures("/bad.md");
+        assert!(!store.is_quarantined("/bad.md"));
+    }
+
+    #[test]
+    fn only_the_latest_errors_stay() {
+        let store = open_store();
+
+        for i in 0..ERROR_HISTORY + 5 {
+            store.record_failure(&format!("/bad{}.md", i), "panic", "broken");
+        }
+
+        let oldest: String = store
+            .query_row(
+                "SELECT path FROM index_error ORDER BY id LIMIT 1",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        let count: u32 = store
+            .query_row("SELECT COUNT(*) FROM index_error", [], |row| row.get(0))
+            .unwrap();
+
+        assert_eq!(count, ERROR_HISTORY);
+        assert_eq!(oldest, "/bad5.md");
+    }
+
+    #[test]
+    fn queries_read_what_the_indexer_writes() {
+        let dir = ScratchDir::new("store");
+
+        let store = Store::open(&dir.join("intern.sqlite3")).unwrap();
+
+        store.enforce_data_model();
+
+        let file = add_file(&store, "/a.md");
+        let journal = store
+            .sqlite
+            .query_row("PRAGMA journal_mode", [], |row| row.get::<_, String>(0))
+            .unwrap();
+
+        assert_eq!(journal, "wal");
+        assert_eq!(store.select_file("/a.md").unwrap().unwrap().id, file);
+        // Queries can't change anything.
+        assert!(store
+            .prepare("DELETE FROM monitored_file")
+            .and_then(|mut stmt| stmt.execute([]))
+            .is_err());
+        drop(store);
+    }
+
+    #[test]
+    fn transactions_go_in_whole_or_not_at_all() {
+        let dir = ScratchDir::new("begin");
+
+        let store = Store::open(&dir.join("intern.sqlite3")).unwrap();
+
+        store.enforce_data_model();
+
+        // The writer reads back what it wrote before the reader can see it.
+        let transaction = store.begin().unwrap();
+        let file = add_file(&store, "/a.md");
+        let stems = store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+
+        assert!(file > 0);
+        assert_eq!(stems.keys().collect::<Vec<_>>(), ["fox"]);
+        assert!(store.select_file("/a.md").unwrap().is_none());
+        drop(transaction);
+        assert!(store.select_all_stems().unwrap().is_empty());
+
+        let transaction = store.begin().unwrap();
+
+        add_file(&store, "/a.md");
+        store.insert_bulk_stems(vec!["fox".to_string()]).unwrap();
+        transaction.commit().unwrap();
+
+        let more = store.insert_bulk_stems(vec!["dog".to_string()]).unwrap();
+
+        assert!(store.select_file("/a.md").unwrap().is_some());
+        assert_eq!(more.keys().collect::<Vec<_>>(), ["dog"]);
+        assert_eq!(store.select_all_stems().unwrap().len(), 2);
+        drop(store);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_keep_their_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let store = open_store();
+        let latin1 = Path::new(OsStr::from_bytes(b"/archive/caf\xe9.txt"));
+        let file = store.insert_file(latin1, &0).unwrap();
+
+        assert_eq!(file.path, "/archive/caf\u{fffd}.txt");
+        assert_eq!(store.locate_file(&file.path), latin1);
+        assert_eq!(store.locate_file("/notes/a.md"), Path::new("/notes/a.md"));
+    }
+}
@@ -0,0 +1,149 @@
+use mio::net::TcpStream;
+use std::collections::HashSet;
+use std::io::Write;
+use tracing::{debug, info};
+
+use crate::protocol::ResponseFormat;
+use crate::store::IndexChange;
+use crate::users::{visible, User};
+
+// A query that waits for files to start matching it, remembering which
+// files already do, so that each one only counts once.
+pub struct StandingQuery {
+    stems: HashSet<String>,
+    matching: HashSet<String>,
+}
+
+impl StandingQuery {
+    pub fn new(stems: HashSet<String>, matching: Vec<String>) -> StandingQuery {
+        StandingQuery {
+            stems,
+            matching: matching.into_iter().collect(),
+        }
+    }
+
+    // Whether a freshly indexed file has just started matching.  A file
+    // that stops matching is forgotten, so that it counts again if it
+    // comes back.
+    pub fn starts_matching(&mut self, change: &IndexChange) -> bool {
+        let (before, after) = self.observe(change);
+
+        after && !before
+    }
+
+    // Catch up with a change, returning whether the file matched before it
+    // and whether it matches now.
+    pub fn observe(&mut self, change: &IndexChange) -> (bool, bool) {
+        if matches(&self.stems, &change.stems) {
+            (!self.matching.insert(change.path.to_string()), true)
+        } else {
+            (self.matching.remove(&change.path), false)
+        }
+    }
+}
+
+// A standing query, held open so that we can tell the client whenever a
+// file starts matching it.
+struct Subscription {
+    client: TcpStream,
+    query: StandingQuery,
+    user: Option<User>,
+    format: ResponseFormat,
+}
+
+// Every client waiting to hear about newly matching files.
+#[derive(Default)]
+pub struct Subscriptions {
+    subscribers: Vec<Subscription>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Subscriptions {
+        Subscriptions::default()
+    }
+
+    // Keep a client's connection along with what it's waiting for, who it's
+    // for, in multi-user mode, and how it wants to hear about it.
+    pub fn add(
+        &mut self,
+        client: TcpStream,
+        query: StandingQuery,
+        user: Option<User>,
+        format: ResponseFormat,
+    ) {
+        self.subscribers.push(Subscription {
+            client,
+            query,
+            user,
+            format,
+        });
+        info!("{} subscription(s)", self.subscribers.len());
+    }
+
+    // Tell every subscriber whose query a freshly indexed file now matches,
+    // and who can read it, forgetting any client that has gone away.
+    pub fn notify(&mut self, change: &IndexChange) {
+        self.subscribers.retain_mut(|sub| {
+            let news = sub.query.starts_matching(change)
+                && visible(sub.user.as_ref(), &change.path);
+
+            if !news {
+                return true;
+            }
+
+            let line = sub.format.lines(&[change.path.to_string()]);
+
+            match sub.client.write_all(&line) {
+                Ok(_) => true,
+                Err(e) => {
+                    debug!("Dropping subscriber: {:?}", e);
+                    false
+                }
+            }
+        });
+    }
+}
+
+// A file matches a standing query when it has every stem the query does.
+fn matches(query: &HashSet<String>, file: &HashSet<String>) -> bool {
+    !query.is_empty() && query.is_subset(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::ChangeKind;
+
+    fn stems(words: &[&str]) -> HashSet<String> {
+        words.iter().map(|w| w.to_string()).collect()
+    }
+
+    fn change(path: &str, words: &[&str]) -> IndexChange {
+        IndexChange {
+            path: path.to_string(),
+            kind: ChangeKind::Updated,
+            stems: stems(words),
+        }
+    }
+
+    #[test]
+    fn matches_needs_every_stem() {
+        let file = stems(&["todo", "urgent", "tag:work"]);
+
+        assert!(matches(&stems(&["todo", "urgent"]), &file));
+        assert!(matches(&stems(&["todo", "tag:work"]), &file));
+        assert!(!matches(&stems(&["todo", "later"]), &file));
+        assert!(!matches(&stems(&[]), &file));
+    }
+
+    #[test]
+    fn standing_queries_only_count_new_matches() {
+        let mut query = StandingQuery::new(stems(&["todo"]), vec!["/a.md".to_string()]);
+
+        assert!(!query.starts_matching(&change("/a.md", &["todo"])));
+        assert!(query.starts_matching(&change("/b.md", &["todo"])));
+        assert!(!query.starts_matching(&change("/b.md", &["todo", "more"])));
+        assert!(!query.starts_matching(&change("/b.md", &["done"])));
+        assert!(query.starts_matching(&change("/b.md", &["todo"])));
+    }
+}
@@ -0,0 +1,293 @@
+use regex::Regex;
+use std::path::Path;
+use std::sync::OnceLock;
+
+// A kind of definition and a pattern that finds it.
+type Pattern = (&'static str, &'static str);
+
+// Patterns for definitions in the languages we recognize, by extension,
+// each pattern with the kind of definition it finds, unless it says so
+// itself with a `kind` group, and the defined `name`.  Like ctags, this
+// only looks at one line at a time, so it misses some definitions and
+// finds the odd thing that isn't one, which is fine for getting around.
+const LANGUAGES: [(&[&str], &[Pattern]); 9] = [
+    (
+        &["rs"],
+        &[
+            (
+                "",
+                concat!(
+                    r"^\s*(?:pub(?:\([^)]*\))?\s+)?(?:(?:async|const|unsafe|extern)\s+)*",
+                    r"(?:\x22[^\x22]*\x22\s+)?",
+                    r"(?P<kind>fn|struct|enum|trait|type|mod|union|const|static)\s+",
+                    r"(?:mut\s+)?(?P<name>[A-Za-z_]\w*)",
+                ),
+            ),
+            ("macro", r"^\s*macro_rules!\s*(?P<name>[A-Za-z_]\w*)"),
+        ],
+    ),
+    (
+        &["py", "pyw"],
+        &[(
+            "",
+            r"^\s*(?:async\s+)?(?P<kind>def|class)\s+(?P<name>[A-Za-z_]\w*)",
+        )],
+    ),
+    (
+        &["js", "jsx", "mjs", "cjs", "ts", "tsx"],
+        &[
+            (
+                "",
+                concat!(
+                    r"^\s*(?:export\s+)?(?:default\s+)?(?:declare\s+)?",
+                    r"(?:abstract\s+)?(?:async\s+)?",
+                    r"(?P<kind>function|class|interface|type|enum)\*?\s+",
+                    r"(?P<name>[A-Za-z_$][\w$]*)",
+                ),
+            ),
+            // Functions assigned to names, including arrow functions.
+            (
+                "function",
+                concat!(
+                    r"^\s*(?:export\s+)?(?:const|let|var)\s+(?P<name>[A-Za-z_$][\w$]*)",
+                    r"\s*=\s*(?:async\s+)?",
+                    r"(?:function\b|\([^)]*\)\s*=>|[A-Za-z_$][\w$]*\s*=>)",
+                ),
+            ),
+        ],
+    ),
+    (
+        &["go"],
+        &[
+            ("func", r"^func\s+(?:\([^)]*\)\s*)?(?P<name>[A-Za-z_]\w*)"),
+            ("type", r"^type\s+(?P<name>[A-Za-z_]\w*)"),
+        ],
+    ),
+    (
+        &["c", "h", "cc", "cpp", "cxx", "hh", "hpp", "hxx"],
+        &[
+            (
+                "",
+                concat!(
+                    r"^\s*(?:typedef\s+)?(?P<kind>struct|class|enum|union|namespace)\s+",
+                    r"(?P<name>[A-Za-z_]\w*)\s*(?:[:{]|$)",
+                ),
+            ),
+            ("macro", r"^\s*#\s*define\s+(?P<name>[A-Za-z_]\w*)"),
+            // A function definition starts at the left margin, with any
+            // type before the name, and doesn't end with a semicolon.
+            (
+                "function",
+                concat!(
+                    r"^(?:[A-Za-z_][\w\s\*&:<>,]*[\s\*&])?",
+                    r"(?P<name>[A-Za-z_][\w:~]*)\s*\([^;]*$",
+                ),
+            ),
+        ],
+    ),
+    (
+        &["java", "cs", "kt", "scala"],
+        &[
+            (
+                "",
+                concat!(
+                    r"^\s*(?:(?:public|private|protected|internal|static|final|",
+                    r"abstract|sealed|data|open|partial)\s+)*",
+                    r"(?P<kind>class|interface|enum|record|struct|object|trait)\s+",
+                    r"(?P<name>[A-Za-z_]\w*)",
+                ),
+            ),
+            (
+                "function",
+                concat!(
+                    r"^\s*(?:(?:public|private|protected|override)\s+)*",
+                    r"fun\s+(?P<name>[A-Za-z_]\w*)",
+                ),
+            ),
+            // A method needs at least one modifier, or it's too much like
+            // a call.
+            (
+                "method",
+                concat!(
+                    r"^\s*(?:(?:public|private|protected|internal|static|final|",
+                    r"abstract|synchronized|override|virtual|async)\s+)+",
+                    r"[\w<>\[\],.?]+\s+(?P<name>[A-Za-z_]\w*)\s*\(",
+                ),
+            ),
+        ],
+    ),
+    (
+        &["rb"],
+        &[(
+            "",
+            r"^\s*(?P<kind>def|class|module)\s+(?:self\.)?(?P<name>[A-Za-z_]\w*[?!=]?)",
+        )],
+    ),
+    (
+        &["sh", "bash", "zsh"],
+        &[(
+            "function",
+            r"^\s*(?:function\s+)?(?P<name>[A-Za-z_][\w-]*)\s*\(\)",
+        )],
+    ),
+    (
+        &["php"],
+        &[(
+            "",
+            concat!(
+                r"^\s*(?:(?:abstract|final|public|private|protected|static)\s+)*",
+                r"(?P<kind>function|class|interface|trait)\s+&?(?P<name>[A-Za-z_]\w*)",
+            ),
+        )],
+    ),
+];
+
+// Words that look like function names to the C pattern, but aren't.
+const C_KEYWORDS: [&str; 6] = ["if", "for", "while", "switch", "return", "sizeof"];
+
+// The patterns, compiled, for each language.
+static PATTERNS: OnceLock<Vec<Vec<(&str, Regex)>>> = OnceLock::new();
+
+// A definition, with its name, its kind, like `fn` or `class`, and the
+// line it's on, counting from one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: String,
+    pub line: u32,
+}
+
+// Where we are in a source file, which has to carry over from one chunk of
+// the file to the next, since a chunk can end in the middle of a line.
+pub struct Symbols {
+    patterns: &'static [(&'static str, Regex)],
+    line: u32,
+    partial: String,
+}
+
+impl Symbols {
+    // Start looking for definitions in a file, or nothing, if it isn't in
+    // a language we recognize.
+    pub fn for_path(path: &Path) -> Option<Symbols> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let language = LANGUAGES
+            .iter()
+            .position(|(extensions, _)| extensions.contains(&extension.as_str()))?;
+        let patterns = PATTERNS.get_or_init(|| {
+            LANGUAGES
+                .iter()
+                .map(|(_, patterns)| {
+                    patterns
+                        .iter()
+                        .map(|(kind, pattern)| (*kind, Regex::new(pattern).unwrap()))
+                        .collect()
+                })
+                .collect()
+        });
+
+        Some(Symbols {
+            patterns: &patterns[language],
+            line: 0,
+            partial: String::new(),
+        })
+    }
+
+    // The definitions in the next chunk of the file, holding on to any
+    // line that the chunk leaves unfinished.
+    pub fn read(&mut self, chunk: &str) -> Vec<Symbol> {
+        let text = std::mem::take(&mut self.partial) + chunk;
+        let (lines, rest) = match text.rfind('\n') {
+            Some(end) => text.split_at(end + 1),
+            None => ("", text.as_str()),
+        };
+        let symbols = lines.lines().filter_map(|l| self.define(l)).collect();
+
+        self.partial = rest.to_string();
+        symbols
+    }
+
+    // The definition on the last line, if the file doesn't end in a line
+    // break.
+    pub fn finish(&mut self) -> Option<Symbol> {
+        let line = std::mem::take(&mut self.partial);
+
+        self.define(&line)
+    }
+
+    fn define(&mut self, line: &str) -> Option<Symbol> {
+        self.line += 1;
+        self.patterns.iter().find_map(|(kind, pattern)| {
+            let found = pattern.captures(line)?;
+            let name = found.name("name")?.as_str();
+            let kind = found.name("kind").map_or(*kind, |k| k.as_str());
+
+            if C_KEYWORDS.contains(&name) {
+                None
+            } else {
+                Some(Symbol {
+                    name: name.to_string(),
+                    kind: kind.to_string(),
+                    line: self.line,
+                })
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(path: &str, chunks: &[&str]) -> Vec<(String, String, u32)> {
+        let mut symbols = Symbols::for_path(Path::new(path)).unwrap();
+        let mut found = chunks
+            .iter()
+            .flat_map(|chunk| symbols.read(chunk))
+            .collect::<Vec<_>>();
+
+        found.extend(symbols.finish());
+        found
+            .into_iter()
+            .map(|s| (s.name, s.kind, s.line))
+            .collect()
+    }
+
+    #[test]
+    fn definitions_come_with_their_lines() {
+        let rust = "use std::io;\n\npub fn handle_queries(\n) {}\n\
+                    pub(crate) struct Request;\nimpl Request {\n\
+                    async fn read() {}\n}\n";
+        let c = "static int\ncount_words(const char *text)\n{\n    if (text) {\n";
+
+        assert_eq!(
+            names("server.rs", &[rust]),
+            [
+                ("handle_queries".to_string(), "fn".to_string(), 3),
+                ("Request".to_string(), "struct".to_string(), 5),
+                ("read".to_string(), "fn".to_string(), 7),
+            ]
+        );
+        assert_eq!(
+            names("words.c", &[c]),
+            [("count_words".to_string(), "function".to_string(), 2)]
+        );
+        assert!(Symbols::for_path(Path::new("notes.md")).is_none());
+    }
+
+    #[test]
+    fn lines_carry_over_between_chunks() {
+        assert_eq!(
+            names(
+                "app.py",
+                &["import os\nclass Sto", "re:\n    def ", "save(self): pass"]
+            ),
+            [
+                ("Store".to_string(), "class".to_string(), 2),
+                ("save".to_string(), "def".to_string(), 3),
+            ]
+        );
+    }
+}
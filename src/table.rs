@@ -0,0 +1,200 @@
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::info;
+
+// Whether to index the columns of spreadsheet exports that hold nothing but
+// numbers.
+static TABULAR_NUMBERS: OnceLock<bool> = OnceLock::new();
+
+// Read the `tabularNumbers` item, which indexes every column of CSV and TSV
+// files, rather than leaving out the columns of numbers.
+pub fn set_tabular_numbers(config: &gjson::Value) {
+    let numbers = config.get("tabularNumbers").bool();
+
+    if numbers {
+        info!("Indexing numeric columns in tables");
+    }
+
+    TABULAR_NUMBERS.get_or_init(|| numbers);
+}
+
+// A cell of a table, as the column it's in, whether it's in the header, and
+// where it starts and ends in the text.
+struct Cell {
+    column: usize,
+    header: bool,
+    start: usize,
+    end: usize,
+}
+
+// Where we are in a CSV or TSV file, which has to carry over from one chunk
+// of the file to the next, since a chunk can end in the middle of a row,
+// or even inside quotes.
+pub struct Table {
+    delimiter: char,
+    numeric: Option<Vec<bool>>,
+    column: usize,
+    header: bool,
+    quoted: bool,
+}
+
+impl Table {
+    // Start reading a file as a table, or nothing, if it isn't one, or if
+    // every column gets indexed anyway, in which case it's just text.
+    pub fn for_path(path: &Path) -> Option<Table> {
+        let extension = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let delimiter = match extension.as_str() {
+            "csv" => ',',
+            "tsv" | "tab" => '\t',
+            _ => return None,
+        };
+
+        if *TABULAR_NUMBERS.get_or_init(|| false) {
+            None
+        } else {
+            Some(Table::new(delimiter))
+        }
+    }
+
+    fn new(delimiter: char) -> Table {
+        Table {
+            delimiter,
+            numeric: None,
+            column: 0,
+            header: true,
+            quoted: false,
+        }
+    }
+
+    // The parts of the next chunk of the file worth indexing, as the byte
+    // ranges of the header and of the cells outside of numeric columns.
+    // The first chunk decides which columns only hold numbers.
+    pub fn cells(&mut self, chunk: &str) -> Vec<(usize, usize)> {
+        let cells = self.split(chunk);
+        let numeric = self
+            .numeric
+            .get_or_insert_with(|| numeric_columns(chunk, &cells));
+
+        cells
+            .iter()
+            .filter(|c| c.header || !numeric.get(c.column).copied().unwrap_or(false))
+            .map(|c| (c.start, c.end))
+            .collect()
+    }
+
+    // Break text into cells, minding quotes, which can hide delimiters
+    // and line breaks.  A doubled quote inside quotes ends them and starts
+    // them again, which comes to the same thing.
+    fn split(&mut self, chunk: &str) -> Vec<Cell> {
+        let mut cells = Vec::<Cell>::new();
+        let mut start = 0;
+
+        for (i, c) in chunk.char_indices() {
+            if self.quoted {
+                self.quoted = c != '"';
+                continue;
+            }
+
+            if c == '"' {
+                self.quoted = true;
+            } else if c == self.delimiter || c == '\n' {
+                cells.push(self.cell(start, i));
+                start = i + 1;
+                if c == '\n' {
+                    self.column = 0;
+                    self.header = false;
+                } else {
+                    self.column += 1;
+                }
+            }
+        }
+
+        // The rest of the last cell comes with the next chunk.
+        cells.push(self.cell(start, chunk.len()));
+        cells
+    }
+
+    fn cell(&self, start: usize, end: usize) -> Cell {
+        Cell {
+            column: self.column,
+            header: self.header,
+            start,
+            end,
+        }
+    }
+}
+
+// Decide which columns hold nothing but numbers, like amounts, counts, and
+// dates, going by a sample of the table, where a column needs at least one
+// number and no words to count.
+fn numeric_columns(chunk: &str, cells: &[Cell]) -> Vec<bool> {
+    let columns = cells.iter().map(|c| c.column + 1).max().unwrap_or(0);
+    let mut numbers = vec![false; columns];
+    let mut words = vec![false; columns];
+
+    for cell in cells.iter().filter(|c| !c.header) {
+        let text = &chunk[cell.start..cell.end];
+
+        if text.chars().any(char::is_alphabetic) {
+            words[cell.column] = true;
+        } else if text.chars().any(char::is_numeric) {
+            numbers[cell.column] = true;
+        }
+    }
+
+    numbers.iter().zip(&words).map(|(n, w)| *n && !w).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cell_text(table: &mut Table, chunk: &str) -> Vec<String> {
+        table
+            .cells(chunk)
+            .iter()
+            .map(|(start, end)| chunk[*start..*end].to_string())
+            .filter(|c| !c.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn numeric_columns_stay_out() {
+        let mut table = Table::new(',');
+        let first =
+            "Customer,Amount,Note\nAcme,\"1,200.00\",\"late, again\nand again\"\n";
+        let second = "Initech,35,3\nUmbrella Corp,12,none\n";
+
+        assert_eq!(
+            cell_text(&mut table, first),
+            [
+                "Customer",
+                "Amount",
+                "Note",
+                "Acme",
+                "\"late, again\nand again\""
+            ]
+        );
+        assert_eq!(
+            cell_text(&mut table, second),
+            ["Initech", "3", "Umbrella Corp", "none"]
+        );
+    }
+
+    #[test]
+    fn rows_carry_over_between_chunks() {
+        let mut table = Table::new('\t');
+
+        assert_eq!(
+            cell_text(&mut table, "Name\tCity\nAda "),
+            ["Name", "City", "Ada "]
+        );
+        assert_eq!(
+            cell_text(&mut table, "Lovelace\tLondon\n"),
+            ["Lovelace", "London"]
+        );
+    }
+}
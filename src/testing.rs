@@ -0,0 +1,53 @@
+// Fixtures that the tests all over the crate share.
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::store::Store;
+
+// An empty index in memory, with every table in place.
+pub fn open_store() -> Store {
+    let store = Store::open_in_memory().unwrap();
+
+    store.enforce_data_model();
+    store
+}
+
+// A folder for one test to work in, which goes away when the test ends,
+// whether or not it passes.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    // Start from an empty folder, named for the test, in case an earlier
+    // run got killed before it could clean up.
+    pub fn new(name: &str) -> ScratchDir {
+        let path =
+            std::env::temp_dir().join(format!("intern-{}-{}", name, std::process::id()));
+
+        fs::remove_dir_all(&path).ok();
+        fs::create_dir_all(&path).unwrap();
+        ScratchDir { path }
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
@@ -0,0 +1,424 @@
+use chardetng::EncodingDetector;
+use encoding_rs::{Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use memmap2::Mmap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+use std::str;
+use std::sync::OnceLock;
+use tracing::{debug, info};
+
+// How much of a file to check for NUL bytes when deciding whether it's
+// binary, which is the same amount git checks.
+const BINARY_SNIFF_BYTES: usize = 8000;
+
+// How much of a file to decode at a time while indexing it, so that even a
+// file of hundreds of megabytes never has to fit in memory all at once.
+pub const CHUNK_BYTES: usize = 1 << 20;
+
+// Whether to map large files into memory to index them, rather than
+// reading them.
+static MEMORY_MAP: OnceLock<bool> = OnceLock::new();
+
+// Read the `memoryMap` item, which maps files of a chunk or more into
+// memory while indexing them, saving a copy of every chunk.
+pub fn set_memory_map(config: &gjson::Value) {
+    let map = config.get("memoryMap").bool();
+
+    if map {
+        info!("Mapping large files into memory to index them");
+    }
+
+    MEMORY_MAP.get_or_init(|| map);
+}
+
+// The fingerprint of an empty file.
+pub const EMPTY_HASH: &str = "cbf29ce484222325";
+
+// Fingerprint a file's bytes, with 64-bit FNV-1a, which is quick and,
+// unlike the standard library's hasher, the same from one build to the
+// next, since the fingerprints go in the database.
+pub fn content_hash(path: &Path) -> io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; CHUNK_BYTES];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+
+    loop {
+        let size = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => size,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+
+        for byte in &buffer[..size] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    Ok(format!("{:016x}", hash))
+}
+
+// Read a file as text, whatever its encoding, along with the name of the
+// encoding, or nothing, for a binary file.  Anything that doesn't decode
+// turns into replacement characters.
+pub fn read_text(path: &Path) -> io::Result<Option<(String, &'static str)>> {
+    let bytes = fs::read(path)?;
+    let (encoding, bom) = match sniff_encoding(&bytes, true) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom..]);
+
+    Ok(Some((text.into_owned(), encoding.name())))
+}
+
+// Turn byte offsets into a file's decoded text, which is what the index
+// keeps, into offsets into the file itself, counting any byte-order mark
+// and the bytes of the file's own encoding, for clients that seek to
+// them.  This is nothing, for a file that isn't text anymore or an offset
+// that isn't between characters.
+pub fn source_offsets(path: &Path, offsets: &[usize]) -> io::Result<Option<Vec<usize>>> {
+    let bytes = fs::read(path)?;
+    let (encoding, bom) = match sniff_encoding(&bytes, true) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    if encoding == UTF_8 {
+        return Ok(Some(offsets.iter().map(|offset| bom + offset).collect()));
+    }
+
+    let (text, _) = encoding.decode_without_bom_handling(&bytes[bom..]);
+    // Encoding into UTF-16 isn't something encoding_rs does, but its
+    // lengths are easy to count.
+    let encoded_len = |part: &str| {
+        if encoding == UTF_16LE || encoding == UTF_16BE {
+            part.encode_utf16().count() * 2
+        } else {
+            encoding.encode(part).0.len()
+        }
+    };
+    let mut sorted = offsets.to_vec();
+
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut mapped = Vec::<(usize, usize)>::with_capacity(sorted.len());
+    let (mut last, mut position) = (0, bom);
+
+    for offset in sorted {
+        match text.get(last..offset) {
+            Some(part) => position += encoded_len(part),
+            None => return Ok(None),
+        }
+
+        mapped.push((offset, position));
+        last = offset;
+    }
+
+    Ok(Some(
+        offsets
+            .iter()
+            .map(|offset| {
+                let at = mapped.binary_search_by_key(offset, |&(o, _)| o).unwrap();
+
+                mapped[at].1
+            })
+            .collect(),
+    ))
+}
+
+// Where a file's bytes come from, either read into a buffer a chunk at a
+// time, or mapped into memory, along with how far into the file we are,
+// so that they get decoded right where they are.
+enum Source {
+    Buffered(File, Vec<u8>),
+    Mapped(Mmap, usize),
+}
+
+// A file's text, decoded a chunk at a time.  Every chunk but the last ends
+// in whitespace, so that no word straddles two chunks, and the chunks run
+// together make up the same text that read_text returns.
+pub struct TextChunks {
+    source: Source,
+    encoding: &'static Encoding,
+    decoder: Decoder,
+    chunk_bytes: usize,
+    carry: String,
+    done: bool,
+}
+
+impl TextChunks {
+    // Start reading a file in chunks of about the given number of bytes,
+    // working out its encoding from the first chunk, or nothing, for a
+    // binary file.
+    pub fn open(path: &Path, chunk_bytes: usize) -> io::Result<Option<TextChunks>> {
+        let map = *MEMORY_MAP.get_or_init(|| false);
+
+        TextChunks::open_with(path, chunk_bytes, map)
+    }
+
+    // Open a file, mapping it into memory if asked to and it's big enough
+    // for that to pay off.
+    fn open_with(
+        path: &Path,
+        chunk_bytes: usize,
+        map: bool,
+    ) -> io::Result<Option<TextChunks>> {
+        let mut file = File::open(path)?;
+        let mapped = if map && file.metadata()?.len() >= chunk_bytes as u64 {
+            map_file(&file, path)
+        } else {
+            None
+        };
+        let mut source = match mapped {
+            Some(mapped) => Source::Mapped(mapped, 0),
+            None => {
+                let mut bytes = Vec::<u8>::new();
+
+                read_up_to(&mut file, &mut bytes, chunk_bytes)?;
+                Source::Buffered(file, bytes)
+            }
+        };
+        let first = match &source {
+            Source::Buffered(_, bytes) => &bytes[..],
+            Source::Mapped(mapped, _) => &mapped[..mapped.len().min(chunk_bytes)],
+        };
+        let complete = first.len() < chunk_bytes;
+        let (encoding, bom) = match sniff_encoding(first, complete) {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+
+        match &mut source {
+            Source::Buffered(_, bytes) => {
+                bytes.drain(..bom);
+            }
+            Source::Mapped(_, position) => *position = bom,
+        }
+
+        Ok(Some(TextChunks {
+            source,
+            encoding,
+            decoder: encoding.new_decoder_without_bom_handling(),
+            chunk_bytes,
+            carry: String::new(),
+            done: false,
+        }))
+    }
+
+    // The name of the encoding the text is in.
+    pub fn encoding(&self) -> &'static str {
+        self.encoding.name()
+    }
+}
+
+impl Iterator for TextChunks {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<io::Result<String>> {
+        let chunk_bytes = self.chunk_bytes;
+
+        while !self.done {
+            let (bytes, last) = match &mut self.source {
+                // Top up the buffer, since dropping a byte-order mark can
+                // leave the first chunk short without the file having run
+                // out.
+                Source::Buffered(file, bytes) => {
+                    if let Err(e) = read_up_to(file, bytes, chunk_bytes) {
+                        self.done = true;
+                        return Some(Err(e));
+                    }
+
+                    (&bytes[..], bytes.len() < chunk_bytes)
+                }
+                Source::Mapped(mapped, position) => {
+                    let start = *position;
+
+                    *position = mapped.len().min(start + chunk_bytes);
+                    (&mapped[start..*position], *position == mapped.len())
+                }
+            };
+            let mut text = std::mem::take(&mut self.carry);
+
+            if let Some(needed) = self.decoder.max_utf8_buffer_length(bytes.len()) {
+                text.reserve(needed);
+            }
+
+            // With the space reserved, the decoder always uses every byte.
+            let _ = self.decoder.decode_to_string(bytes, &mut text, last);
+
+            if let Source::Buffered(_, bytes) = &mut self.source {
+                bytes.clear();
+            }
+
+            if last {
+                self.done = true;
+                return if text.is_empty() {
+                    None
+                } else {
+                    Some(Ok(text))
+                };
+            }
+
+            // Save whatever follows the last whitespace for the next chunk,
+            // since it might be the start of a word.  Text without any
+            // whitespace waits for more, unless there's already so much
+            // that we have to split it somewhere.
+            match text.char_indices().rev().find(|(_, c)| c.is_whitespace()) {
+                Some((cut, space)) => {
+                    self.carry = text.split_off(cut + space.len_utf8());
+                    return Some(Ok(text));
+                }
+                None if text.len() < chunk_bytes * 2 => self.carry = text,
+                None => return Some(Ok(text)),
+            }
+        }
+
+        None
+    }
+}
+
+// Map a file into memory, or nothing, if the system won't, in which case
+// we read it instead.
+fn map_file(file: &File, path: &Path) -> Option<Mmap> {
+    // Mapping is only unsafe in that the file can change underneath us,
+    // which is why it's optional.  A file cut short while we index it can
+    // take the whole daemon down.
+    match unsafe { Mmap::map(file) } {
+        Ok(mapped) => Some(mapped),
+        Err(e) => {
+            debug!("Can't map {}, so reading it instead: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+// Work out what encoding some text is in, from the start of a file, or all
+// of it, if `complete`, along with the length of any byte-order mark, or
+// nothing, for a binary file.  A byte-order mark settles the question, and
+// so does the text being valid UTF-8, but older files are often in
+// something like Latin-1 or Shift JIS, so we guess from the bytes.  Text
+// never has NUL bytes, so those mark a binary file.
+fn sniff_encoding(bytes: &[u8], complete: bool) -> Option<(&'static Encoding, usize)> {
+    if let Some(found) = Encoding::for_bom(bytes) {
+        return Some(found);
+    }
+
+    if bytes[..bytes.len().min(BINARY_SNIFF_BYTES)].contains(&0) {
+        return None;
+    }
+
+    match str::from_utf8(bytes) {
+        Ok(_) => Some((UTF_8, 0)),
+        // The start of a file can end partway through a character.
+        Err(e) if !complete && e.error_len().is_none() => Some((UTF_8, 0)),
+        Err(_) => {
+            let mut detector = EncodingDetector::new();
+
+            detector.feed(bytes, complete);
+            Some((detector.guess(None, true), 0))
+        }
+    }
+}
+
+// Read from a file until the buffer holds a given number of bytes, or the
+// file runs out.
+fn read_up_to(file: &mut File, bytes: &mut Vec<u8>, limit: usize) -> io::Result<()> {
+    let wanted = limit.saturating_sub(bytes.len()) as u64;
+
+    file.by_ref().take(wanted).read_to_end(bytes)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+
+    #[test]
+    fn read_text_decodes_legacy_encodings() {
+        let dir = ScratchDir::new("text");
+        let latin1 = dir.join("latin1.txt");
+        let utf16 = dir.join("utf16.txt");
+        let binary = dir.join("binary.dat");
+
+        fs::write(&latin1, b"Le caf\xe9 cr\xe8me est tr\xe8s bon, d\xe9j\xe0 vu.")
+            .unwrap();
+        fs::write(&utf16, b"\xff\xfeh\x00i\x00").unwrap();
+        fs::write(&binary, b"\x7fELF\x02\x01\x00\x00").unwrap();
+
+        let (text, encoding) = read_text(&latin1).unwrap().unwrap();
+
+        assert!(text.contains("café crème"));
+        assert_eq!(encoding, "windows-1252");
+        assert_eq!(read_text(&utf16).unwrap().unwrap(), ("hi".to_string(), "UTF-16LE"));
+        assert!(read_text(&binary).unwrap().is_none());
+    }
+
+    #[test]
+    fn offsets_count_the_bytes_on_disk() {
+        let dir = ScratchDir::new("offsets");
+        let (bom, utf16, latin1) =
+            (dir.join("bom.txt"), dir.join("16.txt"), dir.join("1.txt"));
+
+        fs::write(&bom, "\u{feff}café au lait").unwrap();
+        fs::write(&utf16, b"\xff\xfec\x00a\x00f\x00\xe9\x00 \x00a\x00u\x00").unwrap();
+        fs::write(&latin1, b"Le caf\xe9 cr\xe8me").unwrap();
+
+        // "au" comes right after "café ", which is six bytes of UTF-8.
+        assert_eq!(source_offsets(&bom, &[6, 0]).unwrap(), Some(vec![9, 3]));
+        assert_eq!(source_offsets(&utf16, &[6, 0]).unwrap(), Some(vec![12, 2]));
+        assert_eq!(source_offsets(&latin1, &[10]).unwrap(), Some(vec![9]));
+        // That's the middle of the "é," once it's decoded.
+        assert_eq!(source_offsets(&latin1, &[7]).unwrap(), None);
+    }
+
+    #[test]
+    fn content_hash_follows_the_bytes() {
+        let dir = ScratchDir::new("hash");
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+
+        fs::write(&a, "").unwrap();
+        // The published FNV-1a value for an empty input.
+        assert_eq!(content_hash(&a).unwrap(), "cbf29ce484222325");
+        assert_eq!(content_hash(&a).unwrap(), EMPTY_HASH);
+        fs::write(&a, "same words").unwrap();
+        fs::write(&b, "same words").unwrap();
+        assert_eq!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+        fs::write(&b, "same wordz").unwrap();
+        assert_ne!(content_hash(&a).unwrap(), content_hash(&b).unwrap());
+    }
+
+    #[test]
+    fn chunks_split_between_words() {
+        let dir = ScratchDir::new("chunks");
+        let utf8 = dir.join("utf8.txt");
+        let utf16 = dir.join("utf16.txt");
+        let text = "Crème brûlée for everyone,\nand déjà vu for dessert.";
+        let mut utf16_bytes = vec![0xff, 0xfe];
+
+        utf16_bytes.extend(text.encode_utf16().flat_map(|c| c.to_le_bytes()));
+        fs::write(&utf8, text).unwrap();
+        fs::write(&utf16, utf16_bytes).unwrap();
+
+        for (path, map) in [&utf8, &utf16]
+            .iter()
+            .flat_map(|path| [(path, false), (path, true)])
+        {
+            let chunks = TextChunks::open_with(path, 7, map)
+                .unwrap()
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+                .unwrap();
+
+            assert!(chunks.len() > 2);
+            assert!(chunks[..chunks.len() - 1]
+                .iter()
+                .all(|c| c.ends_with(char::is_whitespace)));
+            assert_eq!(chunks.concat(), text);
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::OpenOptions;
+use std::sync::Mutex;
+use tracing::subscriber::set_global_default;
+use tracing::{info, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
+
+// What to trace, without a `filter` item, which is everything of ours at
+// the `debug` level, where the spans are, and other crates' warnings.
+const DEFAULT_FILTER: &str = "warn,intern=debug";
+
+// Send the spans around folder scans, indexing, and queries, with their
+// timings, to a subscriber, as the `tracing` block asks.  Its `filter`
+// takes the same syntax as `RUST_LOG`, like `intern=debug`, and its `file`
+// is where the traces go, or the standard error, without one.  The log
+// file gets the same events either way.  Since flexi_logger already owns
+// the `log` side, the subscriber only hears from `tracing` itself.
+pub fn set_tracing(config: &gjson::Value) {
+    if !config.exists() {
+        return;
+    }
+
+    let filter_info = config.get("filter");
+    let directives = if filter_info.exists() {
+        filter_info.str()
+    } else {
+        DEFAULT_FILTER
+    };
+    let filter = EnvFilter::try_new(directives).unwrap_or_else(|e| {
+        warn!("Can't trace {}: {}", directives, e);
+        EnvFilter::new(DEFAULT_FILTER)
+    });
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE)
+        .with_ansi(false);
+    let file = config.get("file");
+    let result = if file.exists() {
+        match OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file.str())
+        {
+            Ok(out) => {
+                set_global_default(subscriber.with_writer(Mutex::new(out)).finish())
+            }
+            Err(e) => {
+                warn!("Can't write traces to {}: {}", file.str(), e);
+                return;
+            }
+        }
+    } else {
+        set_global_default(subscriber.with_writer(std::io::stderr).finish())
+    };
+
+    match result {
+        Ok(()) => info!("Tracing {}", directives),
+        Err(e) => warn!("Can't start tracing: {}", e),
+    }
+}
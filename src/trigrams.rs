@@ -0,0 +1,60 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::sync::OnceLock;
+use tracing::info;
+
+use crate::indexer::strip_accents;
+
+// Whether to keep the trigram index, which `@contains` needs.
+static TRIGRAMS: OnceLock<bool> = OnceLock::new();
+
+// Read the `trigrams` item, which keeps track of the three-character runs
+// in every indexed word, so that `@contains` can find words by any part
+// of them.  It makes the database bigger, so it's off by default.
+pub fn set_trigrams(config: &gjson::Value) {
+    let trigrams = config.get("trigrams").bool();
+
+    if trigrams {
+        info!("Keeping the trigram index");
+    }
+
+    TRIGRAMS.get_or_init(|| trigrams);
+}
+
+// Whether the trigram index is on.
+pub fn trigrams_enabled() -> bool {
+    *TRIGRAMS.get_or_init(|| false)
+}
+
+// Put a word, or part of one, in the form that the trigram index uses,
+// which ignores case and, unless the configuration says otherwise,
+// accents.
+pub fn fold(text: &str, accents: &Regex) -> String {
+    strip_accents(text, accents).to_lowercase()
+}
+
+// The runs of three characters in some folded text, which is nothing, for
+// text any shorter.
+pub fn trigrams(text: &str) -> HashSet<String> {
+    let chars = text.chars().collect::<Vec<_>>();
+
+    chars.windows(3).map(|w| w.iter().collect()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::accents;
+
+    #[test]
+    fn words_break_into_trigrams() {
+        let folded = fold("Ménage", &accents(&gjson::parse("{}")));
+        let grams = trigrams(&folded);
+
+        assert_eq!(folded, "menage");
+        assert_eq!(grams.len(), 4);
+        assert!(grams.contains("nag"));
+        assert!(trigrams("ab").is_empty());
+        assert_eq!(trigrams("aaaa"), HashSet::from(["aaa".to_string()]));
+    }
+}
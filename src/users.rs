@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use tracing::{debug, info};
+
+// Whether one daemon serves everybody on the machine, showing each of them
+// only the files they could read for themselves.
+static MULTI_USER: OnceLock<bool> = OnceLock::new();
+
+// Read the `multiUser` item, for running as a system service that indexes
+// folders belonging to several users.
+pub fn set_multi_user(config: &gjson::Value) {
+    let multi = config.get("multiUser").bool();
+
+    if multi {
+        info!("Filtering results by who's asking");
+    }
+
+    MULTI_USER.get_or_init(|| multi);
+}
+
+pub fn multi_user() -> bool {
+    *MULTI_USER.get_or_init(|| false)
+}
+
+// The tokens that clients can present, each with the folders it can see,
+// where a token without folders sees everything.
+static TOKENS: OnceLock<HashMap<String, Vec<PathBuf>>> = OnceLock::new();
+
+// Read the `tokens` block, which maps each token to a list of folders.
+// Once there are any tokens, every request needs one.
+pub fn set_tokens(config: &gjson::Value) {
+    let mut tokens = HashMap::<String, Vec<PathBuf>>::new();
+
+    config.get("tokens").each(|token, folders| {
+        tokens.insert(
+            token.to_string(),
+            folders
+                .array()
+                .iter()
+                .map(|f| PathBuf::from(f.to_string()))
+                .collect(),
+        );
+        true
+    });
+
+    if !tokens.is_empty() {
+        info!("Requiring one of {} tokens", tokens.len());
+    }
+
+    TOKENS.get_or_init(|| tokens);
+}
+
+// Split the token off the front of a request, written as `token:secret`,
+// from the rest of the request.
+pub fn split_token(request: &str) -> (Option<&str>, &str) {
+    match request.strip_prefix("token:") {
+        Some(rest) => {
+            let (token, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+
+            (Some(token), rest.trim_start())
+        }
+        None => (None, request),
+    }
+}
+
+// Narrow whoever is asking to the folders that their token can see, or
+// explain why not, if tokens are required and theirs isn't one of them.
+// Outside of multi-user mode, a limited token reads as the daemon's owner.
+pub fn authorize(
+    user: Option<User>,
+    token: Option<&str>,
+) -> Result<Option<User>, String> {
+    let tokens = TOKENS.get_or_init(HashMap::new);
+
+    if tokens.is_empty() {
+        return Ok(user);
+    }
+
+    let folders = match token.and_then(|t| tokens.get(t)) {
+        Some(folders) => folders,
+        None if token.is_some() => return Err("unknown token".to_string()),
+        None => return Err("a token is required".to_string()),
+    };
+
+    if folders.is_empty() {
+        return Ok(user);
+    }
+
+    let mut user = user.unwrap_or_else(|| User::new(own_uid()));
+
+    user.folders = Some(folders.clone());
+    Ok(Some(user))
+}
+
+#[cfg(unix)]
+fn own_uid() -> u32 {
+    use std::os::unix::fs::MetadataExt;
+
+    fs::metadata("/proc/self").map_or(0, |m| m.uid())
+}
+
+#[cfg(not(unix))]
+fn own_uid() -> u32 {
+    0
+}
+
+// Someone asking for results, with the groups they belong to, which is
+// what deciding whether they can read a file takes, and the folders their
+// token limits them to, if any.
+#[derive(Clone, Debug)]
+pub struct User {
+    uid: u32,
+    groups: Vec<u32>,
+    folders: Option<Vec<PathBuf>>,
+}
+
+impl User {
+    // Look up a user's groups, going by /etc/passwd and /etc/group, so
+    // that users only known to a directory service only get the files
+    // that they own or that anybody can read.
+    pub fn new(uid: u32) -> User {
+        let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+        let group = fs::read_to_string("/etc/group").unwrap_or_default();
+        let account = passwd
+            .lines()
+            .map(|l| l.split(':').collect::<Vec<_>>())
+            .find(|f| f.len() > 3 && f[2].parse() == Ok(uid));
+        let mut groups = Vec::<u32>::new();
+
+        if let Some(account) = account {
+            groups.extend(account[3].parse::<u32>().ok());
+            groups.extend(group.lines().filter_map(|l| {
+                let fields = l.split(':').collect::<Vec<_>>();
+
+                if fields.len() > 3 && fields[3].split(',').any(|m| m == account[0]) {
+                    fields[2].parse::<u32>().ok()
+                } else {
+                    None
+                }
+            }));
+        }
+
+        User {
+            uid,
+            groups,
+            folders: None,
+        }
+    }
+
+    // Whoever is on the other end of a local connection, or nothing, if
+    // we can't tell.
+    pub fn connected(local: SocketAddr, peer: SocketAddr) -> Option<User> {
+        let uid = peer_uid(local, peer);
+
+        debug!("{} is user {:?}", peer, uid);
+        uid.map(User::new)
+    }
+
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    // The folders that the user's token limits them to, if it does.
+    pub fn folders(&self) -> Option<&[PathBuf]> {
+        self.folders.as_deref()
+    }
+
+    // The superuser can read everything, so sees the whole index, unless
+    // their token says otherwise.
+    pub fn sees_everything(&self) -> bool {
+        self.uid == 0 && self.folders.is_none()
+    }
+
+    // Whether the user runs the daemon, or is the superuser, who could stop
+    // it anyway, without a token narrowing what they see, which is who gets
+    // to pause and resume indexing for everybody.
+    pub fn runs_the_daemon(&self) -> bool {
+        (self.uid == own_uid() || self.uid == 0) && self.folders.is_none()
+    }
+
+    // Whether the user could open a file for reading, which takes reading
+    // the file and getting into every folder on the way to it, and whether
+    // their token lets them see it.
+    pub fn can_read(&self, path: &Path) -> bool {
+        if let Some(folders) = &self.folders {
+            if !folders.iter().any(|f| path.starts_with(f)) {
+                return false;
+            }
+        }
+
+        if self.uid == 0 {
+            return true;
+        }
+
+        path.ancestors()
+            .skip(1)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .all(|dir| self.allowed(dir, 0o1))
+            && self.allowed(path, 0o4)
+    }
+
+    // Whether the permission bits for the owner, the group, or everybody
+    // else, whichever applies to the user, allow some kind of access.
+    #[cfg(unix)]
+    fn allowed(&self, path: &Path, bits: u32) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => return false,
+        };
+        let shift = if meta.uid() == self.uid {
+            6
+        } else if self.groups.contains(&meta.gid()) {
+            3
+        } else {
+            0
+        };
+
+        meta.mode() >> shift & bits == bits
+    }
+
+    #[cfg(not(unix))]
+    fn allowed(&self, _path: &Path, _bits: u32) -> bool {
+        false
+    }
+}
+
+// Whether someone can see a file, where nobody in particular, outside of
+// multi-user mode, sees everything.
+pub fn visible(user: Option<&User>, path: &str) -> bool {
+    user.is_none_or(|u| u.can_read(Path::new(path)))
+}
+
+// Find out who owns the client's end of a local connection, which Linux
+// lists, along with every other socket, in /proc/net.  Our end of the
+// connection is the client's remote address.
+#[cfg(target_os = "linux")]
+fn peer_uid(local: SocketAddr, peer: SocketAddr) -> Option<u32> {
+    let table = match peer {
+        SocketAddr::V4(_) => "/proc/net/tcp",
+        SocketAddr::V6(_) => "/proc/net/tcp6",
+    };
+    let sockets = fs::read_to_string(table).ok()?;
+    let (ours, theirs) = (proc_address(local), proc_address(peer));
+
+    sockets.lines().skip(1).find_map(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        if fields.len() > 7
+            && fields[1].eq_ignore_ascii_case(&theirs)
+            && fields[2].eq_ignore_ascii_case(&ours)
+        {
+            fields[7].parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peer_uid(_local: SocketAddr, _peer: SocketAddr) -> Option<u32> {
+    None
+}
+
+// Write an address the way /proc/net does, as the address's 32-bit words
+// in the machine's byte order, and the port, all in hexadecimal.
+#[cfg(target_os = "linux")]
+fn proc_address(addr: SocketAddr) -> String {
+    let octets = match addr {
+        SocketAddr::V4(a) => a.ip().octets().to_vec(),
+        SocketAddr::V6(a) => a.ip().octets().to_vec(),
+    };
+    let words = octets
+        .chunks(4)
+        .map(|w| format!("{:08X}", u32::from_ne_bytes([w[0], w[1], w[2], w[3]])))
+        .collect::<String>();
+
+    format!("{}:{:04X}", words, addr.port())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::testing::ScratchDir;
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    #[test]
+    fn finds_who_is_on_the_other_end() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, peer) = listener.accept().unwrap();
+        let uid = fs::metadata("/proc/self").unwrap().uid();
+
+        assert_eq!(peer, client.local_addr().unwrap());
+        assert_eq!(peer_uid(server.local_addr().unwrap(), peer), Some(uid));
+    }
+
+    #[test]
+    fn permission_bits_decide_who_can_read() {
+        let dir = ScratchDir::new("users");
+        let note = dir.join("note.md");
+
+        fs::write(&note, "private").unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&note, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let meta = fs::metadata(&note).unwrap();
+        let owner = User {
+            uid: meta.uid(),
+            groups: Vec::new(),
+            folders: None,
+        };
+        let member = User {
+            uid: meta.uid() + 1,
+            groups: vec![meta.gid()],
+            folders: None,
+        };
+        let stranger = User {
+            uid: meta.uid() + 2,
+            groups: Vec::new(),
+            folders: None,
+        };
+
+        assert!(owner.can_read(&note));
+        assert!(member.can_read(&note));
+        assert!(!stranger.can_read(&note));
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        assert!(!member.can_read(&note));
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn tokens_limit_what_users_see() {
+        let limited = User {
+            uid: 0,
+            groups: Vec::new(),
+            folders: Some(vec![PathBuf::from("/home/me/work")]),
+        };
+
+        assert_eq!(split_token("token:abc @stats"), (Some("abc"), "@stats"));
+        assert_eq!(split_token("tomatoes"), (None, "tomatoes"));
+        assert!(limited.can_read(Path::new("/home/me/work/plan.md")));
+        assert!(!limited.can_read(Path::new("/home/me/workshop/plan.md")));
+        assert!(!limited.sees_everything());
+        assert!(!limited.runs_the_daemon());
+    }
+
+    #[test]
+    fn only_the_daemons_owner_runs_it() {
+        let owner = User::new(own_uid());
+        let other = User::new(own_uid() + 1000);
+
+        assert!(owner.runs_the_daemon());
+        assert!(!other.runs_the_daemon());
+        assert!(User::new(0).runs_the_daemon());
+    }
+}
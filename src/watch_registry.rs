@@ -0,0 +1,95 @@
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+// Keeps track of the watches we want versus the watches we actually
+// have, since the system (inotify, in particular) silently drops a watch when its file is deleted
+// (even if it's immediately recreated, as many editors do on save) or
+// when a watch errors out.
+pub struct WatchRegistry {
+    watcher: RecommendedWatcher,
+    desired: HashMap<PathBuf, RecursiveMode>,
+    active: HashSet<PathBuf>,
+}
+
+impl WatchRegistry {
+    pub fn new(watcher: RecommendedWatcher) -> WatchRegistry {
+        WatchRegistry {
+            watcher,
+            desired: HashMap::new(),
+            active: HashSet::new(),
+        }
+    }
+
+    // Watch a path, remembering that we want it watched even if the
+    // attempt fails, so that we can try again later.  The exception is
+    // running out of watches, where retrying would only fail again; the
+    // caller needs to poll those paths, instead.
+    pub fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
+        self.desired.insert(path.to_path_buf(), mode);
+        match self.watcher.watch(path, mode) {
+            Ok(_) => {
+                self.active.insert(path.to_path_buf());
+                Ok(())
+            }
+            Err(e) => {
+                self.active.remove(path);
+                if is_limit_error(&e) {
+                    self.desired.remove(path);
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    // Note that the system no longer watches a path, because the file
+    // went away or the watch reported an error.
+    pub fn dropped(&mut self, path: &Path) {
+        if self.active.remove(path) {
+            debug!("lost watch on {:?}", path);
+        }
+    }
+
+    // Try to re-establish every desired watch that isn't active, returning
+    // the paths that came back, which may have changed in the meantime.
+    pub fn restore(&mut self) -> Vec<PathBuf> {
+        let missing = self
+            .desired
+            .iter()
+            .filter(|(path, _)| !self.active.contains(*path) && path.exists())
+            .map(|(path, mode)| (path.to_path_buf(), *mode))
+            .collect::<Vec<_>>();
+        let mut restored = Vec::<PathBuf>::new();
+
+        for (path, mode) in missing {
+            match self.watcher.watch(&path, mode) {
+                Ok(_) => {
+                    info!("Restored watch on {:?}", path);
+                    self.active.insert(path.to_path_buf());
+                    restored.push(path);
+                }
+                Err(e) => warn!("Still can't watch {:?}: {}", path, e),
+            }
+        }
+
+        restored
+    }
+
+    // The number of watches currently in place.
+    pub fn active_count(&self) -> usize {
+        self.active.len()
+    }
+}
+
+// Whether the error means we've exhausted the system's supply of watches,
+// which inotify reports as ENOSPC (see fs.inotify.max_user_watches).
+pub fn is_limit_error(error: &notify::Error) -> bool {
+    const ENOSPC: i32 = 28;
+
+    match error {
+        notify::Error::Io(e) => e.raw_os_error() == Some(ENOSPC),
+        _ => false,
+    }
+}
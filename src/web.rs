@@ -0,0 +1,492 @@
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info};
+
+use crate::config::RankingConfig;
+use crate::dates::{day_timestamp, format_timestamp, parse_date};
+use crate::feed::{atom, recent_files, DEFAULT_FEED_SIZE};
+use crate::json;
+use crate::metrics::{index_rows_read, record_query_time, render_metrics};
+use crate::normalizer::Normalizer;
+use crate::query::{
+    best_match, find_line, index_snippet, run_search, Cancellation, SearchCache,
+};
+use crate::server::{log_query, max_results, stats_lines};
+use crate::store::{MonitoredFile, Store};
+use crate::users::{authorize, multi_user, visible, User};
+
+// The dashboard is a single page that talks to the JSON endpoints below.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+// The most we'll read of a request's line and headers.
+const MAX_REQUEST_BYTES: usize = 16384;
+
+// How long a client has to send its request, and then to read the
+// response, since either one holds up the daemon.
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Open the optional HTTP listener, if the configuration asks for one.
+pub fn start_listener(http_info: &gjson::Value) -> Option<TcpListener> {
+    if !http_info.exists() {
+        return None;
+    }
+
+    let address = if http_info.get("address").exists() {
+        http_info.get("address").to_string()
+    } else {
+        "127.0.0.1".to_string()
+    };
+    let addr = format!("{}:{}", address, http_info.get("port").u32());
+
+    match TcpListener::bind(&addr) {
+        Ok(listener) => {
+            listener.set_nonblocking(true).unwrap();
+            info!("Serving HTTP on {}", addr);
+            Some(listener)
+        }
+        Err(e) => {
+            error!("Can't serve HTTP on {}: {}", addr, e);
+            None
+        }
+    }
+}
+
+// Answer any pending HTTP requests: the metrics, the dashboard, the
+// searches and statistics behind it, and the feed of recent changes.
+// Traffic here is light, so we handle each connection synchronously
+// rather than registering it with the poll, with time limits, so that a
+// client that stalls can't hold up the daemon for long.
+#[allow(clippy::too_many_arguments)]
+pub fn serve(
+    listener: &TcpListener,
+    db_path: &Path,
+    store: &Store,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    run_id: i64,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+) {
+    loop {
+        let mut client = match listener.accept() {
+            Ok((client, _addr)) => client,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                debug!("http: {:?}", e);
+                return;
+            }
+        };
+        client.set_nonblocking(false).unwrap();
+        client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        client.set_write_timeout(Some(WRITE_TIMEOUT)).unwrap();
+
+        let request = match read_request(&mut client) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("http: {:?}", e);
+                continue;
+            }
+        };
+        let target = request.split_whitespace().nth(1).unwrap_or("");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+        match path {
+            "/" => respond(&mut client, "200 OK", "text/html; charset=utf-8", DASHBOARD),
+            "/metrics" | "/api/stats" if !sees_the_index(&client, query) => {
+                respond(&mut client, "403 Forbidden", "text/plain", "")
+            }
+            "/metrics" => respond(
+                &mut client,
+                "200 OK",
+                "text/plain; version=0.0.4",
+                &render_metrics(db_path),
+            ),
+            "/api/stats" => match stats_lines(store, run_id) {
+                Ok(lines) => {
+                    let lines = lines.iter().map(|l| json::quote(l)).collect::<Vec<_>>();
+
+                    respond(
+                        &mut client,
+                        "200 OK",
+                        "application/json",
+                        &format!(r#"{{"lines":[{}]}}"#, lines.join(",")),
+                    );
+                }
+                Err(e) => {
+                    error!("Unable to read statistics: {}", e);
+                    respond(&mut client, "500 Internal Server Error", "text/plain", "");
+                }
+            },
+            "/api/search" => {
+                let start = Instant::now();
+                let rows = index_rows_read();
+                let Some(user) = requesting_user(&client, query) else {
+                    respond(&mut client, "403 Forbidden", "text/plain", "");
+                    continue;
+                };
+                let _limit =
+                    store.limit_to_folders(user.as_ref().and_then(User::folders));
+                let (body, results) = match search(
+                    query,
+                    store,
+                    punc,
+                    accents,
+                    stemmer,
+                    ranking,
+                    cache,
+                    user.as_ref(),
+                ) {
+                    Ok(found) => found,
+                    Err(e) => {
+                        error!("Unable to search: {}", e);
+                        respond(
+                            &mut client,
+                            "500 Internal Server Error",
+                            "text/plain",
+                            "",
+                        );
+                        continue;
+                    }
+                };
+                let terms = parameter(query, "q").unwrap_or_default();
+
+                record_query_time(start.elapsed());
+                log_query(
+                    store,
+                    &terms,
+                    user.as_ref(),
+                    start.elapsed(),
+                    results,
+                    index_rows_read() - rows,
+                );
+                respond(&mut client, "200 OK", "application/json", &body);
+            }
+            "/feed" => {
+                let Some(user) = requesting_user(&client, query) else {
+                    respond(&mut client, "403 Forbidden", "text/plain", "");
+                    continue;
+                };
+                let _limit =
+                    store.limit_to_folders(user.as_ref().and_then(User::folders));
+
+                match feed(query, store, punc, accents, stemmer, ranking, cache, user) {
+                    Ok(body) => respond(
+                        &mut client,
+                        "200 OK",
+                        "application/atom+xml; charset=utf-8",
+                        &body,
+                    ),
+                    Err(e) => {
+                        error!("Unable to list recent changes: {}", e);
+                        respond(
+                            &mut client,
+                            "500 Internal Server Error",
+                            "text/plain",
+                            "",
+                        );
+                    }
+                }
+            }
+            _ => respond(&mut client, "404 Not Found", "text/plain", ""),
+        }
+    }
+}
+
+// Read a request's line and headers, which can arrive in pieces, up to the
+// blank line that ends them, giving up on a client that sends too much or
+// takes too long about it.
+fn read_request(client: &mut TcpStream) -> io::Result<String> {
+    let started = Instant::now();
+    let mut request = Vec::<u8>::new();
+    let mut buffer = [0; 4096];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_BYTES || started.elapsed() > READ_TIMEOUT {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too long or too slow",
+            ));
+        }
+
+        match client.read(&mut buffer)? {
+            0 => break,
+            size => request.extend_from_slice(&buffer[..size]),
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&request).to_string())
+}
+
+// Whoever is searching from the dashboard, going by who owns the browser's
+// end of the connection, or nothing, if we can't tell.
+fn requester(client: &TcpStream) -> Option<User> {
+    User::connected(client.local_addr().ok()?, client.peer_addr().ok()?)
+}
+
+// Who's asking, in multi-user mode, going by the connection and then by the
+// `token` parameter, which is nobody in particular otherwise, or nothing,
+// if they can't ask at all.
+fn requesting_user(client: &TcpStream, query: &str) -> Option<Option<User>> {
+    let user = if multi_user() {
+        Some(requester(client)?)
+    } else {
+        None
+    };
+
+    authorize(user, parameter(query, "token").as_deref()).ok()
+}
+
+// Whether whoever is asking can see the whole index, which counts of its
+// words and files would otherwise give away, like `@stats`.
+fn sees_the_index(client: &TcpStream, query: &str) -> bool {
+    match requesting_user(client, query) {
+        Some(Some(user)) => user.sees_everything(),
+        Some(None) => true,
+        None => false,
+    }
+}
+
+// Run a search from the dashboard, narrowed by the `ext` (a comma-separated
+// list of extensions), `since`, and `until` (dates, inclusive) parameters,
+// and to what the user can read, in multi-user mode, returning the JSON
+// along with how many results it has.  The `max` parameter, or else the
+// `maxResults` item, cuts the results short, which the JSON mentions.
+#[allow(clippy::too_many_arguments)]
+fn search(
+    query: &str,
+    store: &Store,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    user: Option<&User>,
+) -> rusqlite::Result<(String, usize)> {
+    let terms = parameter(query, "q").unwrap_or_default();
+    let extensions = parameter(query, "ext")
+        .unwrap_or_default()
+        .split(',')
+        .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect::<Vec<_>>();
+    let since = parameter(query, "since")
+        .and_then(|d| parse_date(&d))
+        .map(day_timestamp);
+    let until = parameter(query, "until")
+        .and_then(|d| parse_date(&d)?.succ_opt())
+        .map(day_timestamp);
+    let max = parameter(query, "max")
+        .and_then(|m| m.parse::<usize>().ok())
+        .or(max_results());
+    let mut results = Vec::<String>::new();
+    let mut truncated = None;
+
+    if terms.trim().is_empty() {
+        return Ok((r#"{"results":[]}"#.to_string(), 0));
+    }
+
+    let (serps, sorted) = run_search(
+        &terms,
+        punc,
+        accents,
+        stemmer,
+        store,
+        ranking,
+        cache,
+        &Cancellation::never(),
+    )?;
+
+    for path in sorted.iter().filter(|p| !p.is_empty()) {
+        let extension = Path::new(path)
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        let modified = serps[path]
+            .values()
+            .flatten()
+            .map(|w| w.modified as i64)
+            .next()
+            .unwrap_or(0);
+
+        if (!extensions.is_empty() && !extensions.contains(&extension))
+            || !visible(user, path)
+            || since.is_some_and(|s| modified < s)
+            || until.is_some_and(|u| modified >= u)
+        {
+            continue;
+        }
+
+        if max.is_some_and(|m| results.len() >= m) {
+            truncated = max;
+            break;
+        }
+
+        let (line, text) = best_match(&serps[path])
+            .and_then(|found| {
+                find_line(&store.locate_file(path), found, punc)
+                    .or_else(|| Some((0, index_snippet(store, found)?)))
+            })
+            .unwrap_or((0, String::new()));
+
+        let indexed = match store.select_file(path) {
+            Ok(Some(MonitoredFile {
+                indexed_at: Some(at),
+                ..
+            })) => json::quote(&format_timestamp(at as i64)),
+            _ => "null".to_string(),
+        };
+
+        results.push(format!(
+            r#"{{"path":{},"modified":{},"indexed":{},"line":{},"text":{}}}"#,
+            json::quote(path),
+            json::quote(&format_timestamp(modified)),
+            indexed,
+            line,
+            json::quote(text.trim())
+        ));
+    }
+
+    let body = match truncated {
+        Some(max) => format!(
+            r#"{{"results":[{}],"truncated":{}}}"#,
+            results.join(","),
+            max
+        ),
+        None => format!(r#"{{"results":[{}]}}"#, results.join(",")),
+    };
+
+    Ok((body, results.len()))
+}
+
+// List the files that changed most recently as an Atom feed, following a
+// search, if the `q` parameter has one.  The `max` parameter, or else the
+// `maxResults` item, says how many files to list.
+#[allow(clippy::too_many_arguments)]
+fn feed(
+    query: &str,
+    store: &Store,
+    punc: &Regex,
+    accents: &Regex,
+    stemmer: &dyn Normalizer,
+    ranking: &RankingConfig,
+    cache: &mut SearchCache,
+    user: Option<User>,
+) -> rusqlite::Result<String> {
+    let terms = parameter(query, "q").unwrap_or_default();
+    let max = parameter(query, "max")
+        .and_then(|m| m.parse::<usize>().ok())
+        .or(max_results())
+        .unwrap_or(DEFAULT_FEED_SIZE);
+    let matching = if terms.trim().is_empty() {
+        None
+    } else {
+        let (_serps, sorted) = run_search(
+            &terms,
+            punc,
+            accents,
+            stemmer,
+            store,
+            ranking,
+            cache,
+            &Cancellation::never(),
+        )?;
+
+        Some(sorted.into_iter().collect::<HashSet<_>>())
+    };
+    let files = recent_files(store, user.as_ref(), matching.as_ref(), max)?;
+
+    Ok(atom(terms.trim(), &files))
+}
+
+// Find a parameter in a URL's query string, decoding it.
+fn parameter(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| decode(value))
+}
+
+// Undo a form's URL encoding, where a plus is a space and anything else
+// unusual is a percent sign and two hex digits.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::<u8>::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or("");
+
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+// Send a complete response and let the connection close.
+fn respond(client: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+    let response = format!(
+        concat!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+            "Connection: close\r\n\r\n{}"
+        ),
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    if let Err(e) = client.write_all(response.as_bytes()) {
+        debug!("http: {:?}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn requests_can_arrive_in_pieces() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sender = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut client, _) = listener.accept().unwrap();
+
+        client.set_read_timeout(Some(READ_TIMEOUT)).unwrap();
+        sender.write_all(b"GET /api/sea").unwrap();
+
+        let rest = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.write_all(b"rch?q=fox HTTP/1.1\r\n\r\n").unwrap();
+            sender
+        });
+        let request = read_request(&mut client).unwrap();
+
+        assert!(request.starts_with("GET /api/search?q=fox "));
+
+        let mut sender = rest.join().unwrap();
+
+        sender.write_all(&[b'x'; MAX_REQUEST_BYTES + 1]).unwrap();
+        assert!(read_request(&mut client).is_err());
+    }
+}
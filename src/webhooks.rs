@@ -0,0 +1,272 @@
+use regex::Regex;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+use tracing::{debug, error, info, warn};
+
+use crate::config::RankingConfig;
+use crate::json;
+use crate::normalizer::Normalizer;
+use crate::query::{query_stems, run_search, Cancellation, SearchCache};
+use crate::store::{ChangeKind, IndexChange, Store};
+use crate::subscriptions::StandingQuery;
+
+// How long to wait on a webhook's server before giving up on it.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A URL from the `webhooks` block, with the changes it wants to hear about
+// and, optionally, the query a file needs to match.
+struct Webhook {
+    url: String,
+    events: Vec<ChangeKind>,
+    query: Option<(String, StandingQuery)>,
+}
+
+// The configured webhooks, along with the thread that calls them, so that
+// a slow server never holds up indexing.
+pub struct Webhooks {
+    hooks: Vec<Webhook>,
+    sender: Sender<(String, String)>,
+}
+
+impl Webhooks {
+    // Read the `webhooks` block, a list of URLs, each with an optional list
+    // of `events` (`add`, `update`, and `remove`, defaulting to all three)
+    // and an optional `query`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        webhooks_info: &gjson::Value,
+        punc: &Regex,
+        accents: &Regex,
+        stemmer: &dyn Normalizer,
+        store: &Store,
+        ranking: &RankingConfig,
+        cache: &mut SearchCache,
+    ) -> Webhooks {
+        let mut hooks = Vec::<Webhook>::new();
+        let (sender, receiver) = channel::<(String, String)>();
+
+        for hook in webhooks_info.array() {
+            let url = hook.get("url").to_string();
+            let query = hook.get("query");
+            let events = if hook.get("events").exists() {
+                hook.get("events")
+                    .array()
+                    .iter()
+                    .filter_map(|e| change_kind(e.str()))
+                    .collect()
+            } else {
+                vec![ChangeKind::Added, ChangeKind::Updated, ChangeKind::Removed]
+            };
+
+            if split_url(&url).is_none() {
+                warn!("Ignoring webhook with an unusable URL: '{}'", url);
+                continue;
+            }
+
+            let query = if query.exists() {
+                let name = query.to_string();
+                let stems = query_stems(&name, punc, accents, stemmer);
+                let serps = match run_search(
+                    &name,
+                    punc,
+                    accents,
+                    stemmer,
+                    store,
+                    ranking,
+                    cache,
+                    &Cancellation::never(),
+                ) {
+                    Ok((serps, _sorted)) => serps,
+                    Err(e) => {
+                        error!("Unable to search for webhook '{}': {}", name, e);
+                        continue;
+                    }
+                };
+
+                Some((name, StandingQuery::new(stems, serps.into_keys().collect())))
+            } else {
+                None
+            };
+
+            info!("Calling {} on index changes", url);
+            hooks.push(Webhook { url, events, query });
+        }
+
+        thread::spawn(move || {
+            for (url, body) in receiver {
+                match post(&url, &body) {
+                    Ok(status) if (200..300).contains(&status) => {
+                        debug!("{} answered {}", url, status)
+                    }
+                    Ok(status) => warn!("Webhook {} answered {}", url, status),
+                    Err(e) => error!("Unable to call webhook {}: {}", url, e),
+                }
+            }
+        });
+
+        Webhooks { hooks, sender }
+    }
+
+    // Queue a call to every webhook interested in a change.  Webhooks with
+    // a query hear about files that match it, or that just stopped.
+    pub fn check(&mut self, change: &IndexChange) {
+        for hook in self.hooks.iter_mut() {
+            let body = match &mut hook.query {
+                Some((name, query)) => {
+                    let (before, after) = query.observe(change);
+
+                    if !before && !after {
+                        continue;
+                    }
+
+                    payload(change, Some((name, after)))
+                }
+                None => payload(change, None),
+            };
+
+            if hook.events.contains(&change.kind) {
+                self.sender.send((hook.url.to_string(), body)).unwrap();
+            }
+        }
+    }
+}
+
+// Interpret an event name from the configuration.
+fn change_kind(name: &str) -> Option<ChangeKind> {
+    [ChangeKind::Added, ChangeKind::Updated, ChangeKind::Removed]
+        .iter()
+        .copied()
+        .find(|k| k.name() == name)
+}
+
+// Describe a change for a webhook, with the query it was watching and
+// whether the file matches it now, if there is one.
+fn payload(change: &IndexChange, query: Option<(&str, bool)>) -> String {
+    let mut body = format!(
+        r#"{{"event":{},"path":{}"#,
+        json::quote(change.kind.name()),
+        json::quote(&change.path)
+    );
+
+    if let Some((name, matches)) = query {
+        body.push_str(&format!(
+            r#","query":{},"matches":{}"#,
+            json::quote(name),
+            matches
+        ));
+    }
+
+    body.push('}');
+    body
+}
+
+// Break an `http://` URL into the host (with any port) and the path.  We
+// don't speak TLS, which is fine for the local services webhooks mostly
+// talk to.
+pub fn split_url(url: &str) -> Option<(&str, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => (&rest[..slash], &rest[slash..]),
+        None => (rest, "/"),
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some((host, path))
+    }
+}
+
+// Send a JSON body to a URL, returning the HTTP status of the answer.
+pub fn post(url: &str, body: &str) -> io::Result<u16> {
+    let (host, path) = split_url(url)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not an http URL"))?;
+    let address = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address"))?;
+    let mut stream = TcpStream::connect_timeout(&address, WEBHOOK_TIMEOUT)?;
+    let request = format!(
+        concat!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n",
+            "Content-Length: {}\r\nConnection: close\r\n\r\n{}"
+        ),
+        path,
+        host,
+        body.len(),
+        body
+    );
+    let mut status = String::new();
+
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.write_all(request.as_bytes())?;
+    BufReader::new(stream).read_line(&mut status)?;
+    status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, status.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn split_url_needs_plain_http() {
+        assert_eq!(
+            split_url("http://localhost:8123/api/hook"),
+            Some(("localhost:8123", "/api/hook"))
+        );
+        assert_eq!(split_url("http://example.com"), Some(("example.com", "/")));
+        assert_eq!(split_url("https://example.com/"), None);
+        assert_eq!(split_url("http:///hook"), None);
+    }
+
+    #[test]
+    fn post_sends_the_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::<u8>::new();
+            let mut buffer = [0; 512];
+
+            while !request.ends_with(b"}") {
+                let size = stream.read(&mut buffer).unwrap();
+
+                request.extend_from_slice(&buffer[..size]);
+            }
+
+            stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        let change = IndexChange {
+            path: "/notes/a.md".to_string(),
+            kind: ChangeKind::Removed,
+            stems: HashSet::new(),
+        };
+        let body = payload(&change, Some(("DEADLINE", false)));
+
+        assert_eq!(post(&url, &body).unwrap(), 204);
+
+        let request = server.join().unwrap();
+
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            r#""path":"/notes/a.md","query":"DEADLINE","matches":false}"#
+        ));
+    }
+}